@@ -0,0 +1,187 @@
+//! Files fetched from a server kept on disk, sent again only when the server's copy changed.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    get_file, get_file_if_changed, hash_reader, ConnectionInfo, Fetched, ProtocolResult, Transport,
+};
+
+/// Where the cache records what it holds, next to the cached files.
+const INDEX_FILE: &str = "index.json";
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// Hash of the contents, sent to the server to validate the copy.
+    hash: String,
+    size: u64,
+    /// Tick of the last read, the smallest is evicted first.
+    last_used: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    tick: u64,
+}
+
+/// Read files by name through a local cache of at most `max_bytes`.
+///
+/// The first read of a file downloads it, later reads ask the server whether it changed
+/// and only download it again if it did. Least recently read files are evicted once the
+/// cache grows past `max_bytes`, a single file bigger than that is never kept.
+pub struct ReadThroughCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Index,
+}
+
+impl ReadThroughCache {
+    /// Open the cache in `dir`, creating it if needed, and keeping what an earlier run left.
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut index: Index = match fs::read(dir.join(INDEX_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_default(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Index::default(),
+            Err(e) => return Err(e),
+        };
+        // Forget files removed behind our back
+        index
+            .entries
+            .retain(|name, _| cached_path(&dir, name).is_file());
+
+        Ok(Self {
+            dir,
+            max_bytes,
+            index,
+        })
+    }
+
+    /// The contents of `file_name`, `None` if the server does not have it.
+    pub fn read<S: Transport>(
+        &mut self,
+        stream: &S,
+        info: &ConnectionInfo,
+        file_name: &str,
+    ) -> ProtocolResult<Option<Vec<u8>>> {
+        let path = cached_path(&self.dir, file_name);
+
+        let fetched = match self.index.entries.get(file_name) {
+            Some(entry) => get_file_if_changed(stream, info, file_name, &entry.hash)?,
+            None => Fetched::Changed(get_file(stream, info, file_name)?),
+        };
+
+        match fetched {
+            Fetched::NotModified => match fs::read(&path) {
+                Ok(contents) => {
+                    self.touch(file_name);
+                    self.save()?;
+                    Ok(Some(contents))
+                }
+                // Lost since it was validated, fetch it again in full
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.index.entries.remove(file_name);
+                    self.read(stream, info, file_name)
+                }
+                Err(e) => Err(e.into()),
+            },
+            Fetched::Changed(None) => {
+                self.remove(file_name)?;
+                Ok(None)
+            }
+            Fetched::Changed(Some(contents)) => {
+                self.insert(file_name, &contents)?;
+                Ok(Some(contents))
+            }
+        }
+    }
+
+    /// Whether `file_name` is held locally, without asking the server.
+    pub fn contains(&self, file_name: &str) -> bool {
+        self.index.entries.contains_key(file_name)
+    }
+
+    /// Bytes of file contents held in the cache.
+    pub fn used_bytes(&self) -> u64 {
+        self.index.entries.values().map(|entry| entry.size).sum()
+    }
+
+    /// Drop every cached file.
+    pub fn clear(&mut self) -> io::Result<()> {
+        let names: Vec<String> = self.index.entries.keys().cloned().collect();
+        for name in names {
+            self.remove(&name)?;
+        }
+        Ok(())
+    }
+
+    fn touch(&mut self, file_name: &str) {
+        self.index.tick += 1;
+        if let Some(entry) = self.index.entries.get_mut(file_name) {
+            entry.last_used = self.index.tick;
+        }
+    }
+
+    fn insert(&mut self, file_name: &str, contents: &[u8]) -> io::Result<()> {
+        let size = contents.len() as u64;
+        if size > self.max_bytes {
+            return self.remove(file_name);
+        }
+
+        self.index.entries.remove(file_name);
+        while self.used_bytes() + size > self.max_bytes {
+            let Some(oldest) = self
+                .index
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(name, _)| name.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest)?;
+        }
+
+        fs::write(cached_path(&self.dir, file_name), contents)?;
+        self.index.entries.insert(
+            file_name.to_string(),
+            Entry {
+                hash: hash_reader(contents, |_| {})?,
+                size,
+                last_used: 0,
+            },
+        );
+        self.touch(file_name);
+        self.save()
+    }
+
+    fn remove(&mut self, file_name: &str) -> io::Result<()> {
+        if self.index.entries.remove(file_name).is_none() {
+            return Ok(());
+        }
+
+        match fs::remove_file(cached_path(&self.dir, file_name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let bytes = serde_json::to_vec(&self.index)?;
+        fs::write(self.dir.join(INDEX_FILE), bytes)
+    }
+}
+
+/// Cached files are named by the hash of the file name, so any name is a safe path.
+fn cached_path(dir: &Path, file_name: &str) -> PathBuf {
+    let name = hash_reader(file_name.as_bytes(), |_| {}).expect("hashing a slice can't fail");
+    dir.join(name)
+}
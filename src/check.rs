@@ -0,0 +1,195 @@
+use std::{
+    fs, io,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use flate2::read::GzDecoder;
+use p2p_service::{hash_reader, IndexReport};
+
+use crate::{
+    index::{FileIndex, Storage, PARTIAL_PREFIX},
+    logs::log,
+    quarantine::QUARANTINE_DIR,
+    SERVER_FILES,
+};
+
+#[derive(Clone, Copy, Default)]
+pub struct CheckOptions {
+    /// Hash every file and compare with the index, reads everything that's stored.
+    pub hashes: bool,
+    /// Fix what was found, see `IndexReport::repaired`.
+    pub repair: bool,
+}
+
+/// Compare `files` with what is in `SERVER_FILES`.
+///
+/// Safe against a live server: the index is only locked for short moments, and
+/// repairs check again under the lock that the file didn't change since.
+pub fn check(files: &Mutex<FileIndex>, options: CheckOptions) -> io::Result<IndexReport> {
+    let snapshot: Vec<_> = files
+        .lock()
+        .unwrap()
+        .entries()
+        .map(|(name, meta)| (name.clone(), meta.clone()))
+        .collect();
+
+    let mut report = IndexReport {
+        checked: snapshot.len(),
+        ..IndexReport::default()
+    };
+
+    for (name, meta) in &snapshot {
+        let path = format!("{SERVER_FILES}/{name}");
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                report.missing.push(name.clone());
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        if metadata.len() != meta.disk_size {
+            report.resized.push(name.clone());
+            continue;
+        }
+
+        if let (true, Some(hash)) = (options.hashes, &meta.hash) {
+            match hash_stored(&path, meta.storage) {
+                Ok(actual) if actual == *hash => {}
+                Ok(_) => report.corrupt.push(name.clone()),
+                // Gzipped files that no longer decompress
+                Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+                    report.corrupt.push(name.clone())
+                }
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    report.corrupt.push(name.clone())
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    for entry in fs::read_dir(SERVER_FILES)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+
+        if !name.starts_with(PARTIAL_PREFIX)
+            && entry.file_type()?.is_file()
+            && !files.lock().unwrap().contains(&name)
+        {
+            report.orphaned.push(name);
+        }
+    }
+
+    for list in [
+        &mut report.missing,
+        &mut report.orphaned,
+        &mut report.resized,
+        &mut report.corrupt,
+    ] {
+        list.sort();
+    }
+
+    if options.repair {
+        repair(files, &report)?;
+        report.repaired = true;
+    }
+    Ok(report)
+}
+
+fn repair(files: &Mutex<FileIndex>, report: &IndexReport) -> io::Result<()> {
+    for name in &report.missing {
+        let mut files = files.lock().unwrap();
+        if !fs::exists(format!("{SERVER_FILES}/{name}"))? {
+            files.remove(name);
+            log!("Dropped \"{name}\" from the index, its file is gone");
+        }
+    }
+
+    // Read the file again, whatever is there now is what the index should say
+    for name in report.orphaned.iter().chain(&report.resized) {
+        let indexed = hash_unchanged(files, name, |files, hash, metadata| {
+            files.insert(name.clone(), metadata);
+            files.set_hash(name, hash);
+            Ok(())
+        })?;
+        if indexed {
+            log!("Indexed \"{name}\" again");
+        }
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+
+    for name in &report.corrupt {
+        let quarantined = format!("{QUARANTINE_DIR}/{name}.{now}");
+        fs::create_dir_all(QUARANTINE_DIR)?;
+
+        let moved = hash_unchanged(files, name, |files, _, _| {
+            fs::rename(format!("{SERVER_FILES}/{name}"), &quarantined)?;
+            files.remove(name);
+            Ok(())
+        })?;
+        if moved {
+            log!("Moved \"{name}\" to {quarantined}, its contents don't match its hash");
+        }
+    }
+
+    files.lock().unwrap().save()
+}
+
+/// Hash stored file `name`, then call `apply` under the index lock if the file
+/// didn't change while it was hashed. Returns whether `apply` was called.
+///
+/// Uploads rename the new file into place just before locking the index, so a
+/// file replaced in that moment can still slip through, and is put right by the
+/// upload itself once it gets the lock.
+fn hash_unchanged(
+    files: &Mutex<FileIndex>,
+    name: &str,
+    apply: impl FnOnce(&mut FileIndex, String, &fs::Metadata) -> io::Result<()>,
+) -> io::Result<bool> {
+    let path = format!("{SERVER_FILES}/{name}");
+    let storage = files
+        .lock()
+        .unwrap()
+        .get(name)
+        .map(|meta| meta.storage)
+        .unwrap_or_default();
+
+    let (before, hash) = match fs::metadata(&path).and_then(|before| {
+        let hash = hash_stored(&path, storage)?;
+        Ok((before, hash))
+    }) {
+        Ok(hashed) => hashed,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    let mut files = files.lock().unwrap();
+    let after = match fs::metadata(&path) {
+        Ok(after) => after,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    if after.len() != before.len() || after.modified().ok() != before.modified().ok() {
+        return Ok(false);
+    }
+
+    apply(&mut files, hash, &after)?;
+    Ok(true)
+}
+
+/// Hash of a stored file's original contents.
+fn hash_stored(path: &str, storage: Storage) -> io::Result<String> {
+    let file = fs::File::open(path)?;
+    match storage {
+        Storage::Plain => hash_reader(file, |_| {}),
+        Storage::Gzip { .. } => hash_reader(GzDecoder::new(file), |_| {}),
+    }
+}
@@ -0,0 +1,142 @@
+use std::{net::IpAddr, str::FromStr};
+
+/// A range of addresses such as `192.168.0.0/24` or `fd00::/8`.
+///
+/// A bare address is a range holding only that address.
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // IPv4 clients on a dual stack socket show up as ::ffff:a.b.c.d
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(addr)) => prefix_matches(
+                range.to_bits().into(),
+                addr.to_bits().into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(range), IpAddr::V6(addr)) => {
+                prefix_matches(range.to_bits(), addr.to_bits(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the top `prefix_len` of `bits` bits are the same in `a` and `b`.
+fn prefix_matches(a: u128, b: u128, bits: u32, prefix_len: u32) -> bool {
+    let diff = a ^ b;
+    prefix_len == 0 || diff >> (bits - prefix_len) == 0
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("Invalid address '{addr}'"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("Invalid prefix length '{len}'"))?,
+            None => max_len,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_prefixes() {
+        let range = cidr("192.168.0.0/24");
+        assert!(range.contains(addr("192.168.0.0")));
+        assert!(range.contains(addr("192.168.0.255")));
+        assert!(!range.contains(addr("192.168.1.0")));
+        assert!(!range.contains(addr("10.0.0.1")));
+
+        // Prefixes that don't fall on a byte boundary
+        let range = cidr("10.0.0.0/9");
+        assert!(range.contains(addr("10.127.255.255")));
+        assert!(!range.contains(addr("10.128.0.0")));
+    }
+
+    #[test]
+    fn ipv6_prefixes() {
+        let range = cidr("fd00::/8");
+        assert!(range.contains(addr("fd12:3456::1")));
+        assert!(!range.contains(addr("fe80::1")));
+
+        let range = cidr("2001:db8::/33");
+        assert!(range.contains(addr("2001:db8:7fff::1")));
+        assert!(!range.contains(addr("2001:db8:8000::1")));
+    }
+
+    #[test]
+    fn zero_prefix_holds_every_address_of_its_family() {
+        assert!(cidr("0.0.0.0/0").contains(addr("203.0.113.9")));
+        assert!(cidr("::/0").contains(addr("2001:db8::1")));
+        assert!(!cidr("::/0").contains(addr("203.0.113.9")));
+    }
+
+    #[test]
+    fn full_prefix_holds_one_address() {
+        for range in [cidr("192.168.0.7/32"), cidr("192.168.0.7")] {
+            assert!(range.contains(addr("192.168.0.7")));
+            assert!(!range.contains(addr("192.168.0.6")));
+            assert!(!range.contains(addr("192.168.0.8")));
+        }
+        for range in [cidr("2001:db8::7/128"), cidr("2001:db8::7")] {
+            assert!(range.contains(addr("2001:db8::7")));
+            assert!(!range.contains(addr("2001:db8::8")));
+        }
+    }
+
+    #[test]
+    fn mapped_ipv4_clients_match_ipv4_ranges() {
+        assert!(cidr("192.168.0.0/24").contains(addr("::ffff:192.168.0.9")));
+        assert!(!cidr("192.168.0.0/24").contains(addr("::ffff:192.168.1.9")));
+    }
+
+    #[test]
+    fn malformed_ranges_are_refused() {
+        for invalid in [
+            "",
+            "/24",
+            "192.168.0.0/",
+            "192.168.0.0/33",
+            "192.168.0.0/-1",
+            "192.168.0.0/24/8",
+            "192.168.0/24",
+            "fd00::/129",
+            "not an address",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+        }
+    }
+}
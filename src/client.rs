@@ -1,21 +1,98 @@
 use std::{
-    fs, io,
-    net::{Shutdown, TcpStream},
+    cmp::Reverse,
+    collections::HashMap,
+    env, fs,
+    io::{self, Read, Seek, Write},
+    num::NonZeroU32,
     path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::PathBuf, sync::OnceLock};
+
 use dialog::DialogBox;
 use glow::HasContext;
-use imgui::Context;
+use imgui::{Context, Key, ListClipper, MouseButton, ProgressBar};
 use imgui_glow_renderer::AutoRenderer;
 use imgui_sdl2_support::SdlPlatform;
-use p2p_service::{read_bytes, read_usize, write_string, Chunk, SERVER_ADDR};
+use local::{copy_path, LocalFiles, LocalStatus};
+use p2p_service::{
+    abort_multipart, authenticate, available_space,
+    cache::ReadThroughCache,
+    capability, check_health, check_index, complete_multipart, copy_file, delete_files, diff_dir,
+    disconnect, download_path, enable_wire_trace, export_index, fastest_sources, feature,
+    fetch_file_sizes, fetch_files, fetch_files_with_tag, fetch_global_list, fetch_stats,
+    fetch_tree, find_by_hash, follow_log,
+    format::{human_bytes, human_duration, human_rate, local_timestamp, parse_bytes},
+    get_file, get_file_if_changed, get_files, handshake, hash_reader, index_version,
+    initiate_multipart, is_storage_full, is_valid_template, kick_connection, list_all,
+    list_connections, list_older_than, op, out_of_space, read_response, reset_downloads, seal,
+    send_reader, send_stream, set_metadata, set_tags, set_visibility, space_shortfall,
+    speedtest_download, speedtest_upload, start_upload, stat_file, unix_now, upload_part, version,
+    write_op, ConnectionInfo, Fetched, FileEntry, OwnedChunk, ProtocolError, ProtocolResult,
+    RemoteFile, SortKey, Status, Stream, Transport, TreeNode, DEFAULT_DOWNLOAD_TEMPLATE,
+    MAX_BATCH_LEN, MAX_PART_LEN, MAX_TREE_DEPTH, SERVER_ADDR, WIRE_TRACE_VAR,
+};
+use palette::Action;
 use sdl2::{
     event::Event,
-    video::{GLProfile, Window},
+    video::{GLContext, GLProfile, Window},
+    EventPump, Sdl,
 };
+use settings::{Settings, SetupForm, SetupResult, CACHE_DIR, SETTINGS_FILE};
+use transfers::{
+    Bandwidth, CountingReader, Priority, Throttled, Totals, Transfer, TransferQueue, TransferState,
+    DOWNLOAD_LIMIT, SESSION, UPLOAD_LIMIT,
+};
+use ui_state::{UiEvent, UiState};
+
+mod http;
+mod local;
+mod palette;
+mod settings;
+mod transfers;
+mod ui_state;
 
 const FRAMES_BEFORE_KEEP_ALIVE: usize = 16;
+const WINDOW_SIZE: (u32, u32) = (720, 480);
+/// Height of the scrolling file list, the rest of the window scrolls around it.
+const FILE_LIST_HEIGHT: f32 = 240.0;
+/// How long to wait for the server to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long each source of a download gets to accept a connection, see `fastest_sources`.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Environment variable holding the server's shared secret, if it has one.
+const SECRET_VAR: &str = "P2P_SECRET";
+/// Passphrase of servers that encrypt connections, taking precedence over the settings.
+const PASSPHRASE_VAR: &str = "P2P_PASSPHRASE";
+
+/// A connection to the server with the chunk its requests are made through,
+/// kept for as long as the connection is.
+type Session = OwnedChunk<1024, Stream>;
+
+/// Set once a dialog fails to open, no more are attempted after that.
+static NO_DIALOGS: AtomicBool = AtomicBool::new(false);
+/// Set by `--unix`, the server is reached through this socket instead of its address.
+#[cfg(unix)]
+static UNIX_SOCKET: OnceLock<PathBuf> = OnceLock::new();
+/// Opened by the first download, see `read_cached`.
+static DOWNLOAD_CACHE: Mutex<Option<ReadThroughCache>> = Mutex::new(None);
+/// Set once clock skew has been reported, so reconnecting doesn't repeat it.
+static SKEW_REPORTED: AtomicBool = AtomicBool::new(false);
+/// Seconds the server's clock may differ from ours before the user is warned.
+const MAX_CLOCK_SKEW: u64 = 60;
+
+/// Room left free after a download, the same as the server keeps by default.
+const DOWNLOAD_HEADROOM: u64 = 64 * 1024 * 1024;
+
+/// A queue edit picked from the Transfers panel, applied once the list is drawn.
+type QueueAction = fn(&mut TransferQueue, u64);
 
 // Create a new glow context.
 fn glow_context(window: &Window) -> glow::Context {
@@ -24,215 +101,2810 @@ fn glow_context(window: &Window) -> glow::Context {
     }
 }
 
-fn send_file(file_name: &str, stream: &TcpStream) -> io::Result<()> {
-    let mut chunk = Chunk::<1024>::new(stream);
-    let file_name = String::from(file_name);
+/// Upload the file at `path` as `name`, adding the bytes sent so far to `progress`.
+fn send_file(
+    path: &str,
+    name: &str,
+    private: bool,
+    session: &mut Session,
+    info: &ConnectionInfo,
+    progress: Arc<AtomicU64>,
+) -> ProtocolResult<()> {
+    let file = fs::File::open(path)?;
+    let file_size = file.metadata()?.len() as usize;
 
-    chunk.write_and_send(&0u8.to_le_bytes())?;
-    write_string(&mut chunk, &file_name)?;
+    send_upload(
+        name,
+        private,
+        CountingReader::new(Throttled::new(file, &UPLOAD_LIMIT), progress),
+        file_size,
+        session,
+        info,
+    )
+}
+
+/// Upload `size` bytes from `reader`, stored on the server under the base name of `name`.
+fn send_upload(
+    name: &str,
+    private: bool,
+    reader: impl io::Read,
+    size: usize,
+    session: &mut Session,
+    info: &ConnectionInfo,
+) -> ProtocolResult<()> {
+    start_upload(session, info, op::ADD_FILE, name, private)?;
+    send_reader(session, reader, size)?;
 
-    p2p_service::send_file(&mut chunk, &file_name)?;
+    // Older servers do not tell us whether the upload was accepted
+    if info.version >= version::V2 {
+        read_response(session)?;
+    }
 
     println!("File sent successfully!");
 
     Ok(())
 }
 
-fn get_file(stream: &TcpStream, file_name: &str) -> io::Result<Option<Vec<u8>>> {
-    let mut chunk = Chunk::<1024>::new(stream);
+/// The name a local file is stored under on the server unless another is given.
+fn base_name(file: &str) -> String {
+    Path::new(file)
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Why an upload of `size` bytes won't fit on the server, going by its current stats.
+///
+/// The stats are fetched fresh, the free space sent with the handshake may be
+/// long out of date by the time the upload starts.
+fn upload_limit_problem(
+    stream: &Stream,
+    info: &ConnectionInfo,
+    size: u64,
+) -> ProtocolResult<Option<String>> {
+    let stats = fetch_stats(stream, info)?;
+    let stat = |name: &str| {
+        stats
+            .iter()
+            .find(|(stat, _)| stat == name)
+            .map(|(_, value)| *value as u64)
+    };
+
+    if let (Some(quota), Some(stored)) = (
+        info.capabilities.get(capability::QUOTA),
+        stat("stored_bytes"),
+    ) {
+        let room = quota.saturating_sub(stored);
+
+        if size > room {
+            return Ok(Some(format!(
+                "This {} file exceeds the server's {} quota ({} free)",
+                human_bytes(size),
+                human_bytes(quota),
+                human_bytes(room)
+            )));
+        }
+    }
+
+    let free = stat("free_bytes").or(info.capabilities.get(capability::FREE_BYTES));
+    match free {
+        Some(free) if size > free => Ok(Some(format!(
+            "This {} file is larger than the {} the server has free",
+            human_bytes(size),
+            human_bytes(free)
+        ))),
+        _ => Ok(None),
+    }
+}
+
+/// Whether an upload of `size` bytes should be queued, asking first if it looks like it won't fit.
+fn admit_upload(
+    stream: &Stream,
+    info: &ConnectionInfo,
+    file: &str,
+    size: u64,
+) -> ProtocolResult<bool> {
+    let Some(problem) = upload_limit_problem(stream, info, size)? else {
+        return Ok(true);
+    };
+
+    // The server's numbers may have changed since, so the user can still try
+    if confirm(&format!("{problem}. Upload anyway?"), false) {
+        return Ok(true);
+    }
+
+    push_notice(format!("Not uploading '{file}': {problem}"));
+    Ok(false)
+}
+
+/// Queue `file` for upload as `name`, returning whether the connection was lost.
+fn enqueue(
+    stream: &Stream,
+    info: &ConnectionInfo,
+    queue: &mut TransferQueue,
+    file: &str,
+    name: &str,
+    private: bool,
+) -> bool {
+    let size = match fs::metadata(file) {
+        Ok(metadata) => metadata.len(),
+        Err(err) => {
+            show_msg_box(&format!("Could not read file: '{err}'"));
+            return false;
+        }
+    };
+
+    match admit_upload(stream, info, file, size) {
+        Ok(true) => {
+            _ = queue.push(
+                file.to_string(),
+                name.to_string(),
+                private,
+                size,
+                Priority::Interactive,
+            );
+            false
+        }
+        Ok(false) => false,
+        Err(err) => show_error("Could not check the server's space", &err),
+    }
+}
+
+/// An upload from the `TransferQueue`, running on its own connection.
+struct ActiveTransfer {
+    id: u64,
+    handle: JoinHandle<ProtocolResult<()>>,
+}
+
+impl ActiveTransfer {
+    fn start(item: &Transfer) -> Self {
+        let path = item.path.clone();
+        let name = item.name.clone();
+        let private = item.private;
+        let progress = item.progress.clone();
+        let events = ui_state::sender();
+
+        let handle = thread::spawn(move || {
+            let (mut session, info) = connect_session()?;
+            let result = send_file(&path, &name, private, &mut session, &info, progress);
+
+            // Listed as the server stored it, rather than as we asked for it
+            if result.is_ok() {
+                let stream = session.stream();
+                if let Ok(Some(entry)) = stat_file(stream, &info, &base_name(&name)) {
+                    let generation = index_generation(stream, &info);
+                    _ = events.send(UiEvent::FileAdded(entry, generation));
+                }
+            }
 
-    chunk.write_and_send(&1u8.to_le_bytes())?;
-    write_string(&mut chunk, file_name)?;
+            disconnect(session.stream());
+            result
+        });
+
+        Self {
+            id: item.id,
+            handle,
+        }
+    }
+}
 
-    let file_size = read_usize(&mut chunk);
-    p2p_service::receive_file(&mut chunk, file_size)
+/// The server's log being followed on its own connection, see `follow_log`.
+struct LogFollower {
+    /// A handle on the connection, shutting it down ends the thread.
+    stream: Stream,
+    handle: JoinHandle<ProtocolResult<()>>,
 }
 
-fn fetch_files(stream: &TcpStream) -> io::Result<Vec<String>> {
-    let mut chunk = Chunk::<1024>::new(stream);
-    chunk.write_and_send(&2u8.to_le_bytes())?;
+impl LogFollower {
+    fn start() -> ProtocolResult<Self> {
+        let (stream, info) = connect_server()?;
+        let handle_stream = stream.try_clone()?;
+        let events = ui_state::sender();
 
-    chunk.read_stream(8)?;
-    let count = usize::from_le_bytes(chunk.to_byte_array::<8>());
+        let handle = thread::spawn(move || {
+            follow_log(&stream, &info, |line| {
+                _ = events.send(UiEvent::LogLine(line.to_string()));
+            })
+        });
+
+        Ok(Self {
+            stream: handle_stream,
+            handle,
+        })
+    }
 
-    let mut files = Vec::new();
-    for _ in 0..count {
-        let bytes = read_bytes(&mut chunk)?.unwrap();
-        let file_name = String::from_utf8_lossy(&bytes).to_string();
-        files.push(file_name);
+    fn stop(self) {
+        _ = self.stream.shutdown();
     }
+}
 
-    Ok(files)
+/// A local file being hashed in the background, to check the server doesn't already have it.
+struct PendingUpload {
+    file: String,
+    /// What to store it as on the server.
+    name: String,
+    size: u64,
+    hashed: Arc<AtomicU64>,
+    handle: JoinHandle<io::Result<String>>,
 }
 
-fn run(stream: TcpStream) {
-    /* initialize SDL and its video subsystem */
-    let sdl = sdl2::init().unwrap();
-    let video_subsystem = sdl.video().unwrap();
+impl PendingUpload {
+    fn start(file: String, name: String) -> io::Result<Self> {
+        let reader = fs::File::open(&file)?;
+        let size = reader.metadata()?.len();
+        let hashed = Arc::new(AtomicU64::new(0));
+
+        let progress = hashed.clone();
+        let handle = thread::spawn(move || {
+            hash_reader(reader, |bytes| progress.store(bytes, Ordering::Relaxed))
+        });
+
+        Ok(Self {
+            file,
+            name,
+            size,
+            hashed,
+            handle,
+        })
+    }
+}
 
-    /* hint SDL to initialize an OpenGL 3.3 core profile context */
-    let gl_attr = video_subsystem.gl_attr();
+/// The generation of the server's index, `None` if the server can't say.
+fn index_generation(stream: &Stream, info: &ConnectionInfo) -> Option<u64> {
+    if info.version < version::V5 || !info.capabilities.has(feature::INDEX_VERSION) {
+        return None;
+    }
+    index_version(stream, info).ok()
+}
 
-    gl_attr.set_context_version(3, 3);
-    gl_attr.set_context_profile(GLProfile::Core);
+/// The server's listing, with the generation of its index from before it was fetched.
+fn fetch_listing(stream: &Stream, info: &ConnectionInfo) -> ProtocolResult<UiEvent> {
+    let generation = index_generation(stream, info);
+    Ok(UiEvent::ListingReplaced(
+        fetch_files(stream, info)?,
+        generation,
+    ))
+}
 
-    let window_size = (720, 480);
+/// Connect to the server in the settings, with a chunk kept for the whole session.
+fn connect_session() -> ProtocolResult<(Session, ConnectionInfo)> {
+    let (stream, info) = connect_server()?;
+    Ok((Session::owned(stream), info))
+}
 
-    /* create a new window, be sure to call opengl method on the builder when using glow! */
-    let window = video_subsystem
-        .window("P2P Client", window_size.0, window_size.1)
-        .allow_highdpi()
-        .opengl()
-        .position_centered()
-        .build()
-        .unwrap();
+/// Connect to the server in the settings.
+fn connect_server() -> ProtocolResult<(Stream, ConnectionInfo)> {
+    #[cfg(unix)]
+    if let Some(path) = UNIX_SOCKET.get() {
+        let settings = settings::current();
+        let stream = settings
+            .retry_policy()
+            .run(|| UnixStream::connect(path), is_transient)?;
+        return open_session(
+            Stream::Unix(stream),
+            &path.display().to_string(),
+            settings.secret.as_deref(),
+            settings.passphrase.as_deref(),
+        );
+    }
 
-    /* create a new OpenGL context and make it current */
-    let gl_context = window.gl_create_context().unwrap();
-    window.gl_make_current(&gl_context).unwrap();
+    connect(&settings::current().server_addr)
+}
 
-    /* enable vsync to cap framerate */
-    window.subsystem().gl_set_swap_interval(1).unwrap();
+/// Connect to a server, negotiating the protocol and authenticating if a secret is set.
+fn connect(addr: &str) -> ProtocolResult<(Stream, ConnectionInfo)> {
+    let settings = settings::current();
+    connect_with(
+        addr,
+        settings.secret.as_deref(),
+        settings.passphrase.as_deref(),
+    )
+}
 
-    /* create new glow and imgui contexts */
-    let gl = glow_context(&window);
+/// Like `connect`, presenting `secret` unless `SECRET_VAR` is set, and encrypting
+/// with `passphrase` unless `PASSPHRASE_VAR` is set.
+fn connect_with(
+    addr: &str,
+    secret: Option<&str>,
+    passphrase: Option<&str>,
+) -> ProtocolResult<(Stream, ConnectionInfo)> {
+    let stream = settings::current()
+        .retry_policy()
+        .run(|| p2p_service::connect(addr, CONNECT_TIMEOUT), is_transient)?;
+    open_session(Stream::Tcp(stream), addr, secret, passphrase)
+}
 
-    /* create context */
-    let mut imgui = Context::create();
+/// Whether trying to connect again could go differently, a name that didn't resolve won't.
+fn is_transient(err: &io::Error) -> bool {
+    !matches!(
+        err.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::InvalidInput | io::ErrorKind::PermissionDenied
+    )
+}
 
-    /* disable creation of files on disc */
-    imgui.set_ini_filename(None);
-    imgui.set_log_filename(None);
+/// Negotiate the protocol over `stream` to the server at `addr`, then authenticate.
+fn open_session(
+    stream: Stream,
+    addr: &str,
+    secret: Option<&str>,
+    passphrase: Option<&str>,
+) -> ProtocolResult<(Stream, ConnectionInfo)> {
+    let info = handshake(&stream)?;
 
-    /* setup platform and renderer, and fonts to imgui */
-    imgui
-        .fonts()
-        .add_font(&[imgui::FontSource::DefaultFontData { config: None }]);
+    let passphrase = env::var(PASSPHRASE_VAR)
+        .ok()
+        .or(passphrase.map(String::from));
+    let stream = seal_session(stream, &info, passphrase.as_deref())?;
 
-    /* create platform and renderer */
-    let mut platform = SdlPlatform::init(&mut imgui);
-    let mut renderer = AutoRenderer::initialize(gl, &mut imgui).unwrap();
+    // Modification times come from the server's clock, so they would look off by this much
+    if info.clock_skew.unsigned_abs() > MAX_CLOCK_SKEW
+        && !SKEW_REPORTED.swap(true, Ordering::Relaxed)
+    {
+        push_notice(format!(
+            "The clock on '{addr}' is {} seconds {} this computer's",
+            info.clock_skew.unsigned_abs(),
+            if info.clock_skew > 0 {
+                "ahead of"
+            } else {
+                "behind"
+            },
+        ));
+    }
 
-    /* start main loop */
-    let mut event_pump = sdl.event_pump().unwrap();
-    let mut selected_file: Option<String> = None;
-    let mut frames_before_send = 0usize;
+    let secret = env::var(SECRET_VAR).ok().or(secret.map(String::from));
+    if let Some(secret) = secret {
+        authenticate(&stream, secret.as_bytes())?;
+    }
 
-    let mut chunk = Chunk::<1024>::new(&stream);
-    let mut cached_files = fetch_files(&stream).unwrap();
+    Ok((stream, info))
+}
 
-    'main: loop {
-        for event in event_pump.poll_iter() {
-            /* pass all events to imgui platfrom */
-            platform.handle_event(&mut imgui, &event);
+/// Encrypt `stream` if the server asks for it, see `seal`.
+///
+/// With a `passphrase`, a server that doesn't encrypt is refused rather than talked to in the clear.
+fn seal_session(
+    stream: Stream,
+    info: &ConnectionInfo,
+    passphrase: Option<&str>,
+) -> ProtocolResult<Stream> {
+    match (info.capabilities.get(capability::SEAL_ROUNDS), passphrase) {
+        (Some(rounds), Some(passphrase)) => {
+            let rounds = u32::try_from(rounds).unwrap_or(u32::MAX);
+            let sealed = seal::connect(stream, passphrase, rounds)?;
+            Ok(Stream::Sealed(Box::new(sealed)))
+        }
+        (Some(_), None) => Err(ProtocolError::Denied(
+            "The server encrypts connections, its passphrase is needed".to_string(),
+        )),
+        (None, Some(_)) => Err(ProtocolError::Denied(
+            "The server doesn't encrypt connections, but a passphrase is set".to_string(),
+        )),
+        (None, None) => Ok(stream),
+    }
+}
+
+/// The socket set by `--unix`, if the server is reached through one.
+#[cfg(unix)]
+fn unix_socket() -> Option<&'static Path> {
+    UNIX_SOCKET.get().map(PathBuf::as_path)
+}
+
+#[cfg(not(unix))]
+fn unix_socket() -> Option<&'static Path> {
+    None
+}
+
+/// Download from the fastest source that has the file, trying the others in turn if
+/// a peer fails. See `fastest_sources`.
+///
+/// The server lists itself as `SERVER_ADDR`, so it is timed at the address it was
+/// reached on and downloaded from over `stream`. Nothing is transferred if the
+/// file's contents hash to `held`.
+fn get_file_from_sources(
+    stream: &Stream,
+    info: &ConnectionInfo,
+    file_name: &str,
+    sources: Option<&Vec<String>>,
+    held: Option<&str>,
+) -> ProtocolResult<Fetched> {
+    let fetch = |stream: &Stream, info: &ConnectionInfo| {
+        let stream = Throttled::new(stream, &DOWNLOAD_LIMIT);
 
-            if let Event::Quit { .. } = event {
-                break 'main;
+        match held {
+            Some(hash) => get_file_if_changed(&stream, info, file_name, hash),
+            None => get_file(&stream, info, file_name).map(Fetched::Changed),
+        }
+    };
+
+    let Some(sources) = sources.filter(|sources| !sources.is_empty()) else {
+        return fetch(stream, info);
+    };
+
+    let server_addr = match unix_socket() {
+        Some(path) => path.display().to_string(),
+        None => settings::current().server_addr.clone(),
+    };
+    let addrs: Vec<String> = sources
+        .iter()
+        .map(|addr| match addr.as_str() {
+            SERVER_ADDR => server_addr.clone(),
+            _ => addr.clone(),
+        })
+        .collect();
+
+    // A Unix socket is on this machine, nothing is closer
+    let ordered = match unix_socket() {
+        Some(_) if addrs.contains(&server_addr) => vec![server_addr.clone()],
+        _ if addrs.len() > 1 => fastest_sources(&addrs, PROBE_TIMEOUT),
+        _ => addrs,
+    };
+
+    let mut last_err = None;
+    for addr in ordered {
+        // Failing here means the connection is gone, which the caller has to know
+        if addr == server_addr {
+            return fetch(stream, info);
+        }
+
+        let fetched = connect(&addr).and_then(|(peer, peer_info)| {
+            let fetched = fetch(&peer, &peer_info);
+            disconnect(&peer);
+            fetched
+        });
+        match fetched {
+            Ok(fetched) => return Ok(fetched),
+            Err(err) => {
+                eprintln!("Could not download \"{file_name}\" from {addr}: {err}");
+                last_err = Some(err);
             }
         }
+    }
+
+    Err(last_err.unwrap_or_else(|| ProtocolError::NotFound(format!("No source has '{file_name}'"))))
+}
+
+/// Download `file` to the path given by `template`, returning whether the connection was lost.
+fn download_file(
+    stream: &Stream,
+    info: &ConnectionInfo,
+    template: &str,
+    file: &str,
+    sources: Option<&Vec<String>>,
+) -> bool {
+    let path = download_path(
+        template,
+        file,
+        Path::new(&settings::current().downloads_dir),
+    );
+    download_to(stream, info, file, sources, &path)
+}
+
+/// Download `file` to `path`, returning whether the connection was lost.
+fn download_to(
+    stream: &Stream,
+    info: &ConnectionInfo,
+    file: &str,
+    sources: Option<&Vec<String>>,
+    path: &Path,
+) -> bool {
+    if let Err(err) = require_op(info, op::GET_FILE, "downloads") {
+        return show_error("Could not download file", &err);
+    }
+
+    match download_space_problem(stream, info, file, path) {
+        Ok(None) => {}
+        // The free space may change before the download ends, so the user can still try
+        Ok(Some(problem)) if confirm(&format!("{problem}. Download anyway?"), false) => {}
+        Ok(Some(problem)) => {
+            push_notice(format!("Not downloading '{file}': {problem}"));
+            return false;
+        }
+        Err(err) => return show_error("Could not download file", &err),
+    }
 
-        frames_before_send += 1;
-        if frames_before_send >= FRAMES_BEFORE_KEEP_ALIVE {
-            frames_before_send = 0;
-            chunk.write_and_send(&3u8.to_le_bytes()).unwrap();
+    // Skip the transfer if we already have this exact file
+    let held = fs::File::open(path)
+        .and_then(|local| hash_reader(local, |_| {}))
+        .ok();
+
+    // Peers aren't cached, the server's copy is the one a cached file is checked against
+    let server_only = sources.is_none_or(|sources| sources.iter().all(|addr| addr == SERVER_ADDR));
+    let fetched = match held {
+        None if server_only => read_cached(stream, info, file).map(Fetched::Changed),
+        held => get_file_from_sources(stream, info, file, sources, held.as_deref()),
+    };
+
+    match fetched {
+        Ok(Fetched::NotModified) => show_msg_box("File is already up to date"),
+        Ok(Fetched::Changed(contents)) => {
+            if let Some(contents) = contents {
+                SESSION.record_received(contents.len() as u64);
+                SESSION.record_file();
+
+                match save_download(path, &contents) {
+                    Ok(()) => show_msg_box("File downloaded!"),
+                    Err(err) => show_msg_box(&format!("Could not save file: '{err}'")),
+                }
+            }
+        }
+        Err(err) => return show_error("Could not download file", &err),
+    }
+
+    false
+}
+
+/// Download `file` through the cache in `CACHE_DIR`, which is opened on first use.
+///
+/// Downloaded as usual while the cache is turned off or can't be opened.
+fn read_cached(
+    stream: &Stream,
+    info: &ConnectionInfo,
+    file: &str,
+) -> ProtocolResult<Option<Vec<u8>>> {
+    let stream = Throttled::new(stream, &DOWNLOAD_LIMIT);
+    let max_bytes = settings::current().cache_max_bytes;
+
+    let mut cache = DOWNLOAD_CACHE.lock().unwrap();
+    if cache.is_none() && max_bytes > 0 {
+        match ReadThroughCache::open(CACHE_DIR, max_bytes) {
+            Ok(opened) => *cache = Some(opened),
+            Err(err) => eprintln!("Could not open the download cache: {err}"),
+        }
+    }
+
+    match cache.as_mut() {
+        Some(cache) => cache.read(&stream, info, file),
+        None => get_file(&stream, info, file),
+    }
+}
+
+/// The folder a download to `path` lands in, where its free space is checked.
+fn download_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Why `file` won't fit at `path`, going by its size on the server.
+///
+/// A file already at `path` is about to be replaced, so its space counts as free.
+fn download_space_problem(
+    stream: &Stream,
+    info: &ConnectionInfo,
+    file: &str,
+    path: &Path,
+) -> ProtocolResult<Option<String>> {
+    let Some(entry) = stat_file(stream, info, file)? else {
+        return Ok(None);
+    };
+    let replaced = fs::metadata(path).map_or(0, |metadata| metadata.len());
+    let size = entry.size.saturating_sub(replaced);
+
+    // Not worth refusing over, a full disk is still caught when writing
+    Ok(space_shortfall(download_dir(path), size, DOWNLOAD_HEADROOM).unwrap_or(None))
+}
+
+/// Write a download to `path`, removing what was written if the disk fills up.
+///
+/// Downloads can't be resumed, so a partial file would only take up space.
+fn save_download(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let Err(err) = fs::write(path, contents) else {
+        return Ok(());
+    };
+    if !is_storage_full(&err) {
+        return Err(err);
+    }
+
+    let _ = fs::remove_file(path);
+    let available = available_space(download_dir(path)).unwrap_or(0);
+    Err(io::Error::new(
+        io::ErrorKind::StorageFull,
+        out_of_space(contents.len() as u64, available),
+    ))
+}
+
+/// Report a failed request, returning whether the connection has to be re-established.
+fn show_error(context: &str, err: &ProtocolError) -> bool {
+    if let ProtocolError::SessionExpired(_) = err {
+        show_msg_box("Session expired, reconnect to continue");
+    } else {
+        show_msg_box(&format!("{context}: '{err}'"));
+    }
+
+    err.is_disconnect()
+}
+
+/// OpenGL versions to ask for, best first. The renderer copes with any of them.
+const GL_PROFILES: [(u8, u8, GLProfile); 3] = [
+    (3, 3, GLProfile::Core),
+    (3, 0, GLProfile::Compatibility),
+    (2, 1, GLProfile::Compatibility),
+];
+
+/// Why the window could not be brought up, each with a message for the user.
+#[derive(Debug)]
+enum GuiInitError {
+    Sdl(String),
+    Window(String),
+    /// None of `GL_PROFILES` gave a context, the last failure is kept.
+    NoGlContext(String),
+    /// A context was made but imgui could not draw with it.
+    Renderer(String),
+}
+
+impl std::fmt::Display for GuiInitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sdl(err) => write!(f, "Could not start SDL: {err}"),
+            Self::Window(err) => write!(f, "Could not open a window: {err}"),
+            Self::NoGlContext(err) => write!(
+                f,
+                "No OpenGL 2.1 or newer context is available, the graphics driver may be \
+                 missing or too old: {err}"
+            ),
+            Self::Renderer(err) => write!(f, "Could not set up drawing with OpenGL: {err}"),
+        }
+    }
+}
+
+/// The window and the contexts needed to draw into it.
+///
+/// Fields are dropped in order, so everything goes before the GL context it draws with.
+struct Gui {
+    renderer: AutoRenderer,
+    platform: SdlPlatform,
+    imgui: Context,
+    event_pump: EventPump,
+    _gl_context: GLContext,
+    window: Window,
+    _sdl: Sdl,
+    /// The OpenGL version actually obtained, for the About section.
+    gl_version: String,
+}
+
+impl Gui {
+    fn new() -> Result<Self, GuiInitError> {
+        /* initialize SDL and its video subsystem */
+        let sdl = sdl2::init().map_err(GuiInitError::Sdl)?;
+        let video_subsystem = sdl.video().map_err(GuiInitError::Sdl)?;
+
+        /* create a new window, be sure to call opengl method on the builder when using glow! */
+        let window = video_subsystem
+            .window("P2P Client", WINDOW_SIZE.0, WINDOW_SIZE.1)
+            .allow_highdpi()
+            .opengl()
+            .position_centered()
+            .build()
+            .map_err(|err| GuiInitError::Window(err.to_string()))?;
+
+        /* create context */
+        let mut imgui = Context::create();
+
+        /* disable creation of files on disc */
+        imgui.set_ini_filename(None);
+        imgui.set_log_filename(None);
+
+        /* setup platform and renderer, and fonts to imgui */
+        imgui
+            .fonts()
+            .add_font(&[imgui::FontSource::DefaultFontData { config: None }]);
+
+        /* create platform */
+        let platform = SdlPlatform::init(&mut imgui);
+
+        // Older GPUs and VMs lack 3.3, so fall back to whatever the renderer can still use
+        let mut last_error = GuiInitError::NoGlContext("no profiles tried".to_string());
+        let mut found = None;
+
+        for (major, minor, profile) in GL_PROFILES {
+            let gl_attr = video_subsystem.gl_attr();
+            gl_attr.set_context_version(major, minor);
+            gl_attr.set_context_profile(profile);
+
+            let gl_context = match window.gl_create_context() {
+                Ok(gl_context) => gl_context,
+                Err(err) => {
+                    last_error = GuiInitError::NoGlContext(err);
+                    continue;
+                }
+            };
+            if let Err(err) = window.gl_make_current(&gl_context) {
+                last_error = GuiInitError::NoGlContext(err);
+                continue;
+            }
+
+            /* create new glow context and the renderer drawing with it */
+            let gl = glow_context(&window);
+            let version = gl.version();
+            let gl_version = format!(
+                "OpenGL {}.{} ({profile:?} profile requested), {}",
+                version.major, version.minor, version.vendor_info
+            );
+
+            match AutoRenderer::initialize(gl, &mut imgui) {
+                Ok(renderer) => {
+                    found = Some((gl_context, renderer, gl_version));
+                    break;
+                }
+                Err(err) => last_error = GuiInitError::Renderer(err.to_string()),
+            }
+        }
+
+        let Some((gl_context, renderer, gl_version)) = found else {
+            return Err(last_error);
+        };
+        eprintln!("Using {gl_version}");
+
+        /* enable vsync to cap framerate, without it frames are just drawn more often */
+        if let Err(err) = window.subsystem().gl_set_swap_interval(1) {
+            eprintln!("Could not enable vsync: {err}");
+        }
+
+        let event_pump = sdl.event_pump().map_err(GuiInitError::Sdl)?;
+
+        Ok(Self {
+            renderer,
+            platform,
+            imgui,
+            event_pump,
+            _gl_context: gl_context,
+            window,
+            _sdl: sdl,
+            gl_version,
+        })
+    }
+
+    /// Bring up the window, or exit pointing at the command line client if that's impossible.
+    fn new_or_exit() -> Self {
+        match Self::new() {
+            Ok(gui) => gui,
+            Err(err) => {
+                eprintln!("{err}");
+                eprintln!(
+                    "The command line client still works without a window, \
+                     e.g. --list, --download <file> or --upload <file>"
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Handle pending events, returning whether the user asked to close the window.
+    fn poll_quit(&mut self) -> bool {
+        let mut quit = false;
+
+        for event in self.event_pump.poll_iter() {
+            /* pass all events to imgui platfrom */
+            self.platform.handle_event(&mut self.imgui, &event);
+
+            quit |= matches!(event, Event::Quit { .. });
         }
 
+        quit
+    }
+
+    /// Draw one frame of a full window panel titled `title`.
+    fn frame(&mut self, title: &str, build: impl FnOnce(&imgui::Ui)) {
         /* call prepare_frame before calling imgui.new_frame() */
-        platform.prepare_frame(&mut imgui, &window, &event_pump);
+        self.platform
+            .prepare_frame(&mut self.imgui, &self.window, &self.event_pump);
 
-        let ui = imgui.new_frame();
-        /* create imgui UI here */
-        ui.window("File Management")
+        let ui = self.imgui.new_frame();
+        ui.window(title)
             .movable(false)
             .collapsible(false)
             .resizable(false)
             .size(
-                [window_size.0 as f32, window_size.1 as f32],
+                [WINDOW_SIZE.0 as f32, WINDOW_SIZE.1 as f32],
                 imgui::Condition::FirstUseEver,
             )
             .position([0.0, 0.0], imgui::Condition::FirstUseEver)
-            .build(|| {
-                if ui.button("Open Files...") {
-                    let d = dialog::FileSelection::new(".");
-                    selected_file = d.show().expect("Could not open dialog");
+            .build(|| build(ui));
+
+        /* render */
+        let draw_data = self.imgui.render();
+
+        unsafe { self.renderer.gl_context().clear(glow::COLOR_BUFFER_BIT) };
+        self.renderer.render(draw_data).unwrap();
+
+        self.window.gl_swap_window();
+    }
+}
+
+/// Show the setup form until working settings are saved, `None` if the window is closed first.
+fn run_setup(gui: &mut Gui, mut form: SetupForm) -> Option<(Stream, ConnectionInfo)> {
+    loop {
+        if gui.poll_quit() {
+            return None;
+        }
+
+        let mut result = SetupResult::Editing;
+        gui.frame("Setup", |ui| result = form.draw(ui));
+
+        if let SetupResult::Connected(stream, info) = result {
+            return Some((stream, info));
+        }
+    }
+}
+
+/// Show the main window until it is closed.
+///
+/// `listing` is shown instead of fetching the server's, for `--demo-entries`.
+fn run(
+    mut gui: Gui,
+    stream: Stream,
+    mut info: ConnectionInfo,
+    download_template: &str,
+    listing: Option<Vec<String>>,
+) {
+    // Kept for the whole session, and replaced along with the connection
+    let mut session = Session::owned(stream);
+    let gl_version = gui.gl_version.clone();
+    let mut selected_file: Option<String> = None;
+    let mut frames_before_send = 0usize;
+    let mut tag_filter = String::new();
+    let mut catalog: HashMap<String, Vec<String>> = HashMap::new();
+    let mut details: Option<FileEntry> = None;
+    let mut new_tag = String::new();
+    let mut pending_upload: Option<PendingUpload> = None;
+    // The file picked for upload, what to store it as, and the file on the server it duplicates
+    let mut duplicate: Option<(String, String, String)> = None;
+    let mut active_transfer: Option<ActiveTransfer> = None;
+    let mut log_follower: Option<LogFollower> = None;
+    // Shown in place of everything else while the settings are being changed
+    let mut setup: Option<SetupForm> = None;
+    // Typed in by hand when there is no file dialog
+    let mut path_input: Option<String> = None;
+    let mut focus_filter = false;
+    let mut palette_query = String::new();
+    // Stored under the local file's name when left empty
+    let mut upload_name = String::new();
+    // Kept between uploads, applies to each file as it is queued
+    let mut upload_private = false;
+    let mut local = LocalFiles::default();
+    let mut popular_first = false;
+    let mut tree: Option<TreeNode> = None;
+
+    let mut bandwidth = Bandwidth::new();
+    let mut lifetime = Totals::load().unwrap_or_else(|err| {
+        eprintln!("Could not load transfer totals: {err}");
+        Totals::default()
+    });
+
+    let mut queue = TransferQueue::load().unwrap_or_else(|err| {
+        eprintln!("Could not restore queued uploads: {err}");
+        TransferQueue::default()
+    });
+
+    // Restored uploads would only be refused, they wait until the user resumes them
+    if !info.capabilities.has(feature::WRITE) {
+        queue.pause();
+    }
+
+    let mut state = UiState::default();
+    let listing = listing.map(|files| UiEvent::ListingReplaced(files, None));
+    match listing.map_or_else(|| fetch_listing(session.stream(), &info), Ok) {
+        Ok(listing) => state.apply(listing),
+        Err(err) => state.disconnected = show_error("Could not fetch files", &err),
+    }
+
+    loop {
+        if gui.poll_quit() {
+            let busy = pending_upload.is_some() || active_transfer.is_some();
+
+            // Queued uploads are saved, but the one in progress starts over next time
+            if !busy || confirm("A transfer is in progress, quit anyway?", true) {
+                break;
+            }
+        }
+
+        if let Some(transfer) = active_transfer.take_if(|transfer| transfer.handle.is_finished()) {
+            let result = transfer.handle.join().expect("Transfer thread panicked");
+
+            if result.is_ok() {
+                SESSION.record_file();
+            }
+            queue.finish(transfer.id, result.map_err(|err| err.to_string()));
+        }
+
+        // Only ends by itself on an error, stopping it drops it without a join
+        if let Some(follower) = log_follower.take_if(|follower| follower.handle.is_finished()) {
+            if let Err(err) = follower.handle.join().expect("Log thread panicked") {
+                push_notice(format!("Stopped following the server log: {err}"));
+            }
+        }
+
+        if active_transfer.is_none() {
+            active_transfer = queue.start_next().map(ActiveTransfer::start);
+        }
+
+        state.apply_pending();
+        if !state.disconnected {
+            for file in state.take_stale() {
+                match stat_file(session.stream(), &info, &file) {
+                    Ok(Some(entry)) => state.apply(UiEvent::FileAdded(entry, None)),
+                    Ok(None) => state.apply(UiEvent::FileRemoved(file)),
+                    Err(err) => state.disconnected = show_error("Could not fetch details", &err),
                 }
-                ui.separator();
-                ui.text(format!("Selected file: '{selected_file:#?}'"));
+            }
+        }
+        bandwidth.update();
 
-                if ui.button("Upload") {
-                    if let Some(file) = &selected_file {
-                        if let Err(err) = send_file(file, &stream) {
-                            show_msg_box(&format!("Could not send file over network: '{err}'"));
-                        } else {
-                            show_msg_box("File uploaded!");
-                            cached_files.push(
-                                Path::new(&file)
-                                    .file_name()
-                                    .unwrap()
-                                    .to_str()
-                                    .unwrap()
-                                    .to_string(),
-                            );
-                            selected_file = None;
-                        }
-                    }
+        frames_before_send += 1;
+        if frames_before_send >= FRAMES_BEFORE_KEEP_ALIVE {
+            frames_before_send = 0;
+            local.refresh(Path::new(&settings::current().downloads_dir));
+
+            if !state.disconnected {
+                let alive = write_op(&mut session, op::KEEP_ALIVE).is_ok();
+                state.apply(UiEvent::ConnectionStatus(alive));
+            }
+        }
+
+        /* create imgui UI here */
+        gui.frame("File Management", |ui| {
+            if !state.notices.is_empty() {
+                for notice in &state.notices {
+                    ui.text_wrapped(notice);
                 }
 
+                if ui.small_button("Clear") {
+                    state.notices.clear();
+                }
                 ui.separator();
-                ui.text("Server Files");
+            }
+
+            if let Some(form) = &mut setup {
+                match form.draw(ui) {
+                    SetupResult::Editing => return,
+                    SetupResult::Cancelled => {}
+                    SetupResult::Connected(new_stream, new_info) => {
+                        if !state.disconnected {
+                            disconnect(session.stream());
+                        }
+                        session = Session::owned(new_stream);
+                        info = new_info;
+                        state.apply(UiEvent::ConnectionStatus(true));
 
-                if ui.button("Fetch") {
-                    match fetch_files(&stream) {
-                        Ok(files) => {
-                            cached_files.clear();
-                            cached_files = files;
+                        match fetch_listing(session.stream(), &info) {
+                            Ok(listing) => state.apply(listing),
+                            Err(err) => {
+                                state.disconnected = show_error("Could not fetch files", &err)
+                            }
                         }
-                        Err(err) => show_msg_box(&format!("Could not fetch files: '{err}'")),
                     }
                 }
 
-                ui.separator();
+                setup = None;
+                return;
+            }
 
-                for file in &cached_files {
-                    if ui.button(file) {
-                        match get_file(&stream, file) {
-                            Ok(contents) => {
-                                if let Some(contents) = contents {
-                                    if let Ok(_) = fs::write(file, contents) {
-                                        show_msg_box("File downloaded!");
-                                    }
-                                }
-                            }
-                            Err(err) => show_msg_box(&format!("Could not download file: '{err}'")),
+            if ui.small_button("Settings") {
+                setup = Some(SetupForm::new(settings::current(), None, true));
+            }
+
+            if state.disconnected {
+                ui.text("Disconnected from server");
+
+                if ui.button("Reconnect") {
+                    match connect_server() {
+                        Ok((new_stream, new_info)) => {
+                            session = Session::owned(new_stream);
+                            info = new_info;
+                            state.apply(UiEvent::ConnectionStatus(true));
                         }
+                        Err(err) => show_msg_box(&format!("Could't connect to server: '{err}'")),
                     }
                 }
-            });
+                return;
+            }
 
-        /* render */
-        let draw_data = imgui.render();
+            // Shortcuts are typed into text fields, not acted on, while one has focus
+            let mut command = if ui.io().want_text_input {
+                None
+            } else {
+                shortcut(ui)
+            };
 
-        unsafe { renderer.gl_context().clear(glow::COLOR_BUFFER_BIT) };
-        renderer.render(draw_data).unwrap();
+            let can_write = info.capabilities.has(feature::WRITE);
+            let read_only = ui.begin_disabled(!can_write);
 
-        window.gl_swap_window();
-    }
+            if ui.button("Open Files...") {
+                command = Some(Action::Upload);
+            }
+            read_only.end();
 
-    stream
-        .shutdown(Shutdown::Both)
-        .expect("Stream shutdown failed");
-}
+            if !can_write {
+                ui.same_line();
+                ui.text_disabled("The server is read-only");
+            }
 
-fn show_msg_box(msg: &str) {
-    let msg = dialog::Message::new(msg);
-    msg.show_with(dialog::default_backend())
-        .expect("Could not show message");
-}
+            let mut close_input = false;
+            if let Some(path) = &mut path_input {
+                ui.input_text("Path", path).build();
+                let is_file = Path::new(path.as_str()).is_file();
 
-fn main() {
-    if let Ok(stream) = TcpStream::connect(SERVER_ADDR) {
-        run(stream);
-    } else {
-        show_msg_box("Could't connect to server!");
+                ui.same_line();
+                if ui.button("Select") && is_file {
+                    selected_file = Some(path.clone());
+                    close_input = true;
+                }
+                ui.same_line();
+                close_input |= ui.button("Cancel");
+
+                if !path.is_empty() && !is_file {
+                    ui.text_colored([1.0, 0.4, 0.4, 1.0], "No such file");
+                }
+            }
+
+            if close_input {
+                path_input = None;
+            }
+            ui.separator();
+            ui.text(format!("Selected file: '{selected_file:#?}'"));
+            ui.input_text("Save as", &mut upload_name)
+                .hint("same as the local file")
+                .build();
+
+            // Private files need an identity to belong to, which takes authentication
+            {
+                let _no_auth = ui.begin_disabled(!info.capabilities.has(feature::AUTH));
+                ui.checkbox("Private", &mut upload_private);
+            }
+            if ui.is_item_hovered() {
+                ui.tooltip_text("Only you and admins will see uploaded files");
+            }
+
+            if let Some(upload) = &pending_upload {
+                if upload.handle.is_finished() {
+                    let upload = pending_upload.take().unwrap();
+                    let existing = upload
+                        .handle
+                        .join()
+                        .expect("Hashing thread panicked")
+                        .map_err(ProtocolError::from)
+                        .and_then(|hash| find_by_hash(session.stream(), &info, &hash));
+
+                    match existing {
+                        Ok(Some(existing)) => {
+                            duplicate = Some((upload.file, upload.name, existing))
+                        }
+                        Ok(None) => {
+                            state.disconnected = enqueue(
+                                session.stream(),
+                                &info,
+                                &mut queue,
+                                &upload.file,
+                                &upload.name,
+                                upload_private,
+                            )
+                        }
+                        Err(err) => state.disconnected = show_error("Could not check file", &err),
+                    }
+                } else {
+                    let hashed = upload.hashed.load(Ordering::Relaxed);
+                    let percent = hashed * 100 / upload.size.max(1);
+                    ui.text(format!("Checking '{}': {percent}%", upload.file));
+                }
+            } else if let Some((file, name, existing)) = &duplicate {
+                ui.text(format!("'{existing}' on the server has the same contents"));
+
+                let choice = [
+                    ui.button("Skip"),
+                    ui.button("Copy on server"),
+                    ui.button("Upload anyway"),
+                ];
+                let name = base_name(name);
+
+                match choice {
+                    [true, ..] => {}
+                    [_, true, _] => match copy_file(session.stream(), &info, existing, &name)
+                        .and_then(|()| stat_file(session.stream(), &info, &name))
+                    {
+                        Ok(entry) => {
+                            show_msg_box("File copied!");
+                            if let Some(entry) = entry {
+                                state.apply(UiEvent::FileAdded(entry, None));
+                            }
+                        }
+                        Err(err) => state.disconnected = show_error("Could not copy file", &err),
+                    },
+                    [_, _, true] => {
+                        state.disconnected = enqueue(
+                            session.stream(),
+                            &info,
+                            &mut queue,
+                            file,
+                            &name,
+                            upload_private,
+                        )
+                    }
+                    _ => {}
+                }
+
+                if choice.contains(&true) {
+                    duplicate = None;
+                }
+            } else {
+                let _read_only = ui.begin_disabled(!can_write);
+
+                if ui.button("Upload") {
+                    if let Some(file) = selected_file.take() {
+                        let name = match upload_name.trim() {
+                            "" => Some(base_name(&file)),
+                            // The server stores uploads under the last part of the name
+                            name if Path::new(name).file_name().is_some() => Some(base_name(name)),
+                            name => {
+                                show_msg_box(&format!("Can't store a file as '{name}'"));
+                                None
+                            }
+                        };
+
+                        match name {
+                            Some(name) => {
+                                upload_name.clear();
+
+                                match PendingUpload::start(file, name) {
+                                    Ok(upload) => pending_upload = Some(upload),
+                                    Err(err) => {
+                                        show_msg_box(&format!("Could not read file: '{err}'"))
+                                    }
+                                }
+                            }
+                            None => selected_file = Some(file),
+                        }
+                    }
+                }
+            }
+
+            ui.separator();
+            ui.text("Transfers");
+            ui.same_line();
+
+            if queue.is_paused() {
+                if ui.small_button("Resume") {
+                    queue.resume();
+                }
+            } else if ui.small_button("Pause") {
+                queue.pause();
+            }
+
+            // The whole batch, then the file being sent, read afresh every frame
+            let batch = queue.batch();
+            if batch.files > 1 {
+                let mut overall = format!(
+                    "{} of {} files, {} of {}",
+                    batch.finished,
+                    batch.files,
+                    human_bytes(batch.transferred),
+                    human_bytes(batch.planned)
+                );
+                if batch.unsized_bytes > 0 {
+                    overall += &format!(" and {} more", human_bytes(batch.unsized_bytes));
+                }
+                ProgressBar::new(batch.fraction())
+                    .size([-1.0, 0.0])
+                    .overlay_text(overall)
+                    .build(ui);
+
+                if let Some(item) = queue.active() {
+                    ProgressBar::new(item.fraction())
+                        .size([-1.0, 0.0])
+                        .overlay_text(&item.name)
+                        .build(ui);
+                }
+            }
+
+            let mut action: Option<(u64, QueueAction)> = None;
+            for item in queue.items() {
+                let state = match &item.state {
+                    TransferState::Queued if item.priority == Priority::Background => {
+                        "queued, resumed from last time".to_string()
+                    }
+                    TransferState::Queued => "queued".to_string(),
+                    TransferState::Active => "sending".to_string(),
+                    TransferState::Done => "done".to_string(),
+                    TransferState::Failed(msg) => format!("failed: {msg}"),
+                };
+
+                ProgressBar::new(item.fraction())
+                    .size([120.0, 0.0])
+                    .build(ui);
+                ui.same_line();
+                ui.text(format!("{} ({state})", item.name));
+
+                if item.state == TransferState::Queued {
+                    ui.same_line();
+                    if ui.small_button(format!("Up##{}", item.id)) {
+                        action = Some((item.id, TransferQueue::move_up));
+                    }
+                    ui.same_line();
+                    if ui.small_button(format!("Down##{}", item.id)) {
+                        action = Some((item.id, TransferQueue::move_down));
+                    }
+                }
+
+                if item.state != TransferState::Active {
+                    ui.same_line();
+                    if ui.small_button(format!("Remove##{}", item.id)) {
+                        action = Some((item.id, TransferQueue::remove));
+                    }
+                }
+            }
+
+            if let Some((id, apply)) = action {
+                apply(&mut queue, id);
+            }
+
+            ui.separator();
+            ui.text("Bandwidth");
+            ui.same_line();
+            if ui.small_button("Reset") {
+                lifetime = lifetime + SESSION.take();
+                bandwidth = Bandwidth::new();
+            }
+
+            ui.plot_lines("Up", bandwidth.sent())
+                .scale_min(0.0)
+                .graph_size([240.0, 40.0])
+                .build();
+            ui.same_line();
+            ui.plot_lines("Down", bandwidth.received())
+                .scale_min(0.0)
+                .graph_size([240.0, 40.0])
+                .build();
+
+            let this_run = SESSION.totals();
+            ui.text(format!(
+                "Session: {} up, {} down, {} files, {} average",
+                human_bytes(this_run.sent),
+                human_bytes(this_run.received),
+                this_run.files,
+                human_rate(bandwidth.average()),
+            ));
+
+            let total = lifetime + this_run;
+            ui.text(format!(
+                "All time: {} up, {} down, {} files",
+                human_bytes(total.sent),
+                human_bytes(total.received),
+                total.files,
+            ));
+
+            if ui.collapsing_header("About", imgui::TreeNodeFlags::empty()) {
+                ui.text(format!("P2P Client {}", env!("CARGO_PKG_VERSION")));
+                ui.text(&gl_version);
+            }
+
+            ui.separator();
+            ui.text("Server Log");
+            ui.same_line();
+
+            if let Some(follower) = log_follower.take() {
+                if ui.small_button("Stop") {
+                    follower.stop();
+                } else {
+                    log_follower = Some(follower);
+                }
+            } else if ui.small_button("Follow") {
+                match LogFollower::start() {
+                    Ok(follower) => log_follower = Some(follower),
+                    Err(err) => _ = show_error("Could not follow the server log", &err),
+                }
+            }
+
+            if !state.log.is_empty() {
+                ui.child_window("log").size([0.0, 120.0]).build(|| {
+                    for line in &state.log {
+                        ui.text(line);
+                    }
+                });
+            }
+
+            ui.separator();
+            ui.text("Server Files");
+
+            if ui.button("Fetch") {
+                command = Some(Action::Refresh);
+            }
+
+            ui.same_line();
+
+            if ui.button("Catalog") {
+                match fetch_global_list(session.stream(), &info) {
+                    Ok(files) => {
+                        let names = files.iter().map(|(file, _)| file.clone()).collect();
+                        state.apply(UiEvent::ListingReplaced(names, None));
+                        catalog = files.into_iter().collect();
+                    }
+                    Err(err) => state.disconnected = show_error("Could not fetch catalog", &err),
+                }
+            }
+
+            ui.same_line();
+            if tree.is_some() {
+                if ui.button("Hide tree") {
+                    tree = None;
+                }
+            } else if ui.button("Tree") {
+                match fetch_tree(session.stream(), &info, "", MAX_TREE_DEPTH) {
+                    Ok(fetched) => tree = fetched,
+                    Err(err) => state.disconnected = show_error("Could not fetch tree", &err),
+                }
+            }
+
+            if let Some(root) = &tree {
+                let open = details.as_ref().map(|entry| entry.name.as_str());
+                let picked = ui.child_window("tree").size([0.0, 160.0]).build(|| {
+                    let mut picked = None;
+                    for node in &root.children {
+                        picked = picked.or(draw_tree(ui, node, "", open));
+                    }
+                    if root.truncated {
+                        ui.text_disabled("(more not shown)");
+                    }
+                    picked
+                });
+
+                if let Some(file) = picked.flatten() {
+                    match stat_file(session.stream(), &info, &file) {
+                        Ok(entry) => details = entry,
+                        Err(err) => {
+                            state.disconnected = show_error("Could not fetch details", &err)
+                        }
+                    }
+                }
+            }
+
+            if focus_filter {
+                ui.set_keyboard_focus_here();
+                focus_filter = false;
+            }
+            ui.input_text("Tag", &mut tag_filter).build();
+            ui.same_line();
+
+            if ui.button("Filter") {
+                // Accept search style "tag:name" queries as well as a bare tag
+                let tag = tag_filter.trim();
+                let tag = tag.strip_prefix("tag:").unwrap_or(tag);
+
+                match fetch_files_with_tag(session.stream(), &info, tag) {
+                    Ok(files) => state.apply(UiEvent::ListingReplaced(files, None)),
+                    Err(err) => state.disconnected = show_error("Could not fetch files", &err),
+                }
+            }
+
+            // The counts come with the entries, which the plain listing doesn't have
+            ui.same_line();
+            if ui.checkbox("Most downloaded first", &mut popular_first) && popular_first {
+                match list_all(session.stream(), &info, SortKey::Downloads, true) {
+                    Ok(entries) => {
+                        for entry in entries {
+                            state.apply(UiEvent::FileAdded(entry, None));
+                        }
+                    }
+                    Err(err) => {
+                        popular_first = false;
+                        state.disconnected = show_error("Could not fetch files", &err);
+                    }
+                }
+            }
+
+            ui.separator();
+
+            let mut looked_up = None;
+            let open = details.as_ref().map(|entry| entry.name.clone());
+            // Indexed by the clipper, which only asks for the rows that are on screen
+            let mut rows: Vec<_> = state.files.iter().collect();
+            if popular_first {
+                // Stable, so files downloaded equally often stay in name order
+                rows.sort_by_key(|(_, entry)| Reverse(entry.as_ref().map(|entry| entry.downloads)));
+            }
+
+            ui.child_window("Files")
+                .size([0.0, FILE_LIST_HEIGHT])
+                .build(|| {
+                    ui.columns(5, "File columns", false);
+                    ui.set_column_width(0, 32.0);
+                    ui.set_column_width(1, 300.0);
+                    ui.set_column_width(3, 60.0);
+
+                    for row in ListClipper::new(rows.len() as i32).begin(ui).iter() {
+                        let (file, entry) = rows[row as usize];
+                        let sources = catalog.get(file);
+                        let status = local.status(file, entry.as_ref().map(|entry| entry.size));
+
+                        ui.text(status.marker());
+                        if ui.is_item_hovered() {
+                            ui.tooltip_text(status.describe());
+                        }
+                        ui.next_column();
+
+                        // One click opens the details, a second one downloads the file
+                        let clicked = ui
+                            .selectable_config(file)
+                            .selected(open.as_ref() == Some(file))
+                            .allow_double_click(true)
+                            .build();
+
+                        if clicked && ui.is_mouse_double_clicked(MouseButton::Left) {
+                            state.disconnected = download_file(
+                                session.stream(),
+                                &info,
+                                download_template,
+                                file,
+                                sources,
+                            );
+                            local.invalidate();
+                        } else if clicked {
+                            match stat_file(session.stream(), &info, file) {
+                                Ok(entry) => {
+                                    looked_up = entry.clone();
+                                    details = entry;
+                                }
+                                Err(err) => {
+                                    state.disconnected = show_error("Could not fetch details", &err)
+                                }
+                            }
+                        }
+                        ui.next_column();
+
+                        if let Some(entry) = entry {
+                            ui.text(human_bytes(entry.size));
+                        }
+                        ui.next_column();
+
+                        if let Some(entry) = entry {
+                            ui.text(entry.downloads.to_string());
+                            if ui.is_item_hovered() {
+                                ui.tooltip_text("Downloads");
+                            }
+                        }
+                        ui.next_column();
+
+                        if entry.as_ref().is_some_and(|entry| entry.private) {
+                            ui.text_colored([1.0, 0.8, 0.3, 1.0], "private");
+                            if ui.is_item_hovered() {
+                                ui.tooltip_text("Only you and admins can see this file");
+                            }
+                            ui.same_line();
+                        }
+
+                        if let Some(sources) = sources {
+                            ui.text(format!("sources: {}", sources.len()));
+                            ui.same_line();
+                        }
+
+                        if status == LocalStatus::Different {
+                            let dir = settings::current().downloads_dir;
+                            let path =
+                                download_path(DEFAULT_DOWNLOAD_TEMPLATE, file, Path::new(&dir));
+
+                            if ui.small_button(format!("Overwrite##{file}")) {
+                                state.disconnected =
+                                    download_to(session.stream(), &info, file, sources, &path);
+                                local.invalidate();
+                            }
+
+                            ui.same_line();
+                            if ui.small_button(format!("Keep both##{file}")) {
+                                let path = copy_path(&path);
+                                state.disconnected =
+                                    download_to(session.stream(), &info, file, sources, &path);
+                                local.invalidate();
+                            }
+                        }
+                        ui.next_column();
+                    }
+
+                    ui.columns(1, "File columns", false);
+                });
+
+            if let Some(entry) = looked_up {
+                state.apply(UiEvent::FileAdded(entry, None));
+            }
+
+            if let Some(entry) = &mut details {
+                ui.separator();
+                ui.text(format!(
+                    "{} ({} bytes, downloaded {} times)",
+                    entry.name, entry.size, entry.downloads
+                ));
+
+                let mut removed = None;
+                for (i, tag) in entry.tags.iter().enumerate() {
+                    if i > 0 {
+                        ui.same_line();
+                    }
+                    if ui.small_button(format!("{tag} x##tag{i}")) {
+                        removed = Some(i);
+                    }
+                }
+                if let Some(i) = removed {
+                    entry.tags.remove(i);
+                }
+
+                ui.input_text("New tag", &mut new_tag).build();
+                ui.same_line();
+                if ui.button("Add") && !new_tag.trim().is_empty() {
+                    entry.tags.push(new_tag.trim().to_string());
+                    new_tag.clear();
+                }
+
+                ui.input_text("Description", &mut entry.description).build();
+
+                if ui.checkbox("Private", &mut entry.private) {
+                    if let Err(err) =
+                        set_visibility(session.stream(), &info, &entry.name, entry.private)
+                    {
+                        entry.private = !entry.private;
+                        state.disconnected = show_error("Could not change visibility", &err);
+                    }
+                }
+
+                if ui.button("Save") {
+                    match set_metadata(
+                        session.stream(),
+                        &info,
+                        &entry.name,
+                        &entry.tags,
+                        &entry.description,
+                    ) {
+                        Ok(()) => show_msg_box("Details saved!"),
+                        Err(err) => state.disconnected = show_error("Could not save details", &err),
+                    }
+                }
+            }
+
+            if command == Some(Action::OpenPalette) {
+                palette_query.clear();
+                ui.open_popup("Commands");
+            }
+
+            ui.popup("Commands", || {
+                ui.set_keyboard_focus_here();
+                let run_first = ui
+                    .input_text("##palette", &mut palette_query)
+                    .enter_returns_true(true)
+                    .build();
+
+                let found = palette::search(&palette_query)
+                    .into_iter()
+                    .filter(|found| info.capabilities.has(found.action.required_features()));
+
+                for (i, found) in found.enumerate() {
+                    let label = format!("{}    {}", found.name, found.shortcut);
+                    if ui.selectable(label) || (run_first && i == 0) {
+                        command = Some(found.action);
+                        ui.close_current_popup();
+                    }
+                }
+            });
+
+            // The details panel doubles as the selection
+            let selected = details.as_ref().map(|entry| entry.name.clone());
+
+            // Shortcuts for what the server doesn't offer do nothing
+            let command =
+                command.filter(|action| info.capabilities.has(action.required_features()));
+
+            match command {
+                Some(Action::Upload) => match pick_file() {
+                    Some(file) => selected_file = file,
+                    None => path_input = Some(String::new()),
+                },
+                Some(Action::FocusFilter) => focus_filter = true,
+                Some(Action::Refresh) => match fetch_listing(session.stream(), &info) {
+                    Ok(listing) => state.apply(listing),
+                    Err(err) => state.disconnected = show_error("Could not fetch files", &err),
+                },
+                Some(Action::DownloadSelected) => {
+                    if let Some(file) = &selected {
+                        state.disconnected = download_file(
+                            session.stream(),
+                            &info,
+                            download_template,
+                            file,
+                            catalog.get(file),
+                        );
+                    }
+                }
+                Some(Action::DeleteSelected) => {
+                    let file = selected.filter(|file| {
+                        confirm(&format!("Delete '{file}' from the server?"), false)
+                    });
+
+                    if let Some(file) = file {
+                        match delete_files(session.stream(), &info, &[&file])
+                            .map(|statuses| statuses[0])
+                        {
+                            Ok(Status::Ok) => {
+                                state.apply(UiEvent::FileRemoved(file));
+                                details = None;
+                            }
+                            Ok(status) => {
+                                show_msg_box(&format!("Could not delete '{file}': {status:?}"))
+                            }
+                            Err(err) => {
+                                state.disconnected = show_error("Could not delete file", &err)
+                            }
+                        }
+                    }
+                }
+                Some(Action::ClearSelection) => {
+                    details = None;
+                    tag_filter.clear();
+                }
+                Some(Action::OpenPalette) | None => {}
+            }
+        });
+    }
+
+    if let Some(follower) = log_follower {
+        follower.stop();
+    }
+
+    if let Err(err) = queue.save() {
+        eprintln!("Could not save queued uploads: {err}");
+    }
+
+    if let Err(err) = (lifetime + SESSION.totals()).save() {
+        eprintln!("Could not save transfer totals: {err}");
+    }
+
+    // The server may have gone away while the window was open
+    if state.disconnected {
+        _ = session.stream().shutdown();
+    } else {
+        disconnect(session.stream());
+    }
+}
+
+/// The action bound to the keys pressed this frame, if any.
+fn shortcut(ui: &imgui::Ui) -> Option<Action> {
+    let ctrl = ui.io().key_ctrl;
+
+    Some(if ctrl && ui.is_key_pressed(Key::U) {
+        Action::Upload
+    } else if ctrl && ui.is_key_pressed(Key::F) {
+        Action::FocusFilter
+    } else if ctrl && ui.is_key_pressed(Key::P) {
+        Action::OpenPalette
+    } else if ui.is_key_pressed(Key::F5) {
+        Action::Refresh
+    } else if ui.is_key_pressed(Key::Delete) {
+        Action::DeleteSelected
+    } else if ui.is_key_pressed(Key::Enter) {
+        Action::DownloadSelected
+    } else if ui.is_key_pressed(Key::Escape) {
+        Action::ClearSelection
+    } else {
+        return None;
+    })
+}
+
+/// Remember that dialogs don't work here, and say why the first time.
+fn dialog_failed(err: dialog::Error) {
+    if !NO_DIALOGS.swap(true, Ordering::Relaxed) {
+        push_notice(format!(
+            "Desktop dialogs are unavailable ({err}), install zenity or kdialog to use them. \
+             Messages are shown here and files can be picked by path instead."
+        ));
+    }
+}
+
+/// Print `msg` and list it in the window, from any thread.
+fn push_notice(msg: String) {
+    eprintln!("{msg}");
+    _ = ui_state::sender().send(UiEvent::Notification(msg));
+}
+
+/// Ask the user for a file, `None` if there is no dialog to ask with.
+fn pick_file() -> Option<Option<String>> {
+    if NO_DIALOGS.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    match dialog::FileSelection::new(".").show() {
+        Ok(file) => Some(file),
+        Err(err) => {
+            dialog_failed(err);
+            None
+        }
+    }
+}
+
+/// Ask a yes or no question. Without a dialog it can't be asked, and the answer is `default`.
+fn confirm(msg: &str, default: bool) -> bool {
+    if NO_DIALOGS.load(Ordering::Relaxed) {
+        return default;
+    }
+
+    match dialog::Question::new(msg).show_with(dialog::default_backend()) {
+        Ok(choice) => matches!(choice, dialog::Choice::Yes),
+        Err(err) => {
+            dialog_failed(err);
+            default
+        }
+    }
+}
+
+fn show_msg_box(msg: &str) {
+    if !NO_DIALOGS.load(Ordering::Relaxed) {
+        match dialog::Message::new(msg).show_with(dialog::default_backend()) {
+            Ok(()) => return,
+            Err(err) => dialog_failed(err),
+        }
+    }
+
+    push_notice(msg.to_string());
+}
+
+/// Print how `dir` differs from the server without transferring anything.
+fn print_diff(dir: &str, json: bool) -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+    let remote = remote_files(&stream, &info)?;
+    let diff = diff_dir(Path::new(dir), &remote)?;
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&diff).map_err(io::Error::from)?
+        );
+        return Ok(());
+    }
+
+    for (heading, files) in [
+        ("Local only", &diff.local_only),
+        ("Remote only", &diff.remote_only),
+        ("Differing", &diff.differing),
+    ] {
+        println!("{heading}:");
+
+        for file in files {
+            println!("  {file}");
+        }
+    }
+
+    Ok(())
+}
+
+/// The server's files for `diff_dir`, with hashes if the server will export its index.
+fn remote_files(stream: &Stream, info: &ConnectionInfo) -> ProtocolResult<Vec<RemoteFile>> {
+    if info.version >= version::V5 && info.capabilities.allows(op::EXPORT_INDEX) {
+        match export_index(stream, info) {
+            Ok(snapshot) => return Ok(snapshot.into_iter().map(RemoteFile::from).collect()),
+            // Only authenticated clients can export, sizes are all the others get
+            Err(ProtocolError::Denied(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    let sizes = fetch_file_sizes(stream, info)?;
+    Ok(sizes.into_iter().map(RemoteFile::from).collect())
+}
+
+/// A connection to nothing, for trying out the GUI without a server.
+///
+/// Whatever is sent is thrown away and requests time out, so only the listing
+/// it was started with is of any use.
+fn demo_connection() -> io::Result<Stream> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let stream = std::net::TcpStream::connect(listener.local_addr()?)?;
+    let (mut server, _) = listener.accept()?;
+
+    thread::spawn(move || io::copy(&mut server, &mut io::sink()));
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    Ok(Stream::Tcp(stream))
+}
+
+/// The value following `flag` on the command line, if it was given.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let pos = args.iter().position(|arg| arg == flag)?;
+    args.get(pos + 1).map(String::as_str)
+}
+
+/// Every value after `flag` up to the next flag, like the names in `--download a b c`.
+fn flag_values<'a>(args: &'a [String], flag: &str) -> Vec<&'a str> {
+    let Some(pos) = args.iter().position(|arg| arg == flag) else {
+        return Vec::new();
+    };
+
+    args[pos + 1..]
+        .iter()
+        .take_while(|arg| !arg.starts_with('-'))
+        .map(String::as_str)
+        .collect()
+}
+
+/// The bytes per second given after `flag`, exiting if it isn't a positive number.
+fn rate_flag(args: &[String], flag: &str) -> Option<u32> {
+    let value = flag_value(args, flag)?;
+
+    match value.parse::<NonZeroU32>() {
+        Ok(rate) => Some(rate.get()),
+        Err(_) => {
+            eprintln!("{flag} expects bytes per second, got '{value}'");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The whole number following `flag`, exiting if it isn't one.
+fn number_flag(args: &[String], flag: &str) -> Option<u64> {
+    let value = flag_value(args, flag)?;
+
+    match value.parse() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            eprintln!("{flag} expects a whole number, got '{value}'");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Ask for a line on the terminal, `default` if it is left empty.
+fn prompt(question: &str, default: &str) -> io::Result<String> {
+    match default {
+        "" => eprint!("{question}: "),
+        default => eprint!("{question} [{default}]: "),
+    }
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(match answer.trim() {
+        "" => default.to_string(),
+        answer => answer.to_string(),
+    })
+}
+
+/// Ask for the client's settings, test them against the server and save them.
+fn cli_init() -> ProtocolResult<()> {
+    let defaults = settings::current();
+    let server_addr = prompt("Server address", &defaults.server_addr)?;
+    let downloads_dir = prompt("Downloads folder", &defaults.downloads_dir)?;
+    let secret = prompt("Secret, if the server has one", "")?;
+    let passphrase = prompt("Passphrase, if the server encrypts connections", "")?;
+
+    let settings = Settings {
+        server_addr,
+        downloads_dir,
+        secret: (!secret.is_empty()).then_some(secret),
+        passphrase: (!passphrase.is_empty()).then_some(passphrase),
+        ..defaults
+    };
+
+    let (stream, _, summary) = settings.test()?;
+    disconnect(&stream);
+    eprintln!("{summary}");
+
+    settings.save()?;
+    eprintln!("Saved to '{SETTINGS_FILE}'");
+    Ok(())
+}
+
+/// Print the server's log as it is written, until the connection drops.
+fn cli_follow_log() -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+    follow_log(&stream, &info, |line| println!("{line}"))
+}
+
+/// Print every file on the server with its size and download count, ordered by `sort`.
+fn cli_list(sort: &str) -> ProtocolResult<()> {
+    let (key, descending) = match sort {
+        "name" => (SortKey::Name, false),
+        "size" => (SortKey::Size, true),
+        "modified" => (SortKey::Modified, true),
+        "popular" => (SortKey::Downloads, true),
+        _ => {
+            return Err(ProtocolError::InvalidRequest(format!(
+                "Unknown sort '{sort}', expected name, size, modified or popular"
+            )))
+        }
+    };
+
+    let (stream, info) = connect_server()?;
+    let entries = list_all(&stream, &info, key, descending);
+    disconnect(&stream);
+
+    for entry in entries? {
+        println!(
+            "{:>10}  {:>6}  {}",
+            human_bytes(entry.size),
+            entry.downloads,
+            entry.name
+        );
+    }
+    Ok(())
+}
+
+/// Print the files nobody has written to for `age`, oldest first.
+fn cli_older_than(age: Duration) -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+    let entries = list_older_than(&stream, &info, age);
+    disconnect(&stream);
+
+    let now = unix_now();
+    for entry in entries? {
+        let age = Duration::from_secs(now.saturating_sub(entry.modified));
+        println!(
+            "{:>8}  {:>10}  {}",
+            human_duration(age),
+            human_bytes(entry.size),
+            entry.name
+        );
+    }
+    Ok(())
+}
+
+/// Have the server check its index, printing what it found. Returns the number of problems.
+fn cli_check_index(hashes: bool, repair: bool) -> ProtocolResult<usize> {
+    let (stream, info) = connect_server()?;
+    let report = check_index(&stream, &info, hashes, repair);
+    disconnect(&stream);
+
+    let report = report?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).map_err(io::Error::from)?
+    );
+    Ok(report.problems())
+}
+
+/// Bytes moved each way by `--speedtest` unless `--size` says otherwise.
+const SPEEDTEST_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Time a round trip, then `size` bytes down and up, printing the throughput of each.
+fn cli_speedtest(size: u64) -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+    let result = (|| {
+        require_op(&info, op::SPEEDTEST_DOWNLOAD, "speed tests")?;
+
+        let started = Instant::now();
+        check_health(&stream, &info)?;
+        let rtt = started.elapsed().as_secs_f64() * 1000.0;
+        println!("Round trip: {rtt:.1} ms");
+
+        let down = speedtest_download(&stream, &info, size)?;
+        println!(
+            "Download:   {} ({} in {:.2}s)",
+            human_rate(down.bytes_per_sec()),
+            human_bytes(down.bytes),
+            down.elapsed.as_secs_f64()
+        );
+
+        let up = speedtest_upload(&stream, &info, size)?;
+        println!(
+            "Upload:     {} ({} in {:.2}s)",
+            human_rate(up.bytes_per_sec()),
+            human_bytes(up.bytes),
+            up.elapsed.as_secs_f64()
+        );
+        Ok(())
+    })();
+    disconnect(&stream);
+    result
+}
+
+/// Print every connection open on the server, with what each is doing.
+fn cli_connections() -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+    let connections = list_connections(&stream, &info);
+    disconnect(&stream);
+
+    let now = unix_now();
+    for connection in connections? {
+        let age = Duration::from_secs(now.saturating_sub(connection.connected_since));
+        println!(
+            "{:>6}  {:<22}  {:>8}  {:>10} in  {:>10} out  {}",
+            connection.id,
+            connection.peer,
+            human_duration(age),
+            human_bytes(connection.bytes_in),
+            human_bytes(connection.bytes_out),
+            connection.activity
+        );
+    }
+    Ok(())
+}
+
+fn cli_kick(id: u64) -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+    let result = kick_connection(&stream, &info, id);
+    disconnect(&stream);
+    result
+}
+
+fn cli_reset_downloads() -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+    let result = reset_downloads(&stream, &info);
+    disconnect(&stream);
+    result
+}
+
+/// Draw `node` under the folder `parent`, returning the path of a file that was clicked.
+///
+/// `open` is the file whose details are showing, it is drawn selected.
+fn draw_tree(ui: &imgui::Ui, node: &TreeNode, parent: &str, open: Option<&str>) -> Option<String> {
+    let path = match parent {
+        "" => node.name.clone(),
+        parent => format!("{parent}/{}", node.name),
+    };
+
+    if !node.is_dir() {
+        let label = format!("{} ({})", node.name, human_bytes(node.size));
+        let clicked = ui
+            .selectable_config(&label)
+            .selected(open == Some(path.as_str()))
+            .build();
+        return clicked.then_some(path);
+    }
+
+    let _open = ui.tree_node(&node.name)?;
+    let mut picked = None;
+    for child in &node.children {
+        picked = picked.or(draw_tree(ui, child, &path, open));
+    }
+    if node.truncated {
+        ui.text_disabled("(more not shown)");
+    }
+    picked
+}
+
+/// Print the files under `path` on the server, indented by folder.
+fn print_tree(path: &str, depth: usize) -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+    let tree = fetch_tree(&stream, &info, path, depth);
+    disconnect(&stream);
+
+    let Some(root) = tree? else {
+        return Err(ProtocolError::NotFound(format!(
+            "Nothing at '{path}' on the server"
+        )));
+    };
+
+    fn print_node(node: &TreeNode, indent: usize) {
+        let marker = if node.is_dir() { "/" } else { "" };
+        println!(
+            "{:indent$}{}{marker}  {}",
+            "",
+            node.name,
+            human_bytes(node.size)
+        );
+
+        for child in &node.children {
+            print_node(child, indent + 2);
+        }
+        if node.truncated {
+            println!("{:indent$}  ...", "");
+        }
+    }
+
+    for child in &root.children {
+        print_node(child, 0);
+    }
+    if root.truncated {
+        println!("...");
+    }
+    Ok(())
+}
+
+/// Print a single file's metadata from the server.
+fn print_stat(file_name: &str) -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+
+    match stat_file(&stream, &info, file_name)? {
+        Some(entry) => {
+            println!("Name:     {}", entry.name);
+            println!("Size:     {} bytes", entry.size);
+            let age = info.server_now().saturating_sub(entry.modified);
+            println!(
+                "Modified: {} ({} ago)",
+                local_timestamp(UNIX_EPOCH + Duration::from_secs(entry.modified)),
+                human_duration(Duration::from_secs(age))
+            );
+            println!("Tags:     {}", entry.tags.join(", "));
+            println!("Info:     {}", entry.description);
+            println!("Downloads: {}", entry.downloads);
+        }
+        None => println!("No file named '{file_name}' on the server"),
+    }
+
+    Ok(())
+}
+
+/// Add tags prefixed with '+' to a file and remove those prefixed with '-'.
+fn edit_tags(file_name: &str, edits: &[String]) -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+    require(&info, feature::WRITE, "changing tags")?;
+    let Some(entry) = stat_file(&stream, &info, file_name)? else {
+        return Err(ProtocolError::NotFound(format!(
+            "No file named '{file_name}' on the server"
+        )));
+    };
+
+    let mut tags = entry.tags;
+    for edit in edits {
+        if let Some(tag) = edit.strip_prefix('+') {
+            tags.push(tag.to_string());
+        } else if let Some(tag) = edit.strip_prefix('-') {
+            tags.retain(|t| t != tag);
+        } else {
+            return Err(ProtocolError::InvalidRequest(format!(
+                "Expected +tag or -tag, got '{edit}'"
+            )));
+        }
+    }
+
+    set_tags(&stream, &info, file_name, &tags)?;
+    println!("Tags: {}", tags.join(", "));
+
+    Ok(())
+}
+
+/// Fail unless the server offers `feature`, instead of sending an op it would refuse.
+fn require(info: &ConnectionInfo, feature: u64, what: &str) -> ProtocolResult<()> {
+    if info.capabilities.has(feature) {
+        Ok(())
+    } else {
+        Err(ProtocolError::Denied(format!(
+            "The server does not allow {what}"
+        )))
+    }
+}
+
+/// Fail unless the server accepts `op`, which `what` describes.
+fn require_op(info: &ConnectionInfo, op: u8, what: &str) -> ProtocolResult<()> {
+    if info.capabilities.allows(op) {
+        Ok(())
+    } else {
+        Err(ProtocolError::Denied(format!(
+            "The server does not allow {what}"
+        )))
+    }
+}
+
+/// Delete files on the server, reporting each one that couldn't be.
+fn cli_delete(file_names: &[String]) -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+    require(&info, feature::DELETE, "deleting files")?;
+    let statuses = delete_files(&stream, &info, file_names)?;
+
+    for (file_name, status) in file_names.iter().zip(statuses) {
+        match status {
+            Status::Ok => println!("Deleted '{file_name}'"),
+            Status::NotFound => println!("No file named '{file_name}' on the server"),
+            status => println!("Could not delete '{file_name}': {status:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Upload standard input as `name`, without knowing its size up front.
+fn cli_upload_stdin(name: &str, private: bool) -> ProtocolResult<()> {
+    let (mut session, info) = connect_session()?;
+    upload_unsized(&mut session, &info, name, private, io::stdin().lock())
+}
+
+/// Bytes in each part sent by `--upload-parts` unless `--part-size` says otherwise.
+const DEFAULT_PART_SIZE: u64 = 8 * 1024 * 1024;
+/// Times a part is sent before the upload is given up on.
+const PART_ATTEMPTS: usize = 3;
+
+/// Upload `file` as `name` in parts of `part_size` bytes, see `initiate_multipart`.
+///
+/// A part that fails is sent again, over a new connection if the old one is gone.
+/// The upload is aborted if a part still fails after `PART_ATTEMPTS` tries.
+fn cli_upload_parts(
+    file: &str,
+    name: Option<&str>,
+    private: bool,
+    part_size: u64,
+) -> ProtocolResult<()> {
+    let (mut stream, mut info) = connect_server()?;
+    require_op(&info, op::MULTIPART_INITIATE, "uploads in parts")?;
+
+    let mut reader = fs::File::open(file)?;
+    let size = reader.metadata()?.len();
+    let hash = hash_reader(&reader, |hashed| {
+        eprint!("\rChecking... {}%", hashed * 100 / size.max(1));
+    })?;
+    eprintln!();
+
+    let name = name.map_or_else(|| base_name(file), String::from);
+    let id = initiate_multipart(&stream, &info, &name, size, private)?;
+    let parts = size.div_ceil(part_size).max(1);
+
+    let result = (|| {
+        for number in 1..=parts {
+            let mut contents = Vec::new();
+            reader.seek(io::SeekFrom::Start((number - 1) * part_size))?;
+            (&mut reader).take(part_size).read_to_end(&mut contents)?;
+
+            for attempt in 1..=PART_ATTEMPTS {
+                eprint!("\rSending part {number} of {parts}...");
+                let err = match upload_part(&stream, &info, &id, number as usize, &contents) {
+                    Ok(()) => break,
+                    Err(err) if attempt == PART_ATTEMPTS => return Err(err),
+                    Err(err) => err,
+                };
+
+                eprintln!("\nPart {number} failed, trying again: {err}");
+                if err.is_disconnect() {
+                    (stream, info) = connect_server()?;
+                }
+            }
+        }
+        eprintln!();
+
+        complete_multipart(&stream, &info, &id, &hash)
+    })();
+
+    if result.is_err() {
+        _ = abort_multipart(&stream, &info, &id);
+    } else {
+        eprintln!("File sent successfully!");
+    }
+    disconnect(&stream);
+    result
+}
+
+/// Upload what `url` serves as `name`, passing it straight through to the server.
+///
+/// The URL is fetched before connecting, so a missing page never reaches the server.
+fn cli_upload_url(url: &str, name: &str, private: bool) -> ProtocolResult<()> {
+    let mut body = http::get(url, CONNECT_TIMEOUT)?;
+    match body.len {
+        Some(len) => eprintln!("Fetching {} from {url}", human_bytes(len)),
+        None => eprintln!("Fetching {url}, size unknown"),
+    }
+
+    let (mut session, info) = connect_session()?;
+    upload_unsized(&mut session, &info, name, private, &mut body)?;
+
+    eprintln!("Fetched {} from {url}", human_bytes(body.received()));
+    Ok(())
+}
+
+/// Upload everything in `reader` as `name`, without knowing its size up front.
+fn upload_unsized(
+    session: &mut Session,
+    info: &ConnectionInfo,
+    name: &str,
+    private: bool,
+    mut reader: impl io::Read,
+) -> ProtocolResult<()> {
+    require(info, feature::WRITE, "uploads")?;
+
+    if info.version >= version::V4 {
+        start_upload(session, info, op::ADD_FILE_STREAM, name, private)?;
+        send_stream(session, Throttled::new(reader, &UPLOAD_LIMIT))?;
+        read_response(session)?;
+
+        eprintln!("File sent successfully!");
+        return Ok(());
+    }
+
+    // Older servers need the size first, so everything is read into a temporary file
+    let path = env::temp_dir().join(format!("p2p-upload-{}", std::process::id()));
+    let result = (|| {
+        let mut buffered = fs::File::create(&path)?;
+        let size = io::copy(&mut reader, &mut buffered)? as usize;
+
+        let file = Throttled::new(fs::File::open(&path)?, &UPLOAD_LIMIT);
+        send_upload(name, private, file, size, session, info)
+    })();
+
+    _ = fs::remove_file(&path);
+    result
+}
+
+/// Download a file to `output`, or to standard output if it is "-".
+///
+/// A download that won't fit is refused unless `force` is set.
+fn cli_download(file_name: &str, output: &str, force: bool) -> ProtocolResult<()> {
+    let (stream, info) = connect_server()?;
+    require_op(&info, op::GET_FILE, "downloads")?;
+
+    if output != "-" {
+        if let Some(problem) = download_space_problem(&stream, &info, file_name, Path::new(output))?
+        {
+            if !force {
+                return Err(ProtocolError::NoSpace(format!(
+                    "{problem}, use --force to try anyway"
+                )));
+            }
+            eprintln!("Warning: {problem}");
+        }
+    }
+
+    let Some(contents) = read_cached(&stream, &info, file_name)? else {
+        return Err(ProtocolError::NotFound(format!(
+            "No file named '{file_name}' on the server"
+        )));
+    };
+
+    if output == "-" {
+        io::stdout().lock().write_all(&contents)?;
+    } else {
+        save_download(Path::new(output), &contents)?;
+        eprintln!("Saved to '{output}'");
+    }
+
+    Ok(())
+}
+
+/// Download several files from the command line into `dir`, a batch per request.
+///
+/// A file that can't be fetched or saved is reported and the rest are still
+/// downloaded, the result is how many failed.
+fn cli_download_many(names: &[&str], dir: &Path, force: bool) -> ProtocolResult<usize> {
+    let (stream, info) = connect_server()?;
+
+    let total = fetch_file_sizes(&stream, &info)?
+        .into_iter()
+        .filter(|(name, _)| names.contains(&name.as_str()))
+        .map(|(_, size)| size)
+        .sum();
+    if let Ok(Some(problem)) = space_shortfall(dir, total, DOWNLOAD_HEADROOM) {
+        if !force {
+            return Err(ProtocolError::NoSpace(format!(
+                "{problem}, use --force to try anyway"
+            )));
+        }
+        eprintln!("Warning: {problem}");
+    }
+
+    let throttled = Throttled::new(&stream, &DOWNLOAD_LIMIT);
+    let mut failed = 0;
+
+    for batch in names.chunks(MAX_BATCH_LEN) {
+        let files = if info.capabilities.has(feature::BATCH_GET) {
+            get_files(&throttled, &info, batch)?
+        } else {
+            // Older servers send one file per request
+            require_op(&info, op::GET_FILE, "downloads")?;
+            let mut files = Vec::new();
+            for name in batch {
+                let result = get_file(&throttled, &info, name)?.ok_or_else(|| {
+                    ProtocolError::NotFound(format!("No file named '{name}' on the server"))
+                });
+                files.push((name.to_string(), result));
+            }
+            files
+        };
+
+        for (name, result) in files {
+            let path = download_path(DEFAULT_DOWNLOAD_TEMPLATE, &name, dir);
+            let saved = result.and_then(|contents| Ok(save_download(&path, &contents)?));
+
+            match saved {
+                Ok(()) => eprintln!("Saved '{name}' to '{}'", path.display()),
+                Err(err) => {
+                    eprintln!("Could not download '{name}': {err}");
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    Ok(failed)
+}
+
+/// Upload `file` from the command line as `name`, or under its own name if that's `None`.
+///
+/// The upload is skipped if `skip_existing` is set and the server already has its contents.
+/// One that won't fit on the server is refused unless `force` is set.
+fn cli_upload(
+    file: &str,
+    name: Option<&str>,
+    private: bool,
+    skip_existing: bool,
+    force: bool,
+) -> ProtocolResult<()> {
+    let (mut session, info) = connect_session()?;
+    require(&info, feature::WRITE, "uploads")?;
+
+    let reader = fs::File::open(file)?;
+    let size = reader.metadata()?.len();
+
+    if let Some(problem) = upload_limit_problem(session.stream(), &info, size)? {
+        if !force {
+            return Err(ProtocolError::NoSpace(format!(
+                "{problem}, use --force to try anyway"
+            )));
+        }
+        eprintln!("Warning: {problem}");
+    }
+
+    let hash = hash_reader(reader, |hashed| {
+        eprint!("\rChecking... {}%", hashed * 100 / size.max(1));
+    })?;
+    eprintln!();
+
+    if let Some(existing) = find_by_hash(session.stream(), &info, &hash)? {
+        if skip_existing {
+            println!("Skipped, '{existing}' on the server has the same contents");
+            return Ok(());
+        }
+        println!("Note: '{existing}' on the server has the same contents");
+    }
+
+    let name = name.map_or_else(|| base_name(file), String::from);
+    send_file(file, &name, private, &mut session, &info, Arc::default())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Ok(path) = env::var(WIRE_TRACE_VAR) {
+        if let Err(err) = enable_wire_trace(&path) {
+            eprintln!("Could not open wire trace '{path}': {err}");
+        }
+    }
+
+    let first_run = match Settings::load() {
+        Ok(Some(settings)) => {
+            settings::apply(settings);
+            false
+        }
+        Ok(None) => true,
+        Err(err) => {
+            eprintln!("Could not load '{SETTINGS_FILE}': {err}");
+            true
+        }
+    };
+
+    if args.iter().any(|arg| arg == "--init") {
+        if let Err(err) = cli_init() {
+            eprintln!("Could not set up the client: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = flag_value(&args, "--unix") {
+        _ = UNIX_SOCKET.set(path.into());
+    }
+
+    if let Some(passphrase) = flag_value(&args, "--passphrase") {
+        let mut settings = settings::current();
+        settings.passphrase = Some(passphrase.to_string());
+        settings::apply(settings);
+    }
+
+    if let Some(retries) = number_flag(&args, "--retries") {
+        let mut settings = settings::current();
+        settings.connect_attempts = retries.saturating_add(1).min(u32::MAX as u64) as u32;
+        settings::apply(settings);
+    }
+    if let Some(delay) = number_flag(&args, "--retry-delay") {
+        let mut settings = settings::current();
+        settings.retry_delay_ms = delay;
+        settings::apply(settings);
+    }
+
+    // The separate limits take precedence over the combined one
+    if let Some(rate) = rate_flag(&args, "--max-rate") {
+        UPLOAD_LIMIT.set(rate);
+        DOWNLOAD_LIMIT.set(rate);
+    }
+    if let Some(rate) = rate_flag(&args, "--max-upload-rate") {
+        UPLOAD_LIMIT.set(rate);
+    }
+    if let Some(rate) = rate_flag(&args, "--max-download-rate") {
+        DOWNLOAD_LIMIT.set(rate);
+    }
+
+    // Applies to whichever upload is asked for
+    let private = args.iter().any(|arg| arg == "--private");
+
+    if let Some(file) = flag_value(&args, "--upload") {
+        let skip_existing = args.iter().any(|arg| arg == "--skip-existing");
+        let force = args.iter().any(|arg| arg == "--force");
+
+        let result = match (file, flag_value(&args, "--as")) {
+            ("-", Some(name)) => cli_upload_stdin(name, private),
+            ("-", None) => {
+                eprintln!("--upload - expects --as <name>");
+                std::process::exit(1);
+            }
+            (file, name) => cli_upload(file, name, private, skip_existing, force),
+        };
+
+        if let Err(err) = result {
+            eprintln!("Could not upload '{file}': {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(file) = flag_value(&args, "--upload-parts") {
+        let part_size = match flag_value(&args, "--part-size") {
+            Some(value) => parse_bytes(value)
+                .filter(|&size| size > 0 && size <= MAX_PART_LEN as u64)
+                .unwrap_or_else(|| {
+                    let max = human_bytes(MAX_PART_LEN as u64);
+                    eprintln!("--part-size expects a size up to {max} like 8M, got '{value}'");
+                    std::process::exit(1);
+                }),
+            None => DEFAULT_PART_SIZE,
+        };
+
+        let name = flag_value(&args, "--as");
+        if let Err(err) = cli_upload_parts(file, name, private, part_size) {
+            eprintln!("Could not upload '{file}': {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(url) = flag_value(&args, "--upload-url") {
+        // Named after the last part of the path unless --as says otherwise
+        let name = flag_value(&args, "--as").or_else(|| {
+            url.split(['?', '#'])
+                .next()
+                .and_then(|path| path.rsplit('/').next())
+                .filter(|name| !name.is_empty() && !url.ends_with(&format!("//{name}")))
+        });
+        let Some(name) = name else {
+            eprintln!("--upload-url {url} expects --as <name>");
+            std::process::exit(1);
+        };
+
+        if let Err(err) = cli_upload_url(url, name, private) {
+            eprintln!("Could not upload '{url}': {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let file_names = flag_values(&args, "--download");
+    if file_names.len() > 1 {
+        let downloads_dir = settings::current().downloads_dir;
+        let dir = flag_value(&args, "-o").unwrap_or(&downloads_dir);
+        let force = args.iter().any(|arg| arg == "--force");
+
+        match cli_download_many(&file_names, Path::new(dir), force) {
+            Ok(0) => return,
+            Ok(failed) => eprintln!("{failed} of {} files not downloaded", file_names.len()),
+            Err(err) => eprintln!("Could not download files: {err}"),
+        }
+        std::process::exit(1);
+    }
+
+    if let Some(file_name) = flag_value(&args, "--download") {
+        let downloads_dir = settings::current().downloads_dir;
+        let default_output = download_path(
+            DEFAULT_DOWNLOAD_TEMPLATE,
+            file_name,
+            Path::new(&downloads_dir),
+        );
+        let default_output = default_output.to_string_lossy();
+        let output = flag_value(&args, "-o").unwrap_or(&default_output);
+
+        let force = args.iter().any(|arg| arg == "--force");
+
+        if let Err(err) = cli_download(file_name, output, force) {
+            eprintln!("Could not download '{file_name}': {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(pos) = args.iter().position(|arg| arg == "--tag") {
+        let Some(file_name) = args.get(pos + 1) else {
+            eprintln!("--tag expects a file name");
+            std::process::exit(1);
+        };
+
+        if let Err(err) = edit_tags(file_name, &args[pos + 2..]) {
+            eprintln!("Could not tag '{file_name}': {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(pos) = args.iter().position(|arg| arg == "--delete") {
+        let file_names = &args[pos + 1..];
+        if file_names.is_empty() {
+            eprintln!("--delete expects file names");
+            std::process::exit(1);
+        }
+
+        if let Err(err) = cli_delete(file_names) {
+            eprintln!("Could not delete files: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--follow-log") {
+        if let Err(err) = cli_follow_log() {
+            eprintln!("Stopped following the log: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--list") {
+        let sort = flag_value(&args, "--sort").unwrap_or("name");
+
+        if let Err(err) = cli_list(sort) {
+            eprintln!("Could not list files: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(secs) = flag_value(&args, "--older-than") {
+        let Ok(secs) = secs.parse() else {
+            eprintln!("--older-than expects a number of seconds");
+            std::process::exit(1);
+        };
+
+        if let Err(err) = cli_older_than(Duration::from_secs(secs)) {
+            eprintln!("Could not list files: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--check-index") {
+        let hashes = args.iter().any(|arg| arg == "--check-hashes");
+        let repair = args.iter().any(|arg| arg == "--repair");
+
+        match cli_check_index(hashes, repair) {
+            Ok(0) => {}
+            Ok(_) => std::process::exit(1),
+            Err(err) => {
+                eprintln!("Could not check the index: {err}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--speedtest") {
+        let size = match flag_value(&args, "--size") {
+            Some(value) => parse_bytes(value).unwrap_or_else(|| {
+                eprintln!("--size expects a size like 100M, got '{value}'");
+                std::process::exit(1);
+            }),
+            None => SPEEDTEST_SIZE,
+        };
+
+        if let Err(err) = cli_speedtest(size) {
+            eprintln!("Speed test failed: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(pos) = args.iter().position(|arg| arg == "admin") {
+        let result = match args.get(pos + 1).map(String::as_str) {
+            Some("connections") => cli_connections(),
+            Some("kick") => match args.get(pos + 2).and_then(|id| id.parse().ok()) {
+                Some(id) => cli_kick(id).map(|()| println!("Disconnected connection {id}")),
+                None => {
+                    eprintln!("admin kick expects a connection ID");
+                    std::process::exit(1);
+                }
+            },
+            _ => {
+                eprintln!("Expected 'admin connections' or 'admin kick <id>'");
+                std::process::exit(1);
+            }
+        };
+
+        if let Err(err) = result {
+            eprintln!("Admin request failed: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--reset-downloads") {
+        match cli_reset_downloads() {
+            Ok(()) => println!("Download counts reset"),
+            Err(err) => {
+                eprintln!("Could not reset download counts: {err}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(pos) = args.iter().position(|arg| arg == "--tree") {
+        // The path is optional, the root is shown without one
+        let path = args
+            .get(pos + 1)
+            .filter(|arg| !arg.starts_with("--"))
+            .map_or("", String::as_str);
+        let depth = number_flag(&args, "--depth").map_or(MAX_TREE_DEPTH, |depth| depth as usize);
+
+        if let Err(err) = print_tree(path, depth) {
+            eprintln!("Could not list the tree: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(file_name) = flag_value(&args, "--stat") {
+        if let Err(err) = print_stat(file_name) {
+            eprintln!("Could not stat '{file_name}': {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let download_template =
+        flag_value(&args, "--download-template").unwrap_or(DEFAULT_DOWNLOAD_TEMPLATE);
+
+    if !is_valid_template(download_template) {
+        eprintln!(
+            "Invalid download template '{download_template}', using '{DEFAULT_DOWNLOAD_TEMPLATE}'"
+        );
+    }
+
+    if let Some(pos) = args.iter().position(|arg| arg == "--diff") {
+        let Some(dir) = args.get(pos + 1) else {
+            eprintln!("--diff expects a directory");
+            std::process::exit(1);
+        };
+
+        let json = args.iter().any(|arg| arg == "--json");
+
+        if let Err(err) = print_diff(dir, json) {
+            eprintln!("Could not diff '{dir}': {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(count) = flag_value(&args, "--demo-entries") {
+        let Ok(count) = count.parse() else {
+            eprintln!("--demo-entries expects a number of files, got '{count}'");
+            std::process::exit(1);
+        };
+
+        match demo_connection() {
+            Ok(stream) => {
+                let listing = (0..count).map(|i| format!("demo-{i:06}.bin")).collect();
+                run(
+                    Gui::new_or_exit(),
+                    stream,
+                    ConnectionInfo::default(),
+                    download_template,
+                    Some(listing),
+                );
+            }
+            Err(err) => eprintln!("Could not start the demo: {err}"),
+        }
+        return;
+    }
+
+    let mut gui = Gui::new_or_exit();
+
+    // Without saved settings, or a server to connect to, the user is asked for them first
+    let problem = match first_run {
+        true => None,
+        false => match connect_server() {
+            Ok((stream, info)) => return run(gui, stream, info, download_template, None),
+            Err(err) => Some(format!("Could not connect: {err}")),
+        },
+    };
+
+    if let Some((stream, info)) = run_setup(
+        &mut gui,
+        SetupForm::new(settings::current(), problem, false),
+    ) {
+        run(gui, stream, info, download_template, None);
     }
 }
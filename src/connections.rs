@@ -0,0 +1,269 @@
+//! Every open connection and what it is doing, for `op::LIST_CONNECTIONS` and `op::KICK_CONNECTION`.
+//!
+//! A connection is registered when it is accepted and removed when its `Registration`
+//! is dropped. Handlers don't touch the registry, the op being handled and any
+//! `Progress` are recorded against the connection of the thread serving it.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    io,
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use p2p_service::{op, unix_now, ConnectionSummary, Transport};
+
+type Shutter = Box<dyn Fn() -> io::Result<()> + Send + Sync>;
+
+/// Transports that can be shut down from another thread, through a handle of their own.
+pub trait Kickable: Transport {
+    fn shutter(&self) -> io::Result<Shutter>;
+}
+
+impl Kickable for TcpStream {
+    fn shutter(&self) -> io::Result<Shutter> {
+        let stream = self.try_clone()?;
+        Ok(Box::new(move || stream.shutdown(Shutdown::Both)))
+    }
+}
+
+#[cfg(unix)]
+impl Kickable for UnixStream {
+    fn shutter(&self) -> io::Result<Shutter> {
+        let stream = self.try_clone()?;
+        Ok(Box::new(move || stream.shutdown(Shutdown::Both)))
+    }
+}
+
+/// A transfer on a connection, shared with the `Progress` tracking it.
+struct Transfer {
+    direction: &'static str,
+    file: String,
+    size: usize,
+    done: Arc<AtomicUsize>,
+}
+
+struct Connection {
+    peer: String,
+    since: u64,
+    started: AtomicBool,
+    /// The op being handled, `NO_OP` between requests.
+    op: AtomicUsize,
+    transfer: Mutex<Option<Transfer>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    shutter: Shutter,
+    kicked: AtomicBool,
+}
+
+const NO_OP: usize = usize::MAX;
+
+impl Connection {
+    fn activity(&self) -> String {
+        if !self.started.load(Ordering::Relaxed) {
+            return "waiting for a worker".to_string();
+        }
+        if let Some(transfer) = &*self.transfer.lock().unwrap() {
+            let done = transfer.done.load(Ordering::Relaxed);
+            let percent = done * 100 / transfer.size.max(1);
+            let verb = match transfer.direction {
+                "upload" => "uploading",
+                _ => "downloading",
+            };
+            return format!("{verb} {} at {percent}%", transfer.file);
+        }
+
+        match self.op.load(Ordering::Relaxed) {
+            NO_OP => "idle".to_string(),
+            op if op == op::FOLLOW_LOG as usize => "following the log".to_string(),
+            op => format!("handling {}", op::name(op as u8).unwrap_or("unknown")),
+        }
+    }
+}
+
+static CONNECTIONS: Mutex<BTreeMap<u64, Arc<Connection>>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The connection served by this thread, see `Registration::enter`.
+    static CURRENT: RefCell<Option<Arc<Connection>>> = const { RefCell::new(None) };
+}
+
+/// A connection in the registry, removed when dropped.
+pub struct Registration {
+    id: u64,
+    connection: Arc<Connection>,
+}
+
+/// Add an accepted connection to the registry, listed as waiting until `enter` is called.
+pub fn register<S: Kickable>(stream: &S) -> io::Result<Registration> {
+    let connection = Arc::new(Connection {
+        peer: stream.peer(),
+        since: unix_now(),
+        started: AtomicBool::new(false),
+        op: AtomicUsize::new(NO_OP),
+        transfer: Mutex::new(None),
+        bytes_in: AtomicU64::new(0),
+        bytes_out: AtomicU64::new(0),
+        shutter: stream.shutter()?,
+        kicked: AtomicBool::new(false),
+    });
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    CONNECTIONS.lock().unwrap().insert(id, connection.clone());
+    Ok(Registration { id, connection })
+}
+
+impl Registration {
+    /// A worker picked the connection up, what this thread does is recorded against it.
+    pub fn enter(&self) {
+        self.connection.started.store(true, Ordering::Relaxed);
+        CURRENT.with(|current| *current.borrow_mut() = Some(self.connection.clone()));
+    }
+
+    /// Whether an admin shut the connection down, so its failure isn't worth logging.
+    pub fn was_kicked(&self) -> bool {
+        self.connection.kicked.load(Ordering::Relaxed)
+    }
+
+    /// `stream`, counting the bytes that go through it against the connection.
+    pub fn track<S>(&self, stream: S) -> Tracked<S> {
+        Tracked {
+            inner: stream,
+            connection: self.connection.clone(),
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        CONNECTIONS.lock().unwrap().remove(&self.id);
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            if current
+                .as_ref()
+                .is_some_and(|connection| Arc::ptr_eq(connection, &self.connection))
+            {
+                *current = None;
+            }
+        });
+    }
+}
+
+fn with_current(f: impl FnOnce(&Connection)) {
+    CURRENT.with(|current| {
+        if let Some(connection) = &*current.borrow() {
+            f(connection);
+        }
+    });
+}
+
+/// Record that this thread's connection is handling `op`, or nothing with `None`.
+pub fn set_op(op: Option<u8>) {
+    with_current(|connection| {
+        let op = op.map_or(NO_OP, usize::from);
+        connection.op.store(op, Ordering::Relaxed);
+    });
+}
+
+/// Record a transfer on this thread's connection, `done` being the bytes moved so far.
+pub fn start_transfer(direction: &'static str, file: &str, size: usize, done: Arc<AtomicUsize>) {
+    with_current(|connection| {
+        *connection.transfer.lock().unwrap() = Some(Transfer {
+            direction,
+            file: file.to_string(),
+            size,
+            done,
+        });
+    });
+}
+
+pub fn end_transfer() {
+    with_current(|connection| *connection.transfer.lock().unwrap() = None);
+}
+
+/// Every open connection, oldest first.
+pub fn list() -> Vec<ConnectionSummary> {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(&id, connection)| ConnectionSummary {
+            id,
+            peer: connection.peer.clone(),
+            connected_since: connection.since,
+            activity: connection.activity(),
+            bytes_in: connection.bytes_in.load(Ordering::Relaxed),
+            bytes_out: connection.bytes_out.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// Shut connection `id` down so its next read or write fails, returning who was on it.
+///
+/// `None` if there is no such connection.
+pub fn kick(id: u64) -> io::Result<Option<String>> {
+    let Some(connection) = CONNECTIONS.lock().unwrap().get(&id).cloned() else {
+        return Ok(None);
+    };
+    connection.kicked.store(true, Ordering::Relaxed);
+    (connection.shutter)()?;
+    Ok(Some(connection.peer.clone()))
+}
+
+/// Shut every connection down the way `kick` does, for when the server stops.
+pub fn kick_all() {
+    let connections: Vec<_> = CONNECTIONS.lock().unwrap().values().cloned().collect();
+    for connection in connections {
+        connection.kicked.store(true, Ordering::Relaxed);
+        _ = (connection.shutter)();
+    }
+}
+
+/// A stream counting what it reads and writes against its connection.
+pub struct Tracked<S> {
+    inner: S,
+    connection: Arc<Connection>,
+}
+
+impl<S: Transport> Transport for Tracked<S> {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.connection
+            .bytes_in
+            .fetch_add(count as u64, Ordering::Relaxed);
+        Ok(count)
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.connection
+            .bytes_out
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn peer(&self) -> String {
+        self.inner.peer()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+}
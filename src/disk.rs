@@ -0,0 +1,15 @@
+use std::{io, path::Path};
+
+pub use p2p_service::is_storage_full;
+
+use crate::SERVER_FILES;
+
+/// Bytes free to unprivileged users on the filesystem holding `SERVER_FILES`.
+pub fn available_space() -> io::Result<u64> {
+    p2p_service::available_space(Path::new(SERVER_FILES))
+}
+
+/// Why `SERVER_FILES` can't take `size` more bytes and keep `headroom` free, if it can't.
+pub fn space_shortfall(size: u64, headroom: u64) -> io::Result<Option<String>> {
+    p2p_service::space_shortfall(Path::new(SERVER_FILES), size, headroom)
+}
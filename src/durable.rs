@@ -0,0 +1,59 @@
+use std::{
+    fs, io,
+    sync::{Condvar, Mutex},
+};
+
+use crate::SERVER_FILES;
+
+#[derive(Default)]
+struct SyncState {
+    /// Callers that have asked for a sync so far.
+    requested: u64,
+    /// Every caller up to this one is covered by a finished sync.
+    synced: u64,
+    running: bool,
+}
+
+/// Flushes renames in `SERVER_FILES` to disk, sharing one fsync between uploads
+/// that finish while another is in flight.
+#[derive(Default)]
+pub struct DirSyncer {
+    state: Mutex<SyncState>,
+    done: Condvar,
+}
+
+impl DirSyncer {
+    /// Return once every rename made before the call is on disk.
+    pub fn sync(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.requested += 1;
+        let ticket = state.requested;
+
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+
+            if state.running {
+                state = self.done.wait(state).unwrap();
+                continue;
+            }
+
+            // Covers everyone who asked before this point, the rest wait for the next round
+            let target = state.requested;
+            state.running = true;
+            drop(state);
+
+            let result = fs::File::open(SERVER_FILES).and_then(|dir| dir.sync_all());
+
+            state = self.state.lock().unwrap();
+            state.running = false;
+            if result.is_ok() {
+                state.synced = target;
+            }
+            self.done.notify_all();
+
+            result?;
+        }
+    }
+}
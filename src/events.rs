@@ -0,0 +1,115 @@
+//! What happens on a server, for embedders to react to without polling its files.
+//!
+//! The server emits an event for every upload, download, deletion and connection,
+//! and hands each one to the observers added with `on_event`.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        mpsc::{self, Sender},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::FileEntry;
+
+/// Something that happened on the server, passed to every observer added with `on_event`.
+#[derive(Clone, Debug)]
+pub enum ServerEvent {
+    /// A file was stored, with the address of the client that sent it.
+    UploadCompleted(FileEntry, Option<SocketAddr>),
+    DownloadCompleted {
+        name: String,
+        peer: Option<SocketAddr>,
+        bytes: u64,
+    },
+    FileDeleted(String),
+    ClientConnected(SocketAddr),
+    ClientDisconnected(SocketAddr),
+    /// An op was handled and answered in `elapsed`.
+    RequestHandled {
+        op: u8,
+        peer: Option<SocketAddr>,
+        elapsed: Duration,
+    },
+}
+
+type Observer = Box<dyn Fn(&ServerEvent) + Send + Sync>;
+
+static OBSERVERS: Mutex<Vec<Observer>> = Mutex::new(Vec::new());
+
+/// Hands events to the notifier thread, started by the first `emit`.
+static NOTIFIER: OnceLock<Sender<ServerEvent>> = OnceLock::new();
+
+/// Call `observer` with every event emitted from now on.
+///
+/// Observers run one at a time on a thread of their own, so a slow one delays
+/// the others but never the client that caused the event.
+pub fn on_event(observer: impl Fn(&ServerEvent) + Send + Sync + 'static) {
+    OBSERVERS.lock().unwrap().push(Box::new(observer));
+}
+
+/// Pass `event` to every observer, for the server's use only.
+#[doc(hidden)]
+pub fn emit(event: ServerEvent) {
+    let notifier = NOTIFIER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel::<ServerEvent>();
+
+        thread::spawn(move || {
+            for event in receiver {
+                for observer in OBSERVERS.lock().unwrap().iter() {
+                    observer(&event);
+                }
+            }
+        });
+        sender
+    });
+
+    // The notifier only stops if an observer panicked, the event is lost either way
+    _ = notifier.send(event);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+
+    use super::*;
+
+    /// Events seen by a new observer, see `on_event`.
+    fn observe() -> Receiver<ServerEvent> {
+        let (sender, receiver) = mpsc::channel();
+        on_event(move |event| _ = sender.send(event.clone()));
+        receiver
+    }
+
+    #[test]
+    fn observers_receive_emitted_events() {
+        let events = observe();
+        let entry = FileEntry {
+            name: "a.txt".to_string(),
+            size: 10,
+            modified: 0,
+            tags: Vec::new(),
+            description: String::new(),
+            private: false,
+            downloads: 0,
+        };
+
+        emit(ServerEvent::UploadCompleted(entry, None));
+        emit(ServerEvent::FileDeleted("a.txt".to_string()));
+
+        let timeout = Duration::from_secs(5);
+        match events.recv_timeout(timeout).unwrap() {
+            ServerEvent::UploadCompleted(entry, None) => {
+                assert_eq!((entry.name.as_str(), entry.size), ("a.txt", 10))
+            }
+            event => panic!("expected the upload, got {event:?}"),
+        }
+        match events.recv_timeout(timeout).unwrap() {
+            ServerEvent::FileDeleted(name) => assert_eq!(name, "a.txt"),
+            event => panic!("expected the deletion, got {event:?}"),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Files deleted once nobody has written to them for a while, see `--expire-after`.
+
+use std::{fs, io, sync::Mutex, time::Duration};
+
+use p2p_service::{
+    events::{self, ServerEvent},
+    format::human_duration,
+    unix_now,
+};
+
+use crate::{
+    index::FileIndex,
+    logs::{log, log_err},
+    SERVER_FILES,
+};
+
+/// Delete every file last written more than `ttl` ago, private ones included.
+///
+/// Files are deleted while the index lock is held, so an upload can't replace
+/// one between it being picked and removed.
+pub fn sweep(files: &Mutex<FileIndex>, ttl: Duration) {
+    let now = unix_now();
+    let mut files = files.lock().unwrap();
+
+    let expired: Vec<(String, u64)> = files
+        .modified_before(now.saturating_sub(ttl.as_secs()))
+        // Files whose write time couldn't be read look ancient, they are kept
+        .filter(|(_, meta)| meta.modified > 0)
+        .map(|(name, meta)| (name.clone(), meta.modified))
+        .collect();
+    if expired.is_empty() {
+        return;
+    }
+
+    for (name, modified) in expired {
+        match fs::remove_file(format!("{SERVER_FILES}/{name}")) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                log_err!("Could not expire \"{name}\": {err}");
+                continue;
+            }
+        }
+
+        files.remove(&name);
+        let age = Duration::from_secs(now.saturating_sub(modified));
+        log!(
+            "Expired \"{name}\", last written {} ago",
+            human_duration(age)
+        );
+        events::emit(ServerEvent::FileDeleted(name));
+    }
+
+    if let Err(err) = files.save() {
+        log_err!("Could not save the index after expiring files: {err}");
+    }
+}
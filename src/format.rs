@@ -0,0 +1,189 @@
+//! Human readable sizes, rates, durations and times, shared by the client and server
+//! so they all read the same.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+/// `bytes` in the largest binary unit that keeps it at 1 or more, such as "1.5 MiB".
+pub fn human_bytes(bytes: u64) -> String {
+    scaled(bytes as f64)
+}
+
+/// A rate in bytes per second, such as "1.5 MiB/s".
+pub fn human_rate(bytes_per_sec: f64) -> String {
+    format!("{}/s", scaled(bytes_per_sec.max(0.0)))
+}
+
+fn scaled(bytes: f64) -> String {
+    let mut value = bytes;
+    let mut unit = 0;
+    // Compared as shown, so 1023.96 KiB moves up to "1.0 MiB" rather than reading "1024.0 KiB"
+    while (value * 10.0).round() >= 10_240.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// The two largest units of `duration`, such as "3m 12s" or "2h 05m".
+pub fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, mins) = (secs / 86_400, secs / 3600 % 24, secs / 60 % 60);
+
+    match (days, hours, mins) {
+        (0, 0, 0) => format!("{secs}s"),
+        (0, 0, _) => format!("{mins}m {:02}s", secs % 60),
+        (0, _, _) => format!("{hours}h {mins:02}m"),
+        _ => format!("{days}d {hours}h"),
+    }
+}
+
+/// Parse a size like "100M", "1.5GiB" or "4096", in the binary units `human_bytes` uses.
+pub fn parse_bytes(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().ok()?;
+
+    let power = match unit.trim().trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return None,
+    };
+
+    Some((number * 1024f64.powi(power)) as u64)
+}
+
+/// `time` as "YYYY-MM-DD HH:MM:SS +HH:MM" in the local time zone.
+///
+/// Only Unix has the offset looked up, elsewhere the time is shown in UTC as "+00:00".
+pub fn local_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs()) as i64;
+
+    timestamp(secs, utc_offset(secs))
+}
+
+/// `secs` after the epoch as a timestamp in the zone `offset` seconds ahead of UTC.
+fn timestamp(secs: i64, offset: i64) -> String {
+    let local = secs + offset;
+    let (year, month, day) = civil_date(local.div_euclid(86_400));
+    let of_day = local.rem_euclid(86_400);
+    let (hour, min, sec) = (of_day / 3600, of_day / 60 % 60, of_day % 60);
+
+    let sign = if offset < 0 { '-' } else { '+' };
+    let (offset_hours, offset_mins) = (offset.abs() / 3600, offset.abs() / 60 % 60);
+    format!(
+        "{year:04}-{month:02}-{day:02} {hour:02}:{min:02}:{sec:02} \
+         {sign}{offset_hours:02}:{offset_mins:02}"
+    )
+}
+
+/// Seconds the local time zone is ahead of UTC at `secs` after the epoch.
+#[cfg(unix)]
+fn utc_offset(secs: i64) -> i64 {
+    use std::mem::MaybeUninit;
+
+    let time = secs as libc::time_t;
+    let mut tm = MaybeUninit::<libc::tm>::uninit();
+
+    // SAFETY: `tm` is only read after localtime_r fills it in
+    unsafe {
+        if libc::localtime_r(&time, tm.as_mut_ptr()).is_null() {
+            return 0;
+        }
+        #[allow(clippy::unnecessary_cast)]
+        return tm.assume_init().tm_gmtoff as i64;
+    }
+}
+
+/// No time zone to look up, times are shown in UTC.
+#[cfg(not(unix))]
+fn utc_offset(_secs: i64) -> i64 {
+    0
+}
+
+/// The year, month and day `days` after the epoch (Howard Hinnant's algorithm).
+pub(crate) fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_at_unit_boundaries() {
+        assert_eq!(human_bytes(0), "0.0 B");
+        assert_eq!(human_bytes(1023), "1023.0 B");
+        assert_eq!(human_bytes(1024), "1.0 KiB");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(1_048_575), "1.0 MiB");
+        assert_eq!(human_bytes(1_048_576), "1.0 MiB");
+        assert_eq!(human_bytes(u64::MAX), "16.0 EiB");
+    }
+
+    #[test]
+    fn rates_round_up_into_the_next_unit() {
+        assert_eq!(human_rate(0.0), "0.0 B/s");
+        assert_eq!(human_rate(-5.0), "0.0 B/s");
+        assert_eq!(human_rate(1023.94), "1023.9 B/s");
+        assert_eq!(human_rate(1023.96), "1.0 KiB/s");
+    }
+
+    #[test]
+    fn durations_show_their_two_largest_units() {
+        assert_eq!(human_duration(Duration::ZERO), "0s");
+        assert_eq!(human_duration(Duration::from_secs(59)), "59s");
+        assert_eq!(human_duration(Duration::from_secs(60)), "1m 00s");
+        assert_eq!(human_duration(Duration::from_secs(3599)), "59m 59s");
+        assert_eq!(human_duration(Duration::from_secs(3600)), "1h 00m");
+        assert_eq!(human_duration(Duration::from_secs(86_400 + 7200)), "1d 2h");
+    }
+
+    #[test]
+    fn sizes_parse_in_binary_units() {
+        assert_eq!(parse_bytes("4096"), Some(4096));
+        assert_eq!(parse_bytes("1K"), Some(1024));
+        assert_eq!(parse_bytes("1.5GiB"), Some(1536 * 1024 * 1024));
+        assert_eq!(parse_bytes("100 MB"), Some(100 * 1024 * 1024));
+        assert_eq!(parse_bytes("10X"), None);
+        assert_eq!(parse_bytes(""), None);
+    }
+
+    #[test]
+    fn timestamps_are_shifted_by_the_offset() {
+        assert_eq!(timestamp(0, 0), "1970-01-01 00:00:00 +00:00");
+        assert_eq!(timestamp(1_700_000_000, 0), "2023-11-14 22:13:20 +00:00");
+        assert_eq!(
+            timestamp(1_700_000_000, 5 * 3600 + 1800),
+            "2023-11-15 03:43:20 +05:30"
+        );
+        assert_eq!(timestamp(0, -8 * 3600), "1969-12-31 16:00:00 -08:00");
+    }
+
+    #[test]
+    fn local_timestamp_uses_the_local_offset() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let expected = timestamp(1_700_000_000, utc_offset(1_700_000_000));
+        assert_eq!(local_timestamp(time), expected);
+    }
+}
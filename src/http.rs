@@ -0,0 +1,260 @@
+//! Just enough HTTP/1.1 to fetch a file for `--upload-url`.
+//!
+//! Only plain `http://` URLs are supported, there is no TLS library to speak HTTPS with.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use p2p_service::connect;
+
+/// Redirects followed before giving up, in case they loop.
+const MAX_REDIRECTS: usize = 5;
+/// Longest status or header line accepted.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+/// The body of a successful response, read as it arrives.
+pub struct Body {
+    inner: BodyReader,
+    /// The size the server announced, `None` for chunked or unsized responses.
+    pub len: Option<u64>,
+    received: u64,
+}
+
+impl Body {
+    /// Bytes of the body read so far.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = match &mut self.inner {
+            BodyReader::Sized(reader) => reader.read(buf)?,
+            BodyReader::Chunked(reader) => reader.read(buf)?,
+            BodyReader::UntilClose(reader) => reader.read(buf)?,
+        };
+        self.received += bytes_read as u64;
+
+        // A connection that ends early must not look like a whole file
+        if bytes_read == 0 && !buf.is_empty() && self.len.is_some_and(|len| self.received < len) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Connection closed after {} of {} bytes",
+                    self.received,
+                    self.len.unwrap_or_default()
+                ),
+            ));
+        }
+        Ok(bytes_read)
+    }
+}
+
+enum BodyReader {
+    Sized(io::Take<BufReader<TcpStream>>),
+    Chunked(ChunkedReader),
+    UntilClose(BufReader<TcpStream>),
+}
+
+/// Fetch `url`, following redirects, failing on any status but 2xx.
+///
+/// Nothing of the body is read yet, so errors surface before anything is sent on.
+pub fn get(url: &str, timeout: Duration) -> io::Result<Body> {
+    let mut url = url.to_string();
+
+    for _ in 0..=MAX_REDIRECTS {
+        let (host, path) = split_url(&url)?;
+        let addr = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{host}:80")
+        };
+
+        let stream = connect(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        write!(
+            &stream,
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: p2p-client\r\n\
+             Accept-Encoding: identity\r\nConnection: close\r\n\r\n"
+        )?;
+
+        let mut reader = BufReader::new(stream);
+        let status_line = read_line(&mut reader)?;
+        let mut parts = status_line.splitn(3, ' ');
+        let code: u16 = match (parts.next(), parts.next().map(str::parse)) {
+            (Some(version), Some(Ok(code))) if version.starts_with("HTTP/") => code,
+            _ => return Err(invalid(format!("Not an HTTP response: '{status_line}'"))),
+        };
+        let reason = parts.next().unwrap_or_default().to_string();
+
+        let mut len = None;
+        let mut chunked = false;
+        let mut location = None;
+
+        loop {
+            let line = read_line(&mut reader)?;
+            if line.is_empty() {
+                break;
+            }
+
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => {
+                    len = Some(
+                        value
+                            .parse()
+                            .map_err(|_| invalid(format!("Invalid Content-Length '{value}'")))?,
+                    )
+                }
+                "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                "location" => location = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        match code {
+            200..=299 => {}
+            301 | 302 | 303 | 307 | 308 => {
+                let location =
+                    location.ok_or_else(|| invalid(format!("Redirect {code} with no Location")))?;
+                url = resolve(host, &location)?;
+                continue;
+            }
+            404 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{url} answered 404 {reason}"),
+                ))
+            }
+            _ => return Err(io::Error::other(format!("{url} answered {code} {reason}"))),
+        }
+
+        let (inner, len) = match (chunked, len) {
+            (true, _) => (BodyReader::Chunked(ChunkedReader::new(reader)), None),
+            (false, Some(len)) => (BodyReader::Sized(reader.take(len)), Some(len)),
+            (false, None) => (BodyReader::UntilClose(reader), None),
+        };
+        return Ok(Body {
+            inner,
+            len,
+            received: 0,
+        });
+    }
+
+    Err(io::Error::other(format!(
+        "Gave up after {MAX_REDIRECTS} redirects"
+    )))
+}
+
+/// The host (with any port) and the path of an `http://` URL.
+fn split_url(url: &str) -> io::Result<(&str, &str)> {
+    if url.starts_with("https://") {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "HTTPS URLs aren't supported, only http://",
+        ));
+    }
+
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid(format!("'{url}' is not an http:// URL")))?;
+
+    let (host, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(invalid(format!("'{url}' has no host")));
+    }
+
+    Ok((host, path))
+}
+
+/// Where a redirect from `host` to `location` leads.
+fn resolve(host: &str, location: &str) -> io::Result<String> {
+    if location.contains("://") {
+        Ok(location.to_string())
+    } else if location.starts_with('/') {
+        Ok(format!("http://{host}{location}"))
+    } else {
+        Err(invalid(format!("Can't follow redirect to '{location}'")))
+    }
+}
+
+/// One CRLF terminated line, without the line ending.
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    reader.take(MAX_LINE_LEN).read_line(&mut line)?;
+
+    if !line.ends_with('\n') {
+        return Err(invalid("Response line too long or cut short".to_string()));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads a `Transfer-Encoding: chunked` body as the bytes it carries.
+struct ChunkedReader {
+    inner: BufReader<TcpStream>,
+    /// Bytes left in the current chunk.
+    remaining: u64,
+    done: bool,
+}
+
+impl ChunkedReader {
+    fn new(inner: BufReader<TcpStream>) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl Read for ChunkedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.remaining == 0 {
+            // Extensions after ';' carry nothing we need
+            let line = read_line(&mut self.inner)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            self.remaining = u64::from_str_radix(size, 16)
+                .map_err(|_| invalid(format!("Invalid chunk size '{size}'")))?;
+
+            if self.remaining == 0 {
+                // Skip any trailers up to the blank line ending the body
+                while !read_line(&mut self.inner)?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let bytes_read = self.inner.read(&mut buf[..max])?;
+        if bytes_read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        self.remaining -= bytes_read as u64;
+        if self.remaining == 0 {
+            read_line(&mut self.inner)?;
+        }
+        Ok(bytes_read)
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    cmp::Ordering,
+    collections::{hash_map, HashMap},
+    fs,
+    io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering as AtomicOrdering},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use p2p_service::{hash_reader, ConnectionInfo, FileEntry, SnapshotEntry, SortKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    logs::log_err,
+    temp::{self, TEMP_DIR},
+    SERVER_FILES,
+};
+
+/// Where metadata that cannot be recovered from the file itself is kept.
+pub const INDEX_FILE: &str = "server_index.json";
+/// Prefix older versions gave uploads still being written, now kept in `temp::TEMP_DIR`.
+pub const PARTIAL_PREFIX: &str = ".partial-";
+/// How a gzip compressed index starts, a JSON one never does.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+const MAX_TAG_LEN: usize = 32;
+/// Most tags a single file can carry.
+pub const MAX_TAGS: usize = 16;
+pub const MAX_DESCRIPTION_LEN: usize = 256;
+
+/// How a file's contents are kept on disk.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Storage {
+    #[default]
+    Plain,
+    /// Gzip compressed, `size` is the length of the original contents.
+    Gzip { size: usize },
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct FileMeta {
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub storage: Storage,
+    /// Hash of the original contents, see `hash_reader`.
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// Identity of the client that uploaded the file, if it had one.
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Private files are hidden from everyone but their owner.
+    #[serde(default)]
+    pub private: bool,
+    /// Uploaded straight to this server while it mirrors another, replication leaves it
+    /// alone. See `ConflictPolicy::LocalWins`.
+    #[serde(default)]
+    pub local: bool,
+    /// Times the file was sent to a client in full.
+    #[serde(default)]
+    pub downloads: u64,
+    /// Bytes the file takes up in `SERVER_FILES`, always read from disk.
+    #[serde(skip)]
+    pub disk_size: u64,
+    /// Seconds since the epoch the file was last written, always read from disk.
+    #[serde(skip)]
+    pub modified: u64,
+}
+
+impl FileMeta {
+    /// Length of the original contents, regardless of how they are stored.
+    pub fn content_size(&self) -> u64 {
+        match self.storage {
+            Storage::Plain => self.disk_size,
+            Storage::Gzip { size } => size as u64,
+        }
+    }
+
+    pub fn to_snapshot(&self, name: String) -> SnapshotEntry {
+        SnapshotEntry {
+            name,
+            size: self.content_size(),
+            modified: self.modified,
+            hash: self.hash.clone(),
+            tags: self.tags.clone(),
+            description: self.description.clone(),
+            private: self.private,
+        }
+    }
+
+    pub fn to_entry(&self, name: String) -> FileEntry {
+        FileEntry {
+            name,
+            size: self.content_size(),
+            modified: self.modified,
+            tags: self.tags.clone(),
+            description: self.description.clone(),
+            private: self.private,
+            downloads: self.downloads,
+        }
+    }
+
+    /// Whether the client on the other end of `info` may list and download the file.
+    ///
+    /// Private files are only visible to their owner and to admins.
+    pub fn visible_to(&self, info: &ConnectionInfo) -> bool {
+        !self.private || info.admin || self.is_owned_by(info.identity.as_deref())
+    }
+
+    pub fn is_owned_by(&self, identity: Option<&str>) -> bool {
+        self.owner.is_some() && self.owner.as_deref() == identity
+    }
+}
+
+/// How hard `FileIndex::save` works to get changes onto the disk.
+#[derive(Clone, Copy, Default)]
+pub enum IndexSync {
+    /// Written on every change, left to the OS to flush. A crash can lose recent changes,
+    /// and download counts since the last change or shutdown.
+    #[default]
+    Off,
+    /// Written and fsynced on every change, slow but nothing is lost.
+    Always,
+    /// Written and fsynced by `FileIndex::flush`, called at this interval. A crash
+    /// loses at most the changes since the last flush.
+    Periodic(Duration),
+}
+
+/// Every file being served along with its metadata.
+#[derive(Default)]
+pub struct FileIndex {
+    files: HashMap<String, FileMeta>,
+    /// A file with each content hash, so duplicates can be found without a scan.
+    by_hash: HashMap<String, String>,
+    stored_bytes: u64,
+    /// Save the index gzip compressed, it is read back either way.
+    compress: bool,
+    /// Never write anything, for servers run with `--no-write`.
+    read_only: bool,
+    sync: IndexSync,
+    /// Changed since the index was last written, see `save_later` and `flush`.
+    dirty: AtomicBool,
+    /// Goes up whenever a file is added, replaced or removed, see `op::INDEX_VERSION`.
+    generation: u64,
+}
+
+impl FileIndex {
+    /// Build the index from the files on disk, restoring any saved metadata.
+    ///
+    /// `compress` only decides how the index is saved, a saved index in either
+    /// format is loaded. A `read_only` index is never saved, changes only last
+    /// until the server stops.
+    pub fn load(compress: bool, read_only: bool, sync: IndexSync) -> io::Result<Self> {
+        let mut saved = read_saved()?;
+
+        let mut index = Self {
+            compress,
+            read_only,
+            sync,
+            ..Self::default()
+        };
+        for entry in fs::read_dir(SERVER_FILES)? {
+            let entry = entry?;
+            // Names go over the wire as UTF-8, so these can't be listed or fetched
+            let file_name = match entry.file_name().into_string() {
+                Ok(file_name) => file_name,
+                Err(file_name) => {
+                    log_err!("Skipping {file_name:?}, its name is not valid UTF-8");
+                    continue;
+                }
+            };
+
+            // Left behind by an upload that never finished, which `temp::clean_startup`
+            // removes unless the server can't write, or the temporary files themselves
+            if file_name.starts_with(PARTIAL_PREFIX) || file_name == TEMP_DIR {
+                continue;
+            }
+
+            let mut meta = saved.remove(&file_name).unwrap_or_default();
+
+            // Files from before hashes were kept, or added by hand
+            if meta.hash.is_none() {
+                let file = fs::File::open(entry.path())?;
+                meta.hash = Some(match meta.storage {
+                    Storage::Plain => hash_reader(file, |_| {})?,
+                    Storage::Gzip { .. } => hash_reader(GzDecoder::new(file), |_| {})?,
+                });
+            }
+
+            if let Some(hash) = &meta.hash {
+                index.by_hash.insert(hash.clone(), file_name.clone());
+            }
+            index.files.insert(file_name.clone(), meta);
+            index.insert(file_name, &entry.metadata()?);
+        }
+
+        Ok(index)
+    }
+
+    /// The saved index as it is, without adding files missing from it or dropping
+    /// entries whose file is gone, so it can be compared with the files by `check`.
+    pub fn load_saved(compress: bool) -> io::Result<Self> {
+        let mut index = Self {
+            compress,
+            ..Self::default()
+        };
+
+        for (file_name, meta) in read_saved()? {
+            if let Some(hash) = &meta.hash {
+                index
+                    .by_hash
+                    .entry(hash.clone())
+                    .or_insert_with(|| file_name.clone());
+            }
+            index.files.insert(file_name.clone(), meta);
+
+            match fs::metadata(format!("{SERVER_FILES}/{file_name}")) {
+                Ok(metadata) => {
+                    index.insert(file_name, &metadata);
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Persist the index after a change, how depends on its `IndexSync`.
+    pub fn save(&self) -> io::Result<()> {
+        match self.sync {
+            _ if self.read_only => Ok(()),
+            IndexSync::Off => self.write_now(false),
+            IndexSync::Always => self.write_now(true),
+            IndexSync::Periodic(_) => {
+                self.dirty.store(true, AtomicOrdering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    /// Persist a change that is cheap to lose, like a download count, with whatever
+    /// is saved or flushed next. Only `IndexSync::Always` writes it straight away.
+    pub fn save_later(&self) -> io::Result<()> {
+        match self.sync {
+            _ if self.read_only => Ok(()),
+            IndexSync::Always => self.write_now(true),
+            IndexSync::Off | IndexSync::Periodic(_) => {
+                self.dirty.store(true, AtomicOrdering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the index, which takes any changes left for later along with it.
+    fn write_now(&self, sync: bool) -> io::Result<()> {
+        self.write(sync)?;
+        self.dirty.store(false, AtomicOrdering::Relaxed);
+        Ok(())
+    }
+
+    /// Write and fsync the index if it changed since it was last written.
+    pub fn flush(&self) -> io::Result<()> {
+        if !self.dirty.swap(false, AtomicOrdering::Relaxed) {
+            return Ok(());
+        }
+
+        let result = self.write(true);
+        if result.is_err() {
+            // Try again next time
+            self.dirty.store(true, AtomicOrdering::Relaxed);
+        }
+        result
+    }
+
+    fn write(&self, sync: bool) -> io::Result<()> {
+        let json = serde_json::to_vec(&self.files)?;
+        let bytes = if self.compress {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&json)?;
+            encoder.finish()?
+        } else {
+            json
+        };
+
+        // A crash part way through leaves the saved index as it was
+        let temp = temp::index_path();
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(&bytes)?;
+        if sync {
+            file.sync_all()?;
+        }
+        fs::rename(&temp, INDEX_FILE)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    #[inline]
+    pub fn contains(&self, file_name: &str) -> bool {
+        self.files.contains_key(file_name)
+    }
+
+    /// Every file with its metadata, private ones included.
+    #[inline]
+    pub fn entries(&self) -> hash_map::Iter<'_, String, FileMeta> {
+        self.files.iter()
+    }
+
+    /// Every file the client on the other end of `info` is allowed to see.
+    pub fn visible<'a>(
+        &'a self,
+        info: &'a ConnectionInfo,
+    ) -> impl Iterator<Item = (&'a String, &'a FileMeta)> {
+        self.files
+            .iter()
+            .filter(move |(_, meta)| meta.visible_to(info))
+    }
+
+    /// The files the client on the other end of `info` can see, ordered by `key` then by name.
+    pub fn sorted<'a>(
+        &'a self,
+        info: &'a ConnectionInfo,
+        key: SortKey,
+        descending: bool,
+    ) -> Vec<(&'a String, &'a FileMeta)> {
+        let mut files: Vec<_> = self.visible(info).collect();
+
+        files.sort_by(|(a_name, a), (b_name, b)| {
+            let order = match key {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => a.content_size().cmp(&b.content_size()),
+                SortKey::Modified => a.modified.cmp(&b.modified),
+                SortKey::Downloads => a.downloads.cmp(&b.downloads),
+            };
+
+            // Names are unique, which keeps the order the same from one request to the next
+            let order = order.then_with(|| a_name.cmp(b_name));
+            if descending {
+                order.reverse()
+            } else {
+                order
+            }
+        });
+
+        files
+    }
+
+    /// Files last written before `cutoff`, in seconds since the epoch, private ones included.
+    pub fn modified_before(&self, cutoff: u64) -> impl Iterator<Item = (&String, &FileMeta)> {
+        self.files
+            .iter()
+            .filter(move |(_, meta)| meta.modified < cutoff)
+    }
+
+    pub fn owned_by<'a>(&'a self, identity: &'a str) -> impl Iterator<Item = &'a String> {
+        self.files
+            .iter()
+            .filter(move |(_, meta)| meta.owner.as_deref() == Some(identity))
+            .map(|(file_name, _)| file_name)
+    }
+
+    /// Total bytes used by every file on disk.
+    #[inline]
+    pub fn stored_bytes(&self) -> u64 {
+        self.stored_bytes
+    }
+
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Add a file with empty metadata, keeping existing metadata if it is replaced.
+    ///
+    /// `metadata` is the file's metadata on disk.
+    pub fn insert(&mut self, file_name: String, metadata: &fs::Metadata) -> &mut FileMeta {
+        self.generation += 1;
+        let meta = self.files.entry(file_name).or_default();
+
+        self.stored_bytes = self.stored_bytes - meta.disk_size + metadata.len();
+        meta.disk_size = metadata.len();
+        meta.modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |elapsed| elapsed.as_secs());
+        meta
+    }
+
+    pub fn remove(&mut self, file_name: &str) -> Option<FileMeta> {
+        let meta = self.files.remove(file_name)?;
+        self.stored_bytes -= meta.disk_size;
+        self.generation += 1;
+
+        if let Some(hash) = &meta.hash {
+            self.unlink_hash(hash, file_name);
+        }
+        Some(meta)
+    }
+
+    /// Set every file's download count back to zero.
+    pub fn reset_downloads(&mut self) {
+        for meta in self.files.values_mut() {
+            meta.downloads = 0;
+        }
+    }
+
+    /// Record the hash of a file's contents, replacing any previous one.
+    pub fn set_hash(&mut self, file_name: &str, hash: String) {
+        let Some(meta) = self.files.get_mut(file_name) else {
+            return;
+        };
+
+        if let Some(old) = meta.hash.replace(hash.clone()) {
+            self.unlink_hash(&old, file_name);
+        }
+        self.by_hash
+            .entry(hash)
+            .or_insert_with(|| file_name.to_string());
+    }
+
+    /// A file whose contents have `hash`, if there is one.
+    #[inline]
+    pub fn find_by_hash(&self, hash: &str) -> Option<&String> {
+        self.by_hash.get(hash)
+    }
+
+    /// Stop finding `file_name` by `hash`, falling back to another file with the same contents.
+    fn unlink_hash(&mut self, hash: &str, file_name: &str) {
+        if self.by_hash.get(hash).map(String::as_str) != Some(file_name) {
+            return;
+        }
+
+        match self
+            .files
+            .iter()
+            .find(|(_, meta)| meta.hash.as_deref() == Some(hash))
+        {
+            Some((other, _)) => self.by_hash.insert(hash.to_string(), other.clone()),
+            None => self.by_hash.remove(hash),
+        };
+    }
+
+    #[inline]
+    pub fn get(&self, file_name: &str) -> Option<&FileMeta> {
+        self.files.get(file_name)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, file_name: &str) -> Option<&mut FileMeta> {
+        self.files.get_mut(file_name)
+    }
+
+    pub fn with_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = &'a String> {
+        self.files
+            .iter()
+            .filter(move |(_, meta)| meta.tags.iter().any(|t| t == tag))
+            .map(|(file_name, _)| file_name)
+    }
+}
+
+/// The metadata saved by `FileIndex::save`, empty if nothing was saved yet.
+fn read_saved() -> io::Result<HashMap<String, FileMeta>> {
+    match fs::read(INDEX_FILE) {
+        Ok(bytes) if bytes.starts_with(&GZIP_MAGIC) => {
+            Ok(serde_json::from_reader(GzDecoder::new(bytes.as_slice()))?)
+        }
+        Ok(json) => Ok(serde_json::from_slice(&json)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Tags are short and limited to alphanumerics, '-' and '_'.
+pub fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= MAX_TAG_LEN
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Descriptions are a single line of bounded length.
+pub fn is_valid_description(description: &str) -> bool {
+    description.len() <= MAX_DESCRIPTION_LEN && !description.contains(['\n', '\r'])
+}
@@ -1,42 +1,755 @@
 use std::{
-    collections::HashSet,
-    fs,
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    hash::{BuildHasher, Hasher},
     io::{self, Read, Write},
-    net::TcpStream,
-    path::Path,
-    sync::{mpsc, Arc, Mutex},
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    ops::{ControlFlow, Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex, OnceLock,
+    },
     thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+pub mod cache;
+pub mod events;
+pub mod format;
+#[cfg(feature = "nat")]
+pub mod nat;
+#[cfg(feature = "test-util")]
+pub mod pipe;
+pub mod seal;
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
 pub const SERVER_ADDR: &'static str = "192.168.0.148:8000";
 
 pub type SharedFiles = Arc<Mutex<HashSet<String>>>;
 
-pub struct Chunk<'a, const N: usize> {
-    stream: &'a TcpStream,
+/// Environment variable naming a file to write the wire trace to, see `enable_wire_trace`.
+pub const WIRE_TRACE_VAR: &str = "P2P_WIRE_TRACE";
+
+static WIRE_TRACE_ON: AtomicBool = AtomicBool::new(false);
+static WIRE_TRACE: Mutex<Option<fs::File>> = Mutex::new(None);
+
+/// Append a line to `path` for every protocol event on any `Chunk`.
+///
+/// Each line has the peer, the bytes read and written so far through the chunk,
+/// then the event. Tracing is a single atomic load per event while disabled.
+pub fn enable_wire_trace(path: impl AsRef<Path>) -> io::Result<()> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+
+    *WIRE_TRACE.lock().unwrap() = Some(file);
+    WIRE_TRACE_ON.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cold]
+fn write_trace(peer: &str, bytes_in: u64, bytes_out: u64, event: fmt::Arguments) {
+    if let Some(file) = WIRE_TRACE.lock().unwrap().as_mut() {
+        _ = writeln!(file, "{peer} in@{bytes_in} out@{bytes_out} {event}");
+    }
+}
+
+/// Most list items allocated up front, counts come from the other side and
+/// can't be trusted.
+const MAX_PREALLOC: usize = 1024;
+/// Most items `read_string_list` accepts, a larger count is taken as a broken or
+/// hostile peer.
+pub const MAX_LIST_LEN: usize = 1_000_000;
+/// Largest blob `read_compressed` accepts, before and after decompressing.
+const MAX_COMPRESSED_LEN: usize = 64 * 1024 * 1024;
+const MAX_DECOMPRESSED_LEN: u64 = 512 * 1024 * 1024;
+/// Most bytes `op::READ_HEAD` answers with, servers cut longer requests short.
+pub const MAX_HEAD_LEN: usize = 512;
+/// Deepest and largest tree `op::TREE` answers with, see `fetch_tree`.
+pub const MAX_TREE_DEPTH: usize = 16;
+pub const MAX_TREE_NODES: usize = 10_000;
+/// Most bytes a single speed test moves, see `speedtest_download`.
+pub const MAX_SPEEDTEST_BYTES: u64 = 1024 * 1024 * 1024;
+/// What speed tests are filled with, the value doesn't matter as nothing compresses it.
+pub const SPEEDTEST_BYTE: u8 = 0x5a;
+/// Longest a handshake may take, it is a few bytes each way.
+pub const HANDSHAKE_DEADLINE: Duration = Duration::from_secs(5);
+/// Longest a control op like `op::HEALTH` may take, see `Chunk::set_deadline`.
+pub const CONTROL_DEADLINE: Duration = Duration::from_secs(10);
+/// Most files `op::GET_FILES` sends in one response.
+pub const MAX_BATCH_LEN: usize = 256;
+/// Highest part number of a multipart upload, parts are numbered from 1.
+pub const MAX_PARTS: usize = 10_000;
+/// Largest part of a multipart upload, see `upload_part`.
+pub const MAX_PART_LEN: usize = 256 * 1024 * 1024;
+/// Most files a peer can announce at once, see `announce`.
+pub const MAX_ANNOUNCED_FILES: usize = 10_000;
+
+/// Op bytes sent by the client to select a request.
+pub mod op {
+    pub const ADD_FILE: u8 = 0;
+    pub const GET_FILE: u8 = 1;
+    pub const FETCH_FILES: u8 = 2;
+    pub const KEEP_ALIVE: u8 = 3;
+    pub const STATS: u8 = 4;
+    pub const SET_TAGS: u8 = 5;
+    pub const GET_TAGS: u8 = 6;
+    pub const FETCH_FILES_WITH_TAG: u8 = 7;
+    pub const ANNOUNCE: u8 = 8;
+    pub const GLOBAL_LIST: u8 = 9;
+    pub const HEALTH: u8 = 10;
+    pub const HANDSHAKE: u8 = 11;
+    pub const FETCH_FILE_SIZES: u8 = 12;
+    pub const AUTHENTICATE: u8 = 13;
+    pub const STAT: u8 = 14;
+    pub const SET_METADATA: u8 = 15;
+    pub const FIND_BY_HASH: u8 = 16;
+    pub const COPY_FILE: u8 = 17;
+    pub const SET_VISIBILITY: u8 = 18;
+    pub const FETCH_OWN_FILES: u8 = 19;
+    /// Sent by a client before it closes the connection, there is no response.
+    pub const DISCONNECT: u8 = 20;
+    pub const GET_FILE_IF_CHANGED: u8 = 21;
+    pub const DELETE_FILES: u8 = 22;
+    pub const LIST_PAGE: u8 = 23;
+    /// Like `ADD_FILE` without the size up front, see `send_stream`.
+    pub const ADD_FILE_STREAM: u8 = 24;
+    pub const EXPORT_INDEX: u8 = 25;
+    /// Answered with every line the server logs from then on, only for admins.
+    pub const FOLLOW_LOG: u8 = 26;
+    /// Answered with the first bytes of a file, see `MAX_HEAD_LEN`.
+    pub const READ_HEAD: u8 = 27;
+    /// Sets every file's download count back to zero, only for admins.
+    pub const RESET_DOWNLOADS: u8 = 28;
+    /// Answered with the files under a path as a `TreeNode`.
+    pub const TREE: u8 = 29;
+    /// Answered with several files in the order they were named, see `get_files`.
+    pub const GET_FILES: u8 = 30;
+    /// Compares the index with the stored files, only for admins. See `check_index`.
+    pub const CHECK_INDEX: u8 = 31;
+    /// Answered with generated data that never touches the disk, see `speedtest_download`.
+    pub const SPEEDTEST_DOWNLOAD: u8 = 32;
+    /// Sends data the server throws away, see `speedtest_upload`.
+    pub const SPEEDTEST_UPLOAD: u8 = 33;
+    /// Starts an upload sent in parts, answered with its ID. See `initiate_multipart`.
+    pub const MULTIPART_INITIATE: u8 = 34;
+    /// Sends one part of a multipart upload with its checksum, see `upload_part`.
+    pub const MULTIPART_PART: u8 = 35;
+    /// Joins the parts of a multipart upload into the file, see `complete_multipart`.
+    pub const MULTIPART_COMPLETE: u8 = 36;
+    /// Drops a multipart upload and its parts, see `abort_multipart`.
+    pub const MULTIPART_ABORT: u8 = 37;
+    /// Answered with the index's generation, see `index_version`.
+    pub const INDEX_VERSION: u8 = 38;
+    /// Answered with the files nobody has written to for a while, see `list_older_than`.
+    pub const LIST_OLDER_THAN: u8 = 39;
+    /// Answered with every open connection, only for admins. See `list_connections`.
+    pub const LIST_CONNECTIONS: u8 = 40;
+    /// Shuts one connection down, only for admins. See `kick_connection`.
+    pub const KICK_CONNECTION: u8 = 41;
+
+    /// The op's name in logs and stats, `None` for bytes that aren't an op.
+    pub fn name(op: u8) -> Option<&'static str> {
+        Some(match op {
+            ADD_FILE => "add_file",
+            GET_FILE => "get_file",
+            FETCH_FILES => "fetch_files",
+            KEEP_ALIVE => "keep_alive",
+            STATS => "stats",
+            SET_TAGS => "set_tags",
+            GET_TAGS => "get_tags",
+            FETCH_FILES_WITH_TAG => "fetch_files_with_tag",
+            ANNOUNCE => "announce",
+            GLOBAL_LIST => "global_list",
+            HEALTH => "health",
+            HANDSHAKE => "handshake",
+            FETCH_FILE_SIZES => "fetch_file_sizes",
+            AUTHENTICATE => "authenticate",
+            STAT => "stat",
+            SET_METADATA => "set_metadata",
+            FIND_BY_HASH => "find_by_hash",
+            COPY_FILE => "copy_file",
+            SET_VISIBILITY => "set_visibility",
+            FETCH_OWN_FILES => "fetch_own_files",
+            DISCONNECT => "disconnect",
+            GET_FILE_IF_CHANGED => "get_file_if_changed",
+            DELETE_FILES => "delete_files",
+            LIST_PAGE => "list_page",
+            ADD_FILE_STREAM => "add_file_stream",
+            EXPORT_INDEX => "export_index",
+            FOLLOW_LOG => "follow_log",
+            READ_HEAD => "read_head",
+            RESET_DOWNLOADS => "reset_downloads",
+            TREE => "tree",
+            GET_FILES => "get_files",
+            CHECK_INDEX => "check_index",
+            SPEEDTEST_DOWNLOAD => "speedtest_download",
+            SPEEDTEST_UPLOAD => "speedtest_upload",
+            MULTIPART_INITIATE => "multipart_initiate",
+            MULTIPART_PART => "multipart_part",
+            MULTIPART_COMPLETE => "multipart_complete",
+            MULTIPART_ABORT => "multipart_abort",
+            INDEX_VERSION => "index_version",
+            LIST_OLDER_THAN => "list_older_than",
+            LIST_CONNECTIONS => "list_connections",
+            KICK_CONNECTION => "kick_connection",
+            _ => return None,
+        })
+    }
+
+    /// The op called `name`, the reverse of `name`.
+    pub fn from_name(name: &str) -> Option<u8> {
+        (0..=u8::MAX).find(|&op| self::name(op) == Some(name))
+    }
+}
+
+/// Wire protocol versions, negotiated by `op::HANDSHAKE`.
+///
+/// Clients that never send a handshake are spoken to in `V1`.
+pub mod version {
+    /// The original protocol, uploads are not acknowledged.
+    pub const V1: u8 = 1;
+    /// Every response except to `op::KEEP_ALIVE` and `op::HANDSHAKE` starts
+    /// with a header, see `write_response`.
+    pub const V2: u8 = 2;
+    /// Full listings (`op::FETCH_FILES`, `op::FETCH_FILE_SIZES`) are sent as a
+    /// single compressed blob, see `write_compressed`.
+    pub const V3: u8 = 3;
+    /// Adds `op::ADD_FILE_STREAM`.
+    pub const V4: u8 = 4;
+    /// The handshake reply is followed by the server's `Capabilities`.
+    pub const V5: u8 = 5;
+    /// File entries carry `FileEntry::downloads`.
+    pub const V6: u8 = 6;
+    /// Uploads say whether the file is private, see `start_upload`.
+    pub const V7: u8 = 7;
+    /// Servers with a passphrase encrypt the connection after the handshake, see `seal`.
+    pub const V8: u8 = 8;
+    /// Announcements carry the hash of each file, see `announce`.
+    pub const V9: u8 = 9;
+
+    pub const LATEST: u8 = V9;
+}
+
+/// Keys a server may put in its `Capabilities`.
+///
+/// Clients ignore keys they don't know, so servers can add more without a new version.
+pub mod capability {
+    /// A bitmask of `feature` flags.
+    pub const FEATURES: &str = "features";
+    /// Most bytes the server stores in total, absent without a quota.
+    pub const QUOTA: &str = "quota";
+    /// Bytes that could be uploaded when the client connected.
+    pub const FREE_BYTES: &str = "free_bytes";
+    /// Most entries `op::LIST_PAGE` returns at once.
+    pub const MAX_PAGE_SIZE: &str = "max_page_size";
+    /// Seconds since the epoch by the server's clock, as it answered the handshake.
+    pub const SERVER_TIME: &str = "server_time";
+    /// Bitmask of the ops the client may send, bit `n` for op `n`. Absent when all are.
+    pub const OPS: &str = "ops";
+    /// Rounds the server's passphrase key is derived with, present when the rest of the
+    /// connection is encrypted, see `seal`.
+    pub const SEAL_ROUNDS: &str = "seal_rounds";
+}
+
+/// Bits of the `capability::FEATURES` value.
+pub mod feature {
+    /// Uploads and other changes are accepted, unset on read-only listeners.
+    pub const WRITE: u64 = 1 << 0;
+    /// Files can be removed with `op::DELETE_FILES`.
+    pub const DELETE: u64 = 1 << 1;
+    /// Clients have to authenticate before anything else.
+    pub const AUTH: u64 = 1 << 2;
+    /// Uploads are only acknowledged once they are on disk.
+    pub const DURABLE: u64 = 1 << 3;
+    /// Files are compressed on disk.
+    pub const COMPRESSED_STORAGE: u64 = 1 << 4;
+    /// `op::ADD_FILE_STREAM` is available.
+    pub const STREAM_UPLOAD: u64 = 1 << 5;
+    /// `op::GET_FILES` is available.
+    pub const BATCH_GET: u64 = 1 << 6;
+    /// `op::INDEX_VERSION` is available.
+    pub const INDEX_VERSION: u64 = 1 << 7;
+}
+
+/// Decides whether a client's credentials grant access to the server.
+///
+/// The server consults this when a client sends `op::AUTHENTICATE`, which it
+/// should do straight after the handshake.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, credentials: &[u8]) -> bool;
+
+    /// Who accepted `credentials` belong to, used to decide which files a client owns.
+    ///
+    /// Authenticators that can't tell clients apart return `None`, and every
+    /// file is then public.
+    fn identity(&self, _credentials: &[u8]) -> Option<String> {
+        None
+    }
+}
+
+/// Accepts clients that present a single shared secret.
+pub struct SharedSecretAuth {
+    secret: Vec<u8>,
+}
+
+impl SharedSecretAuth {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+}
+
+impl Authenticator for SharedSecretAuth {
+    fn authenticate(&self, credentials: &[u8]) -> bool {
+        // Compare every byte so the time taken does not leak the secret
+        credentials.len() == self.secret.len()
+            && credentials
+                .iter()
+                .zip(&self.secret)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// State negotiated for a single connection.
+#[derive(Clone)]
+pub struct ConnectionInfo {
+    pub version: u8,
+    pub authenticated: bool,
+    /// Who the client authenticated as, see `Authenticator::identity`.
+    pub identity: Option<String>,
+    /// Set by the server when the client authenticated with its admin secret.
+    pub admin: bool,
+    /// Set by the server when the client connected to a listener that refuses changes.
+    pub read_only: bool,
+    /// Whether the listener makes clients authenticate, if the server has a secret.
+    pub require_auth: bool,
+    /// What the server offers, empty before `version::V5`.
+    pub capabilities: Capabilities,
+    /// Seconds the server's clock is ahead of ours, zero if the server didn't say.
+    pub clock_skew: i64,
+    /// Set by the server to the client's address, when it could be read.
+    pub peer: Option<SocketAddr>,
+}
+
+impl ConnectionInfo {
+    /// The current time by the server's clock, for comparing with times it sent.
+    pub fn server_now(&self) -> u64 {
+        self.to_server_time(unix_now())
+    }
+
+    /// A time read from our clock, moved onto the server's.
+    pub fn to_server_time(&self, local: u64) -> u64 {
+        local.saturating_add_signed(self.clock_skew)
+    }
+}
+
+impl Default for ConnectionInfo {
+    fn default() -> Self {
+        Self {
+            version: version::V1,
+            authenticated: false,
+            identity: None,
+            admin: false,
+            read_only: false,
+            require_auth: true,
+            capabilities: Capabilities::default(),
+            clock_skew: 0,
+            peer: None,
+        }
+    }
+}
+
+/// Named values describing what a server offers, see `capability` for the keys.
+///
+/// Kept in key order, so they are always sent the same way.
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities(BTreeMap<String, u64>);
+
+impl Capabilities {
+    #[inline]
+    pub fn get(&self, key: &str) -> Option<u64> {
+        self.0.get(key).copied()
+    }
+
+    #[inline]
+    pub fn set(&mut self, key: &str, value: u64) {
+        self.0.insert(key.to_string(), value);
+    }
+
+    /// Whether every bit of `feature` is offered.
+    ///
+    /// Servers too old to send `capability::FEATURES` are assumed to offer everything.
+    pub fn has(&self, feature: u64) -> bool {
+        self.get(capability::FEATURES)
+            .is_none_or(|features| features & feature == feature)
+    }
+
+    /// Whether the server accepts `op`, assumed for servers that don't say.
+    pub fn allows(&self, op: u8) -> bool {
+        op >= 64
+            || self
+                .get(capability::OPS)
+                .is_none_or(|ops| ops & (1 << op) != 0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.0.iter().map(|(key, value)| (key.as_str(), *value))
+    }
+}
+
+/// Most keys accepted by `read_capabilities`.
+const MAX_CAPABILITIES: usize = 256;
+
+pub fn write_capabilities<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    capabilities: &Capabilities,
+) -> io::Result<()> {
+    append_usize(chunk, capabilities.0.len())?;
+
+    for (key, value) in capabilities.iter() {
+        append_string(chunk, key)?;
+        append_usize(chunk, value as usize)?;
+    }
+    chunk.flush_pending()
+}
+
+pub fn read_capabilities<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+) -> io::Result<Capabilities> {
+    let count = read_usize(chunk)?;
+
+    if count > MAX_CAPABILITIES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{count} capabilities is more than the {MAX_CAPABILITIES} allowed"),
+        ));
+    }
+
+    let mut capabilities = Capabilities::default();
+    for _ in 0..count {
+        let key = read_string(chunk)?;
+        capabilities.set(&key, read_usize(chunk)? as u64);
+    }
+
+    Ok(capabilities)
+}
+
+/// A connection a `Chunk` can send and receive over.
+pub trait Transport {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()>;
+
+    fn read_exact(&self, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read(buf)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => buf = &mut buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Who is on the other end, as shown in the wire trace.
+    fn peer(&self) -> String;
+
+    /// Close both directions of the connection, if it can be closed from here.
+    fn shutdown(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Fail reads that wait longer than `timeout`, if the connection supports it.
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Fail writes that block longer than `timeout`, if the connection supports it.
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for TcpStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut { self }, buf)
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        Write::write_all(&mut { self }, buf)
+    }
+
+    fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        Read::read_exact(&mut { self }, buf)
+    }
+
+    fn peer(&self) -> String {
+        self.peer_addr()
+            .map_or_else(|_| "?".to_string(), |addr| addr.to_string())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut { self }, buf)
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        Write::write_all(&mut { self }, buf)
+    }
+
+    fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        Read::read_exact(&mut { self }, buf)
+    }
+
+    // The client end of a socket is almost never bound to a path
+    fn peer(&self) -> String {
+        "unix socket".to_string()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// A connection to a server, over TCP or, on Unix, a local socket.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// Encrypted with a passphrase, see `seal::connect`.
+    Sealed(Box<seal::Sealed<Stream>>),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+            Self::Sealed(stream) => stream
+                .try_clone()
+                .map(|stream| Self::Sealed(Box::new(stream))),
+        }
+    }
+}
+
+impl Transport for Stream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => Transport::read(stream, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Transport::read(stream, buf),
+            Self::Sealed(stream) => Transport::read(&**stream, buf),
+        }
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => Transport::write_all(stream, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Transport::write_all(stream, buf),
+            Self::Sealed(stream) => Transport::write_all(&**stream, buf),
+        }
+    }
+
+    fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => Transport::read_exact(stream, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Transport::read_exact(stream, buf),
+            Self::Sealed(stream) => Transport::read_exact(&**stream, buf),
+        }
+    }
+
+    fn peer(&self) -> String {
+        match self {
+            Self::Tcp(stream) => stream.peer(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.peer(),
+            Self::Sealed(stream) => stream.peer(),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => Transport::shutdown(stream),
+            #[cfg(unix)]
+            Self::Unix(stream) => Transport::shutdown(stream),
+            Self::Sealed(stream) => Transport::shutdown(&**stream),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => Transport::set_read_timeout(stream, timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => Transport::set_read_timeout(stream, timeout),
+            Self::Sealed(stream) => Transport::set_read_timeout(&**stream, timeout),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => Transport::set_write_timeout(stream, timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => Transport::set_write_timeout(stream, timeout),
+            Self::Sealed(stream) => Transport::set_write_timeout(&**stream, timeout),
+        }
+    }
+}
+
+/// The connection a `Chunk` uses, borrowed by `Chunk::new` or owned by `Chunk::owned`.
+enum StreamHandle<'a, S> {
+    Borrowed(&'a S),
+    Owned(S),
+}
+
+impl<S> Deref for StreamHandle<'_, S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        match self {
+            Self::Borrowed(stream) => stream,
+            Self::Owned(stream) => stream,
+        }
+    }
+}
+
+/// A `Chunk` that owns its connection, so it can be kept for a whole session
+/// without anything else holding on to the stream.
+pub type OwnedChunk<const N: usize, S = TcpStream> = Chunk<'static, N, S>;
+
+/// Returned by `Chunk::append_to_buf` when the data doesn't fit after what is pending.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferFull {
+    pub needed: usize,
+    pub free: usize,
+}
+
+impl std::fmt::Display for BufferFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes don't fit in the {} left in the buffer",
+            self.needed, self.free
+        )
+    }
+}
+
+impl std::error::Error for BufferFull {}
+
+impl From<BufferFull> for io::Error {
+    fn from(err: BufferFull) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+pub struct Chunk<'a, const N: usize, S: Transport = TcpStream> {
+    stream: StreamHandle<'a, S>,
     buffer: [u8; N],
     bytes_sent: usize,
     last_insert: usize,
+    /// Bytes at the start of the buffer appended since the last flush, see `append_to_buf`.
+    pending: usize,
+    /// Totals over the chunk's lifetime, only used as offsets in the wire trace.
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl<const N: usize, S: Transport> OwnedChunk<N, S> {
+    /// A chunk that takes `stream` over rather than borrowing it.
+    ///
+    /// The stream is reached through `stream`, to pass to the request functions
+    /// or to `disconnect` at the end of the session.
+    pub fn owned(stream: S) -> Self {
+        Self::with_handle(StreamHandle::Owned(stream))
+    }
 }
 
-impl<'a, const N: usize> Chunk<'a, N> {
-    pub fn new(stream: &'a TcpStream) -> Self {
+impl<'a, const N: usize, S: Transport> Chunk<'a, N, S> {
+    pub fn new(stream: &'a S) -> Self {
+        Self::with_handle(StreamHandle::Borrowed(stream))
+    }
+
+    fn with_handle(stream: StreamHandle<'a, S>) -> Self {
         Self {
             stream,
             buffer: [0u8; N],
             bytes_sent: 0,
             last_insert: 0,
+            pending: 0,
+            bytes_in: 0,
+            bytes_out: 0,
         }
     }
 
-    pub fn run_loop(
+    /// Call `f` until it fails or asks to stop.
+    pub fn run_loop<T: Clone>(
         &mut self,
-        shared_files: SharedFiles,
-        f: impl Fn(&mut Self, SharedFiles) -> io::Result<()>,
+        shared: T,
+        mut f: impl FnMut(&mut Self, T) -> io::Result<ControlFlow<()>>,
     ) -> io::Result<()> {
-        loop {
-            f(self, shared_files.clone())?;
-        }
+        while f(self, shared.clone())?.is_continue() {}
+        Ok(())
+    }
+
+    /// Fail any read or write that waits longer than `deadline`, `None` to wait forever.
+    ///
+    /// Only as good as the stream's `Transport::set_read_timeout` and
+    /// `Transport::set_write_timeout`, streams without timeouts ignore it.
+    pub fn set_deadline(&self, deadline: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(deadline)?;
+        self.stream.set_write_timeout(deadline)
+    }
+
+    /// Run `f` with `deadline` set, then go back to waiting forever.
+    pub fn with_deadline<T>(
+        &mut self,
+        deadline: Duration,
+        f: impl FnOnce(&mut Self) -> ProtocolResult<T>,
+    ) -> ProtocolResult<T> {
+        self.set_deadline(Some(deadline))?;
+        let result = f(self);
+        self.set_deadline(None)?;
+        result
+    }
+
+    /// The connection the chunk reads from and writes to.
+    #[inline]
+    pub fn stream(&self) -> &S {
+        &self.stream
     }
 
     #[inline]
@@ -44,15 +757,31 @@ impl<'a, const N: usize> Chunk<'a, N> {
         self.bytes_sent
     }
 
+    /// Bytes read from the stream since the chunk was made.
+    #[inline]
+    pub fn received(&self) -> u64 {
+        self.bytes_in
+    }
+
     #[inline]
     pub const fn len(&self) -> usize {
         N
     }
 
+    /// Start over, dropping anything appended but not yet flushed.
     #[inline]
     pub fn reset(&mut self) {
         self.bytes_sent = 0;
         self.last_insert = 0;
+        self.pending = 0;
+    }
+
+    /// Reset and also zero the buffer, so nothing from a previous op can be read back.
+    ///
+    /// Slower than `reset`, use it after the buffer held something sensitive.
+    pub fn reset_zeroing(&mut self) {
+        self.reset();
+        self.buffer.fill(0);
     }
 
     #[inline]
@@ -65,21 +794,46 @@ impl<'a, const N: usize> Chunk<'a, N> {
         &mut self.buffer[..count]
     }
 
-    pub fn to_byte_array<const S: usize>(&self) -> [u8; S] {
-        assert!(S <= N);
-        self.buffer[..S]
+    pub fn to_byte_array<const M: usize>(&self) -> [u8; M] {
+        assert!(M <= N);
+        self.buffer[..M]
             .try_into()
             .expect("Cannot convert buffer to array")
     }
 
+    /// Record a protocol event if the wire trace is enabled.
+    #[inline]
+    pub fn trace(&self, event: fmt::Arguments) {
+        if WIRE_TRACE_ON.load(Ordering::Relaxed) {
+            write_trace(&self.stream.peer(), self.bytes_in, self.bytes_out, event);
+        }
+    }
+
     pub fn read(&mut self, count: usize) -> io::Result<usize> {
         let bytes_read = self.stream.read(&mut self.buffer[..count])?;
-        self.last_insert = count;
+        self.trace(format_args!("read {bytes_read}/{count} bytes"));
+
+        self.last_insert = bytes_read;
+        self.bytes_in += bytes_read as u64;
         Ok(bytes_read)
     }
 
     pub fn read_stream(&mut self, count: usize) -> io::Result<()> {
-        self.stream.read_exact(&mut self.buffer[..count])?;
+        // Counted as it arrives, so a read cut short still shows in `received`
+        let mut filled = 0;
+        while filled < count {
+            match self.stream.read(&mut self.buffer[filled..count]) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    filled += n;
+                    self.bytes_in += n as u64;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.trace(format_args!("read {count} bytes"));
+
         self.last_insert = count;
         Ok(())
     }
@@ -99,111 +853,2387 @@ impl<'a, const N: usize> Chunk<'a, N> {
 
     pub fn send(&mut self, count: usize) -> io::Result<()> {
         self.stream.write_all(&self.buffer[..count])?;
+        self.trace(format_args!("sent {count} bytes"));
+
         self.bytes_sent += count;
+        self.bytes_out += count as u64;
+        Ok(())
+    }
+
+    pub fn send_last_write(&mut self) -> io::Result<()> {
+        self.send(self.last_insert)
+    }
+
+    /// Copy `items` in after what was appended since the last flush, to go out in
+    /// one send with it from `flush_pending`.
+    ///
+    /// Nothing is copied if `items` doesn't fit in what is left of the buffer.
+    /// Reads and `write_to_buf` overwrite the buffer from the start, so flush before them.
+    pub fn append_to_buf(&mut self, items: &[u8]) -> Result<usize, BufferFull> {
+        let free = N - self.pending;
+        if items.len() > free {
+            return Err(BufferFull {
+                needed: items.len(),
+                free,
+            });
+        }
+
+        let end = self.pending + items.len();
+        self.buffer[self.pending..end].copy_from_slice(items);
+        self.pending = end;
+        self.last_insert = end;
+        Ok(items.len())
+    }
+
+    /// Bytes appended and not yet sent.
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Send everything appended since the last flush.
+    pub fn flush_pending(&mut self) -> io::Result<()> {
+        match std::mem::take(&mut self.pending) {
+            0 => Ok(()),
+            count => self.send(count),
+        }
+    }
+
+    /// Append `items`, flushing what is pending first if they don't fit behind it.
+    fn append_or_flush(&mut self, items: &[u8]) -> io::Result<()> {
+        if self.append_to_buf(items).is_err() {
+            self.flush_pending()?;
+            self.append_to_buf(items)?;
+        }
         Ok(())
     }
+}
+
+#[inline]
+pub fn write_usize<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    value: usize,
+) -> io::Result<()> {
+    append_usize(chunk, value)?;
+    chunk.flush_pending()
+}
+
+/// Like `write_usize`, left pending to go out with what follows.
+fn append_usize<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    value: usize,
+) -> io::Result<()> {
+    chunk.trace(format_args!("write size {value}"));
+    chunk.append_or_flush(&value.to_le_bytes())
+}
+
+pub fn read_usize<const N: usize, S: Transport>(chunk: &mut Chunk<N, S>) -> io::Result<usize> {
+    chunk.read_stream(8)?;
+    let value = usize::from_le_bytes(chunk.to_byte_array::<8>());
+
+    chunk.trace(format_args!("read size {value}"));
+    Ok(value)
+}
+
+/// Read a length prefix for data that has to fit in the chunk's buffer.
+fn read_len<const N: usize, S: Transport>(chunk: &mut Chunk<N, S>) -> io::Result<usize> {
+    let len = read_usize(chunk)?;
+
+    if len > chunk.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Length {len} is larger than the {N} byte buffer"),
+        ));
+    }
+    Ok(len)
+}
+
+/// Send a length prefixed string, which has to fit in the chunk's buffer.
+pub fn write_string<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    str: &str,
+) -> io::Result<()> {
+    check_fits(chunk, str)?;
+    append_string(chunk, str)?;
+    chunk.flush_pending()
+}
+
+/// Fail if `str` is too long for `write_string` to send through `chunk`.
+fn check_fits<const N: usize, S: Transport>(chunk: &Chunk<N, S>, str: &str) -> io::Result<()> {
+    // The buffer would silently truncate it and the other side would desync
+    if str.len() > chunk.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{str:.32}...' is too long to send, the limit is {N} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+/// Like `write_string`, left pending to go out with what follows.
+fn append_string<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    str: &str,
+) -> io::Result<()> {
+    chunk.append_or_flush(&str.len().to_le_bytes())?;
+    chunk.append_or_flush(str.as_bytes())
+}
+
+pub fn read_string<const N: usize, S: Transport>(chunk: &mut Chunk<N, S>) -> io::Result<String> {
+    let file_name_count = read_len(chunk)?;
+
+    if file_name_count == 0 {
+        return Ok(String::new());
+    }
+
+    chunk.read_stream(file_name_count)?;
+    Ok(String::from_utf8_lossy(chunk.slice(file_name_count)).to_string())
+}
+
+/// Send a count followed by each item as a string.
+pub fn write_string_list<const N: usize, S: Transport, I: AsRef<str>>(
+    chunk: &mut Chunk<N, S>,
+    items: impl ExactSizeIterator<Item = I>,
+) -> io::Result<()> {
+    append_list(chunk, items)?;
+    chunk.flush_pending()
+}
+
+/// Like `write_string_list`, left pending to go out with what follows.
+fn append_list<const N: usize, S: Transport, I: AsRef<str>>(
+    chunk: &mut Chunk<N, S>,
+    items: impl ExactSizeIterator<Item = I>,
+) -> io::Result<()> {
+    append_usize(chunk, items.len())?;
+
+    for item in items {
+        append_string(chunk, item.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Read a list sent by `write_string_list`.
+pub fn read_string_list<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+) -> io::Result<Vec<String>> {
+    let count = read_usize(chunk)?;
+
+    if count > MAX_LIST_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("List of {count} items is longer than the {MAX_LIST_LEN} allowed"),
+        ));
+    }
+
+    read_framed_items(chunk, count)
+        .map(|item| item.map(|bytes| String::from_utf8_lossy(&bytes).into_owned()))
+        .collect()
+}
+
+/// Read `count` length prefixed items, each one as it is iterated over.
+///
+/// Each item has to fit in the chunk's buffer. Iteration ends after the first
+/// error, which is yielded.
+pub fn read_framed_items<'a, 's, const N: usize, S: Transport>(
+    chunk: &'a mut Chunk<'s, N, S>,
+    count: usize,
+) -> FramedItems<'a, 's, N, S> {
+    FramedItems {
+        chunk,
+        remaining: count,
+    }
+}
+
+/// Items read by `read_framed_items`.
+pub struct FramedItems<'a, 's, const N: usize, S: Transport> {
+    chunk: &'a mut Chunk<'s, N, S>,
+    remaining: usize,
+}
+
+impl<const N: usize, S: Transport> Iterator for FramedItems<'_, '_, N, S> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let item = read_len(self.chunk).and_then(|len| {
+            self.chunk.read_stream(len)?;
+            Ok(self.chunk.slice(len).to_vec())
+        });
+
+        // The framing can't be trusted after an error, so nothing more is read
+        self.remaining = match item {
+            Ok(_) => self.remaining - 1,
+            Err(_) => 0,
+        };
+        Some(item)
+    }
+}
+
+/// Send a listing of file names, used by every op that lists files.
+///
+/// Listings must always be read back with `read_file_list` so both sides agree
+/// on the framing.
+#[inline]
+pub fn write_file_list<const N: usize, S: Transport, I: AsRef<str>>(
+    chunk: &mut Chunk<N, S>,
+    files: impl ExactSizeIterator<Item = I>,
+) -> io::Result<()> {
+    write_string_list(chunk, files)
+}
+
+/// Read a listing sent by `write_file_list`.
+#[inline]
+pub fn read_file_list<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+) -> io::Result<Vec<String>> {
+    read_string_list(chunk)
+}
+
+pub fn read_bytes<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+) -> io::Result<Option<Vec<u8>>> {
+    let byte_count = read_len(chunk)?;
+
+    if byte_count == 0 {
+        return Ok(None);
+    }
+
+    chunk.read_stream(byte_count)?;
+    Ok(Some(Vec::from(chunk.slice(byte_count))))
+}
+
+#[inline]
+pub fn write_op<const N: usize, S: Transport>(chunk: &mut Chunk<N, S>, op: u8) -> io::Result<()> {
+    chunk.trace(format_args!("write op {op}"));
+    chunk.write_and_send(&op.to_le_bytes())
+}
+
+/// Metadata about a single file on the server.
+#[derive(Clone, Debug)]
+pub struct FileEntry {
+    pub name: String,
+    /// Length of the file's contents in bytes.
+    pub size: u64,
+    /// Seconds since the epoch the file was last written.
+    pub modified: u64,
+    pub tags: Vec<String>,
+    /// A single line describing the file, empty if it has none.
+    pub description: String,
+    /// Only listed for its owner and admins.
+    pub private: bool,
+    /// Times the file has been downloaded in full, always zero before `version::V6`.
+    pub downloads: u64,
+}
+
+/// Order of the files returned by `list_page`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Name = 0,
+    Size = 1,
+    Modified = 2,
+    /// Times the file has been downloaded from the server.
+    Downloads = 3,
+}
+
+impl SortKey {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0 => Self::Name,
+            1 => Self::Size,
+            2 => Self::Modified,
+            3 => Self::Downloads,
+            _ => return None,
+        })
+    }
+}
+
+/// One page of the server's files, see `list_page`.
+pub struct Page {
+    /// Files in the whole listing, not just this page.
+    pub total: usize,
+    pub entries: Vec<FileEntry>,
+}
+
+/// A file as recorded in an index snapshot, see `export_index`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+    /// Hash of the contents, see `hash_reader`.
+    pub hash: Option<String>,
+    pub tags: Vec<String>,
+    pub description: String,
+    pub private: bool,
+}
+
+/// A file, or a folder of them, in the tree sent for `op::TREE`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TreeNode {
+    pub name: String,
+    /// For folders, the total of everything under them.
+    pub size: u64,
+    /// For folders, the newest time of everything under them.
+    pub modified: u64,
+    /// Empty for files.
+    pub children: Vec<TreeNode>,
+    /// Set when some of the node's children were left out to keep the tree small.
+    pub truncated: bool,
+}
+
+impl TreeNode {
+    pub fn is_dir(&self) -> bool {
+        !self.children.is_empty() || self.truncated
+    }
+}
+
+/// Where a server's index and its stored files disagree, sent for `op::CHECK_INDEX`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct IndexReport {
+    /// Index entries looked at.
+    pub checked: usize,
+    /// In the index, but the file is gone.
+    pub missing: Vec<String>,
+    /// Stored, but not in the index.
+    pub orphaned: Vec<String>,
+    /// A different size on disk than the index last saw.
+    pub resized: Vec<String>,
+    /// Contents no longer match the hash in the index, only checked when asked for.
+    pub corrupt: Vec<String>,
+    /// Whether the problems were fixed: missing entries dropped, orphans and
+    /// resized files indexed again, corrupt files quarantined.
+    pub repaired: bool,
+}
+
+impl IndexReport {
+    pub fn problems(&self) -> usize {
+        self.missing.len() + self.orphaned.len() + self.resized.len() + self.corrupt.len()
+    }
+}
+
+/// A connection open on a server, sent for `op::LIST_CONNECTIONS`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectionSummary {
+    /// What `op::KICK_CONNECTION` knows the connection by, never reused while the server runs.
+    pub id: u64,
+    pub peer: String,
+    /// Seconds since the epoch the connection was accepted.
+    pub connected_since: u64,
+    /// What it is doing, such as "idle" or "uploading big.iso at 37%".
+    pub activity: String,
+    /// Bytes read from and written to the connection so far, encryption included.
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// Send `entry` in the layout of protocol `version`.
+pub fn write_file_entry<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    entry: &FileEntry,
+    version: u8,
+) -> io::Result<()> {
+    append_string(chunk, &entry.name)?;
+    append_usize(chunk, entry.size as usize)?;
+    append_usize(chunk, entry.modified as usize)?;
+    append_list(chunk, entry.tags.iter())?;
+    append_string(chunk, &entry.description)?;
+    chunk.append_or_flush(&[entry.private as u8])?;
+
+    if version >= version::V6 {
+        append_usize(chunk, entry.downloads as usize)?;
+    }
+    chunk.flush_pending()
+}
+
+/// Read an entry sent by `write_file_entry` with the same `version`.
+pub fn read_file_entry<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    version: u8,
+) -> io::Result<FileEntry> {
+    Ok(FileEntry {
+        name: read_string(chunk)?,
+        size: read_usize(chunk)? as u64,
+        modified: read_usize(chunk)? as u64,
+        tags: read_string_list(chunk)?,
+        description: read_string(chunk)?,
+        private: {
+            chunk.read_stream(1)?;
+            chunk.slice(1)[0] != 0
+        },
+        downloads: match version >= version::V6 {
+            true => read_usize(chunk)? as u64,
+            false => 0,
+        },
+    })
+}
+
+/// Status sent at the start of every response in protocol `V2`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    NotFound = 1,
+    Denied = 2,
+    Busy = 3,
+    InvalidRequest = 4,
+    InternalError = 5,
+    RateLimited = 6,
+    Unauthenticated = 7,
+    SessionExpired = 8,
+    NoSpace = 9,
+    /// Fewer bytes arrived than the upload announced, nothing was stored.
+    IncompleteUpload = 10,
+    /// Contents didn't match the checksum sent with them, nothing was stored.
+    ChecksumMismatch = 11,
+}
+
+impl Status {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0 => Self::Ok,
+            1 => Self::NotFound,
+            2 => Self::Denied,
+            3 => Self::Busy,
+            4 => Self::InvalidRequest,
+            5 => Self::InternalError,
+            6 => Self::RateLimited,
+            7 => Self::Unauthenticated,
+            8 => Self::SessionExpired,
+            9 => Self::NoSpace,
+            10 => Self::IncompleteUpload,
+            11 => Self::ChecksumMismatch,
+            _ => return None,
+        })
+    }
+}
+
+/// A request that failed, either on the wire or because the server refused it.
+///
+/// Server refusals carry the server's message so it can be shown verbatim.
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    NotFound(String),
+    Denied(String),
+    Busy(String),
+    InvalidRequest(String),
+    Internal(String),
+    RateLimited(String),
+    Unauthenticated(String),
+    SessionExpired(String),
+    NoSpace(String),
+    IncompleteUpload(String),
+    ChecksumMismatch(String),
+    /// The other side stopped answering within the deadline, see `Chunk::set_deadline`.
+    TimedOut(String),
+}
+
+pub type ProtocolResult<T> = Result<T, ProtocolError>;
+
+impl ProtocolError {
+    /// The error for a failed `status`, or `None` if it succeeded.
+    pub fn from_status(status: Status, msg: String) -> Option<Self> {
+        Some(match status {
+            Status::Ok => return None,
+            Status::NotFound => Self::NotFound(msg),
+            Status::Denied => Self::Denied(msg),
+            Status::Busy => Self::Busy(msg),
+            Status::InvalidRequest => Self::InvalidRequest(msg),
+            Status::InternalError => Self::Internal(msg),
+            Status::RateLimited => Self::RateLimited(msg),
+            Status::Unauthenticated => Self::Unauthenticated(msg),
+            Status::SessionExpired => Self::SessionExpired(msg),
+            Status::NoSpace => Self::NoSpace(msg),
+            Status::IncompleteUpload => Self::IncompleteUpload(msg),
+            Status::ChecksumMismatch => Self::ChecksumMismatch(msg),
+        })
+    }
+
+    /// Whether the connection is no longer usable and the client has to reconnect.
+    ///
+    /// A late answer to a request that timed out would be read as the answer to
+    /// the next one, so timeouts count too.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            Self::SessionExpired(_) | Self::TimedOut(_) => true,
+            Self::Io(err) => matches!(
+                err.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (msg, fallback) = match self {
+            Self::Io(err) => return write!(f, "{err}"),
+            Self::NotFound(msg) => (msg, "Not found"),
+            Self::Denied(msg) => (msg, "Denied"),
+            Self::Busy(msg) => (msg, "Server is busy"),
+            Self::InvalidRequest(msg) => (msg, "Invalid request"),
+            Self::Internal(msg) => (msg, "Internal server error"),
+            Self::RateLimited(msg) => (msg, "Rate limited"),
+            Self::Unauthenticated(msg) => (msg, "Authentication required"),
+            Self::SessionExpired(msg) => (msg, "Session expired"),
+            Self::NoSpace(msg) => (msg, "Server is out of disk space"),
+            Self::IncompleteUpload(msg) => (msg, "Upload was incomplete"),
+            Self::ChecksumMismatch(msg) => (msg, "Checksum mismatch"),
+            Self::TimedOut(msg) => (msg, "Timed out waiting for the other side"),
+        };
+
+        if msg.is_empty() {
+            write!(f, "{fallback}")
+        } else {
+            write!(f, "{msg}")
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        // Sockets report a timed out read as either, depending on the platform, and
+        // "Resource temporarily unavailable" wouldn't tell anyone what happened
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Self::TimedOut(String::new()),
+            _ => Self::Io(err),
+        }
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(err: ProtocolError) -> Self {
+        let kind = match err {
+            ProtocolError::Io(err) => return err,
+            ProtocolError::NotFound(_) => io::ErrorKind::NotFound,
+            ProtocolError::Denied(_) | ProtocolError::Unauthenticated(_) => {
+                io::ErrorKind::PermissionDenied
+            }
+            ProtocolError::InvalidRequest(_) => io::ErrorKind::InvalidInput,
+            ProtocolError::SessionExpired(_) | ProtocolError::TimedOut(_) => {
+                io::ErrorKind::TimedOut
+            }
+            ProtocolError::NoSpace(_) => io::ErrorKind::StorageFull,
+            ProtocolError::IncompleteUpload(_) => io::ErrorKind::UnexpectedEof,
+            ProtocolError::ChecksumMismatch(_) => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::Other,
+        };
+
+        io::Error::new(kind, err.to_string())
+    }
+}
+
+/// Send a response header: a status byte followed by a message, which may be empty.
+pub fn write_response<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    status: Status,
+    msg: &str,
+) -> io::Result<()> {
+    chunk.trace(format_args!("write status {status:?} {msg:?}"));
+    chunk.write_and_send(&[status as u8])?;
+    write_string(chunk, msg)
+}
+
+/// Read a response header, turning a failed status into an error.
+pub fn read_response<const N: usize, S: Transport>(chunk: &mut Chunk<N, S>) -> ProtocolResult<()> {
+    chunk.read_stream(1)?;
+    let byte = chunk.slice(1)[0];
+    let msg = read_string(chunk)?;
+    chunk.trace(format_args!("read status {byte} {msg:?}"));
+
+    let status = Status::from_byte(byte).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown status byte {byte}"),
+        )
+    })?;
+
+    match ProtocolError::from_status(status, msg) {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Responses only carry a header from `V2` onwards.
+#[inline]
+fn read_header<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    info: &ConnectionInfo,
+) -> ProtocolResult<()> {
+    if info.version >= version::V2 {
+        read_response(chunk)
+    } else {
+        Ok(())
+    }
+}
+
+/// Hex encoded SHA-256 of everything in `reader`, used to recognise identical contents.
+///
+/// `progress` is called with the number of bytes hashed so far.
+pub fn hash_reader(mut reader: impl Read, mut progress: impl FnMut(u64)) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = transfer_pool().acquire(HASH_BUF_SIZE);
+    let mut hashed = 0u64;
+
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..bytes_read]);
+        hashed += bytes_read as u64;
+        progress(hashed);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Send `value` as deflate-compressed JSON, prefixed by the compressed length.
+///
+/// Worth it for long, repetitive listings, the whole blob is built before sending.
+/// Higher levels spend more CPU for a smaller blob, `Compression::fast()` suits
+/// a busy sender and `Compression::best()` a slow link. The reader doesn't need
+/// to know the level.
+pub fn write_compressed<const N: usize, S: Transport, T: Serialize + ?Sized>(
+    chunk: &mut Chunk<N, S>,
+    value: &T,
+    level: Compression,
+) -> io::Result<()> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), level);
+    serde_json::to_writer(&mut encoder, value)?;
+    let blob = encoder.finish()?;
+
+    send_reader(chunk, blob.as_slice(), blob.len())
+}
+
+/// Read a value sent with `write_compressed`.
+pub fn read_compressed<const N: usize, S: Transport, T: DeserializeOwned>(
+    chunk: &mut Chunk<N, S>,
+) -> io::Result<T> {
+    let len = read_usize(chunk)?;
+    if len > MAX_COMPRESSED_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Compressed blob of {len} bytes is too large"),
+        ));
+    }
+
+    let blob = receive_file(chunk, len)?.unwrap_or_default();
+    let decoder = DeflateDecoder::new(blob.as_slice()).take(MAX_DECOMPRESSED_LEN);
+    Ok(serde_json::from_reader(decoder)?)
+}
+
+pub fn send_file<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    file_name: &str,
+) -> io::Result<()> {
+    if !Path::new(file_name).exists() {
+        write_usize(chunk, 0)?;
+        return Ok(());
+    }
+
+    let file = fs::File::open(file_name)?;
+    let file_size = file.metadata()?.len() as usize;
+
+    send_reader(chunk, file, file_size)
+}
+
+/// Start an upload of `file_name` with `op`, one of `op::ADD_FILE`,
+/// `op::ADD_FILE_STREAM` or `op::MULTIPART_INITIATE`, the rest of which is up to the caller.
+///
+/// Fails before sending anything if the file is `private` and the server is too old to know.
+pub fn start_upload<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    info: &ConnectionInfo,
+    op: u8,
+    file_name: &str,
+    private: bool,
+) -> ProtocolResult<()> {
+    if private && info.version < version::V7 {
+        return Err(ProtocolError::InvalidRequest(
+            "Private uploads need protocol version 7 or later".to_string(),
+        ));
+    }
+
+    // Checked before the op goes out, so a refused name leaves the connection usable
+    check_fits(chunk, file_name)?;
+    write_op(chunk, op)?;
+    write_string(chunk, file_name)?;
+    if info.version >= version::V7 {
+        chunk.write_and_send(&[private as u8])?;
+    }
+    Ok(())
+}
+
+/// Read whether an upload is private, as sent by `start_upload`.
+pub fn read_private<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    info: &ConnectionInfo,
+) -> io::Result<bool> {
+    if info.version < version::V7 {
+        return Ok(false);
+    }
+    chunk.read_stream(1)?;
+    Ok(chunk.slice(1)[0] != 0)
+}
+
+/// Send `size` bytes pulled from `reader`, prefixed by the size.
+pub fn send_reader<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    mut reader: impl Read,
+    size: usize,
+) -> io::Result<()> {
+    // Send file_size to server
+    write_usize(chunk, size)?;
+
+    chunk.reset();
+
+    // Send file data in chunks
+    while chunk.sent() < size {
+        let bytes_to_read = std::cmp::min(chunk.len(), size - chunk.sent());
+        let bytes_read = reader.read(chunk.slice_mut(bytes_to_read))?;
+
+        if bytes_read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        chunk.send(bytes_read)?;
+    }
+
+    Ok(())
+}
+
+/// Send everything in `reader` as frames, each prefixed by its length, then an empty frame.
+///
+/// For contents whose size isn't known until they have all been read.
+pub fn send_stream<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    mut reader: impl Read,
+) -> io::Result<()> {
+    let mut frame = transfer_pool().acquire(N);
+
+    loop {
+        let bytes_read = reader.read(&mut frame)?;
+        write_usize(chunk, bytes_read)?;
+
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        chunk.write_and_send(&frame[..bytes_read])?;
+    }
+}
+
+/// Read frames sent by `send_stream` into `writer`, returning how many bytes there were.
+///
+/// Fails with `StorageFull` before reading a frame that would take it past `limit` bytes.
+pub fn receive_stream<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    mut writer: impl Write,
+    limit: usize,
+) -> io::Result<usize> {
+    let mut received = 0;
+
+    loop {
+        let frame_size = read_len(chunk)?;
+        if frame_size == 0 {
+            return Ok(received);
+        }
+
+        if received + frame_size > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("Stream is larger than the {limit} bytes allowed"),
+            ));
+        }
+
+        chunk.read_stream(frame_size)?;
+        writer.write_all(chunk.slice(frame_size))?;
+        received += frame_size;
+    }
+}
+
+pub fn receive_file<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    file_size: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    receive_file_with_progress(chunk, file_size, |_| {})
+}
+
+/// Like `receive_file`, calling `progress` with the bytes received so far after every read.
+pub fn receive_file_with_progress<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    file_size: usize,
+    mut progress: impl FnMut(usize),
+) -> io::Result<Option<Vec<u8>>> {
+    if file_size == 0 {
+        return Ok(None);
+    }
+
+    let mut buffer = Vec::new();
+    let mut bytes_received = 0;
+
+    chunk.reset();
+
+    while bytes_received < file_size {
+        let bytes_to_read = std::cmp::min(chunk.len(), file_size - bytes_received);
+        chunk.read_stream(bytes_to_read)?;
+
+        buffer.extend(chunk.slice(bytes_to_read));
+        bytes_received += bytes_to_read;
+        progress(bytes_received);
+    }
+
+    Ok(Some(buffer))
+}
+
+/// Connect to `addr`, trying each address it resolves to until `timeout` runs out.
+///
+/// Failing to resolve the name is reported as `NotFound`, so it can be told apart
+/// from a host that refused or didn't answer.
+pub fn connect(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let deadline = Instant::now() + timeout;
+    let addrs = addr.to_socket_addrs().map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Could not resolve '{addr}': {err}"),
+        )
+    })?;
+
+    let mut last_err = io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("'{addr}' did not resolve to any address"),
+    );
+
+    for socket_addr in addrs {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Could not connect to '{addr}' in time"),
+            ));
+        }
+
+        match TcpStream::connect_timeout(&socket_addr, remaining) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+
+    Err(last_err)
+}
+
+/// Agree on the highest protocol version both sides support.
+///
+/// Fails with `ProtocolError::TimedOut` if the server takes over `HANDSHAKE_DEADLINE`.
+pub fn handshake<S: Transport>(stream: &S) -> ProtocolResult<ConnectionInfo> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    let (negotiated, capabilities) = chunk.with_deadline(HANDSHAKE_DEADLINE, |chunk| {
+        write_op(chunk, op::HANDSHAKE)?;
+        chunk.write_and_send(&[version::LATEST])?;
+
+        chunk.read_stream(1)?;
+        let negotiated = chunk.slice(1)[0];
+
+        let capabilities = if negotiated >= version::V5 {
+            read_capabilities(chunk)?
+        } else {
+            Capabilities::default()
+        };
+        Ok((negotiated, capabilities))
+    })?;
+
+    // Half the round trip is well under a second, so it isn't corrected for
+    let clock_skew = capabilities
+        .get(capability::SERVER_TIME)
+        .map_or(0, |server_time| server_time as i64 - unix_now() as i64);
+
+    Ok(ConnectionInfo {
+        version: negotiated,
+        capabilities,
+        clock_skew,
+        ..ConnectionInfo::default()
+    })
+}
+
+/// Present credentials to the server, failing if they are refused.
+pub fn authenticate<S: Transport>(stream: &S, credentials: &[u8]) -> ProtocolResult<()> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::AUTHENTICATE)?;
+    write_usize(&mut chunk, credentials.len())?;
+    chunk.write_and_send(credentials)?;
+
+    // Always answered with a header, whatever the version
+    read_response(&mut chunk)
+}
+
+/// Request a file from the server, returning `None` if it does not exist.
+pub fn get_file<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    file_name: &str,
+) -> ProtocolResult<Option<Vec<u8>>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::GET_FILE)?;
+    write_string(&mut chunk, file_name)?;
+
+    match read_header(&mut chunk, info) {
+        Err(ProtocolError::NotFound(_)) => return Ok(None),
+        result => result?,
+    }
+
+    let file_size = read_usize(&mut chunk)?;
+    Ok(receive_file(&mut chunk, file_size)?)
+}
+
+/// One file's outcome in `get_files`, the contents or why it wasn't sent.
+pub type DownloadResult = ProtocolResult<Vec<u8>>;
+
+/// Request up to `MAX_BATCH_LEN` files in one round trip.
+///
+/// Every name comes back in the order requested. A file the server doesn't have fails
+/// on its own without failing the rest, only a broken connection fails the batch.
+pub fn get_files<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    names: &[&str],
+) -> ProtocolResult<Vec<(String, DownloadResult)>> {
+    if names.len() > MAX_BATCH_LEN {
+        return Err(ProtocolError::InvalidRequest(format!(
+            "At most {MAX_BATCH_LEN} files can be requested at once"
+        )));
+    }
+
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::GET_FILES)?;
+    write_string_list(&mut chunk, names.iter())?;
+    read_header(&mut chunk, info)?;
+
+    let mut files = Vec::with_capacity(names.len());
+    for name in names {
+        // Each file starts with its status, only found files carry a size and contents
+        chunk.read_stream(1)?;
+        let status = Status::from_byte(chunk.slice(1)[0])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown status in batch"))?;
+
+        let result = match ProtocolError::from_status(status, format!("Could not get '{name}'")) {
+            Some(ProtocolError::NotFound(_)) => {
+                Err(ProtocolError::NotFound(format!("No file named '{name}'")))
+            }
+            Some(err) => Err(err),
+            None => {
+                let file_size = read_usize(&mut chunk)?;
+                Ok(receive_file(&mut chunk, file_size)?.unwrap_or_default())
+            }
+        };
+        files.push((name.to_string(), result));
+    }
+
+    Ok(files)
+}
+
+/// Outcome of `get_file_if_changed`.
+pub enum Fetched {
+    /// The server's copy hashes to the hash the client already holds.
+    NotModified,
+    /// Same as the result of `get_file`.
+    Changed(Option<Vec<u8>>),
+}
+
+/// Request a file unless its contents hash to `held`, see `hash_reader`.
+pub fn get_file_if_changed<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    file_name: &str,
+    held: &str,
+) -> ProtocolResult<Fetched> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::GET_FILE_IF_CHANGED)?;
+    write_string(&mut chunk, file_name)?;
+    write_string(&mut chunk, held)?;
+
+    match read_header(&mut chunk, info) {
+        Err(ProtocolError::NotFound(_)) => return Ok(Fetched::Changed(None)),
+        result => result?,
+    }
+
+    chunk.read_stream(1)?;
+    if chunk.slice(1)[0] == 0 {
+        return Ok(Fetched::NotModified);
+    }
+
+    let file_size = read_usize(&mut chunk)?;
+    Ok(Fetched::Changed(receive_file(&mut chunk, file_size)?))
+}
+
+/// Request the names of all files on the server.
+pub fn fetch_files<S: Transport>(stream: &S, info: &ConnectionInfo) -> ProtocolResult<Vec<String>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::FETCH_FILES)?;
+    read_header(&mut chunk, info)?;
+
+    if info.version >= version::V3 {
+        return Ok(read_compressed(&mut chunk)?);
+    }
+    Ok(read_file_list(&mut chunk)?)
+}
+
+/// Ask the server whether it is healthy.
+///
+/// Returns `None` when healthy, or the reason the server is degraded.
+pub fn check_health<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+) -> ProtocolResult<Option<String>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    chunk.with_deadline(CONTROL_DEADLINE, |chunk| {
+        write_op(chunk, op::HEALTH)?;
+        read_header(chunk, info)?;
+
+        chunk.read_stream(1)?;
+        let degraded = chunk.slice(1)[0] != 0;
+        let reason = read_string(chunk)?;
+
+        Ok(degraded.then_some(reason))
+    })
+}
+
+/// How long a speed test took, as timed by the client and by the server.
+pub struct SpeedSample {
+    pub bytes: u64,
+    /// From the request until the last byte, the figure the user sees.
+    pub elapsed: Duration,
+    /// From the server's first byte to its last, without the request's round trip.
+    pub server_elapsed: Duration,
+}
+
+impl SpeedSample {
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Have the server send `size` bytes of generated data, timing how long they take.
+///
+/// The data goes through the same send loop as a real download, without reading
+/// a file, so the figure is down to the network. At most `MAX_SPEEDTEST_BYTES`.
+pub fn speedtest_download<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    size: u64,
+) -> ProtocolResult<SpeedSample> {
+    require_speedtest(info, size)?;
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    let started = Instant::now();
+
+    write_op(&mut chunk, op::SPEEDTEST_DOWNLOAD)?;
+    write_usize(&mut chunk, size as usize)?;
+    read_header(&mut chunk, info)?;
+
+    let sent = read_usize(&mut chunk)?;
+    discard(&mut chunk, sent)?;
+    let elapsed = started.elapsed();
+    let server_elapsed = Duration::from_micros(read_usize(&mut chunk)? as u64);
+
+    Ok(SpeedSample {
+        bytes: sent as u64,
+        elapsed,
+        server_elapsed,
+    })
+}
+
+/// Send `size` bytes of generated data for the server to throw away, timing how long they take.
+///
+/// At most `MAX_SPEEDTEST_BYTES`.
+pub fn speedtest_upload<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    size: u64,
+) -> ProtocolResult<SpeedSample> {
+    require_speedtest(info, size)?;
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::SPEEDTEST_UPLOAD)?;
+    write_usize(&mut chunk, size as usize)?;
+    // Only one test runs at a time, so wait to hear this one may start
+    read_header(&mut chunk, info)?;
+
+    let started = Instant::now();
+    send_reader(
+        &mut chunk,
+        io::repeat(SPEEDTEST_BYTE).take(size),
+        size as usize,
+    )?;
+    let server_elapsed = Duration::from_micros(read_usize(&mut chunk)? as u64);
+
+    Ok(SpeedSample {
+        bytes: size,
+        elapsed: started.elapsed(),
+        server_elapsed,
+    })
+}
+
+/// V1 has no header to refuse a test with, so the client can't tell a refusal from data.
+fn require_speedtest(info: &ConnectionInfo, size: u64) -> ProtocolResult<()> {
+    if info.version < version::V2 {
+        return Err(ProtocolError::InvalidRequest(
+            "Speed tests need protocol version 2 or later".to_string(),
+        ));
+    }
+    if size > MAX_SPEEDTEST_BYTES {
+        return Err(ProtocolError::InvalidRequest(format!(
+            "Speed tests move at most {}",
+            format::human_bytes(MAX_SPEEDTEST_BYTES)
+        )));
+    }
+    Ok(())
+}
+
+/// Start an upload of `file_name` to be sent in parts, returning its ID.
+///
+/// `size` is the size of the whole file, so the server can refuse it before any
+/// part is sent. Parts can be sent over any connection, in any order, until the
+/// upload is completed, aborted, or left idle past the server's timeout.
+pub fn initiate_multipart<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    file_name: &str,
+    size: u64,
+    private: bool,
+) -> ProtocolResult<String> {
+    require_multipart(info)?;
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    start_upload(&mut chunk, info, op::MULTIPART_INITIATE, file_name, private)?;
+    write_usize(&mut chunk, size as usize)?;
+    read_header(&mut chunk, info)?;
+
+    Ok(read_string(&mut chunk)?)
+}
+
+/// Send part `number` of upload `id`, replacing it if it was sent before.
+///
+/// The part's hash goes with it, the server refuses it with `ChecksumMismatch`
+/// if what arrived differs. At most `MAX_PART_LEN` bytes.
+pub fn upload_part<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    id: &str,
+    number: usize,
+    contents: &[u8],
+) -> ProtocolResult<()> {
+    require_multipart(info)?;
+    if contents.len() > MAX_PART_LEN {
+        return Err(ProtocolError::InvalidRequest(format!(
+            "Parts are at most {}",
+            format::human_bytes(MAX_PART_LEN as u64)
+        )));
+    }
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::MULTIPART_PART)?;
+    write_string(&mut chunk, id)?;
+    write_usize(&mut chunk, number)?;
+    write_string(&mut chunk, &hash_reader(contents, |_| {})?)?;
+    send_reader(&mut chunk, contents, contents.len())?;
+    read_header(&mut chunk, info)
+}
+
+/// Join the parts of upload `id` into its file.
+///
+/// `hash` is the hash of the whole file, the file is only stored if it matches.
+/// On failure the parts are kept, so missing ones can be sent and this tried again.
+pub fn complete_multipart<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    id: &str,
+    hash: &str,
+) -> ProtocolResult<()> {
+    require_multipart(info)?;
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::MULTIPART_COMPLETE)?;
+    write_string(&mut chunk, id)?;
+    write_string(&mut chunk, hash)?;
+    read_header(&mut chunk, info)
+}
+
+/// Drop upload `id` and every part sent for it.
+pub fn abort_multipart<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    id: &str,
+) -> ProtocolResult<()> {
+    require_multipart(info)?;
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::MULTIPART_ABORT)?;
+    write_string(&mut chunk, id)?;
+    read_header(&mut chunk, info)
+}
+
+/// Refusals and checksum errors need a response header.
+fn require_multipart(info: &ConnectionInfo) -> ProtocolResult<()> {
+    if info.version < version::V2 {
+        return Err(ProtocolError::InvalidRequest(
+            "Multipart uploads need protocol version 2 or later".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Read and drop `size` bytes, the way `receive_file` reads them.
+pub fn discard<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    size: usize,
+) -> io::Result<()> {
+    let mut bytes_received = 0;
+    chunk.reset();
+
+    while bytes_received < size {
+        let bytes_to_read = std::cmp::min(chunk.len(), size - bytes_received);
+        chunk.read_stream(bytes_to_read)?;
+        bytes_received += bytes_to_read;
+    }
+    Ok(())
+}
+
+/// Request a single file's metadata, returning `None` if it does not exist.
+pub fn stat_file<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    file_name: &str,
+) -> ProtocolResult<Option<FileEntry>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::STAT)?;
+    write_string(&mut chunk, file_name)?;
+
+    if info.version >= version::V2 {
+        match read_response(&mut chunk) {
+            Err(ProtocolError::NotFound(_)) => return Ok(None),
+            result => result?,
+        }
+    } else {
+        // V1 has no header, so a byte says whether the file exists
+        chunk.read_stream(1)?;
+        if chunk.slice(1)[0] == 0 {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(read_file_entry(&mut chunk, info.version)?))
+}
+
+/// Request the generation of the server's index, which goes up whenever a file is
+/// added or removed.
+///
+/// A listing fetched after this returns is at least as new as the generation, so
+/// it can be told apart from one fetched before a change the client knows about.
+pub fn index_version<S: Transport>(stream: &S, info: &ConnectionInfo) -> ProtocolResult<u64> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::INDEX_VERSION)?;
+    read_header(&mut chunk, info)?;
+
+    Ok(read_usize(&mut chunk)? as u64)
+}
+
+/// Request the first `len` bytes of a file, returning `None` if it does not exist.
+///
+/// Fewer bytes come back for files shorter than `len`, or when `len` is over
+/// `MAX_HEAD_LEN`. Enough to tell a file's type from its contents without
+/// downloading it.
+pub fn read_head<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    file_name: &str,
+    len: usize,
+) -> ProtocolResult<Option<Vec<u8>>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::READ_HEAD)?;
+    write_string(&mut chunk, file_name)?;
+    write_usize(&mut chunk, len)?;
+
+    if info.version >= version::V2 {
+        match read_response(&mut chunk) {
+            Err(ProtocolError::NotFound(_)) => return Ok(None),
+            result => result?,
+        }
+    } else {
+        // V1 has no header, so a byte says whether the file exists
+        chunk.read_stream(1)?;
+        if chunk.slice(1)[0] == 0 {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(read_bytes(&mut chunk)?.unwrap_or_default()))
+}
+
+/// Request the files under `path` as a tree, `depth` levels deep. An empty path is the root.
+///
+/// `None` if there is nothing at `path`. Servers go no deeper than `MAX_TREE_DEPTH`
+/// and send no more than `MAX_TREE_NODES` nodes, marking the folders they cut short.
+pub fn fetch_tree<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    path: &str,
+    depth: usize,
+) -> ProtocolResult<Option<TreeNode>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::TREE)?;
+    write_string(&mut chunk, path)?;
+    write_usize(&mut chunk, depth)?;
+
+    match read_header(&mut chunk, info) {
+        Err(ProtocolError::NotFound(_)) => return Ok(None),
+        result => result?,
+    }
+    Ok(read_compressed(&mut chunk)?)
+}
+
+/// Set the download count of every file on the server back to zero.
+///
+/// Needs the server's admin secret.
+pub fn reset_downloads<S: Transport>(stream: &S, info: &ConnectionInfo) -> ProtocolResult<()> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::RESET_DOWNLOADS)?;
+    read_header(&mut chunk, info)
+}
+
+/// Ask the server to compare its index with the stored files, hashing every file
+/// if `hashes` is set and fixing what it finds if `repair` is set.
+///
+/// Needs the server's admin secret.
+pub fn check_index<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    hashes: bool,
+    repair: bool,
+) -> ProtocolResult<IndexReport> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::CHECK_INDEX)?;
+    chunk.write_and_send(&[hashes as u8 | (repair as u8) << 1])?;
+    read_header(&mut chunk, info)?;
+
+    Ok(read_compressed(&mut chunk)?)
+}
+
+/// Request the metadata of every file the client can see, as it was at a single moment.
+///
+/// The client has to have authenticated, even with servers that have no secret.
+pub fn export_index<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+) -> ProtocolResult<Vec<SnapshotEntry>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::EXPORT_INDEX)?;
+    read_header(&mut chunk, info)?;
+
+    Ok(read_compressed(&mut chunk)?)
+}
+
+/// Request every connection open on the server, the one asking included.
+///
+/// Needs the server's admin secret.
+pub fn list_connections<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+) -> ProtocolResult<Vec<ConnectionSummary>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::LIST_CONNECTIONS)?;
+    read_header(&mut chunk, info)?;
+
+    Ok(read_compressed(&mut chunk)?)
+}
+
+/// Have the server shut connection `id` down, whatever it is in the middle of.
+///
+/// Needs the server's admin secret. Fails with `NotFound` if no connection has that ID.
+pub fn kick_connection<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    id: u64,
+) -> ProtocolResult<()> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::KICK_CONNECTION)?;
+    write_usize(&mut chunk, id as usize)?;
+    read_header(&mut chunk, info)
+}
+
+/// Pass each line the server logs to `on_line`, as it is logged.
+///
+/// Needs the server's admin secret. Only returns once the connection fails or is
+/// shut down, which is how following is stopped.
+pub fn follow_log<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    mut on_line: impl FnMut(&str),
+) -> ProtocolResult<()> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::FOLLOW_LOG)?;
+    read_header(&mut chunk, info)?;
+
+    loop {
+        let line = read_string(&mut chunk)?;
+
+        // Sent to check on the connection when nothing was logged
+        if !line.is_empty() {
+            on_line(&line);
+        }
+    }
+}
+
+/// Request up to `limit` files starting at `offset`, in the order given by `key`.
+///
+/// Files that compare equal are ordered by name, so pages line up between requests.
+/// The server may return fewer than `limit` even when more files follow.
+pub fn list_page<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    key: SortKey,
+    descending: bool,
+    offset: usize,
+    limit: usize,
+) -> ProtocolResult<Page> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::LIST_PAGE)?;
+    chunk.write_and_send(&[key as u8, descending as u8])?;
+    write_usize(&mut chunk, offset)?;
+    write_usize(&mut chunk, limit)?;
+    read_header(&mut chunk, info)?;
+
+    let total = read_usize(&mut chunk)?;
+    let count = read_usize(&mut chunk)?;
+
+    let mut entries = Vec::with_capacity(count.min(MAX_PREALLOC));
+    for _ in 0..count {
+        entries.push(read_file_entry(&mut chunk, info.version)?);
+    }
+
+    Ok(Page { total, entries })
+}
+
+/// Request the files the client can see that nobody has written to for `age`, oldest first.
+///
+/// Ages are measured by the server's clock, however far off the client's is.
+pub fn list_older_than<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    age: Duration,
+) -> ProtocolResult<Vec<FileEntry>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::LIST_OLDER_THAN)?;
+    write_usize(&mut chunk, age.as_secs() as usize)?;
+    read_header(&mut chunk, info)?;
+
+    let count = read_usize(&mut chunk)?;
+    let mut entries = Vec::with_capacity(count.min(MAX_PREALLOC));
+    for _ in 0..count {
+        entries.push(read_file_entry(&mut chunk, info.version)?);
+    }
+
+    Ok(entries)
+}
+
+/// Request every file the client can see a page at a time, in the order given by `key`.
+pub fn list_all<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    key: SortKey,
+    descending: bool,
+) -> ProtocolResult<Vec<FileEntry>> {
+    // Servers from before capabilities only promise that pages aren't empty
+    let limit = info
+        .capabilities
+        .get(capability::MAX_PAGE_SIZE)
+        .map_or(usize::MAX, |size| size as usize);
+
+    let mut entries = Vec::new();
+    loop {
+        let page = list_page(stream, info, key, descending, entries.len(), limit)?;
+
+        if page.entries.is_empty() {
+            return Ok(entries);
+        }
+        entries.extend(page.entries);
+
+        if entries.len() >= page.total {
+            return Ok(entries);
+        }
+    }
+}
+
+/// Request the name and size of every file on the server.
+pub fn fetch_file_sizes<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+) -> ProtocolResult<Vec<(String, u64)>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::FETCH_FILE_SIZES)?;
+    read_header(&mut chunk, info)?;
+
+    if info.version >= version::V3 {
+        return Ok(read_compressed(&mut chunk)?);
+    }
+
+    let count = read_usize(&mut chunk)?;
+
+    let mut files = Vec::with_capacity(count.min(MAX_PREALLOC));
+    for _ in 0..count {
+        let name = read_string(&mut chunk)?;
+        let size = read_usize(&mut chunk)? as u64;
+        files.push((name, size));
+    }
+
+    Ok(files)
+}
+
+/// Differences between a local directory and the server's files.
+#[derive(Default, Serialize)]
+pub struct DirDiff {
+    pub local_only: Vec<String>,
+    pub remote_only: Vec<String>,
+    /// Files on both sides whose sizes or contents do not match.
+    pub differing: Vec<String>,
+}
+
+/// A file on the server, as compared by `diff_dir`.
+pub struct RemoteFile {
+    pub name: String,
+    pub size: u64,
+    /// Hash of the contents, see `hash_reader`. Without it only sizes are compared.
+    pub hash: Option<String>,
+}
+
+impl From<SnapshotEntry> for RemoteFile {
+    fn from(entry: SnapshotEntry) -> Self {
+        Self {
+            name: entry.name,
+            size: entry.size,
+            hash: entry.hash,
+        }
+    }
+}
+
+/// From `fetch_file_sizes`, which has no hashes.
+impl From<(String, u64)> for RemoteFile {
+    fn from((name, size): (String, u64)) -> Self {
+        Self {
+            name,
+            size,
+            hash: None,
+        }
+    }
+}
+
+/// Compare the files directly inside `dir` with the server's.
+///
+/// Files of the same size are hashed when the server's hash is known, so an edit
+/// that keeps the size is still found. Modification times aren't compared, an
+/// uploaded file takes the time it reached the server.
+pub fn diff_dir(dir: &Path, remote: &[RemoteFile]) -> io::Result<DirDiff> {
+    let mut remote: HashMap<&str, &RemoteFile> = remote
+        .iter()
+        .map(|file| (file.name.as_str(), file))
+        .collect();
+    let mut diff = DirDiff::default();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        match remote.remove(name.as_str()) {
+            Some(file) if file.size != metadata.len() => diff.differing.push(name),
+            Some(RemoteFile {
+                hash: Some(hash), ..
+            }) => {
+                if hash_reader(fs::File::open(entry.path())?, |_| {})? != *hash {
+                    diff.differing.push(name);
+                }
+            }
+            Some(_) => {}
+            None => diff.local_only.push(name),
+        }
+    }
+
+    diff.remote_only = remote.into_keys().map(String::from).collect();
+
+    diff.local_only.sort();
+    diff.remote_only.sort();
+    diff.differing.sort();
+
+    Ok(diff)
+}
+
+/// Request the server's counters as `(name, value)` pairs.
+pub fn fetch_stats<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+) -> ProtocolResult<Vec<(String, usize)>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::STATS)?;
+    read_header(&mut chunk, info)?;
+
+    let count = read_usize(&mut chunk)?;
+
+    let mut stats = Vec::with_capacity(count.min(MAX_PREALLOC));
+    for _ in 0..count {
+        let name = read_string(&mut chunk)?;
+        let value = read_usize(&mut chunk)?;
+        stats.push((name, value));
+    }
+
+    Ok(stats)
+}
+
+/// Replace the tags on a file.
+pub fn set_tags<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    file_name: &str,
+    tags: &[String],
+) -> ProtocolResult<()> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::SET_TAGS)?;
+    write_string(&mut chunk, file_name)?;
+    write_string_list(&mut chunk, tags.iter())?;
+
+    if info.version >= version::V2 {
+        return read_response(&mut chunk);
+    }
+
+    // V1 only says whether the tags were accepted
+    chunk.read_stream(1)?;
+    if chunk.slice(1)[0] != 0 {
+        Ok(())
+    } else {
+        Err(ProtocolError::InvalidRequest(String::new()))
+    }
+}
+
+/// Replace both the tags and the description of a file.
+pub fn set_metadata<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    file_name: &str,
+    tags: &[String],
+    description: &str,
+) -> ProtocolResult<()> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::SET_METADATA)?;
+    write_string(&mut chunk, file_name)?;
+    write_string_list(&mut chunk, tags.iter())?;
+    write_string(&mut chunk, description)?;
+
+    if info.version >= version::V2 {
+        return read_response(&mut chunk);
+    }
+
+    chunk.read_stream(1)?;
+    if chunk.slice(1)[0] != 0 {
+        Ok(())
+    } else {
+        Err(ProtocolError::InvalidRequest(String::new()))
+    }
+}
+
+/// Look for a file on the server whose contents hash to `hash`.
+pub fn find_by_hash<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    hash: &str,
+) -> ProtocolResult<Option<String>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::FIND_BY_HASH)?;
+    write_string(&mut chunk, hash)?;
+
+    if info.version >= version::V2 {
+        match read_response(&mut chunk) {
+            Err(ProtocolError::NotFound(_)) => return Ok(None),
+            result => result?,
+        }
+    }
+
+    // V1 sends an empty name when nothing matches
+    let file_name = read_string(&mut chunk)?;
+    Ok((!file_name.is_empty()).then_some(file_name))
+}
+
+/// Copy a file that is already on the server to a new name, without sending it again.
+pub fn copy_file<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    from: &str,
+    to: &str,
+) -> ProtocolResult<()> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::COPY_FILE)?;
+    write_string(&mut chunk, from)?;
+    write_string(&mut chunk, to)?;
+
+    if info.version >= version::V2 {
+        return read_response(&mut chunk);
+    }
+
+    chunk.read_stream(1)?;
+    if chunk.slice(1)[0] != 0 {
+        Ok(())
+    } else {
+        Err(ProtocolError::Denied(String::new()))
+    }
+}
+
+/// Make a file private to its owner, or public again.
+pub fn set_visibility<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    file_name: &str,
+    private: bool,
+) -> ProtocolResult<()> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::SET_VISIBILITY)?;
+    write_string(&mut chunk, file_name)?;
+    chunk.write_and_send(&[private as u8])?;
+
+    if info.version >= version::V2 {
+        return read_response(&mut chunk);
+    }
+
+    chunk.read_stream(1)?;
+    if chunk.slice(1)[0] != 0 {
+        Ok(())
+    } else {
+        Err(ProtocolError::Denied(String::new()))
+    }
+}
+
+/// Request the files owned by the authenticated client, private or not.
+pub fn fetch_own_files<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+) -> ProtocolResult<Vec<String>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::FETCH_OWN_FILES)?;
+    read_header(&mut chunk, info)?;
+
+    Ok(read_file_list(&mut chunk)?)
+}
+
+/// Delete several files in one request, returning how it went for each, in order.
+pub fn delete_files<S: Transport, I: AsRef<str>>(
+    stream: &S,
+    info: &ConnectionInfo,
+    file_names: &[I],
+) -> ProtocolResult<Vec<Status>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::DELETE_FILES)?;
+    write_string_list(&mut chunk, file_names.iter())?;
+    read_header(&mut chunk, info)?;
+
+    let mut statuses = Vec::with_capacity(file_names.len());
+    while statuses.len() < file_names.len() {
+        let count = (file_names.len() - statuses.len()).min(chunk.len());
+        chunk.read_stream(count)?;
+
+        for &byte in chunk.slice(count) {
+            let status = Status::from_byte(byte).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Unknown status {byte}"))
+            })?;
+            statuses.push(status);
+        }
+    }
+
+    Ok(statuses)
+}
+
+/// Tell the server the client is leaving, then close the connection.
+///
+/// Best effort, the connection may already be gone.
+pub fn disconnect<S: Transport>(stream: &S) {
+    _ = write_op(&mut Chunk::<1, S>::new(stream), op::DISCONNECT);
+    _ = stream.shutdown();
+}
+
+/// Request the tags on a file.
+pub fn get_tags<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    file_name: &str,
+) -> ProtocolResult<Vec<String>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::GET_TAGS)?;
+    write_string(&mut chunk, file_name)?;
+    read_header(&mut chunk, info)?;
+
+    Ok(read_string_list(&mut chunk)?)
+}
+
+/// Request the names of all files carrying `tag`.
+pub fn fetch_files_with_tag<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    tag: &str,
+) -> ProtocolResult<Vec<String>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::FETCH_FILES_WITH_TAG)?;
+    write_string(&mut chunk, tag)?;
+    read_header(&mut chunk, info)?;
+
+    Ok(read_file_list(&mut chunk)?)
+}
+
+/// Tell the server which files this peer is serving from `addr`, with the hash of
+/// each file's contents if it is known. See `hash_reader`.
+///
+/// `addr` has to be an IP address and port on the host the client connects from,
+/// and the client has to be authenticated. Announcements expire, so peers should
+/// repeat this periodically. Hashes are only sent from `version::V9` on.
+pub fn announce<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    addr: &str,
+    files: &[(String, Option<String>)],
+) -> ProtocolResult<()> {
+    if files.len() > MAX_ANNOUNCED_FILES {
+        return Err(ProtocolError::InvalidRequest(format!(
+            "At most {MAX_ANNOUNCED_FILES} files can be announced at once"
+        )));
+    }
+
+    let mut chunk = Chunk::<1024, S>::new(stream);
+
+    write_op(&mut chunk, op::ANNOUNCE)?;
+    write_string(&mut chunk, addr)?;
+    write_file_list(&mut chunk, files.iter().map(|(name, _)| name))?;
+
+    // An empty hash stands for one the peer doesn't know
+    if info.version >= version::V9 {
+        let hashes = files.iter().map(|(_, hash)| hash.as_deref().unwrap_or(""));
+        write_string_list(&mut chunk, hashes)?;
+    }
+
+    read_header(&mut chunk, info)
+}
+
+/// Request every file known to the server and its peers, along with the
+/// addresses each one can be fetched from.
+pub fn fetch_global_list<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+) -> ProtocolResult<Vec<(String, Vec<String>)>> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::GLOBAL_LIST)?;
+    read_header(&mut chunk, info)?;
+
+    let count = read_usize(&mut chunk)?;
+
+    let mut files = Vec::with_capacity(count.min(MAX_PREALLOC));
+    for _ in 0..count {
+        let file = read_string(&mut chunk)?;
+        let sources = read_string_list(&mut chunk)?;
+        files.push((file, sources));
+    }
+
+    Ok(files)
+}
+
+/// `sources` ordered fastest first, going by how long each took to accept a connection.
+///
+/// Sources that don't answer within `timeout` come last, in the order they were given.
+/// They are tried one after the other, so this takes up to `timeout` for each.
+pub fn fastest_sources(sources: &[String], timeout: Duration) -> Vec<String> {
+    let probed = sources
+        .iter()
+        .map(|addr| {
+            let start = Instant::now();
+            let latency = connect(addr, timeout).ok().map(|_| start.elapsed());
+            (addr.clone(), latency)
+        })
+        .collect();
+
+    order_by_latency(probed)
+}
+
+fn order_by_latency(mut probed: Vec<(String, Option<Duration>)>) -> Vec<String> {
+    // Stable, so sources that are just as fast keep their order
+    probed.sort_by_key(|(_, latency)| latency.unwrap_or(Duration::MAX));
+    probed.into_iter().map(|(addr, _)| addr).collect()
+}
+
+pub const DEFAULT_DOWNLOAD_TEMPLATE: &str = "{name}";
+
+const TEMPLATE_PLACEHOLDERS: [&str; 5] = ["name", "stem", "ext", "date", "n"];
+
+/// Check that every `{...}` in a download template is a known placeholder,
+/// and that it names the file somehow.
+pub fn is_valid_template(template: &str) -> bool {
+    if template.contains(['/', '\\']) {
+        return false;
+    }
+
+    let mut rest = template;
+    let mut names_file = false;
+
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            return false;
+        };
+
+        let placeholder = &rest[start + 1..start + len];
+        if !TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+            return false;
+        }
+
+        names_file |= placeholder == "name" || placeholder == "stem";
+        rest = &rest[start + len + 1..];
+    }
+
+    names_file && !rest.contains('}')
+}
+
+/// Seconds since the epoch by this machine's clock.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Today's date as `YYYY-MM-DD` in UTC.
+fn today() -> String {
+    let (year, month, day) = format::civil_date((unix_now() / 86_400) as i64);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn expand_template(template: &str, file_name: &str, date: &str, n: usize) -> String {
+    let path = Path::new(file_name);
+    let stem = path
+        .file_stem()
+        .map_or(file_name.into(), |stem| stem.to_string_lossy());
+    let ext = path
+        .extension()
+        .map(|ext| ext.to_string_lossy())
+        .unwrap_or_default();
+
+    // Don't leave a trailing '.' behind for files without an extension
+    let template = if ext.is_empty() {
+        template.replace(".{ext}", "")
+    } else {
+        template.to_string()
+    };
+
+    template
+        .replace("{name}", file_name)
+        .replace("{stem}", &stem)
+        .replace("{ext}", &ext)
+        .replace("{date}", date)
+        .replace("{n}", &n.to_string())
+}
+
+/// Where to save a download of `file_name` inside `dir`, following `template`.
+///
+/// Invalid templates fall back to `DEFAULT_DOWNLOAD_TEMPLATE`. Templates using
+/// `{n}` count up from 1 until they find a name that is not taken.
+pub fn download_path(template: &str, file_name: &str, dir: &Path) -> PathBuf {
+    let template = if is_valid_template(template) {
+        template
+    } else {
+        DEFAULT_DOWNLOAD_TEMPLATE
+    };
+
+    let date = today();
+
+    if !template.contains("{n}") {
+        return dir.join(expand_template(template, file_name, &date, 0));
+    }
+
+    (1..)
+        .map(|n| dir.join(expand_template(template, file_name, &date, n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Bytes free to unprivileged users on the filesystem holding `path`.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: `path` is NUL terminated and `stat` is only read after statvfs fills it in
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Free space can't be checked here, so nothing is ever refused up front.
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Whether `err` came from the disk running out of space.
+pub fn is_storage_full(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::StorageFull
+}
+
+/// Why the filesystem holding `path` can't take `size` more bytes and keep `headroom`
+/// free, if it can't. Fails only when the free space couldn't be checked.
+pub fn space_shortfall(path: &Path, size: u64, headroom: u64) -> io::Result<Option<String>> {
+    let available = available_space(path)?;
+    let needed = size.saturating_add(headroom);
+
+    Ok((needed > available).then(|| out_of_space(needed, available)))
+}
+
+/// Says how much more room was needed, like "Out of disk space, 38.2 GB needed, 21.4 GB free".
+pub fn out_of_space(needed: u64, available: u64) -> String {
+    format!(
+        "Out of disk space, {} needed, {} free",
+        format::human_bytes(needed),
+        format::human_bytes(available)
+    )
+}
+
+struct PoolInner {
+    free: Vec<Box<[u8]>>,
+    retained: usize,
+    max_retained: usize,
+}
+
+/// Reuses heap buffers so transfers do not allocate a fresh one each time.
+///
+/// At most `max_retained` bytes are kept around, buffers returned beyond that
+/// are freed.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<Mutex<PoolInner>>,
+}
+
+impl BufferPool {
+    pub fn new(max_retained: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(PoolInner {
+                free: Vec::new(),
+                retained: 0,
+                max_retained,
+            })),
+        }
+    }
+
+    /// Take a zeroed buffer of exactly `size` bytes.
+    pub fn acquire(&self, size: usize) -> PooledBuf {
+        let reused = {
+            let mut inner = self.inner.lock().unwrap();
+            let index = inner.free.iter().position(|buf| buf.len() == size);
+
+            index.map(|index| {
+                let buf = inner.free.swap_remove(index);
+                inner.retained -= buf.len();
+                buf
+            })
+        };
+
+        let buf = match reused {
+            Some(mut buf) => {
+                buf.fill(0);
+                buf
+            }
+            None => vec![0u8; size].into_boxed_slice(),
+        };
+
+        PooledBuf {
+            buf: Some(buf),
+            pool: Arc::clone(&self.inner),
+        }
+    }
 
-    pub fn send_last_write(&mut self) -> io::Result<()> {
-        self.stream.write_all(&self.buffer[..self.last_insert])?;
-        self.bytes_sent += self.last_insert;
-        Ok(())
+    /// Bytes currently held by idle buffers.
+    pub fn retained(&self) -> usize {
+        self.inner.lock().unwrap().retained
     }
 }
 
-#[inline]
-pub fn write_usize<const N: usize>(chunk: &mut Chunk<N>, value: usize) -> io::Result<()> {
-    chunk.write_and_send(&value.to_le_bytes())
-}
+/// Size of the buffers `hash_reader` reads through.
+const HASH_BUF_SIZE: usize = 64 * 1024;
+/// Bytes kept by `transfer_pool`, enough for a buffer per server worker.
+const TRANSFER_POOL_RETAINED: usize = 8 * HASH_BUF_SIZE;
 
-pub fn read_usize<const N: usize>(chunk: &mut Chunk<N>) -> usize {
-    chunk
-        .read_stream(8)
-        .expect("Could not read string size bytes");
-    usize::from_le_bytes(chunk.to_byte_array::<8>())
+/// The pool shared by the heap buffers used in transfers.
+pub fn transfer_pool() -> &'static BufferPool {
+    static POOL: OnceLock<BufferPool> = OnceLock::new();
+    POOL.get_or_init(|| BufferPool::new(TRANSFER_POOL_RETAINED))
 }
 
-pub fn write_string<const N: usize>(chunk: &mut Chunk<N>, str: &str) -> io::Result<()> {
-    chunk.write_and_send(&str.as_bytes().len().to_le_bytes())?;
-    chunk.write_and_send(str.as_bytes())
+/// A buffer from a `BufferPool`, which goes back to the pool when dropped.
+pub struct PooledBuf {
+    buf: Option<Box<[u8]>>,
+    pool: Arc<Mutex<PoolInner>>,
 }
 
-pub fn read_string<const N: usize>(chunk: &mut Chunk<N>) -> io::Result<String> {
-    let file_name_count = read_usize(chunk);
+impl Deref for PooledBuf {
+    type Target = [u8];
 
-    if file_name_count == 0 {
-        return Ok(String::new());
+    fn deref(&self) -> &[u8] {
+        self.buf.as_deref().unwrap()
     }
+}
 
-    chunk.read_stream(file_name_count)?;
-    Ok(String::from_utf8_lossy(chunk.slice(file_name_count)).to_string())
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buf.as_deref_mut().unwrap()
+    }
 }
 
-pub fn read_bytes<const N: usize>(chunk: &mut Chunk<N>) -> io::Result<Option<Vec<u8>>> {
-    let byte_count = read_usize(chunk);
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        let buf = self.buf.take().unwrap();
+        let mut pool = self.pool.lock().unwrap();
 
-    if byte_count == 0 {
-        return Ok(None);
+        if pool.retained + buf.len() <= pool.max_retained {
+            pool.retained += buf.len();
+            pool.free.push(buf);
+        }
     }
+}
 
-    chunk.read_stream(byte_count)?;
-    Ok(Some(Vec::from(chunk.slice(byte_count))))
+/// A token bucket that allows at most `rate` events per second.
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
 }
 
-pub fn send_file<const N: usize>(chunk: &mut Chunk<N>, file_name: &str) -> io::Result<()> {
-    if !Path::new(file_name).exists() {
-        write_usize(chunk, 0)?;
-        return Ok(());
+impl RateLimiter {
+    /// Create a new RateLimiter.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the rate is zero.
+    pub fn new(rate: u32) -> Self {
+        assert!(rate > 0);
+
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
     }
 
-    let mut file = fs::File::open(file_name)?;
-    let file_size = file.metadata()?.len() as usize;
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
 
-    // Send file_size to server
-    write_usize(chunk, file_size)?;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
 
-    chunk.reset();
+    /// Take a token if one is available, without blocking.
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
 
-    // Send file data in chunks
-    while chunk.sent() < file_size {
-        let bytes_to_read = std::cmp::min(chunk.len(), file_size - chunk.sent());
-        let bytes_read = file.read(chunk.slice_mut(bytes_to_read))?;
-        chunk.send(bytes_read)?;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
     }
 
-    Ok(())
+    /// Take a token, sleeping until one becomes available.
+    ///
+    /// Returns `true` if the caller had to wait.
+    pub fn acquire(&mut self) -> bool {
+        if self.try_acquire() {
+            return false;
+        }
+
+        let wait = (1.0 - self.tokens) / self.rate;
+        thread::sleep(Duration::from_secs_f64(wait));
+
+        self.refill();
+        self.tokens = (self.tokens - 1.0).max(0.0);
+        true
+    }
+
+    /// Take `count` tokens, sleeping until they have all been earned.
+    ///
+    /// `count` may be more than a second's worth, the wait covers the difference.
+    /// Returns `true` if the caller had to wait.
+    pub fn acquire_many(&mut self, count: usize) -> bool {
+        self.refill();
+        self.tokens -= count as f64;
+
+        if self.tokens >= 0.0 {
+            return false;
+        }
+
+        // Left in debt, the next refill brings it back to about zero
+        thread::sleep(Duration::from_secs_f64(-self.tokens / self.rate));
+        true
+    }
 }
 
-pub fn receive_file<const N: usize>(
-    chunk: &mut Chunk<N>,
-    file_size: usize,
-) -> io::Result<Option<Vec<u8>>> {
-    if file_size == 0 {
-        return Ok(None);
+/// How many times a failed operation is tried, and how long to wait in between.
+///
+/// The wait doubles after every failure, from `base_delay` up to `max_delay`.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Tries in total, counting the first. Zero or one means no retries.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Up to this fraction of each delay is taken off at random, from 0 to 1.
+    ///
+    /// Keeps clients that failed at the same moment from all retrying at the same moment.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.2,
+        }
     }
+}
 
-    let mut buffer = Vec::new();
-    let mut bytes_received = 0;
+impl RetryPolicy {
+    /// Try once and give up.
+    pub const NEVER: Self = Self {
+        max_attempts: 1,
+        base_delay: Duration::ZERO,
+        max_delay: Duration::ZERO,
+        jitter: 0.0,
+    };
 
-    chunk.reset();
+    /// How long to wait after `attempt` failed, counting from 1, or `None` to give up.
+    pub fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt == 0 || attempt >= self.max_attempts {
+            return None;
+        }
 
-    while bytes_received < file_size {
-        let bytes_to_read = std::cmp::min(chunk.len(), file_size - bytes_received);
-        let bytes_read = chunk.read(bytes_to_read)?;
+        let factor = 2u32.saturating_pow(attempt - 1);
+        let delay = self.base_delay.saturating_mul(factor).min(self.max_delay);
 
-        buffer.extend(chunk.slice(bytes_to_read));
-        bytes_received += bytes_read;
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return Some(delay);
+        }
+        Some(delay.mul_f64(1.0 - jitter * random_fraction()))
     }
 
-    Ok(Some(buffer))
+    /// Run `op` until it succeeds, fails with an error `retryable` turns down, or the
+    /// attempts run out, sleeping between tries.
+    pub fn run<T>(
+        &self,
+        mut op: impl FnMut() -> io::Result<T>,
+        retryable: impl Fn(&io::Error) -> bool,
+    ) -> io::Result<T> {
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            match op() {
+                Err(err) if retryable(&err) => match self.next_delay(attempt) {
+                    Some(delay) => thread::sleep(delay),
+                    None => return Err(err),
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A number in `0.0..1.0`, different on every call but nowhere near cryptographic.
+fn random_fraction() -> f64 {
+    // Every `RandomState` is seeded differently, so this is as good as a small RNG
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
 }
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
+    workers: Arc<Mutex<Vec<Worker>>>,
     sender: Option<mpsc::Sender<Job>>,
+    /// Set when the pool is dropped, workers won't start another job once it is.
+    shutting_down: Arc<AtomicBool>,
+    /// Dropping the sender stops the supervisor, see `supervise`.
+    supervisor: Option<(mpsc::Sender<()>, thread::JoinHandle<()>)>,
+    log: fn(String),
+}
+
+/// How a `ThreadPool` looks after its workers.
+#[derive(Clone, Copy)]
+pub struct PoolOptions {
+    /// Where the pool reports workers shutting down and being replaced.
+    pub log: fn(String),
+    /// Replace a worker once its heartbeat shows one job running this long.
+    ///
+    /// The job isn't stopped, its worker leaves the pool when it ends. Off by default,
+    /// since a job such as a client connection can rightly run for hours.
+    pub stuck_after: Option<Duration>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            log: |line| println!("{line}"),
+            stuck_after: None,
+        }
+    }
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// How often the pool checks for workers that have died or are stuck.
+const SUPERVISOR_INTERVAL: Duration = Duration::from_secs(1);
+
 pub enum PoolCreationError {
     NotEnoughThreads,
 }
@@ -217,20 +3247,56 @@ impl ThreadPool {
     ///
     /// The `new` function will panic if the size is zero.
     pub fn new(size: usize) -> Self {
+        Self::with_options(size, PoolOptions::default())
+    }
+
+    /// Like `new`, looking after the workers as `options` says.
+    ///
+    /// # Panics
+    ///
+    /// If the size is zero.
+    pub fn with_options(size: usize, options: PoolOptions) -> Self {
         assert!(size > 0);
 
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let epoch = Instant::now();
 
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(
+                id,
+                Arc::clone(&receiver),
+                Arc::clone(&shutting_down),
+                epoch,
+            ));
         }
+        let workers = Arc::new(Mutex::new(workers));
+
+        let (stop, stopped) = mpsc::channel();
+        let supervisor = {
+            let workers = Arc::clone(&workers);
+            let shutting_down = Arc::clone(&shutting_down);
+            thread::spawn(move || {
+                supervise(
+                    &workers,
+                    &receiver,
+                    &shutting_down,
+                    epoch,
+                    options,
+                    &stopped,
+                )
+            })
+        };
 
         Self {
             workers,
             sender: Some(sender),
+            shutting_down,
+            supervisor: Some((stop, supervisor)),
+            log: options.log,
         }
     }
 
@@ -252,14 +3318,80 @@ impl ThreadPool {
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        // Jobs still queued are dropped, only the ones already running are waited for
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        // Stopped first, so it doesn't mistake workers leaving for workers dying
+        if let Some((stop, supervisor)) = self.supervisor.take() {
+            drop(stop);
+            _ = supervisor.join();
+        }
 
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+        let mut workers = self.workers.lock().unwrap();
+        (self.log)(format!("Shutting down {} workers", workers.len()));
+        drop(self.sender.take());
 
+        for worker in workers.iter_mut() {
+            // One that panicked has already reported it
             if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+                _ = thread.join();
+            }
+
+            (self.log)(format!("Worker {} shut down", worker.id));
+        }
+    }
+}
+
+/// Replace any worker whose thread has ended, or whose heartbeat shows it stuck in a job,
+/// keeping the pool at its size until `stop` is dropped.
+///
+/// Workers only end early if a job panicked, or the thread was otherwise torn down.
+fn supervise(
+    workers: &Mutex<Vec<Worker>>,
+    receiver: &Arc<Mutex<mpsc::Receiver<Job>>>,
+    shutting_down: &Arc<AtomicBool>,
+    epoch: Instant,
+    options: PoolOptions,
+    stop: &mpsc::Receiver<()>,
+) {
+    while let Err(mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(SUPERVISOR_INTERVAL) {
+        for worker in workers.lock().unwrap().iter_mut() {
+            if shutting_down.load(Ordering::SeqCst) {
+                break;
             }
+
+            let dead = worker
+                .thread
+                .as_ref()
+                .is_some_and(|thread| thread.is_finished());
+            let busy = worker.heartbeat.busy_for(epoch);
+
+            if dead {
+                _ = worker.thread.take().map(thread::JoinHandle::join);
+                (options.log)(format!("Worker {} died, starting a new one", worker.id));
+            } else if let Some(busy) = busy.filter(|busy| {
+                options
+                    .stuck_after
+                    .is_some_and(|stuck_after| *busy >= stuck_after)
+            }) {
+                // Left to finish its job, nothing waits for it after that
+                worker.heartbeat.retired.store(true, Ordering::SeqCst);
+                drop(worker.thread.take());
+                (options.log)(format!(
+                    "Worker {} stuck in a job for {}, starting a new one",
+                    worker.id,
+                    format::human_duration(busy)
+                ));
+            } else {
+                continue;
+            }
+
+            *worker = Worker::new(
+                worker.id,
+                Arc::clone(receiver),
+                Arc::clone(shutting_down),
+                epoch,
+            );
         }
     }
 }
@@ -267,29 +3399,707 @@ impl Drop for ThreadPool {
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
+    heartbeat: Arc<Heartbeat>,
+}
+
+/// What a worker is up to, written by the worker and read by `supervise`.
+#[derive(Default)]
+struct Heartbeat {
+    /// When the running job started, in milliseconds after the pool's epoch, plus one.
+    /// Zero between jobs.
+    busy_since: AtomicU64,
+    /// Set when the worker has been replaced, it leaves once its job ends.
+    retired: AtomicBool,
+}
+
+impl Heartbeat {
+    fn beat(&self, epoch: Instant, busy: bool) {
+        let since = if busy {
+            epoch.elapsed().as_millis() as u64 + 1
+        } else {
+            0
+        };
+        self.busy_since.store(since, Ordering::SeqCst);
+    }
+
+    /// How long the running job has been going, if there is one.
+    fn busy_for(&self, epoch: Instant) -> Option<Duration> {
+        match self.busy_since.load(Ordering::SeqCst) {
+            0 => None,
+            since => Some(
+                epoch
+                    .elapsed()
+                    .saturating_sub(Duration::from_millis(since - 1)),
+            ),
+        }
+    }
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Self {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-
-            match message {
-                Ok(job) => {
-                    println!("Worker {id} got a job; executing.");
-                    job();
-                }
+    fn new(
+        id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+        shutting_down: Arc<AtomicBool>,
+        epoch: Instant,
+    ) -> Self {
+        let heartbeat = Arc::new(Heartbeat::default());
+
+        // The pool reports the shutdown once this thread has ended
+        let thread = {
+            let heartbeat = Arc::clone(&heartbeat);
+            thread::spawn(move || loop {
+                let message = receiver.lock().unwrap().recv();
 
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down.");
-                    break;
+                match message {
+                    Ok(_) if shutting_down.load(Ordering::SeqCst) => break,
+                    Ok(job) => {
+                        heartbeat.beat(epoch, true);
+                        job();
+                        heartbeat.beat(epoch, false);
+
+                        if heartbeat.retired.load(Ordering::SeqCst) {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
                 }
-            }
-        });
+            })
+        };
 
         Self {
             id,
             thread: Some(thread),
+            heartbeat,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque, net::TcpListener, sync::atomic::AtomicUsize};
+
+    use super::*;
+    use crate::pipe::{DuplexPipe, PipeOptions};
+
+    fn addrs(list: &[&str]) -> Vec<String> {
+        list.iter().map(|addr| addr.to_string()).collect()
+    }
+
+    #[test]
+    fn buffer_pool_never_retains_more_than_its_cap() {
+        const CAP: usize = 10 * 1024;
+        let pool = BufferPool::new(CAP);
+
+        let workers: Vec<_> = (0..16)
+            .map(|worker| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    for i in 0..500 {
+                        // Sizes that don't add up to the cap, a few held at once
+                        let size = 1000 + (worker * 7 + i) % 5 * 333;
+                        let mut held = [pool.acquire(size), pool.acquire(size / 2 + 1)];
+
+                        for buf in &mut held {
+                            assert!(buf.iter().all(|&byte| byte == 0), "reused dirty");
+                            buf.fill(0xff);
+                        }
+                        assert!(pool.retained() <= CAP);
+                        drop(held);
+                        assert!(pool.retained() <= CAP);
+                    }
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert!(pool.retained() <= CAP);
+        assert!(pool.retained() > 0, "nothing was kept for reuse");
+    }
+
+    #[test]
+    fn buffer_pool_reuses_released_buffers() {
+        let pool = BufferPool::new(4096);
+
+        let first = pool.acquire(1024);
+        let addr = first.as_ptr();
+        drop(first);
+        assert_eq!(pool.retained(), 1024);
+
+        assert_eq!(pool.acquire(1024).as_ptr(), addr);
+        // Buffers past the cap are freed rather than kept
+        drop(pool.acquire(8192));
+        assert_eq!(pool.retained(), 1024);
+    }
+
+    fn remote(name: &str, contents: &[u8], hashed: bool) -> RemoteFile {
+        RemoteFile {
+            name: name.to_string(),
+            size: contents.len() as u64,
+            hash: hashed.then(|| hash_reader(contents, |_| {}).unwrap()),
+        }
+    }
+
+    #[test]
+    fn diff_dir_groups_local_remote_and_differing_files() {
+        let dir = std::env::temp_dir().join(format!("p2p-diff-{}", std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("folder")).unwrap();
+
+        for (name, contents) in [
+            ("same", "unchanged"),
+            ("edited", "new words"),
+            ("resized", "longer than before"),
+            ("unhashed", "new words"),
+            ("local", "only here"),
+        ] {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let remote = [
+            remote("same", b"unchanged", true),
+            remote("edited", b"old words", true),
+            remote("resized", b"shorter", false),
+            // Same size and no hash to tell them apart
+            remote("unhashed", b"old words", false),
+            remote("remote", b"only there", true),
+            remote("folder", b"a file on the server", true),
+        ];
+        let diff = diff_dir(&dir, &remote);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let diff = diff.unwrap();
+        assert_eq!(diff.local_only, ["local"]);
+        assert_eq!(diff.remote_only, ["folder", "remote"]);
+        assert_eq!(diff.differing, ["edited", "resized"]);
+    }
+
+    #[test]
+    fn sources_are_ordered_fastest_first() {
+        let ms = Duration::from_millis;
+        let probed = vec![
+            ("slow".to_string(), Some(ms(30))),
+            ("down".to_string(), None),
+            ("fast".to_string(), Some(ms(2))),
+            ("also down".to_string(), None),
+            ("as fast".to_string(), Some(ms(2))),
+        ];
+
+        assert_eq!(
+            order_by_latency(probed),
+            addrs(&["fast", "as fast", "slow", "down", "also down"])
+        );
+    }
+
+    #[test]
+    fn unreachable_sources_come_last() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap().to_string();
+
+        // Bound then dropped, so nothing listens there
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+
+        let sources = addrs(&[&dead, &live]);
+        assert_eq!(
+            fastest_sources(&sources, Duration::from_secs(1)),
+            addrs(&[&live, &dead])
+        );
+    }
+
+    /// A pool of two whose workers are both held in a job, with more jobs queued behind.
+    #[test]
+    fn no_job_starts_once_the_pool_shuts_down() {
+        let pool = ThreadPool::with_options(
+            2,
+            PoolOptions {
+                log: |_| {},
+                ..PoolOptions::default()
+            },
+        );
+        let (release, held) = mpsc::channel::<()>();
+        let held = Arc::new(Mutex::new(held));
+        let (started, holding) = mpsc::channel();
+
+        for _ in 0..2 {
+            let (held, started) = (Arc::clone(&held), started.clone());
+            pool.execute(move || {
+                started.send(()).unwrap();
+                _ = held.lock().unwrap().recv();
+            });
+        }
+        for _ in 0..2 {
+            holding.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..5 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let workers = Arc::clone(&pool.workers);
+        let shutting_down = Arc::clone(&pool.shutting_down);
+        let dropping = thread::spawn(move || drop(pool));
+        while !shutting_down.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Both workers are free to take the queued jobs now, and mustn't
+        drop(release);
+        dropping.join().unwrap();
+
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+        assert!(workers
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|worker| worker.thread.is_none()));
+    }
+
+    /// Workers whose threads are still going, and how many the pool has.
+    fn running_workers(pool: &ThreadPool) -> (usize, usize) {
+        let workers = pool.workers.lock().unwrap();
+        let running = workers
+            .iter()
+            .filter(|worker| {
+                worker
+                    .thread
+                    .as_ref()
+                    .is_some_and(|thread| !thread.is_finished())
+            })
+            .count();
+        (running, workers.len())
+    }
+
+    /// Wait until `size` jobs run at once, which only a pool at full size can do.
+    fn runs_at_once(pool: &ThreadPool, size: usize) {
+        let (started, waiting) = mpsc::channel();
+        let (release, held) = mpsc::channel::<()>();
+        let held = Arc::new(Mutex::new(held));
+        for _ in 0..size {
+            let (started, held) = (started.clone(), Arc::clone(&held));
+            pool.execute(move || {
+                started.send(()).unwrap();
+                // Keeps the worker busy until released, without holding the lock
+                while let Err(mpsc::TryRecvError::Empty) = held.lock().unwrap().try_recv() {
+                    thread::sleep(Duration::from_millis(5));
+                }
+            });
+        }
+        for _ in 0..size {
+            waiting.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        drop(release);
+    }
+
+    #[test]
+    fn workers_that_die_are_replaced() {
+        let pool = ThreadPool::with_options(
+            3,
+            PoolOptions {
+                log: |_| {},
+                ..PoolOptions::default()
+            },
+        );
+
+        // Ends the worker's loop, as nothing in the pool catches it
+        pool.execute(|| panic!("worker killed on purpose"));
+        wait_until(|| running_workers(&pool).0 < 3);
+
+        wait_until(|| running_workers(&pool) == (3, 3));
+        runs_at_once(&pool, 3);
+    }
+
+    #[test]
+    fn workers_stuck_in_a_job_are_replaced() {
+        let pool = ThreadPool::with_options(
+            2,
+            PoolOptions {
+                log: |_| {},
+                stuck_after: Some(Duration::from_millis(100)),
+            },
+        );
+
+        let (unstick, stuck) = mpsc::channel::<()>();
+        let (started, starting) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            _ = stuck.recv();
+        });
+        starting.recv_timeout(Duration::from_secs(5)).unwrap();
+        let heartbeats: Vec<_> = pool
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|worker| Arc::clone(&worker.heartbeat))
+            .collect();
+
+        // The stuck worker is swapped out while its job still runs
+        wait_until(|| {
+            heartbeats
+                .iter()
+                .any(|beat| beat.retired.load(Ordering::SeqCst))
+        });
+        assert_eq!(running_workers(&pool), (2, 2));
+        runs_at_once(&pool, 2);
+
+        drop(unstick);
+    }
+
+    fn wait_until(done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Keeps each write apart and plays `input` back to reads.
+    #[derive(Default)]
+    struct Wire {
+        writes: RefCell<Vec<Vec<u8>>>,
+        input: RefCell<VecDeque<u8>>,
+    }
+
+    impl Transport for Wire {
+        fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut input = self.input.borrow_mut();
+            let n = buf.len().min(input.len());
+            for (byte, played) in buf.iter_mut().zip(input.drain(..n)) {
+                *byte = played;
+            }
+            Ok(n)
+        }
+
+        fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+            self.writes.borrow_mut().push(buf.to_vec());
+            Ok(())
+        }
+
+        fn peer(&self) -> String {
+            "wire".to_string()
+        }
+    }
+
+    #[test]
+    fn appends_fill_the_buffer_exactly_and_no_further() {
+        let wire = Wire::default();
+        let mut chunk = Chunk::<8, Wire>::new(&wire);
+
+        assert_eq!(chunk.append_to_buf(b"12345"), Ok(5));
+        assert_eq!(chunk.append_to_buf(b"678"), Ok(3));
+        assert_eq!(chunk.pending(), 8);
+        assert_eq!(
+            chunk.append_to_buf(b"9"),
+            Err(BufferFull { needed: 1, free: 0 })
+        );
+
+        // The refused byte wasn't copied, and everything else goes out in one write
+        chunk.flush_pending().unwrap();
+        assert_eq!(chunk.pending(), 0);
+        assert_eq!(*wire.writes.borrow(), [b"12345678".to_vec()]);
+        assert_eq!(chunk.append_to_buf(b"123456789").unwrap_err().free, 8);
+    }
+
+    #[test]
+    fn strings_fill_the_buffer_exactly_and_no_further() {
+        let wire = Wire::default();
+        let mut chunk = Chunk::<8, Wire>::new(&wire);
+
+        write_string(&mut chunk, "abcdefgh").unwrap();
+        assert_eq!(
+            *wire.writes.borrow(),
+            [8usize.to_le_bytes().to_vec(), b"abcdefgh".to_vec()]
+        );
+
+        let err = write_string(&mut chunk, "abcdefghi").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(wire.writes.borrow().len(), 2, "sent part of a string");
+    }
+
+    #[test]
+    fn received_lengths_fill_the_buffer_exactly_and_no_further() {
+        let wire = Wire::default();
+        let mut chunk = Chunk::<8, Wire>::new(&wire);
+
+        wire.input.borrow_mut().extend(8usize.to_le_bytes());
+        wire.input.borrow_mut().extend(b"abcdefgh");
+        assert_eq!(read_string(&mut chunk).unwrap(), "abcdefgh");
+
+        wire.input.borrow_mut().extend(9usize.to_le_bytes());
+        wire.input.borrow_mut().extend(b"abcdefghi");
+        let err = read_string(&mut chunk).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reset_zeroing_clears_what_was_read() {
+        let wire = Wire::default();
+        let mut chunk = Chunk::<8, Wire>::new(&wire);
+        wire.input.borrow_mut().extend(b"secret!!");
+        chunk.read_stream(8).unwrap();
+
+        // A plain reset leaves the bytes to be read back
+        chunk.reset();
+        assert_eq!(chunk.slice(8), b"secret!!");
+
+        chunk.reset_zeroing();
+        assert_eq!(chunk.slice(8), [0; 8]);
+        assert_eq!(chunk.to_byte_array::<8>(), [0; 8]);
+    }
+
+    #[test]
+    fn file_lists_round_trip() {
+        let (a, b) = DuplexPipe::pair_with(PipeOptions {
+            max_read: Some(5),
+            ..PipeOptions::default()
+        });
+        let mut writer = Chunk::<1024, DuplexPipe>::new(&a);
+        let mut reader = Chunk::<1024, DuplexPipe>::new(&b);
+
+        let names = ["a.txt", "", "folder/b.bin", "ünïcode ✓"];
+        write_file_list(&mut writer, names.iter()).unwrap();
+        write_file_list(&mut writer, Vec::<String>::new().into_iter()).unwrap();
+        write_string(&mut writer, "after").unwrap();
+
+        assert_eq!(read_file_list(&mut reader).unwrap(), names);
+        assert!(read_file_list(&mut reader).unwrap().is_empty());
+        // Nothing was left unread to throw off what follows
+        assert_eq!(read_string(&mut reader).unwrap(), "after");
+    }
+
+    #[test]
+    fn connecting_to_a_dead_address_gives_up_in_time() {
+        // Reserved for documentation, nothing ever answers there
+        let timeout = Duration::from_millis(300);
+        let started = Instant::now();
+
+        let err = connect("192.0.2.1:8000", timeout).unwrap_err();
+        assert!(
+            started.elapsed() < timeout + Duration::from_secs(1),
+            "{err}"
+        );
+        // Without a route to it the attempt fails straight away instead
+        if err.kind() == io::ErrorKind::TimedOut {
+            assert!(started.elapsed() >= timeout);
+        }
+    }
+
+    #[test]
+    fn connection_errors_say_what_went_wrong() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let refused = connect(&format!("127.0.0.1:{port}"), Duration::from_secs(5)).unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+
+        let unresolved = connect("no port", Duration::from_secs(5)).unwrap_err();
+        assert_eq!(unresolved.kind(), io::ErrorKind::NotFound);
+        assert!(unresolved.to_string().contains("resolve"), "{unresolved}");
+    }
+
+    #[test]
+    fn framed_items_are_read_as_they_are_iterated() {
+        let (a, b) = DuplexPipe::pair();
+        let mut writer = Chunk::<1024, DuplexPipe>::new(&a);
+        let mut reader = Chunk::<8, DuplexPipe>::new(&b);
+
+        write_string(&mut writer, "first").unwrap();
+        let mut items = read_framed_items(&mut reader, 4);
+
+        // Only the first item has been sent, reading any more would block
+        assert_eq!(items.next().unwrap().unwrap(), b"first");
+
+        // Longer than the reader's buffer, so the framing is broken from here on
+        write_string(&mut writer, "much too long").unwrap();
+        write_string(&mut writer, "third").unwrap();
+        let err = items.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(items.next().is_none());
+    }
+
+    #[test]
+    fn every_compression_level_round_trips() {
+        let names: Vec<String> = (0..2000).map(|i| format!("file-{i:04}.txt")).collect();
+
+        let sizes: Vec<u64> = (0..=9)
+            .map(|level| {
+                let (a, b) = DuplexPipe::pair();
+                let mut writer = Chunk::<1024, DuplexPipe>::new(&a);
+                let mut reader = Chunk::<1024, DuplexPipe>::new(&b);
+
+                write_compressed(&mut writer, &names, Compression::new(level)).unwrap();
+                let read: Vec<String> = read_compressed(&mut reader).unwrap();
+                assert_eq!(read, names, "level {level}");
+                reader.received()
+            })
+            .collect();
+
+        // Not every step up is smaller, but working harder never costs bytes overall
+        assert!(sizes[9] <= sizes[1], "{sizes:?}");
+        assert!(sizes[1] < sizes[0], "{sizes:?}");
+    }
+
+    fn policy(jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 6,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            jitter,
+        }
+    }
+
+    #[test]
+    fn retry_delays_double_up_to_the_cap() {
+        let delays: Vec<_> = (0..8)
+            .map(|attempt| policy(0.0).next_delay(attempt))
+            .collect();
+        let ms = |ms| Some(Duration::from_millis(ms));
+
+        assert_eq!(
+            delays,
+            [
+                None,
+                ms(100),
+                ms(200),
+                ms(400),
+                ms(500),
+                ms(500),
+                None,
+                None
+            ]
+        );
+        assert_eq!(RetryPolicy::NEVER.next_delay(1), None);
+    }
+
+    #[test]
+    fn retry_jitter_only_shortens_delays() {
+        for attempt in 1..6 {
+            let full = policy(0.0).next_delay(attempt).unwrap();
+            for _ in 0..100 {
+                let delay = policy(0.25).next_delay(attempt).unwrap();
+                assert!(delay <= full, "{delay:?} over {full:?}");
+                assert!(delay >= full.mul_f64(0.75), "{delay:?} under {full:?}");
+            }
+        }
+
+        // Out of range jitter is clamped rather than making delays negative
+        for _ in 0..100 {
+            assert!(policy(5.0).next_delay(1).is_some());
+        }
+    }
+
+    #[test]
+    fn retries_stop_at_the_first_unretryable_error() {
+        let policy = RetryPolicy {
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            ..policy(0.0)
+        };
+
+        let mut tries = 0;
+        let result: io::Result<()> = policy.run(
+            || {
+                tries += 1;
+                Err(io::ErrorKind::TimedOut.into())
+            },
+            |_| true,
+        );
+        assert!(result.is_err());
+        assert_eq!(tries, 6);
+
+        tries = 0;
+        let result: io::Result<()> = policy.run(
+            || {
+                tries += 1;
+                Err(io::ErrorKind::PermissionDenied.into())
+            },
+            |err| err.kind() == io::ErrorKind::TimedOut,
+        );
+        assert!(result.is_err());
+        assert_eq!(tries, 1);
+    }
+
+    #[test]
+    fn templates_are_checked_for_known_placeholders() {
+        for valid in [
+            "{name}",
+            "{stem}_{date}.{ext}",
+            "{stem}_{n}.{ext}",
+            "copy of {name}",
+        ] {
+            assert!(is_valid_template(valid), "{valid}");
+        }
+        for invalid in [
+            "",
+            "{ext}",
+            "{date}_{n}",
+            "{nmae}",
+            "{name",
+            "name}",
+            "{name}}",
+            "dir/{name}",
+            "dir\\{name}",
+        ] {
+            assert!(!is_valid_template(invalid), "{invalid}");
         }
     }
+
+    #[test]
+    fn each_placeholder_is_expanded() {
+        let expand = |template| expand_template(template, "report.tar.gz", "2024-02-29", 3);
+
+        assert_eq!(expand("{name}"), "report.tar.gz");
+        assert_eq!(expand("{stem}"), "report.tar");
+        assert_eq!(expand("{ext}"), "gz");
+        assert_eq!(expand("{date}"), "2024-02-29");
+        assert_eq!(expand("{n}"), "3");
+        assert_eq!(expand("{stem}_{date}.{ext}"), "report.tar_2024-02-29.gz");
+    }
+
+    #[test]
+    fn files_without_an_extension_lose_the_dot() {
+        assert_eq!(
+            expand_template("{stem}_{n}.{ext}", "README", "2024-02-29", 1),
+            "README_1"
+        );
+    }
+
+    #[test]
+    fn counter_skips_names_already_taken() {
+        let dir = std::env::temp_dir().join(format!("p2p-template-{}", std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let template = "{stem}_{n}.{ext}";
+        assert_eq!(download_path(template, "a.txt", &dir), dir.join("a_1.txt"));
+
+        fs::write(dir.join("a_1.txt"), "").unwrap();
+        fs::write(dir.join("a_2.txt"), "").unwrap();
+        assert_eq!(download_path(template, "a.txt", &dir), dir.join("a_3.txt"));
+
+        // Without {n} a taken name is used as it is
+        assert_eq!(
+            download_path("{stem}_1.{ext}", "a.txt", &dir),
+            dir.join("a_1.txt")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_templates_fall_back_to_the_name() {
+        let dir = Path::new("downloads");
+        assert_eq!(download_path("{size}", "a.txt", dir), dir.join("a.txt"));
+        assert_eq!(download_path("../{name}", "a.txt", dir), dir.join("a.txt"));
+    }
 }
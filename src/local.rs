@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::SystemTime,
+};
+
+/// How a server file compares with the file of the same name in the downloads folder.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LocalStatus {
+    Missing,
+    /// Same size as the server's copy.
+    Same,
+    Different,
+    /// There is a local file, but the server's size isn't known to compare with.
+    Present,
+}
+
+impl LocalStatus {
+    /// Shown in front of the file in the listing.
+    pub fn marker(self) -> &'static str {
+        match self {
+            LocalStatus::Missing => "   ",
+            LocalStatus::Same => "[=]",
+            LocalStatus::Different => "[!]",
+            LocalStatus::Present => "[?]",
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            LocalStatus::Missing => "Not downloaded",
+            LocalStatus::Same => "Downloaded, same size as on the server",
+            LocalStatus::Different => "Downloaded, differs from the server's copy",
+            LocalStatus::Present => "Downloaded, open details to compare",
+        }
+    }
+}
+
+/// The files in the downloads folder, scanned in the background.
+#[derive(Default)]
+pub struct LocalFiles {
+    /// Size of each file by name, from the last scan that finished.
+    sizes: HashMap<String, u64>,
+    /// The folder and its modified time at the last scan, it is scanned again once either changes.
+    scanned: Option<(PathBuf, Option<SystemTime>)>,
+    scanning: Option<Receiver<io::Result<HashMap<String, u64>>>>,
+}
+
+impl LocalFiles {
+    /// Start a scan of `dir` if it changed since the last one, and pick up a finished scan.
+    ///
+    /// Only the folder's modified time is checked, files replaced in place need
+    /// `invalidate`.
+    pub fn refresh(&mut self, dir: &Path) {
+        if let Some(scanning) = &self.scanning {
+            match scanning.try_recv() {
+                Ok(Ok(sizes)) => self.sizes = sizes,
+                // The folder may not exist until the first download
+                Ok(Err(_)) => self.sizes.clear(),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {}
+            }
+            self.scanning = None;
+        }
+
+        let modified = fs::metadata(dir).and_then(|meta| meta.modified()).ok();
+        let current = (dir.to_path_buf(), modified);
+        if self.scanned.as_ref() == Some(&current) {
+            return;
+        }
+        self.scanned = Some(current);
+
+        let (sender, receiver) = mpsc::channel();
+        let dir = dir.to_path_buf();
+        thread::spawn(move || _ = sender.send(scan(&dir)));
+        self.scanning = Some(receiver);
+    }
+
+    /// Scan again on the next `refresh`, after writing to the folder ourselves.
+    pub fn invalidate(&mut self) {
+        self.scanned = None;
+    }
+
+    pub fn status(&self, name: &str, server_size: Option<u64>) -> LocalStatus {
+        match (self.sizes.get(name), server_size) {
+            (None, _) => LocalStatus::Missing,
+            (Some(_), None) => LocalStatus::Present,
+            (Some(local), Some(server)) if *local == server => LocalStatus::Same,
+            (Some(_), Some(_)) => LocalStatus::Different,
+        }
+    }
+}
+
+fn scan(dir: &Path) -> io::Result<HashMap<String, u64>> {
+    let mut sizes = HashMap::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+
+        if let (true, Ok(name)) = (meta.is_file(), entry.file_name().into_string()) {
+            sizes.insert(name, meta.len());
+        }
+    }
+
+    Ok(sizes)
+}
+
+/// A free path next to `path` for keeping both files, such as "notes (1).txt".
+pub fn copy_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    (1..)
+        .map(|n| path.with_file_name(format!("{stem} ({n}){extension}")))
+        .find(|path| !path.exists())
+        .unwrap()
+}
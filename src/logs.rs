@@ -0,0 +1,94 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Mutex,
+    },
+};
+
+use p2p_service::{events::ServerEvent, op};
+
+use crate::is_control_op;
+
+/// Lines a follower can fall behind by before newer ones are dropped for it.
+const FOLLOWER_BACKLOG: usize = 256;
+
+/// Clients following the log, see `op::FOLLOW_LOG`.
+static FOLLOWERS: Mutex<Vec<SyncSender<String>>> = Mutex::new(Vec::new());
+
+/// Print a line like `println!`, and send it to every client following the log.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::logs::publish(false, format!($($arg)*))
+    };
+}
+
+/// Like `log!`, printed to stderr.
+macro_rules! log_err {
+    ($($arg:tt)*) => {
+        $crate::logs::publish(true, format!($($arg)*))
+    };
+}
+
+pub(crate) use {log, log_err};
+
+pub fn publish(to_stderr: bool, line: String) {
+    if to_stderr {
+        eprintln!("{line}");
+    } else {
+        println!("{line}");
+    }
+
+    // A slow follower misses lines rather than holding up the server
+    FOLLOWERS
+        .lock()
+        .unwrap()
+        .retain(|follower| match follower.try_send(line.clone()) {
+            Ok(()) | Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+}
+
+/// Receive every line logged from now on, until the receiver is dropped.
+pub fn follow() -> Receiver<String> {
+    let (sender, receiver) = mpsc::sync_channel(FOLLOWER_BACKLOG);
+    FOLLOWERS.lock().unwrap().push(sender);
+    receiver
+}
+
+/// Log who transferred what, one line per event.
+pub fn access_log(event: &ServerEvent) {
+    let peer = |peer: &Option<SocketAddr>| match peer {
+        Some(peer) => peer.to_string(),
+        None => "unknown peer".to_string(),
+    };
+
+    match event {
+        ServerEvent::UploadCompleted(entry, from) => log!(
+            "{} uploaded \"{}\" ({} bytes)",
+            peer(from),
+            entry.name,
+            entry.size
+        ),
+        ServerEvent::DownloadCompleted {
+            name,
+            peer: to,
+            bytes,
+        } => log!("{} downloaded \"{name}\" ({bytes} bytes)", peer(to)),
+        ServerEvent::FileDeleted(name) => log!("Deleted \"{name}\""),
+        ServerEvent::ClientConnected(addr) => log!("{addr} connected"),
+        ServerEvent::ClientDisconnected(addr) => log!("{addr} disconnected"),
+        // Control ops are sent all the time, they would drown out everything else
+        ServerEvent::RequestHandled { op, .. } if is_control_op(*op) => {}
+        ServerEvent::RequestHandled {
+            op,
+            peer: from,
+            elapsed,
+        } => log!(
+            "{} {} took {:.1}ms",
+            peer(from),
+            op::name(*op).unwrap_or("unknown op"),
+            elapsed.as_secs_f64() * 1000.0
+        ),
+    }
+}
@@ -1,121 +1,3535 @@
 use std::{
-    collections::HashSet,
-    fs, io,
-    net::{TcpListener, TcpStream},
+    collections::HashMap,
+    env, fs,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    num::{NonZeroU32, NonZeroU64},
+    ops::ControlFlow,
     path::Path,
-    sync::{Arc, Mutex},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+#[cfg(unix)]
+use std::{
+    os::unix::{fs::FileTypeExt, net::UnixListener},
+    path::PathBuf,
+};
+
+use check::CheckOptions;
+use cidr::Cidr;
+use connections::Kickable;
+use durable::DirSyncer;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use index::{FileIndex, IndexSync, Storage, PARTIAL_PREFIX};
+use logs::{log, log_err};
+use metrics::{ConnectionGuard, Rejection};
+use mirror::{ConflictPolicy, Mirror, MirrorConfig};
+use multipart::{Uploads, DEFAULT_MULTIPART_TIMEOUT};
 use p2p_service::{
-    read_string, read_usize, receive_file, send_file, write_string, write_usize, Chunk,
-    SharedFiles, ThreadPool, SERVER_ADDR,
+    capability, discard, enable_wire_trace,
+    events::{self, ServerEvent},
+    feature,
+    format::{human_bytes, human_duration},
+    hash_reader, op, read_bytes, read_file_list, read_private, read_string, read_string_list,
+    read_usize, receive_file, receive_file_with_progress, receive_stream,
+    seal::{self, SealKey, Sealed},
+    send_reader, unix_now, version, write_capabilities, write_compressed, write_file_entry,
+    write_file_list, write_response, write_string, write_string_list, write_usize, Authenticator,
+    Capabilities, Chunk, ConnectionInfo, FileEntry, PoolOptions, RateLimiter, SharedSecretAuth,
+    SnapshotEntry, SortKey, Status, ThreadPool, Transport, CONTROL_DEADLINE, HANDSHAKE_DEADLINE,
+    MAX_ANNOUNCED_FILES, MAX_BATCH_LEN, MAX_HEAD_LEN, MAX_PARTS, MAX_PART_LEN, MAX_SPEEDTEST_BYTES,
+    MAX_TREE_DEPTH, MAX_TREE_NODES, SERVER_ADDR, SPEEDTEST_BYTE,
 };
+use peers::PeerRegistry;
+use progress::Progress;
+use quarantine::Received;
+use serde::Deserialize;
+use temp::{DEFAULT_TEMP_MAX_AGE, TEMP_DIR};
+use timing::OpTimings;
+
+mod check;
+mod cidr;
+mod connections;
+mod disk;
+mod durable;
+mod expiry;
+mod index;
+mod logs;
+mod metrics;
+mod mirror;
+mod multipart;
+mod peers;
+mod progress;
+mod quarantine;
+mod shutdown;
+mod temp;
+mod timing;
+mod tree;
+
+const SERVER_FILES: &'static str = "server_files";
+const THREAD_COUNT: usize = 8;
+/// Usage above this fraction of the quota is reported as degraded.
+const QUOTA_WARN_RATIO: f64 = 0.9;
+const DEFAULT_CONTROL_OP_RATE: u32 = 64;
+const DEFAULT_DISK_HEADROOM: u64 = 64 * 1024 * 1024;
+/// Most files returned by a single `op::LIST_PAGE` request.
+const MAX_PAGE_SIZE: usize = 256;
+/// How often a client following the log is sent an empty line when nothing was logged.
+const LOG_HEARTBEAT: Duration = Duration::from_secs(5);
+/// Delay applied to each control op over the rate limit.
+const CONTROL_OP_THROTTLE: Duration = Duration::from_millis(10);
+/// Consecutive seconds over the rate limit before a client is disconnected.
+const CONTROL_OP_MAX_ABUSE: usize = 5;
+/// How long an upload can go without a byte arriving before it is given up on.
+const DEFAULT_TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An address the server accepts connections on, and the rules for clients using it.
+///
+/// Given with `--listen`, or as a `[[listener]]` table in the `--config` file.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Listener {
+    addr: String,
+    /// Refuse uploads and any other change to the stored files.
+    #[serde(default)]
+    read_only: bool,
+    /// Make clients authenticate when the server has a secret.
+    #[serde(default = "Listener::default_require_auth")]
+    require_auth: bool,
+    /// Encrypt connections to this address with a key derived from this, instead of
+    /// from `--passphrase`.
+    #[serde(default)]
+    passphrase: Option<String>,
+    /// The key connections are encrypted with, see `derive_seal_keys`.
+    #[serde(skip)]
+    seal: Option<Arc<SealKey>>,
+}
+
+/// The file given with `--config`, for settings that don't fit on the command line.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default, rename = "listener")]
+    listeners: Vec<Listener>,
+}
+
+impl ConfigFile {
+    fn parse(text: &str) -> io::Result<Self> {
+        toml::from_str(text).map_err(|err| invalid_arg(err.to_string()))
+    }
+}
+
+impl Listener {
+    fn new(addr: String) -> Self {
+        Self {
+            addr,
+            read_only: false,
+            require_auth: Self::default_require_auth(),
+            passphrase: None,
+            seal: None,
+        }
+    }
+
+    fn default_require_auth() -> bool {
+        true
+    }
+
+    /// What a connection to this listener starts out with.
+    fn connection_info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            read_only: self.read_only,
+            require_auth: self.require_auth,
+            ..ConnectionInfo::default()
+        }
+    }
+}
+
+struct Config {
+    /// Addresses to accept connections on, `SERVER_ADDR` if none are given.
+    listeners: Vec<Listener>,
+    /// Maximum number of new connections accepted per second.
+    accept_rate: Option<u32>,
+    /// Replicate the contents of another server.
+    mirror: Option<MirrorConfig>,
+    /// Gzip uploaded files before writing them to disk.
+    compress_storage: bool,
+    /// Maximum number of bytes stored in `SERVER_FILES`.
+    quota: Option<u64>,
+    /// Keep uploads that fail their checksum in `QUARANTINE_DIR`, up to this many bytes,
+    /// instead of deleting them.
+    quarantine: Option<u64>,
+    /// Highest protocol version offered to clients.
+    max_version: u8,
+    /// Control ops (keep alive, stats, ...) allowed per second on a connection.
+    control_op_rate: u32,
+    /// Require clients to present this secret before using the server.
+    secret: Option<String>,
+    /// Checks clients' credentials instead of `secret`, for builds with their own backend.
+    authenticator: Option<Box<dyn Authenticator>>,
+    /// Clients that authenticate with this secret are admins, see `op::FOLLOW_LOG`.
+    admin_secret: Option<String>,
+    /// Encrypt connections with a key derived from this passphrase, see `seal`.
+    /// Listeners with a passphrase of their own use that instead.
+    passphrase: Option<String>,
+    /// Close connections that go this long without a request other than keep alive.
+    idle_timeout: Option<Duration>,
+    /// Give up on requests, uploads included, that go this long without a byte moving.
+    /// See `op_deadline`.
+    transfer_timeout: Duration,
+    /// Log every protocol event to this file.
+    wire_trace: Option<String>,
+    /// Serve Prometheus metrics over HTTP on this address, needs the `metrics` feature.
+    metrics: Option<String>,
+    /// Only acknowledge uploads once they are synced to disk.
+    durable: bool,
+    /// Never write to disk, for serving from a read-only mount. Implies `--read-only`.
+    no_write: bool,
+    /// Log uploads, downloads and requests, see `logs::access_log`.
+    access_log: bool,
+    /// Answer speed tests, see `op::SPEEDTEST_DOWNLOAD`.
+    speedtest: bool,
+    /// Drop multipart uploads that go this long without a request.
+    multipart_timeout: Duration,
+    /// Remove temporary files left this long, see `temp::sweep`.
+    temp_max_age: Duration,
+    /// Delete stored files nobody has written to for this long, see `expiry::sweep`.
+    expire_after: Option<Duration>,
+    /// Gzip the index when saving it, for servers with a lot of files.
+    compress_index: bool,
+    /// How hard saving the index works to get it onto disk.
+    index_sync: IndexSync,
+    /// Compare the index with the stored files and exit instead of serving.
+    check: Option<CheckOptions>,
+    /// Bytes left free on disk after any upload.
+    disk_headroom: u64,
+    /// Ops refused on every connection, bit `n` for op `n`. See `op_enabled`.
+    disabled_ops: u64,
+    /// Deflate level for compressed responses, from 0 (none) to 9 (smallest).
+    compression_level: u32,
+    /// Only accept connections from these ranges, any address if empty.
+    allow: Vec<Cidr>,
+    /// Also accept connections on a Unix socket at this path.
+    #[cfg(unix)]
+    unix: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listeners: Vec::new(),
+            accept_rate: None,
+            mirror: None,
+            compress_storage: false,
+            quota: None,
+            quarantine: None,
+            max_version: version::LATEST,
+            control_op_rate: DEFAULT_CONTROL_OP_RATE,
+            secret: None,
+            authenticator: None,
+            admin_secret: None,
+            passphrase: None,
+            idle_timeout: None,
+            transfer_timeout: DEFAULT_TRANSFER_TIMEOUT,
+            wire_trace: None,
+            metrics: None,
+            durable: false,
+            no_write: false,
+            access_log: true,
+            speedtest: true,
+            multipart_timeout: DEFAULT_MULTIPART_TIMEOUT,
+            temp_max_age: DEFAULT_TEMP_MAX_AGE,
+            expire_after: None,
+            compress_index: false,
+            index_sync: IndexSync::Off,
+            check: None,
+            disk_headroom: DEFAULT_DISK_HEADROOM,
+            disabled_ops: 0,
+            compression_level: Compression::default().level(),
+            allow: Vec::new(),
+            #[cfg(unix)]
+            unix: None,
+        }
+    }
+}
+
+impl Config {
+    /// What clients' credentials are checked against, if anything.
+    ///
+    /// A configured `authenticator` takes precedence over `secret`.
+    fn take_auth(&mut self) -> Option<Box<dyn Authenticator>> {
+        let secret = self.secret.take();
+        self.authenticator.take().or_else(|| {
+            secret.map(|secret| Box::new(SharedSecretAuth::new(secret)) as Box<dyn Authenticator>)
+        })
+    }
+}
+
+struct ServerState {
+    compress_storage: bool,
+    quota: Option<u64>,
+    /// See `Config::quarantine`.
+    quarantine: Option<u64>,
+    max_version: u8,
+    control_op_rate: u32,
+    idle_timeout: Option<Duration>,
+    transfer_timeout: Duration,
+    /// Set in durable mode, see `store_file`.
+    dir_sync: Option<DirSyncer>,
+    disk_headroom: u64,
+    /// See `Config::disabled_ops`.
+    disabled_ops: u64,
+    /// See `Config::compression_level`.
+    compression: Compression,
+    /// See `Config::allow`.
+    allow: Vec<Cidr>,
+    auth: Option<Box<dyn Authenticator>>,
+    admin: Option<SharedSecretAuth>,
+    files: Mutex<FileIndex>,
+    peers: Mutex<PeerRegistry>,
+    mirror: Option<Mirror>,
+    timings: OpTimings,
+    /// Set while a speed test runs, `None` if they are turned off.
+    speedtest: Option<AtomicBool>,
+    multipart: Uploads,
+}
+
+type SharedState = Arc<ServerState>;
+
+fn invalid_arg(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, arg: &str) -> io::Result<String> {
+    args.next()
+        .ok_or_else(|| invalid_arg(format!("{arg} expects a value")))
+}
+
+fn parse_value<T: FromStr>(args: &mut impl Iterator<Item = String>, arg: &str) -> io::Result<T> {
+    let value = next_value(args, arg)?;
+    value
+        .parse()
+        .map_err(|_| invalid_arg(format!("Invalid value for {arg}: '{value}'")))
+}
+
+/// A bit for each op in a comma separated list of op names, like "get_file,stat".
+fn parse_ops(list: &str) -> io::Result<u64> {
+    let mut ops = 0;
+
+    for name in list
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        match op::from_name(name) {
+            Some(op) if op < 64 => ops |= 1 << op,
+            _ => return Err(invalid_arg(format!("Unknown op '{name}'"))),
+        }
+    }
+    Ok(ops)
+}
+
+fn mirror_config<'a>(config: &'a mut Config, arg: &str) -> io::Result<&'a mut MirrorConfig> {
+    config
+        .mirror
+        .as_mut()
+        .ok_or_else(|| invalid_arg(format!("{arg} must come after --mirror")))
+}
+
+fn listener_config<'a>(config: &'a mut Config, arg: &str) -> io::Result<&'a mut Listener> {
+    config
+        .listeners
+        .last_mut()
+        .ok_or_else(|| invalid_arg(format!("{arg} must come after --listen")))
+}
+
+fn parse_args() -> io::Result<Config> {
+    parse_args_from(env::args().skip(1))
+}
+
+fn parse_args_from(args: impl IntoIterator<Item = String>) -> io::Result<Config> {
+    let mut config = Config::default();
+    let mut args = args.into_iter();
+    // Every op named by `--enable-ops`, however many times it is given
+    let mut enabled_ops: Option<u64> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => config
+                .listeners
+                .push(Listener::new(next_value(&mut args, &arg)?)),
+
+            "--read-only" => listener_config(&mut config, &arg)?.read_only = true,
+
+            "--no-auth" => listener_config(&mut config, &arg)?.require_auth = false,
+
+            "--config" => {
+                let path = next_value(&mut args, &arg)?;
+                let text = fs::read_to_string(&path)
+                    .map_err(|err| invalid_arg(format!("Could not read '{path}': {err}")))?;
+                let file = ConfigFile::parse(&text)
+                    .map_err(|err| invalid_arg(format!("Invalid config '{path}': {err}")))?;
+                config.listeners.extend(file.listeners);
+            }
+
+            "--accept-rate" => {
+                config.accept_rate = Some(parse_value::<NonZeroU32>(&mut args, &arg)?.get())
+            }
+
+            "--mirror" => config.mirror = Some(MirrorConfig::new(next_value(&mut args, &arg)?)),
+
+            "--quota" => config.quota = Some(parse_value(&mut args, &arg)?),
+
+            "--quarantine" => config.quarantine = Some(parse_value(&mut args, &arg)?),
+
+            "--protocol-version" => {
+                config.max_version = parse_value(&mut args, &arg)?;
+
+                if !(version::V1..=version::LATEST).contains(&config.max_version) {
+                    return Err(invalid_arg(format!(
+                        "Unsupported protocol version for {arg}"
+                    )));
+                }
+            }
+
+            "--control-op-rate" => {
+                config.control_op_rate = parse_value::<NonZeroU32>(&mut args, &arg)?.get()
+            }
+
+            "--secret" => config.secret = Some(next_value(&mut args, &arg)?),
+            "--admin-secret" => config.admin_secret = Some(next_value(&mut args, &arg)?),
+            "--passphrase" => config.passphrase = Some(next_value(&mut args, &arg)?),
+
+            "--idle-timeout" => {
+                let secs = parse_value::<NonZeroU64>(&mut args, &arg)?.get();
+                config.idle_timeout = Some(Duration::from_secs(secs));
+            }
+
+            "--transfer-timeout" => {
+                let secs = parse_value::<NonZeroU64>(&mut args, &arg)?.get();
+                config.transfer_timeout = Duration::from_secs(secs);
+            }
+
+            "--multipart-timeout" => {
+                let secs = parse_value::<NonZeroU64>(&mut args, &arg)?.get();
+                config.multipart_timeout = Duration::from_secs(secs);
+            }
+
+            "--temp-max-age" => {
+                let secs = parse_value::<NonZeroU64>(&mut args, &arg)?.get();
+                config.temp_max_age = Duration::from_secs(secs);
+            }
+
+            "--expire-after" => {
+                let secs = parse_value::<NonZeroU64>(&mut args, &arg)?.get();
+                config.expire_after = Some(Duration::from_secs(secs));
+            }
+
+            "--compress-storage" => config.compress_storage = true,
+
+            "--compress-index" => config.compress_index = true,
+
+            "--index-sync" => {
+                let value = next_value(&mut args, &arg)?;
+                config.index_sync = match value.as_str() {
+                    "off" => IndexSync::Off,
+                    "always" => IndexSync::Always,
+                    secs => match secs.parse::<NonZeroU64>() {
+                        Ok(secs) => IndexSync::Periodic(Duration::from_secs(secs.get())),
+                        Err(_) => {
+                            return Err(invalid_arg(format!("Invalid value for {arg}: '{value}'")))
+                        }
+                    },
+                };
+            }
+
+            "--check" => {
+                config.check.get_or_insert_with(CheckOptions::default);
+            }
+            "--check-hashes" => {
+                config
+                    .check
+                    .get_or_insert_with(CheckOptions::default)
+                    .hashes = true
+            }
+            "--repair" => {
+                config
+                    .check
+                    .get_or_insert_with(CheckOptions::default)
+                    .repair = true
+            }
+
+            "--durable" => config.durable = true,
+            "--no-write" => config.no_write = true,
+            "--no-access-log" => config.access_log = false,
+            "--no-speedtest" => config.speedtest = false,
+
+            "--disk-headroom" => config.disk_headroom = parse_value(&mut args, &arg)?,
+
+            // Only the listed ops, on top of the ones every client needs
+            "--enable-ops" => {
+                *enabled_ops.get_or_insert(0) |= parse_ops(&next_value(&mut args, &arg)?)?
+            }
+            "--disable-ops" => config.disabled_ops |= parse_ops(&next_value(&mut args, &arg)?)?,
+
+            "--compression-level" => {
+                config.compression_level = parse_value(&mut args, &arg)?;
+
+                if config.compression_level > Compression::best().level() {
+                    return Err(invalid_arg(format!("{arg} expects a level from 0 to 9")));
+                }
+            }
+
+            "--allow-cidr" => config.allow.push(parse_value(&mut args, &arg)?),
+
+            "--wire-trace" => config.wire_trace = Some(next_value(&mut args, &arg)?),
+
+            "--metrics" => config.metrics = Some(next_value(&mut args, &arg)?),
+            #[cfg(unix)]
+            "--unix" => config.unix = Some(next_value(&mut args, &arg)?.into()),
+
+            "--mirror-secret" => {
+                let secret = next_value(&mut args, &arg)?;
+                mirror_config(&mut config, &arg)?.secret = Some(secret);
+            }
+
+            "--mirror-passphrase" => {
+                let passphrase = next_value(&mut args, &arg)?;
+                mirror_config(&mut config, &arg)?.passphrase = Some(passphrase);
+            }
+
+            "--mirror-delete" => mirror_config(&mut config, &arg)?.delete = true,
+
+            "--mirror-conflict" => {
+                let value = next_value(&mut args, &arg)?;
+                mirror_config(&mut config, &arg)?.conflict = match value.as_str() {
+                    "reject" => ConflictPolicy::Reject,
+                    "local-wins" => ConflictPolicy::LocalWins,
+                    _ => return Err(invalid_arg(format!("Invalid value for {arg}: '{value}'"))),
+                };
+            }
+
+            _ => return Err(invalid_arg(format!("Unknown argument '{arg}'"))),
+        }
+    }
+
+    if let Some(enabled) = enabled_ops {
+        config.disabled_ops |= !enabled;
+    }
+
+    let listener_passphrase = config
+        .listeners
+        .iter()
+        .any(|listener| listener.passphrase.is_some());
+    if (config.passphrase.is_some() || listener_passphrase) && config.max_version < version::V8 {
+        return Err(invalid_arg(
+            "Passphrases need protocol version 8 or later".to_string(),
+        ));
+    }
+
+    if config.expire_after.is_some() && config.no_write {
+        return Err(invalid_arg(
+            "--expire-after can't delete files with --no-write".to_string(),
+        ));
+    }
+
+    if config.quarantine.is_some() && config.no_write {
+        return Err(invalid_arg(
+            "--quarantine can't keep files with --no-write".to_string(),
+        ));
+    }
+
+    Ok(config)
+}
+
+/// Why the disk can't take `file_size` more bytes, if it can't.
+fn space_rejection(state: &ServerState, file_size: usize) -> Option<String> {
+    match disk::space_shortfall(file_size as u64, state.disk_headroom) {
+        Ok(problem) => problem,
+        Err(err) => {
+            // Not worth refusing uploads over, a full disk is still caught when writing
+            log_err!("Could not check free space: {err}");
+            None
+        }
+    }
+}
+
+/// Why an upload of `file_size` bytes by the client of `info` would be refused, if it would be.
+fn upload_rejection(
+    state: &ServerState,
+    info: &ConnectionInfo,
+    file_name: &str,
+    file_size: usize,
+) -> Option<String> {
+    if is_reserved_name(file_name) {
+        return Some("File name is reserved".to_string());
+    }
+
+    let hidden = state
+        .files
+        .lock()
+        .unwrap()
+        .get(file_name)
+        .is_some_and(|meta| !meta.visible_to(info));
+
+    if hidden {
+        return Some("Only the owner can replace a private file".to_string());
+    }
+
+    if state
+        .mirror
+        .as_ref()
+        .is_some_and(|mirror| !mirror.accepts_uploads())
+    {
+        return Some("Uploads are disabled while mirroring".to_string());
+    }
+
+    quota_rejection(state, file_name, file_size)
+}
+
+/// Why storing `file_size` bytes as `file_name` would go over the quota, if it would.
+///
+/// Everything is counted in bytes on disk. Replacing a file frees the space it took, so
+/// only the difference counts, and the upload is taken at its full size as how well it
+/// compresses isn't known yet.
+fn quota_rejection(state: &ServerState, file_name: &str, file_size: usize) -> Option<String> {
+    let quota = state.quota?;
+    let files = state.files.lock().unwrap();
+
+    let replaced = files.get(file_name).map_or(0, |meta| meta.disk_size);
+    let stored = files.stored_bytes().saturating_sub(replaced) + file_size as u64;
+    (stored > quota).then(|| "Storage quota exceeded".to_string())
+}
+
+/// Why the client of `info` can't upload `file_name` as a private file, if it can't.
+///
+/// A private file has to be owned by someone, so it takes an identity, and one
+/// already on the server can only be made private by its owner.
+fn private_rejection(
+    state: &ServerState,
+    info: &ConnectionInfo,
+    file_name: &str,
+) -> Option<String> {
+    if state.auth.is_none() {
+        return Some("This server has no authentication, so files can't be private".to_string());
+    }
+    let Some(identity) = info.identity.as_deref() else {
+        return Some(
+            "Only clients authenticated with an identity can upload private files".to_string(),
+        );
+    };
+
+    let owned_by_other = state
+        .files
+        .lock()
+        .unwrap()
+        .get(file_name)
+        .is_some_and(|meta| !meta.is_owned_by(Some(identity)));
+
+    owned_by_other.then(|| "Only the owner can make a file private".to_string())
+}
+
+/// Send a response header, which `V1` clients do not expect.
+fn respond<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    info: &ConnectionInfo,
+    status: Status,
+    msg: &str,
+) -> io::Result<()> {
+    if info.version >= version::V2 {
+        write_response(chunk, status, msg)
+    } else {
+        Ok(())
+    }
+}
+
+/// Write `contents` to `file`, gzipped if the server compresses storage.
+fn write_stored(
+    state: &ServerState,
+    file: fs::File,
+    mut contents: impl Read,
+) -> io::Result<(fs::File, Storage)> {
+    if state.compress_storage {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        let size = io::copy(&mut contents, &mut encoder)? as usize;
+
+        Ok((encoder.finish()?, Storage::Gzip { size }))
+    } else {
+        let mut file = file;
+        io::copy(&mut contents, &mut file)?;
+        Ok((file, Storage::Plain))
+    }
+}
+
+/// Where a stored file came from, see `store_file`.
+#[derive(Clone, Copy, PartialEq)]
+enum Origin {
+    /// Uploaded by a client of this server.
+    Client,
+    /// Pulled from the primary by `mirror::sync`.
+    Primary,
+}
+
+/// Write `contents` to disk and add it to the index.
+///
+/// The file is written under a temporary name and renamed into place. In durable
+/// mode both the file and the rename are synced before this returns.
+///
+/// `owner` is only recorded for new files, replacing a file keeps its owner. It keeps
+/// its visibility too unless `private` is set, see `private_rejection`. A mirror
+/// marks files from its own clients as local, so replication leaves them alone.
+fn store_file(
+    state: &ServerState,
+    origin: Origin,
+    owner: Option<String>,
+    private: bool,
+    file_name: String,
+    contents: &[u8],
+) -> io::Result<()> {
+    let partial = temp::upload_path(&file_name);
+
+    let written = write_stored(state, fs::File::create(&partial)?, contents);
+    let storage = place_stored(state, &partial, &file_name, written, contents.len() as u64)?;
+
+    let hash = hash_reader(contents, |_| {})?;
+    index_stored(state, origin, owner, private, file_name, storage, hash)
+}
+
+/// Like `store_file` for `size` bytes already received into `received`, which is
+/// moved into place rather than read into memory.
+fn store_received(
+    state: &ServerState,
+    owner: Option<String>,
+    private: bool,
+    file_name: String,
+    received: &str,
+    size: u64,
+) -> io::Result<()> {
+    let hash = hash_reader(fs::File::open(received)?, |_| {})?;
+
+    let storage = if state.compress_storage {
+        let partial = temp::upload_path(&file_name);
+        let written = write_stored(
+            state,
+            fs::File::create(&partial)?,
+            fs::File::open(received)?,
+        );
+        _ = fs::remove_file(received);
+
+        place_stored(state, &partial, &file_name, written, size)?
+    } else {
+        let written = fs::File::open(received).map(|file| (file, Storage::Plain));
+        place_stored(state, received, &file_name, written, size)?
+    };
+
+    index_stored(
+        state,
+        Origin::Client,
+        owner,
+        private,
+        file_name,
+        storage,
+        hash,
+    )
+}
+
+/// Rename `partial`, once `written` to, over the stored `file_name`.
+///
+/// A failed write, such as a full disk, leaves the previous contents in place
+/// and `partial` removed.
+fn place_stored(
+    state: &ServerState,
+    partial: &str,
+    file_name: &str,
+    written: io::Result<(fs::File, Storage)>,
+    size: u64,
+) -> io::Result<Storage> {
+    let path = format!("{SERVER_FILES}/{file_name}");
+
+    let placed = written.and_then(|(file, storage)| {
+        // Gzipped files are smaller on disk, so only plain ones can be compared
+        let stored = file.metadata()?.len();
+        if matches!(storage, Storage::Plain) && stored != size {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("Wrote {stored} of {size} bytes"),
+            ));
+        }
+
+        if state.dir_sync.is_some() {
+            file.sync_all()?;
+        }
+        fs::rename(partial, &path)?;
+        Ok(storage)
+    });
+
+    if placed.is_err() {
+        _ = fs::remove_file(partial);
+    }
+    let storage = placed?;
+
+    if let Some(dir_sync) = &state.dir_sync {
+        dir_sync.sync()?;
+    }
+    Ok(storage)
+}
+
+/// Add a file `place_stored` put in place to the index, see `store_file`.
+fn index_stored(
+    state: &ServerState,
+    origin: Origin,
+    owner: Option<String>,
+    private: bool,
+    file_name: String,
+    storage: Storage,
+    hash: String,
+) -> io::Result<()> {
+    let metadata = fs::metadata(format!("{SERVER_FILES}/{file_name}"))?;
+
+    // Add filename to index
+    let mut shared_files = state.files.lock().unwrap();
+    let meta = shared_files.insert(file_name.clone(), &metadata);
+    meta.storage = storage;
+    if meta.owner.is_none() {
+        meta.owner = owner;
+    }
+    if private {
+        meta.private = true;
+    }
+    if origin == Origin::Client && state.mirror.is_some() {
+        meta.local = true;
+    }
+
+    shared_files.set_hash(&file_name, hash);
+    shared_files.save()
+}
+
+/// Whether `err` means the client went away, rather than something going wrong here.
+fn is_peer_gone(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Report an upload the client gave up on part way, the error ends the connection quietly.
+///
+/// Nothing is stored until an upload has been received in full, so there is
+/// nothing in the index to undo. A streamed upload's temporary file is removed
+/// by `add_file_stream`.
+fn upload_aborted(file_name: &str, received: u64, expected: Option<usize>) -> io::Error {
+    match expected {
+        Some(size) => {
+            log!("Upload of \"{file_name}\" aborted by peer after {received} of {size} bytes")
+        }
+        None => log!("Upload of \"{file_name}\" aborted by peer after {received} bytes"),
+    }
+
+    io::Error::new(io::ErrorKind::ConnectionAborted, "Upload aborted by peer")
+}
+
+/// Whether `err` is a read that gave up waiting, see `op_deadline`.
+fn is_stalled(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Report an upload that stopped arriving, the error ends the connection.
+///
+/// The client is told on a best effort basis, it may not be reading any more.
+fn upload_stalled<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: &ServerState,
+    info: &ConnectionInfo,
+    file_name: &str,
+    received: u64,
+) -> io::Error {
+    let secs = state.transfer_timeout.as_secs();
+    log!("Upload of \"{file_name}\" stalled after {received} bytes, nothing for {secs}s");
+
+    let msg = format!("Upload stalled after {received} bytes");
+    _ = respond(chunk, info, Status::IncompleteUpload, &msg);
+    io::Error::new(io::ErrorKind::TimedOut, msg)
+}
+
+fn add_file<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let file_name = read_string(chunk)?;
+    let private = read_private(chunk, info)?;
+    let file_size = read_usize(chunk)?;
+
+    // The payload is still on its way, so refusing it means giving up on the connection
+    if let Some(reason) = space_rejection(&state, file_size) {
+        log!("Rejected upload of \"{file_name}\": {reason}");
+        respond(chunk, info, Status::NoSpace, &reason)?;
+        return Err(io::Error::new(io::ErrorKind::StorageFull, reason));
+    }
+    let over_quota =
+        stored_name(&file_name).and_then(|name| quota_rejection(&state, &name, file_size));
+    if let Some(reason) = over_quota {
+        log!("Rejected upload of \"{file_name}\": {reason}");
+        respond(chunk, info, Status::Denied, &reason)?;
+        return Err(io::Error::new(io::ErrorKind::QuotaExceeded, reason));
+    }
+
+    log!(
+        "Receiving file: \"{file_name}\" ({})",
+        human_bytes(file_size as u64)
+    );
+
+    let mut progress = Progress::start("upload", &file_name, info.peer, file_size);
+    let start = chunk.received();
+    let received = receive_file_with_progress(chunk, file_size, |done| progress.update(done));
+    let contents = match received {
+        Ok(contents) => contents,
+        Err(err) if is_peer_gone(&err) => {
+            let received = chunk.received() - start;
+            return Err(upload_aborted(&file_name, received, Some(file_size)));
+        }
+        Err(err) if is_stalled(&err) => {
+            let received = chunk.received() - start;
+            return Err(upload_stalled(chunk, &state, info, &file_name, received));
+        }
+        Err(err) => return Err(err),
+    };
+
+    // Should never differ, but a short count must not be stored as if it were the file
+    let received = contents.as_ref().map_or(0, Vec::len);
+    if received != file_size {
+        log!("Upload of \"{file_name}\" was {received} of the {file_size} bytes announced");
+        return respond(
+            chunk,
+            info,
+            Status::IncompleteUpload,
+            &format!("Received {received} of {file_size} bytes"),
+        );
+    }
+    finish_upload(chunk, &state, info, &file_name, private, contents.into())
+}
+
+fn add_file_stream<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let file_name = read_string(chunk)?;
+    let private = read_private(chunk, info)?;
+
+    // The size isn't known up front, so the stream is cut off once it can't fit
+    let limit = disk::available_space()
+        .map(|available| available.saturating_sub(state.disk_headroom))
+        .unwrap_or(u64::MAX)
+        .min(usize::MAX as u64) as usize;
+
+    log!("Receiving file: \"{file_name}\" (streamed)");
+
+    let path = temp::stream_path();
+    let result = receive_into(chunk, &state, info, &file_name, private, &path, limit);
+
+    // Already renamed into place if the upload was stored
+    _ = fs::remove_file(&path);
+    result
+}
+
+/// Receive the frames of a streamed upload into the file at `path`, then store it.
+fn receive_into<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: &ServerState,
+    info: &ConnectionInfo,
+    file_name: &str,
+    private: bool,
+    path: &str,
+    limit: usize,
+) -> io::Result<()> {
+    let start = chunk.received();
+    let received = fs::File::create(path).and_then(|file| {
+        let mut writer = io::BufWriter::new(file);
+        let size = receive_stream(chunk, &mut writer, limit)?;
+        writer.flush()?;
+        Ok(size)
+    });
+
+    let size = match received {
+        Ok(size) => size,
+        Err(err) if disk::is_storage_full(&err) => {
+            log!("Rejected upload of \"{file_name}\": {err}");
+            respond(chunk, info, Status::NoSpace, &err.to_string())?;
+            return Err(err);
+        }
+        Err(err) if is_peer_gone(&err) => {
+            let received = chunk.received() - start;
+            return Err(upload_aborted(file_name, received, None));
+        }
+        Err(err) if is_stalled(&err) => {
+            let received = chunk.received() - start;
+            return Err(upload_stalled(chunk, state, info, file_name, received));
+        }
+        Err(err) => return Err(err),
+    };
+
+    let payload = match size {
+        0 => Payload::Empty,
+        size => Payload::Received {
+            path: path.to_string(),
+            size: size as u64,
+        },
+    };
+    finish_upload(chunk, state, info, file_name, private, payload)
+}
+
+/// The name an upload called `file_name` is stored under.
+///
+/// Clients pick the stored name, only its last part is kept and it can't be empty or "..".
+fn stored_name(file_name: &str) -> Option<String> {
+    Path::new(file_name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(String::from)
+}
+
+/// Longest stored file name in bytes.
+///
+/// Most filesystems allow 255, which leaves room for the prefixes temporary files get.
+const MAX_NAME_LEN: usize = 200;
+
+/// Whether `name` is kept for the server's own use, so no stored file can have it.
+///
+/// A `PARTIAL_PREFIX` name would be taken for a leftover upload and removed on the next start.
+fn is_reserved_name(name: &str) -> bool {
+    name.starts_with(PARTIAL_PREFIX) || name == TEMP_DIR
+}
+
+/// Whether `name` is a bare file name, the only kind stored files have.
+///
+/// Requests naming anything else, like "./a" or "../a", are refused before they
+/// get near the disk so they can't reach past `SERVER_FILES` or the index.
+fn is_bare_name(name: &str) -> bool {
+    Path::new(name).file_name().and_then(|name| name.to_str()) == Some(name)
+}
+
+/// An upload that has been read in full, see `accept_upload`.
+enum Payload {
+    /// Nothing was sent, so there is nothing to store.
+    Empty,
+    Contents(Vec<u8>),
+    /// Received into the temporary file at `path`, which is renamed into place.
+    Received {
+        path: String,
+        size: u64,
+    },
+}
+
+impl Payload {
+    fn len(&self) -> usize {
+        match self {
+            Self::Empty => 0,
+            Self::Contents(contents) => contents.len(),
+            Self::Received { size, .. } => *size as usize,
+        }
+    }
+}
+
+impl From<Option<Vec<u8>>> for Payload {
+    fn from(contents: Option<Vec<u8>>) -> Self {
+        match contents {
+            Some(contents) if !contents.is_empty() => Self::Contents(contents),
+            _ => Self::Empty,
+        }
+    }
+}
+
+/// Store an upload that has been read in full and tell the client how it went.
+fn finish_upload<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: &ServerState,
+    info: &ConnectionInfo,
+    file_name: &str,
+    private: bool,
+    payload: Payload,
+) -> io::Result<()> {
+    match accept_upload(state, info, file_name, private, payload) {
+        Ok(()) => respond(chunk, info, Status::Ok, ""),
+        Err((status, msg)) => respond(chunk, info, status, &msg),
+    }
+}
+
+/// Store an upload that has been read in full, or say why it wasn't.
+fn accept_upload(
+    state: &ServerState,
+    info: &ConnectionInfo,
+    file_name: &str,
+    private: bool,
+    payload: Payload,
+) -> Result<(), (Status, String)> {
+    let file_size = payload.len();
+
+    let Some(file_name) = stored_name(file_name) else {
+        log!("Rejected upload of \"{file_name}\": Invalid file name");
+        return Err((Status::InvalidRequest, "Invalid file name".to_string()));
+    };
+    if file_name.len() > MAX_NAME_LEN {
+        log!("Rejected upload of \"{file_name:.32}...\": File name too long");
+        return Err((
+            Status::InvalidRequest,
+            format!("File name too long, the limit is {MAX_NAME_LEN} bytes"),
+        ));
+    }
+
+    let rejection = upload_rejection(state, info, &file_name, file_size).or_else(|| {
+        private
+            .then(|| private_rejection(state, info, &file_name))
+            .flatten()
+    });
+    if let Some(reason) = rejection {
+        log!("Rejected upload of \"{file_name}\": {reason}");
+        return Err((Status::Denied, reason));
+    }
+
+    let owner = info.identity.clone();
+    let stored = match payload {
+        Payload::Empty => Ok(()),
+        Payload::Contents(contents) => store_file(
+            state,
+            Origin::Client,
+            owner,
+            private,
+            file_name.clone(),
+            &contents,
+        ),
+        Payload::Received { path, size } => {
+            store_received(state, owner, private, file_name.clone(), &path, size)
+        }
+    };
+    if let Err(err) = stored {
+        log_err!("Could not store upload: {err}");
+
+        if disk::is_storage_full(&err) {
+            return Err((Status::NoSpace, "Server is out of disk space".to_string()));
+        }
+        return Err((Status::InternalError, "Could not store file".to_string()));
+    }
+
+    let entry = state
+        .files
+        .lock()
+        .unwrap()
+        .get(&file_name)
+        .map(|meta| meta.to_entry(file_name));
+    if let Some(entry) = entry {
+        events::emit(ServerEvent::UploadCompleted(entry, info.peer));
+    }
+    Ok(())
+}
+
+fn multipart_initiate<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let file_name = read_string(chunk)?;
+    let private = read_private(chunk, info)?;
+    let file_size = read_usize(chunk)?;
+
+    // Checked again on completion, this only saves sending parts that can't be stored
+    let Some(name) = stored_name(&file_name) else {
+        log!("Rejected upload of \"{file_name}\": Invalid file name");
+        return respond(chunk, info, Status::InvalidRequest, "Invalid file name");
+    };
+    // Anything bigger could never be sent in parts
+    if file_size as u64 > MAX_PARTS as u64 * MAX_PART_LEN as u64 {
+        let reason = format!(
+            "Files sent in parts are at most {MAX_PARTS} parts of {}",
+            human_bytes(MAX_PART_LEN as u64)
+        );
+        log!("Rejected upload of \"{name}\": {reason}");
+        return respond(chunk, info, Status::InvalidRequest, &reason);
+    }
+    if let Some(reason) = space_rejection(&state, file_size) {
+        log!("Rejected upload of \"{name}\": {reason}");
+        return respond(chunk, info, Status::NoSpace, &reason);
+    }
+    let rejection = upload_rejection(&state, info, &name, file_size).or_else(|| {
+        private
+            .then(|| private_rejection(&state, info, &name))
+            .flatten()
+    });
+    if let Some(reason) = rejection {
+        log!("Rejected upload of \"{name}\": {reason}");
+        return respond(chunk, info, Status::Denied, &reason);
+    }
+
+    let owner = info.identity.clone();
+    let id = match state
+        .multipart
+        .initiate(name.clone(), file_size as u64, owner, private)
+    {
+        Ok(id) => id,
+        Err(err) => {
+            log_err!("Could not start multipart upload: {err}");
+            return respond(chunk, info, Status::InternalError, "Could not start upload");
+        }
+    };
+
+    log!(
+        "Receiving file in parts: \"{name}\" ({}), upload {id}",
+        human_bytes(file_size as u64)
+    );
+    respond(chunk, info, Status::Ok, "")?;
+    write_string(chunk, &id)
+}
+
+fn multipart_part<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let id = read_string(chunk)?;
+    let number = read_usize(chunk)?;
+    let hash = read_string(chunk)?;
+    let part_size = read_usize(chunk)?;
+
+    // Too big to read in, and the part is already on its way
+    if part_size > MAX_PART_LEN {
+        let reason = format!("Parts are at most {}", human_bytes(MAX_PART_LEN as u64));
+        respond(chunk, info, Status::InvalidRequest, &reason)?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+    }
+
+    let what = format!("{id} part {number}");
+    let start = chunk.received();
+    let received = receive_file(chunk, part_size);
+    let contents = match received {
+        Ok(contents) => contents.unwrap_or_default(),
+        Err(err) if is_peer_gone(&err) => {
+            let received = chunk.received() - start;
+            return Err(upload_aborted(&what, received, Some(part_size)));
+        }
+        Err(err) if is_stalled(&err) => {
+            let received = chunk.received() - start;
+            return Err(upload_stalled(chunk, &state, info, &what, received));
+        }
+        Err(err) => return Err(err),
+    };
+
+    if hash_reader(contents.as_slice(), |_| {})? != hash {
+        log!("Part {number} of upload {id} doesn't match its checksum");
+        // Only kept for a known upload and its owner, the ID isn't checked until it's stored
+        if let Ok(file_name) = state.multipart.file_name(&id, info.identity.as_deref()) {
+            let name = format!("{file_name}.{id}.part{number}");
+            quarantine_mismatch(&state, Received::Contents(&contents), &name);
+        }
+        let msg = format!("Part {number} doesn't match its checksum");
+        return respond(chunk, info, Status::ChecksumMismatch, &msg);
+    }
+
+    match state
+        .multipart
+        .write_part(&id, info.identity.as_deref(), number, &contents)
+    {
+        Ok(()) => respond(chunk, info, Status::Ok, ""),
+        Err((status, msg)) => respond(chunk, info, status, &msg),
+    }
+}
+
+fn multipart_complete<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let id = read_string(chunk)?;
+    let hash = read_string(chunk)?;
+    let owner = info.identity.as_deref();
+
+    let path = temp::stream_path();
+    let (file_name, joined, private) = match state.multipart.assemble(&id, owner, &path) {
+        Ok(assembled) => assembled,
+        Err((status, msg)) => {
+            _ = fs::remove_file(&path);
+            return respond(chunk, info, status, &msg);
+        }
+    };
+
+    // The parts are kept, so the client can send the right ones and try again
+    if joined != hash {
+        log!("Upload {id} of \"{file_name}\" doesn't match its checksum once joined");
+        quarantine_mismatch(&state, Received::File(&path), &format!("{file_name}.{id}"));
+        _ = fs::remove_file(&path);
+        let msg = "File doesn't match its checksum once joined";
+        return respond(chunk, info, Status::ChecksumMismatch, msg);
+    }
+
+    let size = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+    let payload = match size {
+        0 => Payload::Empty,
+        size => Payload::Received {
+            path: path.clone(),
+            size,
+        },
+    };
+    let accepted = accept_upload(&state, info, &file_name, private, payload);
+    // Already renamed into place if the upload was stored
+    _ = fs::remove_file(&path);
+    if let Err((status, msg)) = accepted {
+        return respond(chunk, info, status, &msg);
+    }
+
+    log!("Joined the parts of upload {id} into \"{file_name}\"");
+    if let Err((_, msg)) = state.multipart.remove(&id, owner) {
+        log_err!("Could not clean up upload {id}: {msg}");
+    }
+    respond(chunk, info, Status::Ok, "")
+}
+
+/// Keep what was received for an upload that failed its checksum, if `--quarantine`
+/// is set. Either way the caller is left to remove it.
+fn quarantine_mismatch(state: &ServerState, received: Received, name: &str) {
+    let Some(max_bytes) = state.quarantine else {
+        return;
+    };
+    match quarantine::keep(received, name, max_bytes) {
+        Ok(path) => log!("Kept it as {path} to be looked at"),
+        Err(err) => log_err!("Could not quarantine \"{name}\": {err}"),
+    }
+}
+
+fn multipart_abort<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let id = read_string(chunk)?;
+
+    match state.multipart.remove(&id, info.identity.as_deref()) {
+        Ok(()) => {
+            log!("Upload {id} aborted by peer");
+            respond(chunk, info, Status::Ok, "")
+        }
+        Err((status, msg)) => respond(chunk, info, status, &msg),
+    }
+}
+
+fn find_by_hash<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let hash = read_string(chunk)?;
+
+    let found = {
+        let files = state.files.lock().unwrap();
+        files
+            .find_by_hash(&hash)
+            .filter(|file_name| {
+                files
+                    .get(file_name)
+                    .is_some_and(|meta| meta.visible_to(info))
+            })
+            .cloned()
+    };
+
+    match found {
+        Some(file_name) => {
+            respond(chunk, info, Status::Ok, "")?;
+            write_string(chunk, &file_name)
+        }
+        None if info.version >= version::V2 => {
+            write_response(chunk, Status::NotFound, "No file has those contents")
+        }
+        None => write_string(chunk, ""),
+    }
+}
+
+/// Copy a stored file to a new name, as it is on disk.
+fn copy_stored(
+    state: &ServerState,
+    owner: Option<String>,
+    from: &str,
+    to: String,
+) -> io::Result<()> {
+    let path = format!("{SERVER_FILES}/{to}");
+    fs::copy(format!("{SERVER_FILES}/{from}"), &path)?;
+    let metadata = fs::metadata(&path)?;
+
+    let mut files = state.files.lock().unwrap();
+    let source = files.get(from).cloned().unwrap_or_default();
+
+    let meta = files.insert(to.clone(), &metadata);
+    meta.storage = source.storage;
+    if meta.owner.is_none() {
+        meta.owner = owner;
+    }
+    // Made by a client, like an upload
+    if state.mirror.is_some() {
+        meta.local = true;
+    }
+
+    if let Some(hash) = source.hash {
+        files.set_hash(&to, hash);
+    }
+    files.save()
+}
+
+/// Remove one file for `delete_files`, the index lock is held by the caller.
+fn delete_stored(
+    state: &ServerState,
+    files: &mut FileIndex,
+    info: &ConnectionInfo,
+    file_name: &str,
+) -> Status {
+    // Only bare names are stored, anything else can't refer to a stored file
+    if !is_bare_name(file_name) {
+        return Status::InvalidRequest;
+    }
+
+    if !files
+        .get(file_name)
+        .is_some_and(|meta| meta.visible_to(info))
+    {
+        return Status::NotFound;
+    }
+
+    // Same rule as uploads, a mirrored file deleted here is pulled again on the next sync
+    if state
+        .mirror
+        .as_ref()
+        .is_some_and(|mirror| !mirror.accepts_uploads())
+    {
+        return Status::Denied;
+    }
+
+    match fs::remove_file(format!("{SERVER_FILES}/{file_name}")) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => {
+            log_err!("Could not delete \"{file_name}\": {err}");
+            return Status::InternalError;
+        }
+    }
+
+    files.remove(file_name);
+    events::emit(ServerEvent::FileDeleted(file_name.to_string()));
+    Status::Ok
+}
+
+fn delete_files<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let file_names = read_string_list(chunk)?;
+
+    let statuses: Vec<u8> = {
+        let mut files = state.files.lock().unwrap();
+        let statuses: Vec<u8> = file_names
+            .iter()
+            .map(|name| delete_stored(&state, &mut files, info, name) as u8)
+            .collect();
+
+        if statuses.contains(&(Status::Ok as u8)) {
+            files.save()?;
+        }
+        statuses
+    };
+
+    respond(chunk, info, Status::Ok, "")?;
+
+    for part in statuses.chunks(N) {
+        chunk.write_and_send(part)?;
+    }
+    Ok(())
+}
+
+fn copy_file<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let from = read_string(chunk)?;
+    let to = read_string(chunk)?;
+
+    let disk_size = state
+        .files
+        .lock()
+        .unwrap()
+        .get(&from)
+        .filter(|meta| meta.visible_to(info))
+        .map(|meta| meta.disk_size);
+
+    let (status, msg) = match disk_size {
+        None => (Status::NotFound, format!("No file named '{from}'")),
+        // Only bare names are stored, a path is refused rather than cut down to its last part
+        Some(_) if !is_bare_name(&to) || to == from => {
+            (Status::InvalidRequest, "Invalid file name".to_string())
+        }
+        Some(size) => match upload_rejection(&state, info, &to, size as usize) {
+            Some(reason) => (Status::Denied, reason),
+            None => match copy_stored(&state, info.identity.clone(), &from, to) {
+                Ok(()) => (Status::Ok, String::new()),
+                Err(err) => {
+                    log_err!("Could not copy \"{from}\": {err}");
+                    (Status::InternalError, "Could not copy file".to_string())
+                }
+            },
+        },
+    };
+
+    if info.version >= version::V2 {
+        write_response(chunk, status, &msg)
+    } else {
+        chunk.write_and_send(&[(status == Status::Ok) as u8])
+    }
+}
+
+/// Send a stored file, or report that it is missing.
+///
+/// `preamble` is sent after the header and before the file itself.
+fn send_stored_file<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: &ServerState,
+    info: &ConnectionInfo,
+    name: &str,
+    preamble: &[u8],
+) -> io::Result<()> {
+    if !is_sendable(state, info, name) {
+        if info.version >= version::V2 {
+            return write_response(chunk, Status::NotFound, &format!("No file named '{name}'"));
+        }
+
+        if !preamble.is_empty() {
+            chunk.write_and_send(preamble)?;
+        }
+        write_usize(chunk, 0)?;
+        return Ok(());
+    }
+
+    respond(chunk, info, Status::Ok, "")?;
+
+    if !preamble.is_empty() {
+        chunk.write_and_send(preamble)?;
+    }
+
+    send_contents(chunk, state, info, name)
+}
+
+/// Whether `name` is stored and the client on the other end of `info` may see it.
+///
+/// Only files in the index are sent, so private files are reported missing and
+/// their names don't leak.
+fn is_sendable(state: &ServerState, info: &ConnectionInfo, name: &str) -> bool {
+    if !is_bare_name(name) {
+        return false;
+    }
+
+    let visible = state
+        .files
+        .lock()
+        .unwrap()
+        .get(name)
+        .is_some_and(|meta| meta.visible_to(info));
+
+    visible && Path::new(&format!("{SERVER_FILES}/{name}")).exists()
+}
+
+/// Send a stored file's size and contents, counting it as downloaded once it's all out.
+fn send_contents<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: &ServerState,
+    info: &ConnectionInfo,
+    name: &str,
+) -> io::Result<()> {
+    let file_name = format!("{SERVER_FILES}/{name}");
+    log!("Sending file: \"{file_name}\"");
+
+    let storage = state
+        .files
+        .lock()
+        .unwrap()
+        .get(name)
+        .map(|meta| meta.storage)
+        .unwrap_or_default();
+
+    let file = fs::File::open(&file_name)?;
+    let size = match storage {
+        Storage::Plain => file.metadata()?.len() as usize,
+        Storage::Gzip { size } => size,
+    };
+
+    let mut progress = Progress::start("download", name, info.peer, size);
+    match storage {
+        Storage::Plain => send_reader(chunk, progress.reader(file), size)?,
+        Storage::Gzip { .. } => send_reader(chunk, progress.reader(GzDecoder::new(file)), size)?,
+    }
+    let bytes = size as u64;
+
+    // Only counted once the whole file is out, the index isn't held during the send
+    let mut files = state.files.lock().unwrap();
+    if let Some(meta) = files.get_mut(name) {
+        meta.downloads += 1;
+
+        // The file is already out, so a count that can't be saved only costs the count
+        if let Err(err) = files.save_later() {
+            log_err!("Could not save the download count of \"{name}\": {err}");
+        }
+    }
+    drop(files);
+
+    events::emit(ServerEvent::DownloadCompleted {
+        name: name.to_string(),
+        peer: info.peer,
+        bytes,
+    });
+    Ok(())
+}
+
+fn get_file<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let name = read_string(chunk)?;
+    send_stored_file(chunk, &state, info, &name, &[])
+}
+
+fn get_files<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let names = read_string_list(chunk)?;
+
+    if names.len() > MAX_BATCH_LEN {
+        let msg = format!("At most {MAX_BATCH_LEN} files can be requested at once");
+        return respond(chunk, info, Status::InvalidRequest, &msg);
+    }
+
+    respond(chunk, info, Status::Ok, "")?;
+
+    // A missing file only fails its own entry, the rest are still sent
+    for name in &names {
+        if is_sendable(&state, info, name) {
+            chunk.write_and_send(&[Status::Ok as u8])?;
+            send_contents(chunk, &state, info, name)?;
+        } else {
+            chunk.write_and_send(&[Status::NotFound as u8])?;
+        }
+    }
+    Ok(())
+}
+
+fn get_file_if_changed<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let name = read_string(chunk)?;
+    let held = read_string(chunk)?;
+
+    let unchanged = !held.is_empty()
+        && state
+            .files
+            .lock()
+            .unwrap()
+            .get(&name)
+            .filter(|meta| meta.visible_to(info))
+            .and_then(|meta| meta.hash.as_deref())
+            == Some(held.as_str());
+
+    // A single byte says whether the file follows
+    if unchanged {
+        log!("Not sending \"{name}\", client has it already");
+        respond(chunk, info, Status::Ok, "")?;
+        return chunk.write_and_send(&[0]);
+    }
+
+    send_stored_file(chunk, &state, info, &name, &[1])
+}
+
+fn fetch_files<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    // Snapshot the names so a slow client doesn't hold the index lock
+    let visible: Vec<String> = state
+        .files
+        .lock()
+        .unwrap()
+        .visible(info)
+        .map(|(file, _)| file.clone())
+        .collect();
+
+    respond(chunk, info, Status::Ok, "")?;
+
+    if info.version >= version::V3 {
+        return write_compressed(chunk, &visible, state.compression);
+    }
+    write_file_list(chunk, visible.into_iter())
+}
+
+fn index_version<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let generation = state.files.lock().unwrap().generation();
+
+    respond(chunk, info, Status::Ok, "")?;
+    write_usize(chunk, generation as usize)
+}
+
+fn list_older_than<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let age = read_usize(chunk)? as u64;
+    let cutoff = unix_now().saturating_sub(age);
+
+    let entries: Vec<FileEntry> = state
+        .files
+        .lock()
+        .unwrap()
+        .sorted(info, SortKey::Modified, false)
+        .into_iter()
+        .take_while(|(_, meta)| meta.modified < cutoff)
+        .map(|(name, meta)| meta.to_entry(name.to_string()))
+        .collect();
+
+    respond(chunk, info, Status::Ok, "")?;
+    write_usize(chunk, entries.len())?;
+
+    for entry in &entries {
+        write_file_entry(chunk, entry, info.version)?;
+    }
+    Ok(())
+}
+
+fn fetch_file_sizes<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let sizes: Vec<(String, u64)> = state
+        .files
+        .lock()
+        .unwrap()
+        .visible(info)
+        .map(|(file, meta)| (file.clone(), meta.content_size()))
+        .collect();
+
+    respond(chunk, info, Status::Ok, "")?;
+
+    if info.version >= version::V3 {
+        return write_compressed(chunk, &sizes, state.compression);
+    }
+
+    write_usize(chunk, sizes.len())?;
+    for (file, size) in sizes {
+        write_string(chunk, &file)?;
+        write_usize(chunk, size as usize)?;
+    }
+    Ok(())
+}
+
+fn list_page<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    chunk.read_stream(2)?;
+    let [key, descending] = chunk.to_byte_array::<2>();
+    let offset = read_usize(chunk)?;
+    let limit = read_usize(chunk)?.min(MAX_PAGE_SIZE);
+
+    let Some(key) = SortKey::from_byte(key) else {
+        if info.version >= version::V2 {
+            return write_response(
+                chunk,
+                Status::InvalidRequest,
+                &format!("Unknown sort key {key}"),
+            );
+        }
+
+        // V1 has no header, so an empty page is all that can be said
+        write_usize(chunk, 0)?;
+        return write_usize(chunk, 0);
+    };
+
+    let (total, entries) = {
+        let files = state.files.lock().unwrap();
+        let sorted = files.sorted(info, key, descending != 0);
+
+        let entries: Vec<FileEntry> = sorted
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(name, meta)| meta.to_entry(name.to_string()))
+            .collect();
+        (sorted.len(), entries)
+    };
+
+    respond(chunk, info, Status::Ok, "")?;
+    write_usize(chunk, total)?;
+    write_usize(chunk, entries.len())?;
+
+    for entry in &entries {
+        write_file_entry(chunk, entry, info.version)?;
+    }
+    Ok(())
+}
+
+fn stat<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let name = read_string(chunk)?;
+
+    let entry = state
+        .files
+        .lock()
+        .unwrap()
+        .get(&name)
+        .filter(|meta| meta.visible_to(info))
+        .map(|meta| meta.to_entry(name.clone()));
+
+    match entry {
+        Some(entry) => {
+            respond(chunk, info, Status::Ok, "")?;
+
+            if info.version < version::V2 {
+                chunk.write_and_send(&[1])?;
+            }
+            write_file_entry(chunk, &entry, info.version)
+        }
+        None if info.version >= version::V2 => {
+            write_response(chunk, Status::NotFound, &format!("No file named '{name}'"))
+        }
+        None => chunk.write_and_send(&[0]),
+    }
+}
+
+fn read_head<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let name = read_string(chunk)?;
+    let len = read_usize(chunk)?.min(MAX_HEAD_LEN);
+
+    let storage = state
+        .files
+        .lock()
+        .unwrap()
+        .get(&name)
+        .filter(|meta| meta.visible_to(info))
+        .map(|meta| meta.storage);
+
+    let file = storage.and_then(|storage| {
+        let file = fs::File::open(format!("{SERVER_FILES}/{name}")).ok()?;
+        Some((file, storage))
+    });
+
+    let Some((file, storage)) = file else {
+        if info.version >= version::V2 {
+            return write_response(chunk, Status::NotFound, &format!("No file named '{name}'"));
+        }
+        return chunk.write_and_send(&[0]);
+    };
+
+    // Compressed files are peeked at through the decoder, so the bytes are the original ones
+    let mut head = Vec::with_capacity(len);
+    match storage {
+        Storage::Plain => file.take(len as u64).read_to_end(&mut head)?,
+        Storage::Gzip { .. } => GzDecoder::new(file)
+            .take(len as u64)
+            .read_to_end(&mut head)?,
+    };
+
+    respond(chunk, info, Status::Ok, "")?;
+    if info.version < version::V2 {
+        chunk.write_and_send(&[1])?;
+    }
+
+    write_usize(chunk, head.len())?;
+    if !head.is_empty() {
+        chunk.write_and_send(&head)?;
+    }
+    Ok(())
+}
+
+/// Why `tags` can't be put on a file, if they can't.
+fn tags_rejection(tags: &[String]) -> Option<String> {
+    if tags.len() > index::MAX_TAGS {
+        Some(format!("Files can have at most {} tags", index::MAX_TAGS))
+    } else if !tags.iter().all(|tag| index::is_valid_tag(tag)) {
+        Some("Tags must be 1-32 characters of letters, digits, '-' or '_'".to_string())
+    } else {
+        None
+    }
+}
+
+/// Replace a file's tags, and its description if one is given.
+fn update_metadata<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+    file_name: String,
+    mut tags: Vec<String>,
+    description: Option<String>,
+) -> io::Result<()> {
+    tags.sort();
+    tags.dedup();
+
+    let mut files = state.files.lock().unwrap();
+    let rejection = tags_rejection(&tags).or_else(|| match &description {
+        Some(description) if !index::is_valid_description(description) => Some(format!(
+            "Descriptions must be a single line of at most {} bytes",
+            index::MAX_DESCRIPTION_LEN
+        )),
+        _ => None,
+    });
+
+    let meta = files
+        .get_mut(&file_name)
+        .filter(|meta| meta.visible_to(info));
+
+    let (status, msg) = match (meta, rejection) {
+        (None, _) => (Status::NotFound, format!("No file named '{file_name}'")),
+        (Some(_), Some(msg)) => (Status::InvalidRequest, msg),
+        (Some(meta), None) => {
+            meta.tags = tags;
+            if let Some(description) = description {
+                meta.description = description;
+            }
+            (Status::Ok, String::new())
+        }
+    };
+
+    if status == Status::Ok {
+        files.save()?;
+    }
+    drop(files);
+
+    if info.version >= version::V2 {
+        write_response(chunk, status, &msg)
+    } else {
+        chunk.write_and_send(&[(status == Status::Ok) as u8])
+    }
+}
+
+fn set_tags<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let file_name = read_string(chunk)?;
+    let tags = read_string_list(chunk)?;
+
+    update_metadata(chunk, state, info, file_name, tags, None)
+}
+
+fn set_metadata<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let file_name = read_string(chunk)?;
+    let tags = read_string_list(chunk)?;
+    let description = read_string(chunk)?;
+
+    update_metadata(chunk, state, info, file_name, tags, Some(description))
+}
+
+fn list_connections<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    if !info.admin {
+        return respond(
+            chunk,
+            info,
+            Status::Denied,
+            "Only admins can list connections",
+        );
+    }
+
+    respond(chunk, info, Status::Ok, "")?;
+    write_compressed(chunk, &connections::list(), state.compression)
+}
+
+fn kick_connection<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let id = read_usize(chunk)? as u64;
+
+    if !info.admin {
+        return respond(
+            chunk,
+            info,
+            Status::Denied,
+            "Only admins can disconnect clients",
+        );
+    }
+
+    match connections::kick(id) {
+        Ok(Some(peer)) => {
+            log!("Disconnected {peer} (connection {id}) for an admin");
+            respond(chunk, info, Status::Ok, "")
+        }
+        Ok(None) => respond(
+            chunk,
+            info,
+            Status::NotFound,
+            &format!("No connection {id}"),
+        ),
+        Err(err) => {
+            log_err!("Could not disconnect connection {id}: {err}");
+            respond(chunk, info, Status::InternalError, "Could not disconnect")
+        }
+    }
+}
+
+fn reset_downloads<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    if !info.admin {
+        if info.version >= version::V2 {
+            return write_response(chunk, Status::Denied, "Only admins can reset downloads");
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Client tried to reset downloads without the admin secret",
+        ));
+    }
+
+    {
+        let mut files = state.files.lock().unwrap();
+        files.reset_downloads();
+        files.save()?;
+    }
+
+    log!("Download counts reset");
+    respond(chunk, info, Status::Ok, "")
+}
+
+fn set_visibility<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let file_name = read_string(chunk)?;
+    chunk.read_stream(1)?;
+    let private = chunk.slice(1)[0] != 0;
+
+    let mut files = state.files.lock().unwrap();
+    let (status, msg) = match files.get_mut(&file_name) {
+        Some(meta) if meta.visible_to(info) => match (&meta.owner, &info.identity) {
+            (Some(owner), Some(identity)) if owner == identity => {
+                meta.private = private;
+                (Status::Ok, String::new())
+            }
+            _ => (
+                Status::Denied,
+                "Only the owner can change a file's visibility".to_string(),
+            ),
+        },
+        _ => (Status::NotFound, format!("No file named '{file_name}'")),
+    };
+
+    if status == Status::Ok {
+        files.save()?;
+    }
+    drop(files);
+
+    if info.version >= version::V2 {
+        write_response(chunk, status, &msg)
+    } else {
+        chunk.write_and_send(&[(status == Status::Ok) as u8])
+    }
+}
+
+fn fetch_own_files<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let owned: Vec<String> = match &info.identity {
+        Some(identity) => state
+            .files
+            .lock()
+            .unwrap()
+            .owned_by(identity)
+            .cloned()
+            .collect(),
+        None => Vec::new(),
+    };
+
+    respond(chunk, info, Status::Ok, "")?;
+    write_file_list(chunk, owned.into_iter())
+}
+
+fn export_index<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    if !info.authenticated {
+        if info.version >= version::V2 {
+            return write_response(chunk, Status::Denied, "Authenticate to export the index");
+        }
+        return write_compressed(chunk, &Vec::<SnapshotEntry>::new(), state.compression);
+    }
+
+    // Taken in one go, so the snapshot matches a single state of the index
+    let snapshot: Vec<SnapshotEntry> = state
+        .files
+        .lock()
+        .unwrap()
+        .visible(info)
+        .map(|(name, meta)| meta.to_snapshot(name.clone()))
+        .collect();
+
+    respond(chunk, info, Status::Ok, "")?;
+    write_compressed(chunk, &snapshot, state.compression)
+}
+
+fn tree<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let path = read_string(chunk)?;
+    let depth = read_usize(chunk)?.min(MAX_TREE_DEPTH);
+
+    let built = {
+        let files = state.files.lock().unwrap();
+        let visible = files
+            .visible(info)
+            .map(|(name, meta)| (name.as_str(), meta.content_size(), meta.modified));
+        tree::build(visible, &path, depth, MAX_TREE_NODES)
+    };
+
+    // Older clients get `None` instead of a header
+    if built.is_none() && info.version >= version::V2 {
+        return write_response(chunk, Status::NotFound, &format!("Nothing at '{path}'"));
+    }
+
+    respond(chunk, info, Status::Ok, "")?;
+    write_compressed(chunk, &built, state.compression)
+}
+
+/// Compare the index with the stored files for an admin, repairing it if asked to.
+fn check_index<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    chunk.read_stream(1)?;
+    let flags = chunk.slice(1)[0];
+    let options = CheckOptions {
+        hashes: flags & 1 != 0,
+        repair: flags & 2 != 0,
+    };
+
+    if !info.admin {
+        if info.version >= version::V2 {
+            return write_response(chunk, Status::Denied, "Only admins can check the index");
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Client tried to check the index without the admin secret",
+        ));
+    }
+    if options.repair && info.read_only {
+        return respond(
+            chunk,
+            info,
+            Status::Denied,
+            "Repairs need a writable listener",
+        );
+    }
+
+    let report = check::check(&state.files, options)?;
+    log!(
+        "Index check found {} problems{}",
+        report.problems(),
+        if report.repaired { ", repaired" } else { "" }
+    );
+
+    respond(chunk, info, Status::Ok, "")?;
+    write_compressed(chunk, &report, state.compression)
+}
+
+/// Send every line logged from now on, until the client goes away.
+fn follow_log<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    if !info.admin {
+        respond(
+            chunk,
+            info,
+            Status::Denied,
+            "Only admins can follow the log",
+        )?;
+
+        // Older clients can't be told, and would wait for lines forever
+        if info.version < version::V2 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Client tried to follow the log without the admin secret",
+            ));
+        }
+        return Ok(());
+    }
+
+    // Followed before answering, so nothing logged once the client is told is missed
+    let lines = logs::follow();
+    respond(chunk, info, Status::Ok, "")?;
+
+    loop {
+        // Empty lines keep checking the client is there, the write fails once it has gone
+        let line = match lines.recv_timeout(LOG_HEARTBEAT) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => String::new(),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+
+        let mut end = line.len().min(N);
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        write_string(chunk, &line[..end])?;
+    }
+}
+
+fn get_tags<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let file_name = read_string(chunk)?;
+
+    let visible = state
+        .files
+        .lock()
+        .unwrap()
+        .get(&file_name)
+        .filter(|meta| meta.visible_to(info))
+        .map(|meta| meta.tags.clone());
+
+    let tags = match visible {
+        Some(tags) => tags,
+        None if info.version >= version::V2 => {
+            return write_response(
+                chunk,
+                Status::NotFound,
+                &format!("No file named '{file_name}'"),
+            );
+        }
+        None => Vec::new(),
+    };
+
+    respond(chunk, info, Status::Ok, "")?;
+    write_string_list(chunk, tags.iter())
+}
+
+fn fetch_files_with_tag<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let tag = read_string(chunk)?;
+
+    let tagged: Vec<String> = {
+        let files = state.files.lock().unwrap();
+        files
+            .with_tag(&tag)
+            .filter(|file| files.get(file).is_some_and(|meta| meta.visible_to(info)))
+            .cloned()
+            .collect()
+    };
+
+    respond(chunk, info, Status::Ok, "")?;
+    write_file_list(chunk, tagged.into_iter())
+}
+
+fn handshake<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &mut ConnectionInfo,
+    seal: Option<&SealKey>,
+) -> io::Result<()> {
+    chunk.read_stream(1)?;
+    let client_version = chunk.slice(1)[0];
+
+    info.version = client_version.clamp(version::V1, state.max_version);
+    chunk.write_and_send(&[info.version])?;
+
+    if info.version >= version::V5 {
+        write_capabilities(chunk, &capabilities(&state, info, seal))?;
+    }
+    Ok(())
+}
+
+/// What this server offers the client on the other end of `info`, whose connection
+/// is encrypted with `seal`.
+fn capabilities(
+    state: &ServerState,
+    info: &ConnectionInfo,
+    seal: Option<&SealKey>,
+) -> Capabilities {
+    let mut features = feature::STREAM_UPLOAD | feature::BATCH_GET | feature::INDEX_VERSION;
+    if !info.read_only {
+        features |= feature::WRITE | feature::DELETE;
+    }
+    if state.auth.is_some() && info.require_auth {
+        features |= feature::AUTH;
+    }
+    if state.dir_sync.is_some() {
+        features |= feature::DURABLE;
+    }
+    if state.compress_storage {
+        features |= feature::COMPRESSED_STORAGE;
+    }
+
+    let allowed = |op| op_enabled(state, op) && !(info.read_only && is_write_op(op));
+    let ops = (0..64)
+        .filter(|&op| allowed(op))
+        .fold(0, |ops, op| ops | 1 << op);
+
+    // Features that need a disabled op aren't offered either
+    for (op, feature) in [
+        (op::ADD_FILE, feature::WRITE),
+        (op::DELETE_FILES, feature::DELETE),
+        (op::ADD_FILE_STREAM, feature::STREAM_UPLOAD),
+        (op::GET_FILES, feature::BATCH_GET),
+        (op::INDEX_VERSION, feature::INDEX_VERSION),
+    ] {
+        if !allowed(op) {
+            features &= !feature;
+        }
+    }
 
-const SERVER_FILES: &'static str = "server_files";
-const THREAD_COUNT: usize = 8;
+    let mut capabilities = Capabilities::default();
+    capabilities.set(capability::FEATURES, features);
+    capabilities.set(capability::OPS, ops);
+    capabilities.set(capability::MAX_PAGE_SIZE, MAX_PAGE_SIZE as u64);
+    capabilities.set(capability::SERVER_TIME, unix_now());
 
-fn add_file<const N: usize>(chunk: &mut Chunk<N>, shared_files: SharedFiles) -> io::Result<()> {
-    let file_name = read_string(chunk)?;
-    let file_size = read_usize(chunk);
+    if let Some(quota) = state.quota {
+        capabilities.set(capability::QUOTA, quota);
+    }
+    if let Ok(available) = disk::available_space() {
+        capabilities.set(
+            capability::FREE_BYTES,
+            available.saturating_sub(state.disk_headroom),
+        );
+    }
+    if let Some(key) = seal {
+        capabilities.set(capability::SEAL_ROUNDS, key.rounds() as u64);
+    }
 
-    println!("Receiving file: \"{file_name}\" ({file_size} bytes)");
+    capabilities
+}
 
-    let contents = receive_file(chunk, file_size)?;
-    let file_name = Path::new(&file_name)
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
+/// Encrypt the rest of the connection once the handshake is answered, see `seal`.
+fn seal_connection<S: Transport>(
+    stream: &Sealed<S>,
+    info: &ConnectionInfo,
+    key: &SealKey,
+) -> io::Result<()> {
+    if info.version < version::V8 {
+        return Err(io::Error::other(format!(
+            "Client speaks protocol version {}, too old to encrypt",
+            info.version
+        )));
+    }
+    seal::accept(stream, key)
+}
+
+fn authenticate<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &mut ConnectionInfo,
+) -> io::Result<()> {
+    let credentials = read_bytes(chunk)?.unwrap_or_default();
+    // Don't leave the secret behind in the buffer for later ops
+    chunk.reset_zeroing();
+
+    apply_credentials(
+        info,
+        state.auth.as_deref(),
+        state.admin.as_ref(),
+        &credentials,
+    );
 
-    if let Some(contents) = contents {
-        fs::write(format!("{SERVER_FILES}/{file_name}"), contents)?;
+    // Always answered with a header, whatever the version
+    if info.authenticated {
+        write_response(chunk, Status::Ok, "")
+    } else {
+        write_response(chunk, Status::Denied, "Invalid credentials")
+    }
+}
+
+/// Record on `info` what `credentials` grant, checked against `auth` and the admin secret.
+///
+/// Servers without `auth` let everyone in.
+fn apply_credentials(
+    info: &mut ConnectionInfo,
+    auth: Option<&dyn Authenticator>,
+    admin: Option<&SharedSecretAuth>,
+    credentials: &[u8],
+) {
+    info.admin = admin.is_some_and(|admin| admin.authenticate(credentials));
+    info.authenticated = info.admin
+        || match auth {
+            Some(auth) => auth.authenticate(credentials),
+            None => true,
+        };
+    info.identity = match auth {
+        Some(auth) if info.authenticated => auth.identity(credentials),
+        _ => None,
+    };
+}
 
-        // Add filename to index
-        let mut shared_files = shared_files.lock().unwrap();
-        shared_files.insert(file_name);
+/// Why a peer's announcement of `file_count` files served from `addr` is refused, if it is.
+///
+/// Clients download from announced addresses, so only authenticated clients can
+/// announce, and only an address on the host they connect from.
+fn announce_rejection(
+    info: &ConnectionInfo,
+    addr: &str,
+    file_count: usize,
+) -> Option<(Status, String)> {
+    if !info.authenticated {
+        let msg = "Authenticate to announce files";
+        return Some((Status::Unauthenticated, msg.to_string()));
+    }
+    if file_count > MAX_ANNOUNCED_FILES {
+        let msg = format!("At most {MAX_ANNOUNCED_FILES} files can be announced at once");
+        return Some((Status::InvalidRequest, msg));
     }
 
-    println!("File received successfully!");
-    Ok(())
+    let announced = match addr.parse::<SocketAddr>() {
+        Ok(announced) if announced.port() != 0 && !announced.ip().is_unspecified() => announced,
+        _ => {
+            let msg = format!("'{addr}' is not an IP address and port");
+            return Some((Status::InvalidRequest, msg));
+        }
+    };
+    match info.peer {
+        Some(peer) if peer.ip().to_canonical() != announced.ip().to_canonical() => {
+            let msg = "Peers can only announce their own address";
+            Some((Status::Denied, msg.to_string()))
+        }
+        _ => None,
+    }
 }
 
-fn get_file<const N: usize>(chunk: &mut Chunk<N>) -> io::Result<()> {
-    let file_name = format!("{SERVER_FILES}/{}", read_string(chunk)?);
+fn announce<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let addr = read_string(chunk)?;
+    let files = read_file_list(chunk)?;
+    let hashes = if info.version >= version::V9 {
+        read_string_list(chunk)?
+    } else {
+        Vec::new()
+    };
 
-    if !Path::new(&file_name).exists() {
-        write_usize(chunk, 0)?;
-        return Ok(());
+    let rejection = announce_rejection(info, &addr, files.len()).or_else(|| {
+        (!hashes.is_empty() && hashes.len() != files.len()).then(|| {
+            let msg = "Every announced file needs a hash, empty if unknown";
+            (Status::InvalidRequest, msg.to_string())
+        })
+    });
+    if let Some((status, reason)) = rejection {
+        log!("Rejected announcement from {addr}: {reason}");
+        return respond(chunk, info, status, &reason);
     }
 
-    println!("Sending file: \"{file_name}\"");
+    log!("Peer {addr} announced {} files", files.len());
+
+    let mut hashes = hashes.into_iter();
+    let files = files
+        .into_iter()
+        .map(|file| (file, hashes.next().filter(|hash| !hash.is_empty())))
+        .collect();
+
+    state.peers.lock().unwrap().announce(addr, files);
+    respond(chunk, info, Status::Ok, "")
+}
+
+fn global_list<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let sources = {
+        let files = state.files.lock().unwrap();
+        let mut sources = state.peers.lock().unwrap().sources(
+            files
+                .entries()
+                .map(|(file, meta)| (file, meta.hash.as_deref())),
+        );
+
+        // Files only peers have are always listed
+        sources.retain(|file, _| files.get(file).is_none_or(|meta| meta.visible_to(info)));
+        sources
+    };
 
-    send_file(chunk, &file_name)?;
+    respond(chunk, info, Status::Ok, "")?;
+    write_usize(chunk, sources.len())?;
 
-    println!("File sent successfully!");
+    for (file, addrs) in sources {
+        write_string(chunk, &file)?;
+        write_string_list(chunk, addrs.iter())?;
+    }
     Ok(())
 }
 
-fn fetch_files<const N: usize>(chunk: &mut Chunk<N>, shared_files: SharedFiles) -> io::Result<()> {
-    let shared_files = shared_files.lock().unwrap();
-    write_usize(chunk, shared_files.len())?;
+/// Holds the server's one speed test slot until dropped.
+struct SpeedtestSlot<'a>(&'a AtomicBool);
+
+impl Drop for SpeedtestSlot<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// The slot to run a speed test of `size` bytes in, or why it can't run.
+///
+/// Only one runs at a time, so the server can't be turned into a bandwidth burner.
+fn claim_speedtest(
+    state: &ServerState,
+    size: usize,
+) -> Result<SpeedtestSlot<'_>, (Status, String)> {
+    let Some(running) = &state.speedtest else {
+        return Err((Status::Denied, "Speed tests are turned off".to_string()));
+    };
+    if size as u64 > MAX_SPEEDTEST_BYTES {
+        let max = human_bytes(MAX_SPEEDTEST_BYTES);
+        return Err((
+            Status::InvalidRequest,
+            format!("Speed tests move at most {max}"),
+        ));
+    }
+    if running.swap(true, Ordering::Acquire) {
+        return Err((Status::Busy, "Another speed test is running".to_string()));
+    }
+    Ok(SpeedtestSlot(running))
+}
+
+fn speedtest_download<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let size = read_usize(chunk)?;
+    let _slot = match claim_speedtest(&state, size) {
+        Ok(slot) => slot,
+        Err((status, msg)) => return respond(chunk, info, status, &msg),
+    };
+
+    log!("Sending {} for a speed test", human_bytes(size as u64));
+    respond(chunk, info, Status::Ok, "")?;
+
+    // Through the same loop as a download, only the file is left out
+    let started = Instant::now();
+    send_reader(chunk, io::repeat(SPEEDTEST_BYTE).take(size as u64), size)?;
+    write_usize(chunk, started.elapsed().as_micros() as usize)
+}
+
+fn speedtest_upload<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let size = read_usize(chunk)?;
+    let _slot = match claim_speedtest(&state, size) {
+        Ok(slot) => slot,
+        Err((status, msg)) => return respond(chunk, info, status, &msg),
+    };
+
+    log!("Receiving {} for a speed test", human_bytes(size as u64));
+    respond(chunk, info, Status::Ok, "")?;
+
+    let started = Instant::now();
+    if read_usize(chunk)? != size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Speed test size changed after it was announced",
+        ));
+    }
+    discard(chunk, size)?;
+    write_usize(chunk, started.elapsed().as_micros() as usize)
+}
+
+fn health<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let stored_bytes = state.files.lock().unwrap().stored_bytes();
+    let available = match disk::available_space() {
+        Ok(available) => Some(available),
+        Err(err) => {
+            log_err!("Could not check free space: {err}");
+            None
+        }
+    };
+
+    let reason = degraded_reason(stored_bytes, state.quota, available, state.disk_headroom)
+        .unwrap_or_default();
+
+    respond(chunk, info, Status::Ok, "")?;
+
+    // 0 is healthy, anything else is degraded with a reason
+    chunk.write_and_send(&[!reason.is_empty() as u8])?;
+    write_string(chunk, &reason)
+}
+
+/// Why the server is degraded, if it is, `available` being the free disk space if known.
+///
+/// Storage near the quota and a disk without `headroom` left both count, either way
+/// uploads are about to be refused.
+fn degraded_reason(
+    stored_bytes: u64,
+    quota: Option<u64>,
+    available: Option<u64>,
+    headroom: u64,
+) -> Option<String> {
+    let mut problems = Vec::new();
 
-    for file in shared_files.iter() {
-        write_string(chunk, file)?;
+    if let Some(quota) =
+        quota.filter(|&quota| stored_bytes as f64 >= quota as f64 * QUOTA_WARN_RATIO)
+    {
+        problems.push(format!(
+            "Storage near quota ({} of {})",
+            human_bytes(stored_bytes),
+            human_bytes(quota)
+        ));
+    }
+    if let Some(available) = available.filter(|&available| available <= headroom) {
+        problems.push(format!(
+            "Disk almost full ({} free, {} kept free)",
+            human_bytes(available),
+            human_bytes(headroom)
+        ));
+    }
+
+    (!problems.is_empty()).then(|| problems.join(", "))
+}
+
+fn stats<const N: usize, S: Transport>(
+    chunk: &mut Chunk<N, S>,
+    state: SharedState,
+    info: &ConnectionInfo,
+) -> io::Result<()> {
+    let stats = collect_stats(&state);
+
+    respond(chunk, info, Status::Ok, "")?;
+    write_usize(chunk, stats.len())?;
+
+    for (name, value) in stats {
+        write_string(chunk, &name)?;
+        write_usize(chunk, value)?;
     }
     Ok(())
 }
 
+/// Everything the stats op reports, also served by `--metrics`.
+fn collect_stats(state: &ServerState) -> Vec<(String, usize)> {
+    let mut stats = {
+        let files = state.files.lock().unwrap();
+        vec![
+            ("files".to_string(), files.len()),
+            ("stored_bytes".to_string(), files.stored_bytes() as usize),
+            ("durable".to_string(), state.dir_sync.is_some() as usize),
+        ]
+    };
+
+    // Left out rather than reported as 0 when it can't be read
+    if let Ok(available) = disk::available_space() {
+        stats.push((
+            "free_bytes".to_string(),
+            available.min(usize::MAX as u64) as usize,
+        ));
+    }
+
+    if let Some(mirror) = &state.mirror {
+        stats.push(("mirror_pending".to_string(), mirror.pending()));
+    }
+
+    stats.extend(state.timings.stats());
+    stats.extend(progress::stats());
+    stats.extend(metrics::stats());
+    stats
+}
+
+/// Counts control ops per second on a single connection.
+struct ControlOpMonitor {
+    rate: u32,
+    window_start: Instant,
+    count: u32,
+    /// Consecutive windows that went over the rate.
+    abusive_windows: usize,
+}
+
+impl ControlOpMonitor {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            window_start: Instant::now(),
+            count: 0,
+            abusive_windows: 0,
+        }
+    }
+
+    /// Record a control op, returning `false` once the client should be disconnected.
+    fn record(&mut self) -> bool {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            if self.count > self.rate {
+                self.abusive_windows += 1;
+            } else {
+                self.abusive_windows = 0;
+            }
+
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+
+        self.count += 1;
+
+        if self.count > self.rate {
+            thread::sleep(CONTROL_OP_THROTTLE);
+        }
+
+        self.abusive_windows < CONTROL_OP_MAX_ABUSE
+    }
+}
+
+/// Ops that may be used before authenticating.
+#[inline]
+fn is_public_op(op: u8) -> bool {
+    matches!(
+        op,
+        op::KEEP_ALIVE | op::HEALTH | op::HANDSHAKE | op::AUTHENTICATE | op::DISCONNECT
+    )
+}
+
+/// Whether `op` is allowed by `--enable-ops` and `--disable-ops`.
+///
+/// Ops needed to connect at all can't be turned off.
+#[inline]
+fn op_enabled(state: &ServerState, op: u8) -> bool {
+    is_public_op(op) || op >= 64 || state.disabled_ops & (1 << op) == 0
+}
+
+/// Ops that change the stored files, refused on read-only listeners.
+#[inline]
+fn is_write_op(op: u8) -> bool {
+    matches!(
+        op,
+        op::ADD_FILE
+            | op::ADD_FILE_STREAM
+            | op::SET_TAGS
+            | op::SET_METADATA
+            | op::COPY_FILE
+            | op::SET_VISIBILITY
+            | op::DELETE_FILES
+            | op::MULTIPART_INITIATE
+            | op::MULTIPART_PART
+            | op::MULTIPART_COMPLETE
+            | op::MULTIPART_ABORT
+            | op::RESET_DOWNLOADS
+    )
+}
+
+#[inline]
+fn is_control_op(op: u8) -> bool {
+    matches!(op, op::KEEP_ALIVE | op::STATS | op::HEALTH | op::HANDSHAKE)
+}
+
+/// How long any one read or write may wait while handling `op`.
+///
+/// Between requests the connection waits up to `idle_timeout` instead.
+fn op_deadline(state: &ServerState, op: u8) -> Duration {
+    match op {
+        op::HANDSHAKE => HANDSHAKE_DEADLINE,
+        op if is_control_op(op) => CONTROL_DEADLINE,
+        _ => state.transfer_timeout,
+    }
+}
+
 // Server impl
-fn handle_client(stream: TcpStream, shared_files: SharedFiles) -> io::Result<()> {
-    let mut chunk = Chunk::<1024>::new(&stream);
+fn handle_client<S: Transport>(
+    stream: S,
+    state: SharedState,
+    mut info: ConnectionInfo,
+    seal: Option<Arc<SealKey>>,
+) -> io::Result<()> {
+    // Sent as is unless the listener has a passphrase, see `seal_connection`
+    let stream = Sealed::new(stream);
+    let mut chunk = Chunk::<1024, Sealed<S>>::new(&stream);
+    let mut monitor = ControlOpMonitor::new(state.control_op_rate);
+    let mut last_request = Instant::now();
+
+    if let Some(peer) = info.peer {
+        events::emit(ServerEvent::ClientConnected(peer));
+    }
+    let peer = info.peer;
+    // Each op's state is moved into its handler, so timings are recorded through this one
+    let timings_state = state.clone();
 
     // Read file_name buffer size
-    chunk.run_loop(shared_files, |chunk, shared_files| {
+    let result = chunk.run_loop(state, |chunk, state| {
         chunk.read_stream(1)?;
-        match u8::from_le_bytes(chunk.to_byte_array::<1>()) {
-            0 => add_file(chunk, shared_files)?,
-            1 => get_file(chunk)?,
-            2 => fetch_files(chunk, shared_files)?,
+        let op = u8::from_le_bytes(chunk.to_byte_array::<1>());
+        chunk.trace(format_args!("read op {op}"));
 
-            // Keep alive
-            3 => {}
+        // Nothing is understood in the clear except the handshake that starts encryption
+        if seal.is_some() && !stream.is_sealed() && op != op::HANDSHAKE {
+            write_response(
+                chunk,
+                Status::Denied,
+                "Server only accepts encrypted connections",
+            )?;
+            return Err(io::Error::other("Client didn't encrypt the connection"));
+        }
 
-            n => panic!("Unknown op byte {n}"),
+        // Keep alives hold the connection open, but don't count as activity
+        if op != op::KEEP_ALIVE {
+            last_request = Instant::now();
+        } else if let Some(timeout) = state.idle_timeout {
+            if last_request.elapsed() >= timeout {
+                respond(chunk, &info, Status::SessionExpired, "Session expired")?;
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Client session expired",
+                ));
+            }
         }
 
-        Ok(())
-    })
-}
+        if is_control_op(op) && !monitor.record() {
+            let peer = stream.peer();
+            log_err!("Warning: disconnecting {peer}, too many control ops");
+
+            metrics::rejected(Rejection::RateLimited);
+            write_response(chunk, Status::RateLimited, "Too many requests")?;
+            return Err(io::Error::other("Client was rate limited"));
+        }
+
+        if state.auth.is_some() && info.require_auth && !info.authenticated && !is_public_op(op) {
+            metrics::rejected(Rejection::Unauthenticated);
+            write_response(chunk, Status::Unauthenticated, "Authentication required")?;
+            return Err(io::Error::other("Client is not authenticated"));
+        }
+
+        // The rest of the request isn't read, so the connection can't carry on
+        if info.read_only && is_write_op(op) {
+            metrics::rejected(Rejection::ReadOnly);
+            write_response(chunk, Status::Denied, "Server is read-only on this address")?;
+            return Err(io::Error::other(
+                "Client tried to write to a read-only listener",
+            ));
+        }
+
+        if !op_enabled(&state, op) {
+            metrics::rejected(Rejection::DisabledOp);
+            let name = op::name(op).unwrap_or("unknown");
+            write_response(
+                chunk,
+                Status::Denied,
+                &format!("'{name}' is not permitted on this server"),
+            )?;
+            return Err(io::Error::other(format!(
+                "Client tried the disabled op {name}"
+            )));
+        }
+
+        // Timed here rather than in each handler, the response is written by the time they return
+        let started = Instant::now();
+        chunk.set_deadline(Some(op_deadline(&state, op)))?;
+        connections::set_op(Some(op));
+
+        match op {
+            op::ADD_FILE => add_file(chunk, state, &info)?,
+            op::GET_FILE => get_file(chunk, state, &info)?,
+            op::FETCH_FILES => fetch_files(chunk, state, &info)?,
+            op::INDEX_VERSION => index_version(chunk, state, &info)?,
+            op::LIST_OLDER_THAN => list_older_than(chunk, state, &info)?,
+            op::LIST_CONNECTIONS => list_connections(chunk, state, &info)?,
+            op::KICK_CONNECTION => kick_connection(chunk, &info)?,
+            op::KEEP_ALIVE => {}
+            op::STATS => stats(chunk, state, &info)?,
+            op::SET_TAGS => set_tags(chunk, state, &info)?,
+            op::GET_TAGS => get_tags(chunk, state, &info)?,
+            op::FETCH_FILES_WITH_TAG => fetch_files_with_tag(chunk, state, &info)?,
+            op::ANNOUNCE => announce(chunk, state, &info)?,
+            op::GLOBAL_LIST => global_list(chunk, state, &info)?,
+            op::HEALTH => health(chunk, state, &info)?,
+            op::HANDSHAKE => {
+                handshake(chunk, state.clone(), &mut info, seal.as_deref())?;
+                if let Some(key) = &seal {
+                    seal_connection(&stream, &info, key)?;
+                }
+            }
+            op::FETCH_FILE_SIZES => fetch_file_sizes(chunk, state, &info)?,
+            op::AUTHENTICATE => authenticate(chunk, state, &mut info)?,
+            op::STAT => stat(chunk, state, &info)?,
+            op::SET_METADATA => set_metadata(chunk, state, &info)?,
+            op::FIND_BY_HASH => find_by_hash(chunk, state, &info)?,
+            op::COPY_FILE => copy_file(chunk, state, &info)?,
+            op::SET_VISIBILITY => set_visibility(chunk, state, &info)?,
+            op::FETCH_OWN_FILES => fetch_own_files(chunk, state, &info)?,
+            op::GET_FILE_IF_CHANGED => get_file_if_changed(chunk, state, &info)?,
+            op::DELETE_FILES => delete_files(chunk, state, &info)?,
+            op::LIST_PAGE => list_page(chunk, state, &info)?,
+            op::ADD_FILE_STREAM => add_file_stream(chunk, state, &info)?,
+            op::EXPORT_INDEX => export_index(chunk, state, &info)?,
+            op::FOLLOW_LOG => follow_log(chunk, &info)?,
+            op::READ_HEAD => read_head(chunk, state, &info)?,
+            op::RESET_DOWNLOADS => reset_downloads(chunk, state, &info)?,
+            op::TREE => tree(chunk, state, &info)?,
+            op::GET_FILES => get_files(chunk, state, &info)?,
+            op::CHECK_INDEX => check_index(chunk, state, &info)?,
+            op::SPEEDTEST_DOWNLOAD => speedtest_download(chunk, state, &info)?,
+            op::SPEEDTEST_UPLOAD => speedtest_upload(chunk, state, &info)?,
+            op::MULTIPART_INITIATE => multipart_initiate(chunk, state, &info)?,
+            op::MULTIPART_PART => multipart_part(chunk, state, &info)?,
+            op::MULTIPART_COMPLETE => multipart_complete(chunk, state, &info)?,
+            op::MULTIPART_ABORT => multipart_abort(chunk, state, &info)?,
+            op::DISCONNECT => return Ok(ControlFlow::Break(())),
+
+            // The rest of the request can't be parsed, so give up on the connection
+            n => {
+                respond(
+                    chunk,
+                    &info,
+                    Status::InvalidRequest,
+                    &format!("Unknown op {n}"),
+                )?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown op byte {n}"),
+                ));
+            }
+        }
 
-fn load_all_files(shared_files: &mut SharedFiles) {
-    let paths = fs::read_dir(SERVER_FILES).unwrap();
-    let mut shared_files = shared_files.lock().unwrap();
+        connections::set_op(None);
+        chunk.set_deadline(timings_state.idle_timeout)?;
+        let elapsed = started.elapsed();
+        timings_state.timings.record(op, elapsed);
+        events::emit(ServerEvent::RequestHandled {
+            op,
+            peer: info.peer,
+            elapsed,
+        });
 
-    paths.for_each(|p| _ = shared_files.insert(p.unwrap().file_name().into_string().unwrap()));
+        Ok(ControlFlow::Continue(()))
+    });
+
+    if let Some(peer) = peer {
+        events::emit(ServerEvent::ClientDisconnected(peer));
+    }
+    result
 }
 
 fn main() -> io::Result<()> {
-    let mut shared_files = Arc::new(Mutex::new(HashSet::new()));
+    let mut config = parse_args()?;
+
+    if config.no_write {
+        check_no_write(&config)?;
+    }
+
+    if let Some(options) = config.check {
+        let problems = run_check(config.compress_index, options)?;
+        std::process::exit(if problems > 0 { 1 } else { 0 });
+    }
+
+    if let Some(path) = &config.wire_trace {
+        enable_wire_trace(path)?;
+    }
+
+    if !config.no_write {
+        temp::clean_startup()?;
+    }
+
+    let auth = config.take_auth();
+    let state = Arc::new(ServerState {
+        compress_storage: config.compress_storage,
+        quota: config.quota,
+        quarantine: config.quarantine,
+        max_version: config.max_version,
+        control_op_rate: config.control_op_rate,
+        idle_timeout: config.idle_timeout,
+        transfer_timeout: config.transfer_timeout,
+        dir_sync: config.durable.then(DirSyncer::default),
+        disk_headroom: config.disk_headroom,
+        disabled_ops: config.disabled_ops,
+        compression: Compression::new(config.compression_level),
+        allow: config.allow,
+        auth,
+        admin: config.admin_secret.map(SharedSecretAuth::new),
+        files: Mutex::new(FileIndex::load(
+            config.compress_index,
+            config.no_write,
+            config.index_sync,
+        )?),
+        peers: Mutex::new(PeerRegistry::default()),
+        mirror: config.mirror.map(Mirror::new),
+        timings: OpTimings::default(),
+        speedtest: config.speedtest.then(AtomicBool::default),
+        multipart: Uploads::new(config.multipart_timeout),
+    });
+
+    if state.mirror.is_some() {
+        mirror::spawn(state.clone());
+    }
+
+    if let IndexSync::Periodic(interval) = config.index_sync {
+        let state = state.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(err) = state.files.lock().unwrap().flush() {
+                log_err!("Could not save the index: {err}");
+            }
+        });
+    }
+
+    if !config.no_write {
+        let state = state.clone();
+        let max_age = config.temp_max_age;
+        // Often enough that leftovers last at most a quarter longer than allowed
+        let interval = (state.multipart.timeout().min(max_age) / 4).max(Duration::from_secs(1));
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            state.multipart.sweep();
+            temp::sweep(max_age);
+        });
+    }
+
+    if let Some(ttl) = config.expire_after {
+        let state = state.clone();
+        log!(
+            "Deleting files nobody has written to for {}",
+            human_duration(ttl)
+        );
+        // Files outlive their TTL by at most a quarter, checked at least hourly
+        let interval = (ttl / 4).clamp(Duration::from_secs(1), Duration::from_secs(60 * 60));
+        thread::spawn(move || loop {
+            expiry::sweep(&state.files, ttl);
+            thread::sleep(interval);
+        });
+    }
+
+    events::on_event(metrics::observe);
+    if let Some(addr) = &config.metrics {
+        serve_metrics(addr, state.clone())?;
+    }
+
+    if config.access_log {
+        events::on_event(logs::access_log);
+    }
+
+    let mut listeners = config.listeners;
+    #[cfg(unix)]
+    let mut unix = config.unix.map(bind_unix).transpose()?;
+    #[cfg(not(unix))]
+    let unix: Option<()> = None;
+
+    // Serving only the Unix socket is allowed, but otherwise the default address is used
+    if listeners.is_empty() && unix.is_none() {
+        listeners.push(Listener::new(SERVER_ADDR.to_string()));
+    }
+
+    {
+        #[cfg(unix)]
+        let unix = unix.as_mut().map(|(_, listener)| listener);
+        let mut every: Vec<&mut Listener> = listeners.iter_mut().chain(unix).collect();
+
+        if config.no_write {
+            for listener in &mut every {
+                listener.read_only = true;
+            }
+        }
+        derive_seal_keys(&mut every, config.passphrase.as_deref());
+    }
+
+    // Bind everything up front, so a bad address stops the server before it serves anyone
+    let bound = listeners
+        .into_iter()
+        .map(|listener| Ok((TcpListener::bind(&listener.addr)?, listener)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    shutdown::install();
+
+    let pool = ThreadPool::with_options(
+        THREAD_COUNT,
+        PoolOptions {
+            log: |line| logs::publish(false, line),
+            // Each job is a whole client connection, which can rightly go on for hours
+            stuck_after: None,
+        },
+    );
+    let limiter = config
+        .accept_rate
+        .map(|rate| Mutex::new(RateLimiter::new(rate)));
+
+    thread::scope(|scope| {
+        // Each accept loop is woken with a connection of its own, and ends on seeing the flag
+        scope.spawn(|| {
+            shutdown::wait();
+            log!("Shutting down...");
+
+            for (socket, _) in &bound {
+                _ = socket.local_addr().and_then(wake_tcp);
+            }
+            #[cfg(unix)]
+            if let Some((_, listener)) = &unix {
+                _ = std::os::unix::net::UnixStream::connect(&listener.addr);
+            }
+        });
+
+        for (socket, listener) in &bound {
+            let (pool, limiter, state) = (&pool, &limiter, &state);
+            log!("Listening for connections on {}...", listener.addr);
+
+            scope.spawn(move || accept_loop(socket, listener, pool, limiter.as_ref(), state));
+        }
+
+        #[cfg(unix)]
+        if let Some((socket, listener)) = &unix {
+            let (pool, limiter, state) = (&pool, &limiter, &state);
+            log!("Listening for connections on {}...", listener.addr);
+
+            scope.spawn(move || accept_unix(socket, listener, pool, limiter.as_ref(), state));
+        }
+    });
+
+    // Nothing new is accepted by now, so whoever is still connected can be let go
+    drop(bound);
+    connections::kick_all();
+    if let Err(err) = state.files.lock().unwrap().flush() {
+        log_err!("Could not save the index: {err}");
+    }
+    Ok(())
+}
+
+/// Give each listener in `listeners` the key for its passphrase, or for `default` if it
+/// has none of its own.
+///
+/// Listeners with the same passphrase share a key, since each one takes a while to derive.
+fn derive_seal_keys(listeners: &mut [&mut Listener], default: Option<&str>) {
+    let mut keys: HashMap<String, Arc<SealKey>> = HashMap::new();
+
+    for listener in listeners {
+        let Some(passphrase) = listener.passphrase.as_deref().or(default) else {
+            continue;
+        };
+        let key = keys.entry(passphrase.to_string()).or_insert_with(|| {
+            log!("Deriving the encryption key for {}...", listener.addr);
+            Arc::new(SealKey::new(passphrase, seal::DEFAULT_ROUNDS))
+        });
+        listener.seal = Some(key.clone());
+    }
+}
+
+/// Connect to a listener bound to `addr`, so a blocked accept returns.
+fn wake_tcp(mut addr: SocketAddr) -> io::Result<()> {
+    // Bound to every interface, and reachable on the loopback one
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    TcpStream::connect_timeout(&addr, Duration::from_secs(1)).map(drop)
+}
+
+/// Check the saved index against the stored files for `--check`, printing a JSON
+/// summary. Returns the number of problems found, repaired or not.
+fn run_check(compress_index: bool, options: CheckOptions) -> io::Result<usize> {
+    let files = Mutex::new(FileIndex::load_saved(compress_index)?);
+    let report = check::check(&files, options)?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    eprintln!(
+        "Checked {} files: {} missing, {} orphaned, {} resized, {} corrupt{}",
+        report.checked,
+        report.missing.len(),
+        report.orphaned.len(),
+        report.resized.len(),
+        report.corrupt.len(),
+        if report.repaired { ", repaired" } else { "" }
+    );
+    Ok(report.problems())
+}
+
+#[cfg(feature = "metrics")]
+fn serve_metrics(addr: &str, state: SharedState) -> io::Result<()> {
+    metrics::serve(addr, move || collect_stats(&state))
+}
+
+#[cfg(not(feature = "metrics"))]
+fn serve_metrics(_addr: &str, _state: SharedState) -> io::Result<()> {
+    Err(invalid_arg(
+        "--metrics needs the server built with the metrics feature".to_string(),
+    ))
+}
+
+/// Refuse options that would have `--no-write` write after all.
+fn check_no_write(config: &Config) -> io::Result<()> {
+    if config.mirror.is_some() {
+        return Err(invalid_arg(
+            "--mirror can't be used with --no-write".to_string(),
+        ));
+    }
+    if config.durable {
+        return Err(invalid_arg(
+            "--durable can't be used with --no-write".to_string(),
+        ));
+    }
+    if config.check.is_some_and(|options| options.repair) {
+        return Err(invalid_arg(
+            "--repair can't be used with --no-write".to_string(),
+        ));
+    }
+
+    let mut paths = Vec::new();
+    if let Some(path) = &config.wire_trace {
+        paths.push(("--wire-trace", Path::new(path)));
+    }
+    #[cfg(unix)]
+    if let Some(path) = &config.unix {
+        paths.push(("--unix", path.as_path()));
+    }
+
+    // Files outside the storage folder are fine, it is the mount that can't be written to
+    for (arg, path) in paths {
+        if in_storage(path)? {
+            return Err(invalid_arg(format!(
+                "{arg} '{}' is inside '{SERVER_FILES}', which --no-write keeps untouched",
+                path.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `path` is in `SERVER_FILES`, whether or not it exists yet.
+fn in_storage(path: &Path) -> io::Result<bool> {
+    let storage = fs::canonicalize(SERVER_FILES)?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Ok(fs::canonicalize(parent)?.starts_with(storage))
+}
 
-    load_all_files(&mut shared_files);
+/// Hand every connection to `socket` to the pool, until the server shuts down.
+fn accept_loop(
+    socket: &TcpListener,
+    listener: &Listener,
+    pool: &ThreadPool,
+    limiter: Option<&Mutex<RateLimiter>>,
+    state: &SharedState,
+) {
+    for stream in socket.incoming() {
+        if shutdown::requested() {
+            break;
+        }
 
-    let listener = TcpListener::bind(SERVER_ADDR)?;
-    let pool = ThreadPool::new(THREAD_COUNT);
-    println!("Listening for connections...");
+        // Shared between listeners, so the rate covers the whole server
+        if let Some(limiter) = limiter {
+            if limiter.lock().unwrap().acquire() {
+                log!("Throttling new connections");
+            }
+        }
 
-    for stream in listener.incoming() {
         if let Ok(stream) = stream {
-            let files = shared_files.clone();
-            pool.execute(move || {
-                handle_client(stream, files).unwrap_or_else(|error| {
-                    eprintln!("Client Error: {error}");
-                })
-            });
+            // Dropping the stream closes it before a single byte is exchanged
+            if !state.allow.is_empty() {
+                match stream.peer_addr() {
+                    Ok(peer) if state.allow.iter().any(|range| range.contains(peer.ip())) => {}
+                    peer => {
+                        let peer = peer.map(|peer| peer.to_string()).unwrap_or_default();
+                        log_err!("Warning: refused connection from {peer}, not in --allow-cidr");
+                        continue;
+                    }
+                }
+            }
+
+            // Clients that stop sending anything at all, even keep alives, are dropped as well
+            if let Err(err) = stream.set_read_timeout(state.idle_timeout) {
+                log_err!("Connection failed: {err}");
+                continue;
+            }
+
+            let mut info = listener.connection_info();
+            info.peer = stream.peer_addr().ok();
+            serve(pool, stream, state.clone(), info, listener.seal.clone());
         } else {
-            eprintln!("Connection failed!");
+            log_err!("Connection failed!");
         }
     }
+}
 
-    Ok(())
+/// Bind a Unix socket at `path`, replacing one left behind by a previous run.
+#[cfg(unix)]
+fn bind_unix(path: PathBuf) -> io::Result<(UnixListener, Listener)> {
+    // Binding fails if the path exists, and nothing cleans it up after a crash
+    if fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        fs::remove_file(&path)?;
+    }
+
+    let socket = UnixListener::bind(&path)?;
+    Ok((socket, Listener::new(path.display().to_string())))
+}
+
+/// Like `accept_loop`, for the Unix socket.
+///
+/// Anyone who can open the socket is on this machine, so `--allow-cidr` doesn't apply.
+#[cfg(unix)]
+fn accept_unix(
+    socket: &UnixListener,
+    listener: &Listener,
+    pool: &ThreadPool,
+    limiter: Option<&Mutex<RateLimiter>>,
+    state: &SharedState,
+) {
+    for stream in socket.incoming() {
+        if shutdown::requested() {
+            break;
+        }
+
+        if let Some(limiter) = limiter {
+            if limiter.lock().unwrap().acquire() {
+                log!("Throttling new connections");
+            }
+        }
+
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = stream.set_read_timeout(state.idle_timeout) {
+                    log_err!("Connection failed: {err}");
+                    continue;
+                }
+
+                serve(
+                    pool,
+                    stream,
+                    state.clone(),
+                    listener.connection_info(),
+                    listener.seal.clone(),
+                );
+            }
+            Err(err) => log_err!("Connection failed: {err}"),
+        }
+    }
+}
+
+/// Run `handle_client` for `stream` on the pool.
+fn serve<S: Kickable + Send + 'static>(
+    pool: &ThreadPool,
+    stream: S,
+    state: SharedState,
+    info: ConnectionInfo,
+    seal: Option<Arc<SealKey>>,
+) {
+    let registration = match connections::register(&stream) {
+        Ok(registration) => registration,
+        Err(err) => {
+            log_err!("Connection failed: {err}");
+            return;
+        }
+    };
+
+    let mut connection = ConnectionGuard::queued();
+    pool.execute(move || {
+        connection.start();
+        registration.enter();
+        match handle_client(registration.track(stream), state, info, seal) {
+            Ok(()) => {}
+            // Already logged where the upload was cut off
+            Err(error) if error.kind() == io::ErrorKind::ConnectionAborted => {}
+            // Already logged by the admin's connection
+            Err(_) if registration.was_kicked() => {}
+            Err(error) => log_err!("Client Error: {error}"),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> io::Result<Config> {
+        parse_args_from(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn is_disabled(config: &Config, op: u8) -> bool {
+        config.disabled_ops & (1 << op) != 0
+    }
+
+    #[test]
+    fn enable_ops_can_be_repeated() {
+        let config = parse(&["--enable-ops", "get_file", "--enable-ops", "stat,add_file"]).unwrap();
+
+        for op in [op::GET_FILE, op::STAT, op::ADD_FILE] {
+            assert!(!is_disabled(&config, op), "{op}");
+        }
+        assert!(is_disabled(&config, op::FETCH_FILES));
+    }
+
+    #[test]
+    fn disable_ops_apply_on_top_of_enable_ops() {
+        let config = parse(&["--disable-ops", "stat", "--enable-ops", "get_file,stat"]).unwrap();
+
+        assert!(!is_disabled(&config, op::GET_FILE));
+        assert!(is_disabled(&config, op::STAT));
+        assert!(is_disabled(&config, op::ADD_FILE));
+    }
+
+    #[test]
+    fn every_op_is_enabled_by_default() {
+        assert_eq!(parse(&[]).unwrap().disabled_ops, 0);
+        assert!(parse(&["--enable-ops", "no_such_op"]).is_err());
+    }
+
+    #[test]
+    fn listeners_are_read_from_config_tables() {
+        let file = ConfigFile::parse(
+            r#"
+            [[listener]]
+            addr = "0.0.0.0:7000"
+
+            [[listener]]
+            addr = "127.0.0.1:7001"
+            read_only = true
+            require_auth = false
+            passphrase = "local only"
+            "#,
+        )
+        .unwrap();
+
+        let [public, local] = &file.listeners[..] else {
+            panic!("expected two listeners");
+        };
+        assert_eq!(public.addr, "0.0.0.0:7000");
+        assert!(!public.read_only && public.require_auth);
+        assert_eq!(public.passphrase, None);
+
+        assert_eq!(local.addr, "127.0.0.1:7001");
+        assert!(local.read_only && !local.require_auth);
+        assert_eq!(local.passphrase.as_deref(), Some("local only"));
+    }
+
+    #[test]
+    fn config_tables_need_an_address_and_known_keys() {
+        assert!(ConfigFile::parse("[[listener]]\nread_only = true\n").is_err());
+        assert!(ConfigFile::parse("[[listener]]\naddr = \"a:1\"\ntls = true\n").is_err());
+        assert!(ConfigFile::parse("").unwrap().listeners.is_empty());
+    }
+
+    #[test]
+    fn healthy_below_quota_with_free_space() {
+        assert_eq!(degraded_reason(0, None, None, 0), None);
+        assert_eq!(degraded_reason(89, Some(100), Some(1000), 100), None);
+    }
+
+    #[test]
+    fn degraded_near_quota() {
+        let reason = degraded_reason(90, Some(100), Some(1000), 100).unwrap();
+        assert!(reason.starts_with("Storage near quota"), "{reason}");
+    }
+
+    #[test]
+    fn degraded_without_headroom() {
+        let reason = degraded_reason(0, None, Some(100), 100).unwrap();
+        assert!(reason.starts_with("Disk almost full"), "{reason}");
+    }
+
+    #[test]
+    fn degraded_reasons_are_combined() {
+        let reason = degraded_reason(100, Some(100), Some(0), 100).unwrap();
+        assert!(reason.contains("Storage near quota"), "{reason}");
+        assert!(reason.contains("Disk almost full"), "{reason}");
+    }
+
+    /// Lets in only clients presenting "let me in", as "guest".
+    struct OnlyOne;
+
+    impl Authenticator for OnlyOne {
+        fn authenticate(&self, credentials: &[u8]) -> bool {
+            credentials == b"let me in"
+        }
+
+        fn identity(&self, credentials: &[u8]) -> Option<String> {
+            self.authenticate(credentials).then(|| "guest".to_string())
+        }
+    }
+
+    #[test]
+    fn custom_authenticator_accepts_only_its_credential() {
+        let mut config = parse(&["--secret", "shared"]).unwrap();
+        config.authenticator = Some(Box::new(OnlyOne));
+        let auth = config.take_auth();
+
+        let mut info = ConnectionInfo::default();
+        for credentials in [&b"shared"[..], b"", b"let me in!"] {
+            apply_credentials(&mut info, auth.as_deref(), None, credentials);
+            assert!(!info.authenticated, "{credentials:?}");
+            assert_eq!(info.identity, None);
+        }
+
+        apply_credentials(&mut info, auth.as_deref(), None, b"let me in");
+        assert!(info.authenticated);
+        assert_eq!(info.identity.as_deref(), Some("guest"));
+    }
+
+    #[test]
+    fn shared_secret_is_the_default_authenticator() {
+        let auth = parse(&["--secret", "shared"]).unwrap().take_auth();
+
+        let mut info = ConnectionInfo::default();
+        apply_credentials(&mut info, auth.as_deref(), None, b"shared");
+        assert!(info.authenticated);
+
+        apply_credentials(&mut info, auth.as_deref(), None, b"let me in");
+        assert!(!info.authenticated);
+    }
+
+    #[test]
+    fn bare_names() {
+        assert!(is_bare_name("a.txt"));
+        assert!(is_bare_name(".hidden"));
+
+        for name in ["", ".", "..", "./a", "a/", "x/../a", "../a", "/a", "a/b"] {
+            assert!(!is_bare_name(name), "{name}");
+        }
+    }
 }
@@ -0,0 +1,255 @@
+//! Counters for the stats op and, with the `metrics` feature, a Prometheus endpoint.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use p2p_service::events::ServerEvent;
+
+/// Why a request was refused before reaching its handler, see `rejected`.
+#[derive(Clone, Copy)]
+pub enum Rejection {
+    RateLimited,
+    Unauthenticated,
+    ReadOnly,
+    DisabledOp,
+}
+
+impl Rejection {
+    const ALL: [Self; 4] = [
+        Self::RateLimited,
+        Self::Unauthenticated,
+        Self::ReadOnly,
+        Self::DisabledOp,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::RateLimited => "rate_limited",
+            Self::Unauthenticated => "unauthenticated",
+            Self::ReadOnly => "read_only",
+            Self::DisabledOp => "disabled_op",
+        }
+    }
+}
+
+static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+static BYTES_IN: AtomicUsize = AtomicUsize::new(0);
+static BYTES_OUT: AtomicUsize = AtomicUsize::new(0);
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static CONNECTIONS_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+/// Connections accepted but still waiting for a free worker.
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+static PANICS: AtomicUsize = AtomicUsize::new(0);
+static REJECTED: [AtomicUsize; Rejection::ALL.len()] = [const { AtomicUsize::new(0) }; 4];
+
+/// Count completed transfers, registered with `events::on_event`.
+pub fn observe(event: &ServerEvent) {
+    match event {
+        ServerEvent::UploadCompleted(entry, _) => {
+            UPLOADS.fetch_add(1, Ordering::Relaxed);
+            BYTES_IN.fetch_add(entry.size as usize, Ordering::Relaxed);
+        }
+        ServerEvent::DownloadCompleted { bytes, .. } => {
+            DOWNLOADS.fetch_add(1, Ordering::Relaxed);
+            BYTES_OUT.fetch_add(*bytes as usize, Ordering::Relaxed);
+        }
+        _ => {}
+    }
+}
+
+pub fn rejected(reason: Rejection) {
+    REJECTED[reason as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a connection as queued until `start` is called on it, and as active until dropped.
+pub struct ConnectionGuard {
+    started: bool,
+}
+
+impl ConnectionGuard {
+    pub fn queued() -> Self {
+        CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        QUEUED.fetch_add(1, Ordering::Relaxed);
+        Self { started: false }
+    }
+
+    /// A worker picked the connection up.
+    pub fn start(&mut self) {
+        QUEUED.fetch_sub(1, Ordering::Relaxed);
+        CONNECTIONS_ACTIVE.fetch_add(1, Ordering::Relaxed);
+        self.started = true;
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if !self.started {
+            QUEUED.fetch_sub(1, Ordering::Relaxed);
+            return;
+        }
+
+        CONNECTIONS_ACTIVE.fetch_sub(1, Ordering::Relaxed);
+        // The pool replaces the worker, so the server carries on
+        if std::thread::panicking() {
+            PANICS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The counters as stats, named the way Prometheus names them.
+pub fn stats() -> Vec<(String, usize)> {
+    let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+
+    let mut stats = vec![
+        (
+            "transfers_total{direction=\"upload\"}".to_string(),
+            load(&UPLOADS),
+        ),
+        (
+            "transfers_total{direction=\"download\"}".to_string(),
+            load(&DOWNLOADS),
+        ),
+        (
+            "transfer_bytes_total{direction=\"in\"}".to_string(),
+            load(&BYTES_IN),
+        ),
+        (
+            "transfer_bytes_total{direction=\"out\"}".to_string(),
+            load(&BYTES_OUT),
+        ),
+        ("connections_total".to_string(), load(&CONNECTIONS)),
+        ("connections_active".to_string(), load(&CONNECTIONS_ACTIVE)),
+        ("queue_depth".to_string(), load(&QUEUED)),
+        ("panics_recovered_total".to_string(), load(&PANICS)),
+    ];
+
+    for reason in Rejection::ALL {
+        stats.push((
+            format!("requests_rejected_total{{reason=\"{}\"}}", reason.label()),
+            load(&REJECTED[reason as usize]),
+        ));
+    }
+    stats
+}
+
+/// `value` made safe to put between the quotes of a stat's label.
+pub fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(feature = "metrics")]
+pub use exporter::serve;
+
+/// Stats served over HTTP in the Prometheus text format.
+#[cfg(feature = "metrics")]
+mod exporter {
+    use std::{
+        fmt::Write as _,
+        io::{self, BufRead, BufReader, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    use crate::{
+        logs::{log, log_err},
+        timing::HISTOGRAM,
+    };
+
+    /// Prefix of every metric, so they don't clash with other exporters'.
+    const PREFIX: &str = "p2p_";
+    /// Scrapers get this long to send their request.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Metrics of type `counter`, by name without labels. Anything else is a `gauge`,
+    /// apart from the histogram of `timing::HISTOGRAM`.
+    const COUNTERS: [&str; 5] = [
+        "transfers_total",
+        "transfer_bytes_total",
+        "connections_total",
+        "panics_recovered_total",
+        "requests_rejected_total",
+    ];
+
+    /// Answer `GET /metrics` on `addr` with whatever `collect` returns, on a thread of its own.
+    ///
+    /// Scrapes are answered one at a time, they are rare and cheap.
+    pub fn serve(
+        addr: &str,
+        collect: impl Fn() -> Vec<(String, usize)> + Send + 'static,
+    ) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        log!("Serving metrics on http://{addr}/metrics");
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| answer(stream, &collect));
+                if let Err(err) = result {
+                    log_err!("Metrics request failed: {err}");
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn answer(stream: TcpStream, collect: &impl Fn() -> Vec<(String, usize)>) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut request_line = String::new();
+        BufReader::new(&stream).read_line(&mut request_line)?;
+
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", render(collect())),
+            _ => (
+                "404 Not Found",
+                "Only /metrics is served here\n".to_string(),
+            ),
+        };
+
+        write!(
+            &stream,
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// Stats in the Prometheus text format, with a `TYPE` line ahead of each metric.
+    fn render(mut stats: Vec<(String, usize)>) -> String {
+        // Samples of one metric have to be next to each other, the sort keeps their order
+        stats.sort_by(|(a, _), (b, _)| family(a).cmp(family(b)));
+
+        let mut text = String::new();
+        let mut last = "";
+        for (name, value) in &stats {
+            let family = family(name);
+            if family != last {
+                let kind = if family == HISTOGRAM {
+                    "histogram"
+                } else if COUNTERS.contains(&family) {
+                    "counter"
+                } else {
+                    "gauge"
+                };
+                _ = writeln!(text, "# TYPE {PREFIX}{family} {kind}");
+                last = family;
+            }
+            _ = writeln!(text, "{PREFIX}{name} {value}");
+        }
+        text
+    }
+
+    /// The metric a stat belongs to, its name without labels or histogram suffixes.
+    fn family(name: &str) -> &str {
+        let base = name.split('{').next().unwrap_or(name);
+
+        ["_bucket", "_sum", "_count"]
+            .iter()
+            .find_map(|suffix| base.strip_suffix(suffix))
+            .filter(|family| *family == HISTOGRAM)
+            .unwrap_or(base)
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::TcpStream,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use p2p_service::{
+    authenticate, capability, export_index, fetch_file_sizes,
+    format::human_duration,
+    get_file, handshake,
+    seal::{self, Sealed},
+    version, ConnectionInfo, ProtocolError, RetryPolicy, Transport,
+};
+
+use crate::{
+    is_bare_name, is_reserved_name,
+    logs::{log, log_err},
+    store_file, Origin, SharedState, SERVER_FILES,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// The primary is retried for as long as the server runs.
+const RECONNECT: RetryPolicy = RetryPolicy {
+    max_attempts: u32::MAX,
+    base_delay: Duration::from_secs(1),
+    max_delay: Duration::from_secs(60),
+    jitter: 0.0,
+};
+
+/// What to do with files uploaded directly to a mirror.
+#[derive(Clone, Copy)]
+pub enum ConflictPolicy {
+    /// Refuse local uploads, the primary is the only source of files.
+    Reject,
+    /// Keep local uploads, the mirror will never overwrite or delete them. Which files
+    /// are local is kept in the index, see `FileMeta::local`.
+    LocalWins,
+}
+
+pub struct MirrorConfig {
+    pub primary: String,
+    /// Delete files that no longer exist on the primary.
+    pub delete: bool,
+    pub conflict: ConflictPolicy,
+    /// Shared secret for the primary, if it requires one.
+    pub secret: Option<String>,
+    /// Passphrase of the primary, if it encrypts connections.
+    pub passphrase: Option<String>,
+}
+
+impl MirrorConfig {
+    pub fn new(primary: String) -> Self {
+        Self {
+            primary,
+            delete: false,
+            conflict: ConflictPolicy::Reject,
+            secret: None,
+            passphrase: None,
+        }
+    }
+}
+
+pub struct Mirror {
+    config: MirrorConfig,
+    /// Files new or changed on the primary that have not been pulled yet.
+    pending: AtomicUsize,
+}
+
+impl Mirror {
+    pub fn new(config: MirrorConfig) -> Self {
+        Self {
+            config,
+            pending: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Whether clients may upload to, and delete from, this mirror.
+    pub fn accepts_uploads(&self) -> bool {
+        matches!(self.config.conflict, ConflictPolicy::LocalWins)
+    }
+}
+
+/// Start replicating the primary on a background thread.
+pub fn spawn(state: SharedState) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mirror = state.mirror.as_ref().expect("Server is not a mirror");
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let result = TcpStream::connect(&mirror.config.primary).and_then(|stream| {
+                log!("Mirroring {}", mirror.config.primary);
+                // Losing a working connection starts the backoff over
+                attempt = 1;
+                replicate(stream, &state, mirror)
+            });
+
+            let backoff = RECONNECT.next_delay(attempt).unwrap_or(RECONNECT.max_delay);
+            if let Err(err) = result {
+                log_err!(
+                    "Mirror Error: {err}, retrying in {}",
+                    human_duration(backoff)
+                );
+            }
+
+            thread::sleep(backoff);
+        }
+    })
+}
+
+fn replicate(stream: TcpStream, state: &SharedState, mirror: &Mirror) -> io::Result<()> {
+    let info = handshake(&stream)?;
+
+    let rounds = info.capabilities.get(capability::SEAL_ROUNDS);
+    let stream = match (rounds, &mirror.config.passphrase) {
+        (Some(rounds), Some(passphrase)) => {
+            let rounds = u32::try_from(rounds).unwrap_or(u32::MAX);
+            seal::connect(stream, passphrase, rounds)?
+        }
+        (Some(_), None) => {
+            return Err(io::Error::other(
+                "Primary encrypts connections, set its passphrase with --mirror-passphrase",
+            ))
+        }
+        (None, Some(_)) => {
+            return Err(io::Error::other(
+                "Primary doesn't encrypt connections, but --mirror-passphrase is set",
+            ))
+        }
+        // Passed through as is
+        (None, None) => Sealed::new(stream),
+    };
+
+    if let Some(secret) = &mirror.config.secret {
+        authenticate(&stream, secret.as_bytes())?;
+    }
+
+    loop {
+        sync(&stream, &info, state, mirror)?;
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Whether a name listed by the primary can be stored here as it is.
+///
+/// Anything but a bare name could write outside `SERVER_FILES`, so a primary that
+/// lists one is either broken or hostile.
+fn is_storable(file_name: &str) -> bool {
+    is_bare_name(file_name) && !is_reserved_name(file_name)
+}
+
+/// A file on the primary, its size and, when the primary tells, its hash.
+type Remote = (u64, Option<String>);
+
+/// Every file on the primary.
+///
+/// Hashes catch changes that keep a file's size, but the primary only exports them
+/// to authenticated clients. Otherwise changes are told apart by size alone.
+fn remote_files<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+) -> io::Result<HashMap<String, Remote>> {
+    if info.version >= version::V2 {
+        match export_index(stream, info) {
+            Ok(snapshot) => {
+                return Ok(snapshot
+                    .into_iter()
+                    .map(|entry| (entry.name, (entry.size, entry.hash)))
+                    .collect())
+            }
+            Err(ProtocolError::Denied(_)) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(fetch_file_sizes(stream, info)?
+        .into_iter()
+        .map(|(name, size)| (name, (size, None)))
+        .collect())
+}
+
+fn sync(
+    stream: &Sealed<TcpStream>,
+    info: &ConnectionInfo,
+    state: &SharedState,
+    mirror: &Mirror,
+) -> io::Result<()> {
+    let mut remote = remote_files(stream, info)?;
+    remote.retain(|file_name, _| {
+        let storable = is_storable(file_name);
+        if !storable {
+            log_err!(
+                "Mirror skipping \"{file_name}\", the primary listed it but it isn't a file name"
+            );
+        }
+        storable
+    });
+
+    // Files the primary has that are missing here, or changed since they were pulled
+    let outdated: Vec<String> = {
+        let files = state.files.lock().unwrap();
+        remote
+            .iter()
+            .filter(|(file_name, (size, hash))| match files.get(file_name) {
+                None => true,
+                Some(meta) if meta.local => false,
+                Some(meta) => {
+                    meta.content_size() != *size || (hash.is_some() && meta.hash != *hash)
+                }
+            })
+            .map(|(file_name, _)| file_name.clone())
+            .collect()
+    };
+
+    mirror.pending.store(outdated.len(), Ordering::Relaxed);
+
+    for file_name in outdated {
+        if let Some(contents) = get_file(stream, info, &file_name)? {
+            // Stored like an upload, so durability and compression apply, and saved right away
+            store_file(state, Origin::Primary, None, false, file_name, &contents)?;
+        }
+
+        mirror.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    if mirror.config.delete {
+        let mut files = state.files.lock().unwrap();
+        let removed: Vec<String> = files
+            .entries()
+            .filter(|(file_name, meta)| !remote.contains_key(*file_name) && !meta.local)
+            .map(|(file_name, _)| file_name.clone())
+            .collect();
+
+        for file_name in &removed {
+            log!("Mirror removing \"{file_name}\"");
+            // Already gone is as good as removed, the entry still has to go
+            match fs::remove_file(format!("{SERVER_FILES}/{file_name}")) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    log_err!("Mirror could not remove \"{file_name}\": {err}");
+                    continue;
+                }
+            }
+            files.remove(file_name);
+        }
+
+        if !removed.is_empty() {
+            files.save()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_bare_names_are_storable() {
+        assert!(is_storable("a.txt"));
+
+        for name in ["../a", "/etc/passwd", "a/b", "..", "", ".tmp", ".partial-a"] {
+            assert!(!is_storable(name), "{name}");
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Uploads sent as numbered parts over any number of connections, see `op::MULTIPART_INITIATE`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use p2p_service::{hash_reader, Status, MAX_PARTS};
+
+use crate::{
+    disk,
+    logs::{log, log_err},
+    temp,
+};
+
+/// Uploads with no activity for this long are dropped along with their parts.
+pub const DEFAULT_MULTIPART_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Why a multipart request was refused, sent back as is.
+pub type Refusal = (Status, String);
+
+struct Upload {
+    file_name: String,
+    /// Size of the whole file, as announced when the upload was started.
+    size: u64,
+    /// Who started the upload, only they can add to it.
+    owner: Option<String>,
+    /// Whether the file is stored as private once complete.
+    private: bool,
+    touched: Instant,
+    /// Size of each part received so far, by part number.
+    parts: BTreeMap<usize, u64>,
+}
+
+/// Multipart uploads in progress, their parts kept in `temp::multipart_dir`.
+///
+/// Only the parts are on disk, so uploads don't outlive the server.
+/// `temp::clean_startup` removes what a previous run left.
+pub struct Uploads {
+    timeout: Duration,
+    uploads: Mutex<HashMap<String, Upload>>,
+    started: AtomicU64,
+}
+
+impl Uploads {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            uploads: Mutex::new(HashMap::new()),
+            started: AtomicU64::new(0),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Start an upload of `file_name` of `size` bytes, returning the ID its parts are sent with.
+    pub fn initiate(
+        &self,
+        file_name: String,
+        size: u64,
+        owner: Option<String>,
+        private: bool,
+    ) -> io::Result<String> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos());
+        let count = self.started.fetch_add(1, Ordering::Relaxed);
+
+        // Hex only, so it is safe to use as a folder name
+        let seed = format!("{file_name}:{nanos}:{count}:{}", std::process::id());
+        let id = hash_reader(seed.as_bytes(), |_| {})?[..32].to_string();
+
+        fs::create_dir_all(part_path(&id, None))?;
+        self.uploads.lock().unwrap().insert(
+            id.clone(),
+            Upload {
+                file_name,
+                size,
+                owner,
+                private,
+                touched: Instant::now(),
+                parts: BTreeMap::new(),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Store part `number` of upload `id`, replacing any earlier copy of it.
+    pub fn write_part(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+        number: usize,
+        contents: &[u8],
+    ) -> Result<(), Refusal> {
+        if !(1..=MAX_PARTS).contains(&number) {
+            return Err((
+                Status::InvalidRequest,
+                format!("Part numbers go from 1 to {MAX_PARTS}"),
+            ));
+        }
+        self.touch(id, owner)?;
+
+        // Written outside the lock, so parts of one upload can arrive side by side
+        let path = part_path(id, Some(number));
+        let partial = format!("{path}.partial");
+        if let Err(err) = fs::write(&partial, contents).and_then(|_| fs::rename(&partial, &path)) {
+            _ = fs::remove_file(&partial);
+            return Err(internal(id, err));
+        }
+
+        let mut uploads = self.uploads.lock().unwrap();
+        // Aborted while the part was being written
+        let Some(upload) = uploads.get_mut(id) else {
+            return Err(unknown(id));
+        };
+        upload.parts.insert(number, contents.len() as u64);
+        Ok(())
+    }
+
+    /// Join the parts of upload `id` in order into the file at `into`, returning
+    /// the upload's name, the SHA-256 of what was joined and whether it is private.
+    ///
+    /// The parts are copied a piece at a time rather than read in whole. The upload
+    /// is kept until `remove` is called, so a failed completion can be retried.
+    pub fn assemble(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+        into: &str,
+    ) -> Result<(String, String, bool), Refusal> {
+        self.touch(id, owner)?;
+        let (file_name, size, private, parts) = {
+            let uploads = self.uploads.lock().unwrap();
+            let upload = uploads.get(id).ok_or_else(|| unknown(id))?;
+            let file_name = upload.file_name.clone();
+            (file_name, upload.size, upload.private, upload.parts.clone())
+        };
+
+        if parts.is_empty() {
+            return Err((Status::InvalidRequest, "No parts were uploaded".to_string()));
+        }
+        // Parts are numbered from 1, a gap means one never arrived
+        if let Some(missing) = (1..).zip(parts.keys()).find(|(want, &got)| *want != got) {
+            return Err((
+                Status::InvalidRequest,
+                format!("Part {} is missing", missing.0),
+            ));
+        }
+
+        // Missing parts at the end leave no gap, but come up short
+        let received: u64 = parts.values().sum();
+        if received != size {
+            return Err((
+                Status::InvalidRequest,
+                format!("Parts add up to {received} of the {size} bytes announced"),
+            ));
+        }
+
+        let joined = || -> io::Result<String> {
+            let mut joined: Box<dyn Read> = Box::new(io::empty());
+            for number in parts.keys() {
+                joined = Box::new(joined.chain(fs::File::open(part_path(id, Some(*number)))?));
+            }
+
+            let mut copy = Copy {
+                from: joined,
+                to: io::BufWriter::new(fs::File::create(into)?),
+            };
+            let hash = hash_reader(&mut copy, |_| {})?;
+            copy.to.flush()?;
+            Ok(hash)
+        };
+        let hash = joined().map_err(|err| internal(id, err))?;
+        Ok((file_name, hash, private))
+    }
+
+    /// Name of the file upload `id` is stored as once complete.
+    pub fn file_name(&self, id: &str, owner: Option<&str>) -> Result<String, Refusal> {
+        self.touch(id, owner)?;
+        let uploads = self.uploads.lock().unwrap();
+        let upload = uploads.get(id).ok_or_else(|| unknown(id))?;
+        Ok(upload.file_name.clone())
+    }
+
+    /// Drop upload `id` and its parts.
+    pub fn remove(&self, id: &str, owner: Option<&str>) -> Result<(), Refusal> {
+        self.touch(id, owner)?;
+        self.uploads.lock().unwrap().remove(id);
+
+        fs::remove_dir_all(part_path(id, None)).map_err(|err| internal(id, err))
+    }
+
+    /// Drop uploads that have gone `timeout` without a request.
+    pub fn sweep(&self) {
+        let expired: Vec<(String, Upload)> = {
+            let mut uploads = self.uploads.lock().unwrap();
+            let ids: Vec<String> = uploads
+                .iter()
+                .filter(|(_, upload)| upload.touched.elapsed() >= self.timeout)
+                .map(|(id, _)| id.clone())
+                .collect();
+
+            ids.into_iter()
+                .filter_map(|id| uploads.remove_entry(&id))
+                .collect()
+        };
+
+        for (id, upload) in expired {
+            log!(
+                "Dropped multipart upload of \"{}\" with {} parts, abandoned",
+                upload.file_name,
+                upload.parts.len()
+            );
+            if let Err(err) = fs::remove_dir_all(part_path(&id, None)) {
+                log_err!("Could not remove parts of upload {id}: {err}");
+            }
+        }
+    }
+
+    /// Check `owner` may use upload `id`, and keep it from being swept.
+    fn touch(&self, id: &str, owner: Option<&str>) -> Result<(), Refusal> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let upload = uploads.get_mut(id).ok_or_else(|| unknown(id))?;
+
+        if upload.owner.as_deref() != owner {
+            return Err((
+                Status::Denied,
+                "Upload was started by someone else".to_string(),
+            ));
+        }
+        upload.touched = Instant::now();
+        Ok(())
+    }
+}
+
+/// Part `number` of upload `id`, or the folder of all its parts.
+fn part_path(id: &str, number: Option<usize>) -> String {
+    let dir = temp::multipart_dir();
+    match number {
+        Some(number) => format!("{dir}/{id}/{number}"),
+        None => format!("{dir}/{id}"),
+    }
+}
+
+/// Writes everything read from `from` to `to`, so it can be hashed as it is copied.
+struct Copy<R, W> {
+    from: R,
+    to: W,
+}
+
+impl<R: Read, W: Write> Read for Copy<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.from.read(buf)?;
+        self.to.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+fn unknown(id: &str) -> Refusal {
+    (
+        Status::NotFound,
+        format!("No upload {id}, it may have been completed, aborted or abandoned"),
+    )
+}
+
+fn internal(id: &str, err: io::Error) -> Refusal {
+    log_err!("Multipart upload {id} failed: {err}");
+
+    if disk::is_storage_full(&err) {
+        return (Status::NoSpace, "Server is out of disk space".to_string());
+    }
+    (Status::InternalError, "Could not store parts".to_string())
+}
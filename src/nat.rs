@@ -0,0 +1,161 @@
+//! NAT-PMP (RFC 6886) port mapping, so peers behind a home router can be
+//! reached from outside the LAN.
+
+use std::{
+    fs, io,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+const NATPMP_PORT: u16 = 5351;
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDR: u8 = 0;
+const OP_MAP_TCP: u8 = 2;
+const RESPONSE_BIT: u8 = 128;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const MAX_ATTEMPTS: usize = 4;
+
+/// A TCP port mapped on the gateway, which expires unless it is renewed.
+pub struct PortMapping {
+    gateway: Ipv4Addr,
+    internal_port: u16,
+    external: SocketAddrV4,
+    lifetime: Duration,
+    mapped_at: Instant,
+}
+
+impl PortMapping {
+    /// Ask `gateway` to forward `port` to this host for `lifetime`.
+    pub fn map_tcp(gateway: Ipv4Addr, port: u16, lifetime: Duration) -> io::Result<Self> {
+        let external_ip = external_address(gateway)?;
+        let (external_port, lifetime) = request_mapping(gateway, port, port, lifetime)?;
+
+        Ok(Self {
+            gateway,
+            internal_port: port,
+            external: SocketAddrV4::new(external_ip, external_port),
+            lifetime,
+            mapped_at: Instant::now(),
+        })
+    }
+
+    /// The address other peers should connect to.
+    #[inline]
+    pub fn external(&self) -> SocketAddrV4 {
+        self.external
+    }
+
+    /// Mappings should be renewed halfway through their lifetime.
+    pub fn needs_renewal(&self) -> bool {
+        self.mapped_at.elapsed() >= self.lifetime / 2
+    }
+
+    pub fn renew(&mut self) -> io::Result<()> {
+        let (external_port, lifetime) = request_mapping(
+            self.gateway,
+            self.internal_port,
+            self.external.port(),
+            self.lifetime,
+        )?;
+
+        self.external.set_port(external_port);
+        self.lifetime = lifetime;
+        self.mapped_at = Instant::now();
+        Ok(())
+    }
+
+    /// Remove the mapping from the gateway.
+    pub fn remove(self) -> io::Result<()> {
+        request_mapping(self.gateway, self.internal_port, 0, Duration::ZERO)?;
+        Ok(())
+    }
+}
+
+/// Find the default IPv4 gateway, only supported on Linux.
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        // A destination of 0 is the default route, the gateway is little endian hex
+        if fields.len() > 2 && fields[1] == "00000000" {
+            let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+            Some(Ipv4Addr::from(gateway.swap_bytes()))
+        } else {
+            None
+        }
+    })
+}
+
+fn external_address(gateway: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    let response = request::<12>(gateway, &[VERSION, OP_EXTERNAL_ADDR])?;
+    Ok(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ))
+}
+
+/// Returns the external port and lifetime granted by the gateway.
+fn request_mapping(
+    gateway: Ipv4Addr,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> io::Result<(u16, Duration)> {
+    let mut message = [0u8; 12];
+    message[0] = VERSION;
+    message[1] = OP_MAP_TCP;
+    message[4..6].copy_from_slice(&internal_port.to_be_bytes());
+    message[6..8].copy_from_slice(&external_port.to_be_bytes());
+    message[8..12].copy_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+
+    let response = request::<16>(gateway, &message)?;
+    let external_port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes(response[12..16].try_into().unwrap());
+
+    Ok((external_port, Duration::from_secs(lifetime as u64)))
+}
+
+/// Send `message` to the gateway, retrying with a doubling timeout.
+fn request<const S: usize>(gateway: Ipv4Addr, message: &[u8]) -> io::Result<[u8; S]> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((gateway, NATPMP_PORT))?;
+
+    let mut timeout = INITIAL_TIMEOUT;
+    let mut response = [0u8; S];
+
+    for _ in 0..MAX_ATTEMPTS {
+        socket.send(message)?;
+        socket.set_read_timeout(Some(timeout))?;
+
+        match socket.recv(&mut response) {
+            Ok(count) if count >= S && response[1] == message[1] | RESPONSE_BIT => {
+                let result = u16::from_be_bytes([response[2], response[3]]);
+
+                if result != 0 {
+                    return Err(io::Error::other(format!(
+                        "NAT-PMP request failed with result code {result}"
+                    )));
+                }
+
+                return Ok(response);
+            }
+            Ok(_) => {}
+            Err(err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut => {}
+            Err(err) => return Err(err),
+        }
+
+        timeout *= 2;
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "Gateway did not respond to NAT-PMP",
+    ))
+}
@@ -0,0 +1,101 @@
+use p2p_service::feature;
+
+/// Something the user can do from the keyboard or the command palette.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Upload,
+    FocusFilter,
+    Refresh,
+    DeleteSelected,
+    DownloadSelected,
+    ClearSelection,
+    OpenPalette,
+}
+
+impl Action {
+    /// The `feature` bits a server has to offer for the action to be shown.
+    pub fn required_features(self) -> u64 {
+        match self {
+            Action::Upload => feature::WRITE,
+            Action::DeleteSelected => feature::DELETE,
+            _ => 0,
+        }
+    }
+}
+
+pub struct Command {
+    pub action: Action,
+    pub name: &'static str,
+    pub shortcut: &'static str,
+}
+
+/// Every action listed in the palette, in the order shown for an empty query.
+pub const COMMANDS: &[Command] = &[
+    Command {
+        action: Action::Upload,
+        name: "Upload file",
+        shortcut: "Ctrl+U",
+    },
+    Command {
+        action: Action::FocusFilter,
+        name: "Filter by tag",
+        shortcut: "Ctrl+F",
+    },
+    Command {
+        action: Action::Refresh,
+        name: "Refresh file list",
+        shortcut: "F5",
+    },
+    Command {
+        action: Action::DownloadSelected,
+        name: "Download selected file",
+        shortcut: "Enter",
+    },
+    Command {
+        action: Action::DeleteSelected,
+        name: "Delete selected file",
+        shortcut: "Delete",
+    },
+    Command {
+        action: Action::ClearSelection,
+        name: "Clear selection and filter",
+        shortcut: "Esc",
+    },
+];
+
+/// How well `query` matches `name`, lower is better and `None` is no match.
+///
+/// Every character of the query has to appear in the name in order, ignoring
+/// case. Characters skipped between them count against the match.
+pub fn fuzzy_score(query: &str, name: &str) -> Option<usize> {
+    let mut name = name.chars().flat_map(char::to_lowercase);
+    let mut skipped = 0;
+
+    for wanted in query.chars().flat_map(char::to_lowercase) {
+        if wanted.is_whitespace() {
+            continue;
+        }
+
+        loop {
+            let c = name.next()?;
+            if c == wanted {
+                break;
+            }
+            skipped += 1;
+        }
+    }
+
+    Some(skipped)
+}
+
+/// The commands matching `query`, best match first.
+pub fn search(query: &str) -> Vec<&'static Command> {
+    let mut matches: Vec<_> = COMMANDS
+        .iter()
+        .filter_map(|command| Some((fuzzy_score(query, command.name)?, command)))
+        .collect();
+
+    // Stable, so equally good matches keep their order in `COMMANDS`
+    matches.sort_by_key(|(score, _)| *score);
+    matches.into_iter().map(|(_, command)| command).collect()
+}
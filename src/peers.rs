@@ -0,0 +1,163 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use p2p_service::SERVER_ADDR;
+
+/// Peers that have not announced within this window are forgotten.
+const PEER_TTL: Duration = Duration::from_secs(120);
+
+struct Peer {
+    /// Each file with its hash, if the peer sent one.
+    files: HashMap<String, Option<String>>,
+    last_seen: Instant,
+}
+
+/// Files announced by other peers, keyed by the address they serve from.
+#[derive(Default)]
+pub struct PeerRegistry {
+    peers: HashMap<String, Peer>,
+}
+
+impl PeerRegistry {
+    /// Record the files a peer is serving, replacing its previous announcement.
+    pub fn announce(&mut self, addr: String, files: Vec<(String, Option<String>)>) {
+        self.peers.insert(
+            addr,
+            Peer {
+                files: files.into_iter().collect(),
+                last_seen: Instant::now(),
+            },
+        );
+    }
+
+    /// Forget peers that have gone quiet.
+    pub fn prune(&mut self) {
+        self.peers
+            .retain(|_, peer| peer.last_seen.elapsed() < PEER_TTL);
+    }
+
+    /// Merge this server's files, each with its hash, with every live peer, mapping
+    /// each file to the addresses it can be fetched from. This server is listed first.
+    ///
+    /// Peers with a different hash have a different file under the same name, so
+    /// they aren't listed as a source of it. This server's copy decides which is
+    /// the file, or for files only peers have, the copy most of them have. Peers that
+    /// sent no hash can't be told apart and are always listed.
+    pub fn sources<'a>(
+        &mut self,
+        local: impl Iterator<Item = (&'a String, Option<&'a str>)>,
+    ) -> BTreeMap<String, Vec<String>> {
+        self.prune();
+
+        let mut sources: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut local_hashes = HashMap::new();
+
+        for (file, hash) in local {
+            sources
+                .entry(file.clone())
+                .or_default()
+                .push(SERVER_ADDR.to_string());
+            local_hashes.insert(file.as_str(), hash);
+        }
+
+        // Each file the peers have, then the peers with each copy of it
+        let mut copies: BTreeMap<&str, BTreeMap<Option<&str>, Vec<&str>>> = BTreeMap::new();
+        for (addr, peer) in &self.peers {
+            for (file, hash) in &peer.files {
+                copies
+                    .entry(file)
+                    .or_default()
+                    .entry(hash.as_deref())
+                    .or_default()
+                    .push(addr);
+            }
+        }
+
+        for (file, by_hash) in copies {
+            let agreed = match local_hashes.get(file) {
+                Some(hash) => *hash,
+                None => by_hash
+                    .iter()
+                    .filter(|(hash, _)| hash.is_some())
+                    .max_by_key(|(_, addrs)| addrs.len())
+                    .and_then(|(hash, _)| *hash),
+            };
+
+            let addrs = sources.entry(file.to_string()).or_default();
+            for (hash, peers) in by_hash {
+                if hash.is_none() || agreed.is_none() || hash == agreed {
+                    addrs.extend(peers.iter().map(|addr| addr.to_string()));
+                }
+            }
+        }
+
+        sources
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announce(registry: &mut PeerRegistry, addr: &str, files: &[(&str, Option<&str>)]) {
+        let files = files
+            .iter()
+            .map(|(file, hash)| (file.to_string(), hash.map(String::from)))
+            .collect();
+        registry.announce(addr.to_string(), files);
+    }
+
+    fn sources_of(
+        registry: &mut PeerRegistry,
+        local: &[(String, Option<&str>)],
+        file: &str,
+    ) -> Vec<String> {
+        let sources = registry.sources(local.iter().map(|(file, hash)| (file, *hash)));
+        let mut addrs = sources.get(file).cloned().unwrap_or_default();
+        addrs.sort();
+        addrs
+    }
+
+    #[test]
+    fn peers_with_a_different_copy_are_left_out() {
+        let mut registry = PeerRegistry::default();
+        announce(&mut registry, "10.0.0.1:1", &[("a", Some("same"))]);
+        announce(&mut registry, "10.0.0.2:1", &[("a", Some("other"))]);
+        announce(&mut registry, "10.0.0.3:1", &[("a", None)]);
+
+        let local = [("a".to_string(), Some("same"))];
+        assert_eq!(
+            sources_of(&mut registry, &local, "a"),
+            ["10.0.0.1:1", "10.0.0.3:1", SERVER_ADDR]
+        );
+    }
+
+    #[test]
+    fn the_copy_most_peers_have_wins() {
+        let mut registry = PeerRegistry::default();
+        announce(&mut registry, "10.0.0.1:1", &[("a", Some("popular"))]);
+        announce(&mut registry, "10.0.0.2:1", &[("a", Some("popular"))]);
+        announce(&mut registry, "10.0.0.3:1", &[("a", Some("rare"))]);
+
+        assert_eq!(
+            sources_of(&mut registry, &[], "a"),
+            ["10.0.0.1:1", "10.0.0.2:1"]
+        );
+    }
+
+    #[test]
+    fn files_without_hashes_are_merged_by_name() {
+        let mut registry = PeerRegistry::default();
+        announce(&mut registry, "10.0.0.1:1", &[("a", None)]);
+        announce(&mut registry, "10.0.0.2:1", &[("a", None), ("b", None)]);
+
+        let local = [("a".to_string(), None)];
+        assert_eq!(
+            sources_of(&mut registry, &local, "a"),
+            ["10.0.0.1:1", "10.0.0.2:1", SERVER_ADDR]
+        );
+        assert_eq!(sources_of(&mut registry, &local, "b"), ["10.0.0.2:1"]);
+    }
+}
@@ -0,0 +1,326 @@
+//! In-memory connections, for exercising the protocol without sockets.
+//!
+//! An upload, listing and download against a stand-in server on the other end:
+//!
+//! ```
+//! use std::thread;
+//!
+//! use p2p_service::{
+//!     fetch_files, get_file, op, pipe::DuplexPipe, read_response, read_string, read_usize,
+//!     receive_file, send_reader, start_upload, version, write_file_list, write_response,
+//!     write_usize, Chunk, ConnectionInfo, Status,
+//! };
+//!
+//! let (client, server) = DuplexPipe::pair();
+//!
+//! // Holds one file, answering each op the way the real server does at version 2
+//! let server = thread::spawn(move || {
+//!     let mut chunk = Chunk::<1024, DuplexPipe>::new(&server);
+//!     let mut stored = None;
+//!
+//!     while chunk.read_stream(1).is_ok() {
+//!         match chunk.slice(1)[0] {
+//!             op::ADD_FILE => {
+//!                 let name = read_string(&mut chunk).unwrap();
+//!                 let size = read_usize(&mut chunk).unwrap();
+//!                 let contents = receive_file(&mut chunk, size).unwrap().unwrap();
+//!                 stored = Some((name, contents));
+//!                 write_response(&mut chunk, Status::Ok, "").unwrap();
+//!             }
+//!             op::FETCH_FILES => {
+//!                 write_response(&mut chunk, Status::Ok, "").unwrap();
+//!                 write_file_list(&mut chunk, stored.iter().map(|(name, _)| name)).unwrap();
+//!             }
+//!             op::GET_FILE => {
+//!                 read_string(&mut chunk).unwrap();
+//!                 let (_, contents) = stored.as_ref().unwrap();
+//!                 write_response(&mut chunk, Status::Ok, "").unwrap();
+//!                 write_usize(&mut chunk, contents.len()).unwrap();
+//!                 chunk.write_and_send(contents).unwrap();
+//!             }
+//!             other => panic!("unexpected op {other}"),
+//!         }
+//!     }
+//! });
+//!
+//! let info = ConnectionInfo {
+//!     version: version::V2,
+//!     ..ConnectionInfo::default()
+//! };
+//! let mut chunk = Chunk::<1024, DuplexPipe>::new(&client);
+//!
+//! start_upload(&mut chunk, &info, op::ADD_FILE, "hello.txt", false).unwrap();
+//! send_reader(&mut chunk, &b"hello"[..], 5).unwrap();
+//! read_response(&mut chunk).unwrap();
+//!
+//! assert_eq!(fetch_files(&client, &info).unwrap(), ["hello.txt"]);
+//! assert_eq!(get_file(&client, &info, "hello.txt").unwrap().unwrap(), b"hello");
+//!
+//! // Hanging up ends the server's loop
+//! drop(chunk);
+//! drop(client);
+//! server.join().unwrap();
+//! ```
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::Transport;
+
+/// Faults and delays applied to everything written to a `DuplexPipe`.
+#[derive(Clone, Default)]
+pub struct PipeOptions {
+    /// Delay before each write is delivered.
+    pub latency: Duration,
+    /// Throttle writes to this many bytes per second.
+    pub bytes_per_sec: Option<u64>,
+    /// Hand back at most this many bytes per read, to exercise short reads.
+    ///
+    /// `Some(0)` is taken as no limit, a read of nothing would look like EOF.
+    pub max_read: Option<usize>,
+    /// Fail every write once this many bytes have been written.
+    pub fail_after: Option<usize>,
+}
+
+/// Bytes flowing in one direction.
+#[derive(Default)]
+struct Channel {
+    state: Mutex<ChannelState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct ChannelState {
+    bytes: VecDeque<u8>,
+    /// The writing end was dropped, reads return EOF once `bytes` is drained.
+    closed: bool,
+}
+
+impl Channel {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// One end of an in-memory connection, see `DuplexPipe::pair`.
+pub struct DuplexPipe {
+    incoming: Arc<Channel>,
+    outgoing: Arc<Channel>,
+    options: PipeOptions,
+    written: AtomicUsize,
+}
+
+impl DuplexPipe {
+    /// Two connected ends, whatever is written to one can be read from the other.
+    pub fn pair() -> (Self, Self) {
+        Self::pair_with(PipeOptions::default())
+    }
+
+    /// Like `pair`, with `options` applied to writes in both directions.
+    pub fn pair_with(options: PipeOptions) -> (Self, Self) {
+        let a_to_b = Arc::new(Channel::default());
+        let b_to_a = Arc::new(Channel::default());
+
+        let a = Self {
+            incoming: b_to_a.clone(),
+            outgoing: a_to_b.clone(),
+            options: options.clone(),
+            written: AtomicUsize::new(0),
+        };
+        let b = Self {
+            incoming: a_to_b,
+            outgoing: b_to_a,
+            options,
+            written: AtomicUsize::new(0),
+        };
+
+        (a, b)
+    }
+}
+
+impl Transport for DuplexPipe {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.incoming.state.lock().unwrap();
+        while state.bytes.is_empty() && !state.closed {
+            state = self.incoming.ready.wait(state).unwrap();
+        }
+
+        let count = buf.len().min(state.bytes.len()).min(
+            self.options
+                .max_read
+                .filter(|&max| max > 0)
+                .unwrap_or(usize::MAX),
+        );
+
+        for (dst, src) in buf.iter_mut().zip(state.bytes.drain(..count)) {
+            *dst = src;
+        }
+        Ok(count)
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        let written = self.written.fetch_add(buf.len(), Ordering::Relaxed) + buf.len();
+        if self.options.fail_after.is_some_and(|limit| written > limit) {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Injected pipe failure",
+            ));
+        }
+
+        let mut delay = self.options.latency;
+        if let Some(rate) = self.options.bytes_per_sec {
+            delay += Duration::from_secs_f64(buf.len() as f64 / rate.max(1) as f64);
+        }
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+
+        self.outgoing
+            .state
+            .lock()
+            .unwrap()
+            .bytes
+            .extend(buf.iter().copied());
+        self.outgoing.ready.notify_all();
+        Ok(())
+    }
+
+    fn peer(&self) -> String {
+        "pipe".to_string()
+    }
+}
+
+impl Read for &DuplexPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Transport::read(*self, buf)
+    }
+}
+
+impl Write for &DuplexPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Transport::write_all(*self, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for DuplexPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Transport::read(&*self, buf)
+    }
+}
+
+impl Write for DuplexPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Transport::write_all(&*self, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for DuplexPipe {
+    fn drop(&mut self) {
+        self.outgoing.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read_string, write_string, Chunk};
+
+    #[test]
+    fn written_bytes_are_read_from_the_other_end() {
+        let (a, b) = DuplexPipe::pair();
+        a.write_all(b"ping").unwrap();
+        b.write_all(b"pong").unwrap();
+
+        let mut buf = [0; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn reads_are_cut_short() {
+        let (a, b) = DuplexPipe::pair_with(PipeOptions {
+            max_read: Some(3),
+            ..PipeOptions::default()
+        });
+        a.write_all(b"abcdefg").unwrap();
+
+        let mut buf = [0; 16];
+        assert_eq!(Transport::read(&b, &mut buf).unwrap(), 3);
+        assert_eq!(Transport::read(&b, &mut buf).unwrap(), 3);
+        assert_eq!(Transport::read(&b, &mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'g');
+    }
+
+    #[test]
+    fn zero_max_read_is_no_limit() {
+        let (a, b) = DuplexPipe::pair_with(PipeOptions {
+            max_read: Some(0),
+            ..PipeOptions::default()
+        });
+        a.write_all(b"abc").unwrap();
+
+        let mut buf = [0; 16];
+        assert_eq!(Transport::read(&b, &mut buf).unwrap(), 3);
+    }
+
+    #[test]
+    fn writes_fail_past_the_limit() {
+        let (a, b) = DuplexPipe::pair_with(PipeOptions {
+            fail_after: Some(4),
+            ..PipeOptions::default()
+        });
+        a.write_all(b"abcd").unwrap();
+        let err = a.write_all(b"e").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        // What made it through is still delivered
+        let mut buf = [0; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+    }
+
+    #[test]
+    fn dropped_end_reads_as_eof_once_drained() {
+        let (a, b) = DuplexPipe::pair();
+        a.write_all(b"last").unwrap();
+        drop(a);
+
+        let mut buf = [0; 8];
+        assert_eq!(Transport::read(&b, &mut buf).unwrap(), 4);
+        assert_eq!(Transport::read(&b, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn chunk_reassembles_short_reads() {
+        let (a, b) = DuplexPipe::pair_with(PipeOptions {
+            max_read: Some(1),
+            ..PipeOptions::default()
+        });
+        let name = "a name longer than any single read";
+
+        write_string(&mut Chunk::<1024, DuplexPipe>::new(&a), name).unwrap();
+        let read = read_string(&mut Chunk::<1024, DuplexPipe>::new(&b)).unwrap();
+        assert_eq!(read, name);
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use p2p_service::format::{human_bytes, human_rate};
+
+use crate::{connections, logs::log, metrics::escape_label};
+
+/// How long a transfer runs before its first progress line, and the most time between lines.
+///
+/// Anything that finishes sooner only gets the usual start and end lines.
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+/// Percent a transfer can advance before a line is logged ahead of `LOG_INTERVAL`.
+const LOG_STEP: usize = 10;
+
+/// A transfer in flight, as reported by the stats op.
+struct Active {
+    direction: &'static str,
+    file: String,
+    peer: Option<SocketAddr>,
+    done: Arc<AtomicUsize>,
+}
+
+static ACTIVE: Mutex<BTreeMap<u64, Active>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Logs how far along a large upload or download is, and lists it in stats until dropped.
+pub struct Progress {
+    id: u64,
+    direction: &'static str,
+    file: String,
+    peer: Option<SocketAddr>,
+    size: usize,
+    done: Arc<AtomicUsize>,
+    started: Instant,
+    last_logged: Instant,
+    logged_percent: usize,
+}
+
+impl Progress {
+    /// Start tracking `size` bytes of `file` going `direction` ("upload" or "download").
+    pub fn start(
+        direction: &'static str,
+        file: &str,
+        peer: Option<SocketAddr>,
+        size: usize,
+    ) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let done = Arc::new(AtomicUsize::new(0));
+
+        ACTIVE.lock().unwrap().insert(
+            id,
+            Active {
+                direction,
+                file: file.to_string(),
+                peer,
+                done: done.clone(),
+            },
+        );
+        connections::start_transfer(direction, file, size, done.clone());
+
+        let now = Instant::now();
+        Self {
+            id,
+            direction,
+            file: file.to_string(),
+            peer,
+            size,
+            done,
+            started: now,
+            last_logged: now,
+            logged_percent: 0,
+        }
+    }
+
+    /// Record that `done` bytes have been transferred so far.
+    pub fn update(&mut self, done: usize) {
+        self.done.store(done, Ordering::Relaxed);
+
+        let elapsed = self.started.elapsed();
+        if elapsed < LOG_INTERVAL || done >= self.size {
+            return;
+        }
+
+        let percent = done * 100 / self.size.max(1);
+        if self.last_logged.elapsed() < LOG_INTERVAL && percent < self.logged_percent + LOG_STEP {
+            return;
+        }
+
+        log!(
+            "{} {}: {percent}% ({}/{}, {}, peer {})",
+            self.direction,
+            self.file,
+            human_bytes(done as u64),
+            human_bytes(self.size as u64),
+            human_rate(done as f64 / elapsed.as_secs_f64()),
+            self.peer
+                .map_or_else(|| "local".to_string(), |peer| peer.to_string()),
+        );
+        self.last_logged = Instant::now();
+        self.logged_percent = percent;
+    }
+
+    /// Wrap `reader`, updating the progress with every read.
+    pub fn reader<R: Read>(&mut self, inner: R) -> ProgressReader<'_, R> {
+        ProgressReader {
+            inner,
+            progress: self,
+            read: 0,
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.id);
+        connections::end_transfer();
+    }
+}
+
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a mut Progress,
+    read: usize,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.read += bytes_read;
+        self.progress.update(self.read);
+        Ok(bytes_read)
+    }
+}
+
+/// The number of transfers in flight, and the bytes each has moved so far named like
+/// `transfer_bytes{direction="upload",file="big.iso",peer="10.0.0.7:5123"}`.
+pub fn stats() -> Vec<(String, usize)> {
+    let active = ACTIVE.lock().unwrap();
+    let mut stats = vec![("active_transfers".to_string(), active.len())];
+
+    for transfer in active.values() {
+        let peer = transfer
+            .peer
+            .map_or_else(|| "local".to_string(), |peer| peer.to_string());
+
+        stats.push((
+            format!(
+                "transfer_bytes{{direction=\"{}\",file=\"{}\",peer=\"{peer}\"}}",
+                transfer.direction,
+                escape_label(&transfer.file)
+            ),
+            transfer.done.load(Ordering::Relaxed),
+        ));
+    }
+    stats
+}
@@ -0,0 +1,82 @@
+//! Uploads that failed their checksum, kept to be looked at rather than deleted.
+//! See `--quarantine`.
+
+use std::{
+    fs, io,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use p2p_service::format::human_bytes;
+
+use crate::logs::log;
+
+/// Where received files that don't match their checksum are kept, and where `check`
+/// moves stored files whose contents no longer match their hash.
+pub const QUARANTINE_DIR: &str = "server_quarantine";
+
+/// Held while making room, so two uploads don't both count the same free space.
+static MAKING_ROOM: Mutex<()> = Mutex::new(());
+
+/// What was received for an upload that failed its checksum.
+pub enum Received<'a> {
+    /// Still in memory, like a single part.
+    Contents(&'a [u8]),
+    /// In the temporary file at this path, which is moved rather than copied.
+    File(&'a str),
+}
+
+/// Keep `received` in `QUARANTINE_DIR` under `name`, returning where it went.
+///
+/// The oldest files there are removed first so the folder stays under `max_bytes`.
+/// Something bigger than that on its own isn't kept at all.
+pub fn keep(received: Received, name: &str, max_bytes: u64) -> io::Result<String> {
+    let size = match received {
+        Received::Contents(contents) => contents.len() as u64,
+        Received::File(path) => fs::metadata(path)?.len(),
+    };
+    if size > max_bytes {
+        return Err(io::Error::other(format!(
+            "{} is more than the quarantine holds",
+            human_bytes(size)
+        )));
+    }
+
+    let _guard = MAKING_ROOM.lock().unwrap();
+    fs::create_dir_all(QUARANTINE_DIR)?;
+    make_room(max_bytes - size)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis());
+    let path = format!("{QUARANTINE_DIR}/{name}.{now}");
+    match received {
+        Received::Contents(contents) => fs::write(&path, contents)?,
+        Received::File(from) => fs::rename(from, &path)?,
+    }
+    Ok(path)
+}
+
+/// Remove the oldest files in `QUARANTINE_DIR` until what's left takes at most `max_bytes`.
+fn make_room(max_bytes: u64) -> io::Result<()> {
+    let mut kept = Vec::new();
+    for entry in fs::read_dir(QUARANTINE_DIR)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            kept.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+    }
+    kept.sort_by_key(|(modified, ..)| *modified);
+
+    let mut total: u64 = kept.iter().map(|(_, len, _)| len).sum();
+    for (_, len, path) in kept {
+        if total <= max_bytes {
+            break;
+        }
+        fs::remove_file(&path)?;
+        total -= len;
+        log!("Removed {} from quarantine to make room", path.display());
+    }
+    Ok(())
+}
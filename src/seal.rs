@@ -0,0 +1,413 @@
+//! Connections encrypted with a key derived from a shared passphrase, no certificates needed.
+//!
+//! Right after the handshake, a server with a passphrase sends a salt and a nonce,
+//! and the client answers with a nonce of its own. Both sides derive a master key
+//! from the passphrase and salt with PBKDF2, then a key for the connection from the
+//! master key and both nonces, so no two connections share a key.
+//!
+//! From then on everything either side sends goes in records sealed with
+//! ChaCha20-Poly1305: a 4 byte length, then the ciphertext with its tag. A record's
+//! nonce is the direction it travels in and the count of records sent that way
+//! before it, so no nonce is ever used twice with one key. A record that fails its
+//! tag check fails the read, whether the passphrase was wrong or the bytes were changed.
+
+use std::{
+    io,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{Stream, Transport};
+
+pub const SALT_LEN: usize = 16;
+/// Length of the nonce each side adds to the connection's key.
+pub const NONCE_LEN: usize = 16;
+/// Rounds of PBKDF2 servers derive their master key with.
+pub const DEFAULT_ROUNDS: u32 = 600_000;
+/// Fewest rounds a client accepts, so a server in the middle can't make the passphrase cheap to guess.
+pub const MIN_ROUNDS: u32 = 100_000;
+/// Most rounds a client accepts, so a server can't keep it busy deriving a key.
+pub const MAX_ROUNDS: u32 = 10_000_000;
+
+/// Most plaintext bytes in a record, longer writes are split.
+const MAX_RECORD_LEN: usize = 16 * 1024;
+const TAG_LEN: usize = 16;
+/// The first record the server seals, so a wrong passphrase is noticed before any request.
+const CONFIRMATION: &[u8] = b"p2p sealed";
+
+/// Which end of the connection a `Sealed` stream is, each seals records with its own nonces.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client = 0,
+    Server = 1,
+}
+
+/// A master key and what it was derived with, kept for as long as the server runs.
+pub struct SealKey {
+    key: [u8; 32],
+    salt: [u8; SALT_LEN],
+    rounds: u32,
+}
+
+impl SealKey {
+    /// Derive a master key from `passphrase` with a fresh salt. Slow on purpose.
+    pub fn new(passphrase: &str, rounds: u32) -> Self {
+        let salt = random_bytes();
+        Self {
+            key: master_key(passphrase, &salt, rounds),
+            salt,
+            rounds,
+        }
+    }
+
+    #[inline]
+    pub fn rounds(&self) -> u32 {
+        self.rounds
+    }
+}
+
+fn master_key(passphrase: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, rounds, &mut key);
+    key
+}
+
+/// The client's last master key, so reconnecting doesn't mean deriving it again.
+static CLIENT_KEY: Mutex<Option<ClientKey>> = Mutex::new(None);
+
+struct ClientKey {
+    passphrase: String,
+    salt: [u8; SALT_LEN],
+    rounds: u32,
+    key: [u8; 32],
+}
+
+fn client_master_key(passphrase: &str, salt: [u8; SALT_LEN], rounds: u32) -> [u8; 32] {
+    let mut cached = CLIENT_KEY.lock().unwrap();
+    match &*cached {
+        Some(cached)
+            if cached.passphrase == passphrase
+                && cached.salt == salt
+                && cached.rounds == rounds =>
+        {
+            cached.key
+        }
+        _ => {
+            let key = master_key(passphrase, &salt, rounds);
+            *cached = Some(ClientKey {
+                passphrase: passphrase.to_string(),
+                salt,
+                rounds,
+                key,
+            });
+            key
+        }
+    }
+}
+
+fn connection_key(master: &[u8; 32], server_nonce: &[u8], client_nonce: &[u8]) -> Key {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(master).expect("HMAC takes keys of any length");
+    mac.update(b"p2p connection key");
+    mac.update(server_nonce);
+    mac.update(client_nonce);
+    Key::clone_from_slice(&mac.finalize().into_bytes())
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Seal the server's end of `stream` with `key`, right after answering the handshake.
+pub fn accept<S: Transport>(stream: &Sealed<S>, key: &SealKey) -> io::Result<()> {
+    if stream.is_sealed() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Connection is already encrypted",
+        ));
+    }
+
+    let server_nonce: [u8; NONCE_LEN] = random_bytes();
+    stream
+        .inner
+        .write_all(&[key.salt.as_slice(), &server_nonce].concat())?;
+
+    let mut client_nonce = [0; NONCE_LEN];
+    stream.inner.read_exact(&mut client_nonce)?;
+
+    stream.seal(
+        connection_key(&key.key, &server_nonce, &client_nonce),
+        Side::Server,
+    );
+    stream.write_all(CONFIRMATION)
+}
+
+/// Seal the client's end of `stream`, to a server that said it derives keys with `rounds`.
+///
+/// Fails with `PermissionDenied` if the server's passphrase isn't `passphrase`.
+pub fn connect<S: Transport>(stream: S, passphrase: &str, rounds: u32) -> io::Result<Sealed<S>> {
+    if !(MIN_ROUNDS..=MAX_ROUNDS).contains(&rounds) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Server derives keys with {rounds} rounds, outside {MIN_ROUNDS}-{MAX_ROUNDS}"),
+        ));
+    }
+
+    let mut params = [0; SALT_LEN + NONCE_LEN];
+    stream.read_exact(&mut params)?;
+    let (salt, server_nonce) = params.split_at(SALT_LEN);
+    let salt: [u8; SALT_LEN] = salt.try_into().unwrap();
+
+    let client_nonce: [u8; NONCE_LEN] = random_bytes();
+    stream.write_all(&client_nonce)?;
+
+    let master = client_master_key(passphrase, salt, rounds);
+    let sealed = Sealed::new(stream);
+    sealed.seal(
+        connection_key(&master, server_nonce, &client_nonce),
+        Side::Client,
+    );
+
+    let mut confirmation = [0; CONFIRMATION.len()];
+    match sealed.read_exact(&mut confirmation) {
+        Ok(()) if confirmation == CONFIRMATION => Ok(sealed),
+        Ok(()) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Server didn't confirm the encryption",
+        )),
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Wrong passphrase, or the connection was tampered with",
+        )),
+        Err(err) => Err(err),
+    }
+}
+
+/// A stream whose bytes are sent as they are until `accept` or `connect` seals it.
+pub struct Sealed<S> {
+    inner: S,
+    state: Arc<SealState>,
+}
+
+#[derive(Default)]
+struct SealState {
+    cipher: OnceLock<(ChaCha20Poly1305, Side)>,
+    /// Records sent so far, locked for the whole write so records go out in order.
+    sent: Mutex<u64>,
+    reader: Mutex<Reader>,
+}
+
+/// A record being read, and what is left of the last one opened.
+#[derive(Default)]
+struct Reader {
+    received: u64,
+    /// Bytes of the next record read so far, kept if a read times out half way.
+    record: Vec<u8>,
+    plain: Vec<u8>,
+    read: usize,
+}
+
+impl<S: Transport> Sealed<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            state: Arc::default(),
+        }
+    }
+
+    #[inline]
+    pub fn is_sealed(&self) -> bool {
+        self.state.cipher.get().is_some()
+    }
+
+    fn seal(&self, key: Key, side: Side) {
+        _ = self.state.cipher.set((ChaCha20Poly1305::new(&key), side));
+    }
+
+    /// Read the rest of the next record from the stream, `false` if it ended between records.
+    fn read_record(&self, reader: &mut Reader) -> io::Result<bool> {
+        let mut buf = [0; 4096];
+        loop {
+            let wanted = match reader.record.get(..4) {
+                Some(len) => {
+                    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                    if !(TAG_LEN..=MAX_RECORD_LEN + TAG_LEN).contains(&len) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Encrypted record of {len} bytes is out of bounds"),
+                        ));
+                    }
+                    4 + len
+                }
+                None => 4,
+            };
+            if reader.record.len() == wanted && wanted > 4 {
+                return Ok(true);
+            }
+
+            let missing = (wanted - reader.record.len()).min(buf.len());
+            match self.inner.read(&mut buf[..missing])? {
+                0 if reader.record.is_empty() => return Ok(false),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => reader.record.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+}
+
+/// Nonce of record `count` sent by `side`.
+fn nonce(side: Side, count: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[0] = side as u8;
+    nonce[4..].copy_from_slice(&count.to_le_bytes());
+    nonce
+}
+
+fn tampered() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Encrypted record failed its check, the passphrase is wrong or it was tampered with",
+    )
+}
+
+impl<S: Transport> Transport for Sealed<S> {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((cipher, side)) = self.state.cipher.get() else {
+            return self.inner.read(buf);
+        };
+        let peer_side = match side {
+            Side::Client => Side::Server,
+            Side::Server => Side::Client,
+        };
+
+        let mut reader = self.state.reader.lock().unwrap();
+        while reader.read == reader.plain.len() {
+            if !self.read_record(&mut reader)? {
+                return Ok(0);
+            }
+
+            let plain = cipher
+                .decrypt(&nonce(peer_side, reader.received), &reader.record[4..])
+                .map_err(|_| tampered())?;
+            reader.received += 1;
+            reader.record.clear();
+            reader.plain = plain;
+            reader.read = 0;
+        }
+
+        let start = reader.read;
+        let count = buf.len().min(reader.plain.len() - start);
+        buf[..count].copy_from_slice(&reader.plain[start..start + count]);
+        reader.read += count;
+        Ok(count)
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        let Some((cipher, side)) = self.state.cipher.get() else {
+            return self.inner.write_all(buf);
+        };
+
+        let mut sent = self.state.sent.lock().unwrap();
+        for plain in buf.chunks(MAX_RECORD_LEN) {
+            let sealed = cipher
+                .encrypt(&nonce(*side, *sent), plain)
+                .map_err(|_| io::Error::other("Could not encrypt record"))?;
+            *sent += 1;
+
+            let mut record = Vec::with_capacity(4 + sealed.len());
+            record.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+            record.extend_from_slice(&sealed);
+            self.inner.write_all(&record)?;
+        }
+        Ok(())
+    }
+
+    fn peer(&self) -> String {
+        self.inner.peer()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+}
+
+impl Sealed<Stream> {
+    /// Another handle on the same connection, sharing its keys and counters.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            inner: self.inner.try_clone()?,
+            state: self.state.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+
+    /// A connected pair, with the server's end sealed with `key` on a thread of its own.
+    fn serve(key: SealKey) -> (TcpStream, thread::JoinHandle<io::Result<Sealed<TcpStream>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let server = thread::spawn(move || {
+            let sealed = Sealed::new(listener.accept()?.0);
+            accept(&sealed, &key)?;
+            Ok(sealed)
+        });
+        (client, server)
+    }
+
+    #[test]
+    fn sealed_bytes_arrive_as_sent() {
+        let (client, server) = serve(SealKey::new("open sesame", MIN_ROUNDS));
+        let client = connect(client, "open sesame", MIN_ROUNDS).unwrap();
+        let server = server.join().unwrap().unwrap();
+
+        // More than a record, so it is split and joined again
+        let sent: Vec<u8> = (0..MAX_RECORD_LEN * 2 + 5).map(|i| i as u8).collect();
+        client.write_all(&sent).unwrap();
+        let mut received = vec![0; sent.len()];
+        server.read_exact(&mut received).unwrap();
+        assert_eq!(received, sent);
+
+        server.write_all(b"and back").unwrap();
+        let mut received = [0; 8];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"and back");
+    }
+
+    #[test]
+    fn wrong_passphrase_is_refused() {
+        let (client, server) = serve(SealKey::new("open sesame", MIN_ROUNDS));
+
+        let refused = connect(client, "open barley", MIN_ROUNDS).err().unwrap();
+        assert_eq!(refused.kind(), io::ErrorKind::PermissionDenied);
+
+        // The client hung up without a word, and the server read nothing from it
+        let server = server.join().unwrap().unwrap();
+        assert_eq!(server.read(&mut [0; 16]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{env, fs, io, path::PathBuf, sync::RwLock, time::Duration};
+
+use p2p_service::{
+    disconnect, fetch_stats, ConnectionInfo, ProtocolResult, RetryPolicy, Stream, SERVER_ADDR,
+};
+use serde::{Deserialize, Serialize};
+
+/// Where the client's settings are kept, the client runs its setup while this is missing.
+pub const SETTINGS_FILE: &str = "client_settings.json";
+
+/// Downloaded files are kept here, so downloading one again only has to check it changed.
+pub const CACHE_DIR: &str = "client_cache";
+
+/// The settings in use, the defaults until `apply` is called.
+static CURRENT: RwLock<Option<Settings>> = RwLock::new(None);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Settings {
+    pub server_addr: String,
+    /// Downloads are saved here, following the download template.
+    pub downloads_dir: String,
+    /// Presented to servers that have one, `SECRET_VAR` is used instead when it is set.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// The server's passphrase, if it encrypts connections. `PASSPHRASE_VAR` is used
+    /// instead when it is set. Servers that don't encrypt are refused while there is one.
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    /// Tries at connecting before giving up, see `retry_policy`.
+    #[serde(default = "default_connect_attempts")]
+    pub connect_attempts: u32,
+    /// Wait before the first retry, doubled after each one.
+    #[serde(default = "default_retry_delay_ms")]
+    pub retry_delay_ms: u64,
+    /// Room for files kept in `CACHE_DIR` after they are downloaded, 0 to keep none.
+    #[serde(default = "default_cache_max_bytes")]
+    pub cache_max_bytes: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            server_addr: SERVER_ADDR.to_string(),
+            downloads_dir: default_downloads_dir(),
+            secret: None,
+            passphrase: None,
+            connect_attempts: default_connect_attempts(),
+            retry_delay_ms: default_retry_delay_ms(),
+            cache_max_bytes: default_cache_max_bytes(),
+        }
+    }
+}
+
+fn default_connect_attempts() -> u32 {
+    RetryPolicy::default().max_attempts
+}
+
+fn default_retry_delay_ms() -> u64 {
+    RetryPolicy::default().base_delay.as_millis() as u64
+}
+
+fn default_cache_max_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+impl Settings {
+    /// How connections to the server are retried.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.connect_attempts,
+            base_delay: Duration::from_millis(self.retry_delay_ms),
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// The saved settings, `None` if they have never been saved.
+    pub fn load() -> io::Result<Option<Self>> {
+        match fs::read_to_string(SETTINGS_FILE) {
+            Ok(json) => Ok(Some(serde_json::from_str(&json)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Save the settings, creating the downloads folder if it doesn't exist yet.
+    pub fn save(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.downloads_dir)?;
+        fs::write(SETTINGS_FILE, serde_json::to_string_pretty(self)?)
+    }
+
+    /// Connect with these settings and ask for the server's stats, describing how it went.
+    pub fn test(&self) -> ProtocolResult<(Stream, ConnectionInfo, String)> {
+        let (stream, info) = crate::connect_with(
+            &self.server_addr,
+            self.secret.as_deref(),
+            self.passphrase.as_deref(),
+        )?;
+
+        let stats = match fetch_stats(&stream, &info) {
+            Ok(stats) => stats,
+            Err(err) => {
+                disconnect(&stream);
+                return Err(err);
+            }
+        };
+        let files = stats
+            .iter()
+            .find(|(name, _)| name == "files")
+            .map_or(0, |(_, count)| *count);
+
+        let summary = format!(
+            "Connected to {}, {files} files on the server (protocol version {})",
+            self.server_addr, info.version
+        );
+        Ok((stream, info, summary))
+    }
+}
+
+/// The settings in use.
+pub fn current() -> Settings {
+    CURRENT.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Use `settings` from now on, for connections made after the call.
+pub fn apply(settings: Settings) {
+    *CURRENT.write().unwrap() = Some(settings);
+}
+
+/// The user's Downloads folder, or the working directory if there's no home to find it in.
+fn default_downloads_dir() -> String {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"));
+
+    match home {
+        Some(home) => PathBuf::from(home)
+            .join("Downloads")
+            .to_string_lossy()
+            .to_string(),
+        None => ".".to_string(),
+    }
+}
+
+/// How the setup form was left, see `SetupForm::draw`.
+pub enum SetupResult {
+    Editing,
+    Cancelled,
+    /// The settings were tested and saved, and this is the connection the test made.
+    Connected(Stream, ConnectionInfo),
+}
+
+/// Asks for the server address, downloads folder and secret, and tests them before saving.
+pub struct SetupForm {
+    settings: Settings,
+    /// Shown under the form, from the last test or from why the form was opened.
+    status: Option<Result<String, String>>,
+    /// Whether there is something to go back to, there isn't on the first run.
+    cancellable: bool,
+}
+
+impl SetupForm {
+    pub fn new(settings: Settings, problem: Option<String>, cancellable: bool) -> Self {
+        Self {
+            settings,
+            status: problem.map(Err),
+            cancellable,
+        }
+    }
+
+    pub fn draw(&mut self, ui: &imgui::Ui) -> SetupResult {
+        ui.input_text("Server address", &mut self.settings.server_addr)
+            .build();
+        ui.input_text("Downloads folder", &mut self.settings.downloads_dir)
+            .build();
+
+        let mut secret = self.settings.secret.clone().unwrap_or_default();
+        ui.input_text("Secret", &mut secret)
+            .password(true)
+            .hint("only if the server has one")
+            .build();
+        self.settings.secret = (!secret.is_empty()).then_some(secret);
+
+        let mut passphrase = self.settings.passphrase.clone().unwrap_or_default();
+        ui.input_text("Passphrase", &mut passphrase)
+            .password(true)
+            .hint("only if the server encrypts connections")
+            .build();
+        self.settings.passphrase = (!passphrase.is_empty()).then_some(passphrase);
+
+        let test = ui.button("Test connection");
+        ui.same_line();
+        let save = ui.button("Save");
+
+        let mut result = SetupResult::Editing;
+        if self.cancellable {
+            ui.same_line();
+            if ui.button("Cancel") {
+                result = SetupResult::Cancelled;
+            }
+        }
+
+        if test || save {
+            match self.settings.test() {
+                Ok((stream, info, _)) if save => match self.settings.save() {
+                    Ok(()) => {
+                        apply(self.settings.clone());
+                        result = SetupResult::Connected(stream, info);
+                    }
+                    Err(err) => {
+                        disconnect(&stream);
+                        self.status = Some(Err(format!("Could not save settings: {err}")));
+                    }
+                },
+                Ok((stream, _, summary)) => {
+                    disconnect(&stream);
+                    self.status = Some(Ok(summary));
+                }
+                Err(err) => self.status = Some(Err(format!("Could not connect: {err}"))),
+            }
+        }
+
+        match &self.status {
+            Some(Ok(summary)) => ui.text_colored([0.4, 1.0, 0.4, 1.0], summary),
+            Some(Err(problem)) => ui.text_colored([1.0, 0.4, 0.4, 1.0], problem),
+            None => {}
+        }
+
+        result
+    }
+}
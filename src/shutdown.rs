@@ -0,0 +1,46 @@
+//! Stopping the server when it is sent SIGINT or SIGTERM.
+//!
+//! The signal only sets a flag, everything else happens on the thread in `wait`.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// How often `wait` looks at the flag.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Have SIGINT and SIGTERM ask the server to stop, rather than kill it on the spot.
+#[cfg(unix)]
+pub fn install() {
+    extern "C" fn on_signal(_: libc::c_int) {
+        REQUESTED.store(true, Ordering::SeqCst);
+    }
+
+    let handler: extern "C" fn(libc::c_int) = on_signal;
+    // Storing to an atomic is all the handler does, which is safe in a signal handler
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+}
+
+/// Signals are left to end the process as usual.
+#[cfg(not(unix))]
+pub fn install() {}
+
+/// Whether the server has been asked to stop.
+#[inline]
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Return once the server has been asked to stop.
+pub fn wait() {
+    while !requested() {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
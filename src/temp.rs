@@ -0,0 +1,217 @@
+//! Files the server writes before they are ready, all kept under `TEMP_DIR`.
+//!
+//! Nothing outside `TEMP_DIR` is ever removed here, apart from partial uploads
+//! named the way older versions named them and the index's own temporary file,
+//! which is kept next to `INDEX_FILE`.
+
+use std::{
+    fmt, fs, io,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use p2p_service::format::human_bytes;
+
+use crate::{
+    index::{INDEX_FILE, PARTIAL_PREFIX},
+    logs::{log, log_err},
+    SERVER_FILES,
+};
+
+/// Name of the folder in `SERVER_FILES` holding temporary files, so they can be
+/// renamed into place without crossing filesystems. No upload can take this name.
+pub const TEMP_DIR: &str = ".tmp";
+/// Temporary files older than this are taken as left behind by the periodic sweep.
+pub const DEFAULT_TEMP_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Prefix of uploads being written, see `store_file`.
+const UPLOAD_PREFIX: &str = "upload-";
+/// Prefix of streamed uploads being received, see `stream_path`.
+const STREAM_PREFIX: &str = "stream-";
+/// The index being written, written by older versions into `TEMP_DIR`.
+const INDEX_TEMP: &str = "index";
+/// Folder of multipart upload parts, a folder per upload. Swept by `Uploads`.
+const MULTIPART: &str = "multipart";
+
+/// Where an upload of `file_name` is written before it is renamed into place.
+pub fn upload_path(file_name: &str) -> String {
+    format!("{SERVER_FILES}/{TEMP_DIR}/{UPLOAD_PREFIX}{file_name}")
+}
+
+/// A fresh file for a streamed upload to be received into, before its name is checked.
+///
+/// Numbered rather than named after the upload, so two streams never share one.
+pub fn stream_path() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    format!("{SERVER_FILES}/{TEMP_DIR}/{STREAM_PREFIX}{n}")
+}
+
+/// Where the index is written before it replaces the saved one.
+///
+/// Next to `INDEX_FILE` rather than in `TEMP_DIR`, which can be on another
+/// filesystem, so the rename never has to cross one.
+pub fn index_path() -> String {
+    format!("{INDEX_FILE}.tmp")
+}
+
+/// Where the parts of multipart uploads are kept.
+pub fn multipart_dir() -> String {
+    format!("{SERVER_FILES}/{TEMP_DIR}/{MULTIPART}")
+}
+
+/// What a cleanup removed, for its log line.
+#[derive(Default)]
+struct Cleaned {
+    uploads: usize,
+    indexes: usize,
+    /// Multipart uploads whose parts were dropped.
+    sessions: usize,
+    /// Files nothing here writes, such as ones copied in by hand.
+    other: usize,
+    bytes: u64,
+}
+
+impl Cleaned {
+    fn is_empty(&self) -> bool {
+        self.uploads + self.indexes + self.sessions + self.other == 0
+    }
+}
+
+impl fmt::Display for Cleaned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} partial uploads, {} index writes, {} multipart uploads and {} other files ({})",
+            self.uploads,
+            self.indexes,
+            self.sessions,
+            self.other,
+            human_bytes(self.bytes)
+        )
+    }
+}
+
+/// Remove everything a previous run left in `TEMP_DIR`, and make sure it exists.
+///
+/// Called before anything else touches the stored files, so nothing in there
+/// can still be in use.
+pub fn clean_startup() -> io::Result<()> {
+    let dir = format!("{SERVER_FILES}/{TEMP_DIR}");
+    let mut cleaned = Cleaned::default();
+
+    // Written by older versions straight into SERVER_FILES
+    for entry in fs::read_dir(SERVER_FILES)? {
+        let entry = entry?;
+        let is_partial = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(PARTIAL_PREFIX));
+
+        if is_partial && entry.file_type()?.is_file() {
+            cleaned.bytes += entry.metadata()?.len();
+            fs::remove_file(entry.path())?;
+            cleaned.uploads += 1;
+        }
+    }
+
+    match fs::remove_file(index_path()) {
+        Ok(()) => cleaned.indexes += 1,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    if Path::new(&dir).is_dir() {
+        remove_leftovers(&dir, Duration::ZERO, true, &mut cleaned)?;
+    }
+    fs::create_dir_all(&dir)?;
+
+    if !cleaned.is_empty() {
+        log!("Cleaned up after the last run: {cleaned}");
+    }
+    Ok(())
+}
+
+/// Remove files in `TEMP_DIR` that haven't changed for `max_age`.
+///
+/// Multipart uploads are left alone, they expire on their own timeout.
+pub fn sweep(max_age: Duration) {
+    let mut cleaned = Cleaned::default();
+
+    if let Err(err) = remove_leftovers(
+        &format!("{SERVER_FILES}/{TEMP_DIR}"),
+        max_age,
+        false,
+        &mut cleaned,
+    ) {
+        log_err!("Could not clean up temporary files: {err}");
+    }
+    if !cleaned.is_empty() {
+        log!("Cleaned up stale temporary files: {cleaned}");
+    }
+}
+
+fn remove_leftovers(
+    dir: &str,
+    max_age: Duration,
+    multipart: bool,
+    cleaned: &mut Cleaned,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        if name == MULTIPART {
+            if multipart {
+                cleaned.sessions += fs::read_dir(entry.path())?.count();
+                cleaned.bytes += dir_size(&entry.path())?;
+                fs::remove_dir_all(entry.path())?;
+            }
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+
+        if metadata.is_dir() {
+            cleaned.bytes += dir_size(&entry.path())?;
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            cleaned.bytes += metadata.len();
+            fs::remove_file(entry.path())?;
+        }
+
+        match &*name {
+            INDEX_TEMP => cleaned.indexes += 1,
+            name if name.starts_with(UPLOAD_PREFIX) || name.starts_with(STREAM_PREFIX) => {
+                cleaned.uploads += 1
+            }
+            _ => cleaned.other += 1,
+        }
+    }
+    Ok(())
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
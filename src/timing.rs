@@ -0,0 +1,76 @@
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use p2p_service::op;
+
+/// Upper bounds of every bucket but the last, which takes the rest.
+const BUCKETS: [Duration; 5] = [
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+];
+
+/// How the buckets are labelled in stats, in milliseconds like a Prometheus `le`.
+///
+/// Stats are whole numbers, so the histogram is in milliseconds rather than seconds.
+const BUCKET_LABELS: [&str; BUCKETS.len() + 1] = ["1", "10", "100", "1000", "10000", "+Inf"];
+
+/// Name of the histogram in stats, see `OpTimings::stats`.
+pub const HISTOGRAM: &str = "request_duration_milliseconds";
+
+/// Times recorded for one op.
+#[derive(Default)]
+struct Recorded {
+    buckets: [usize; BUCKETS.len() + 1],
+    total: Duration,
+}
+
+/// How long each op took to handle, response included, in fixed buckets.
+#[derive(Default)]
+pub struct OpTimings {
+    recorded: Mutex<BTreeMap<u8, Recorded>>,
+}
+
+impl OpTimings {
+    pub fn record(&self, op: u8, elapsed: Duration) {
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| elapsed < *bound)
+            .unwrap_or(BUCKETS.len());
+
+        let mut recorded = self.recorded.lock().unwrap();
+        let recorded = recorded.entry(op).or_default();
+        recorded.buckets[bucket] += 1;
+        recorded.total += elapsed;
+    }
+
+    /// A Prometheus histogram of every op seen so far. For each op this is one stat
+    /// per bucket, like `request_duration_milliseconds_bucket{op="get_file",le="10"}`,
+    /// then the `_sum` of its times and the `_count` of them.
+    ///
+    /// Bucket counts are cumulative, each bucket includes the faster ones.
+    pub fn stats(&self) -> Vec<(String, usize)> {
+        let recorded = self.recorded.lock().unwrap();
+        let mut stats = Vec::new();
+
+        for (&op, recorded) in recorded.iter() {
+            let name = op::name(op).unwrap_or("unknown");
+            let mut count = 0;
+
+            for (in_bucket, label) in recorded.buckets.iter().zip(BUCKET_LABELS) {
+                count += in_bucket;
+                stats.push((
+                    format!("{HISTOGRAM}_bucket{{op=\"{name}\",le=\"{label}\"}}"),
+                    count,
+                ));
+            }
+            stats.push((
+                format!("{HISTOGRAM}_sum{{op=\"{name}\"}}"),
+                recorded.total.as_millis() as usize,
+            ));
+            stats.push((format!("{HISTOGRAM}_count{{op=\"{name}\"}}"), count));
+        }
+        stats
+    }
+}
@@ -0,0 +1,728 @@
+use std::{
+    cmp::Reverse,
+    collections::VecDeque,
+    fs, io,
+    io::Read,
+    ops::Add,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use p2p_service::{RateLimiter, Transport};
+use serde::{Deserialize, Serialize};
+
+/// Where pending uploads are kept between runs of the client.
+pub const QUEUE_FILE: &str = "client_queue.json";
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum TransferState {
+    Queued,
+    Active,
+    Done,
+    Failed(String),
+}
+
+/// Which queued transfer runs first, higher priorities go ahead of lower ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Carried over from an earlier run of the client.
+    Background,
+    /// Asked for by the user in this run.
+    Interactive,
+}
+
+pub struct Transfer {
+    pub id: u64,
+    pub priority: Priority,
+    /// Local path of the file being uploaded.
+    pub path: String,
+    /// Name the file is stored under on the server.
+    pub name: String,
+    /// Whether the file is stored as private, see `FileEntry::private`.
+    pub private: bool,
+    pub size: u64,
+    pub state: TransferState,
+    /// Bytes sent so far, updated by the thread doing the transfer.
+    pub progress: Arc<AtomicU64>,
+}
+
+impl Transfer {
+    /// Fraction of the file sent, between 0 and 1.
+    pub fn fraction(&self) -> f32 {
+        match self.state {
+            TransferState::Done => 1.0,
+            _ => self.progress.load(Ordering::Relaxed) as f32 / self.size.max(1) as f32,
+        }
+    }
+}
+
+/// Progress of several transfers taken together, such as everything queued at once.
+///
+/// Transfers of unknown size, like streamed ones, are left out of the fraction
+/// until they finish, only what they have moved so far is counted.
+#[derive(Clone, Copy, Default)]
+pub struct BatchProgress {
+    pub files: usize,
+    pub finished: usize,
+    /// Total size of the transfers whose size is known.
+    pub planned: u64,
+    /// Bytes moved so far by the transfers whose size is known.
+    pub transferred: u64,
+    /// Bytes moved so far by the transfers whose size isn't known.
+    pub unsized_bytes: u64,
+}
+
+impl BatchProgress {
+    /// Count a transfer of `size` bytes that has moved `done` so far.
+    ///
+    /// A finished transfer with a size counts as fully moved, whatever `done` says.
+    pub fn add(&mut self, size: Option<u64>, done: u64, finished: bool) {
+        self.files += 1;
+        self.finished += finished as usize;
+
+        match size {
+            Some(size) => {
+                self.planned += size;
+                self.transferred += if finished { size } else { done.min(size) };
+            }
+            None => self.unsized_bytes += done,
+        }
+    }
+
+    /// Fraction of the batch done, between 0 and 1.
+    pub fn fraction(&self) -> f32 {
+        if self.files > 0 && self.finished == self.files {
+            return 1.0;
+        }
+        self.transferred as f32 / self.planned.max(1) as f32
+    }
+}
+
+/// A pending upload as saved in `QUEUE_FILE`.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SavedTransfer {
+    /// Saved before uploads could be renamed, stored under the file's own name.
+    Path(String),
+    Named {
+        path: String,
+        name: String,
+        /// Missing from queues saved before uploads could be private.
+        #[serde(default)]
+        private: bool,
+    },
+}
+
+/// Uploads waiting to run, one at a time, by priority and then in order.
+///
+/// Pausing stops the next transfer from starting, the active one runs to the end.
+#[derive(Default)]
+pub struct TransferQueue {
+    items: Vec<Transfer>,
+    paused: bool,
+    next_id: u64,
+    /// ID of the first transfer of the current batch, see `batch`.
+    batch_start: u64,
+}
+
+impl TransferQueue {
+    /// Restore the uploads that were still queued when the client last closed.
+    pub fn load() -> io::Result<Self> {
+        let saved: Vec<SavedTransfer> = match fs::read_to_string(QUEUE_FILE) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        let mut queue = Self::default();
+        for transfer in saved {
+            let (path, name, private) = match transfer {
+                SavedTransfer::Path(path) => {
+                    let Some(name) = Path::new(&path).file_name() else {
+                        continue;
+                    };
+                    let name = name.to_string_lossy().to_string();
+                    (path, name, false)
+                }
+                SavedTransfer::Named {
+                    path,
+                    name,
+                    private,
+                } => (path, name, private),
+            };
+
+            // Files may have been moved since, they are dropped from the queue
+            if let Ok(metadata) = fs::metadata(&path) {
+                queue.push(path, name, private, metadata.len(), Priority::Background);
+            }
+        }
+
+        Ok(queue)
+    }
+
+    /// Save every upload that hasn't finished, an active one starts over next time.
+    pub fn save(&self) -> io::Result<()> {
+        let pending: Vec<SavedTransfer> = self
+            .items
+            .iter()
+            .filter(|item| matches!(item.state, TransferState::Queued | TransferState::Active))
+            .map(|item| SavedTransfer::Named {
+                path: item.path.clone(),
+                name: item.name.clone(),
+                private: item.private,
+            })
+            .collect();
+
+        fs::write(QUEUE_FILE, serde_json::to_string(&pending)?)
+    }
+
+    pub fn push(
+        &mut self,
+        path: String,
+        name: String,
+        private: bool,
+        size: u64,
+        priority: Priority,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // Anything added once the last batch is over starts a new one
+        let idle = self
+            .items
+            .iter()
+            .all(|item| matches!(item.state, TransferState::Done | TransferState::Failed(_)));
+        if idle {
+            self.batch_start = id;
+        }
+
+        self.items.push(Transfer {
+            id,
+            priority,
+            path,
+            name,
+            private,
+            size,
+            state: TransferState::Queued,
+            progress: Arc::new(AtomicU64::new(0)),
+        });
+        id
+    }
+
+    #[inline]
+    pub fn items(&self) -> &[Transfer] {
+        &self.items
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    #[inline]
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    #[inline]
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.active().is_some()
+    }
+
+    /// Progress of the transfers added since the queue was last idle, taken together.
+    ///
+    /// Failed transfers count as finished, with none of their bytes.
+    pub fn batch(&self) -> BatchProgress {
+        let mut batch = BatchProgress::default();
+
+        for item in self.items.iter().filter(|item| item.id >= self.batch_start) {
+            match item.state {
+                TransferState::Failed(_) => batch.add(Some(0), 0, true),
+                TransferState::Done => batch.add(Some(item.size), item.size, true),
+                _ => batch.add(
+                    Some(item.size),
+                    item.progress.load(Ordering::Relaxed),
+                    false,
+                ),
+            }
+        }
+        batch
+    }
+
+    /// The transfer running now, if there is one.
+    pub fn active(&self) -> Option<&Transfer> {
+        self.items
+            .iter()
+            .find(|item| item.state == TransferState::Active)
+    }
+
+    /// Swap a queued transfer with the queued one before it.
+    pub fn move_up(&mut self, id: u64) {
+        if let Some(pos) = self.queued_position(id) {
+            let before = self.items[..pos]
+                .iter()
+                .rposition(|item| item.state == TransferState::Queued);
+
+            if let Some(before) = before {
+                self.items.swap(before, pos);
+            }
+        }
+    }
+
+    /// Swap a queued transfer with the queued one after it.
+    pub fn move_down(&mut self, id: u64) {
+        if let Some(pos) = self.queued_position(id) {
+            let after = self.items[pos + 1..]
+                .iter()
+                .position(|item| item.state == TransferState::Queued);
+
+            if let Some(after) = after {
+                self.items.swap(pos, pos + 1 + after);
+            }
+        }
+    }
+
+    /// Remove a transfer that isn't running.
+    pub fn remove(&mut self, id: u64) {
+        self.items
+            .retain(|item| item.id != id || item.state == TransferState::Active);
+    }
+
+    /// Mark the next queued transfer active, unless one is running or the queue is paused.
+    ///
+    /// Background transfers only start once no interactive ones are waiting.
+    pub fn start_next(&mut self) -> Option<&Transfer> {
+        if self.paused || self.is_active() {
+            return None;
+        }
+
+        // `min_by_key` keeps the first of equal items, so the queue order holds within a priority
+        let item = self
+            .items
+            .iter_mut()
+            .filter(|item| item.state == TransferState::Queued)
+            .min_by_key(|item| Reverse(item.priority))?;
+
+        item.state = TransferState::Active;
+        Some(item)
+    }
+
+    /// Record how the active transfer `id` ended.
+    pub fn finish(&mut self, id: u64, result: Result<(), String>) {
+        if let Some(item) = self.items.iter_mut().find(|item| item.id == id) {
+            item.state = match result {
+                Ok(()) => TransferState::Done,
+                Err(msg) => TransferState::Failed(msg),
+            };
+        }
+    }
+
+    fn queued_position(&self, id: u64) -> Option<usize> {
+        self.items
+            .iter()
+            .position(|item| item.id == id && item.state == TransferState::Queued)
+    }
+}
+
+/// Adds the number of bytes read through it to `count`, and to `SESSION` as sent.
+pub struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R, count: Arc<AtomicU64>) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.count.fetch_add(bytes_read as u64, Ordering::Relaxed);
+        SESSION.record_sent(bytes_read as u64);
+        Ok(bytes_read)
+    }
+}
+
+/// Caps on bytes per second, shared by every transfer in that direction.
+pub static UPLOAD_LIMIT: Throttle = Throttle::new();
+pub static DOWNLOAD_LIMIT: Throttle = Throttle::new();
+
+/// A byte rate limit that does nothing until a rate is set.
+pub struct Throttle(Mutex<Option<RateLimiter>>);
+
+impl Throttle {
+    pub const fn new() -> Self {
+        Self(Mutex::new(None))
+    }
+
+    /// Limit transfers to `rate` bytes per second.
+    pub fn set(&self, rate: u32) {
+        *self.0.lock().unwrap() = Some(RateLimiter::new(rate));
+    }
+
+    /// Wait until `bytes` more fit under the limit.
+    pub fn take(&self, bytes: usize) {
+        if let Some(limiter) = self.0.lock().unwrap().as_mut() {
+            limiter.acquire_many(bytes);
+        }
+    }
+}
+
+/// Paces reads through `inner` to `throttle`.
+///
+/// Wraps the file being uploaded, or the connection a download comes in on.
+pub struct Throttled<T> {
+    inner: T,
+    throttle: &'static Throttle,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, throttle: &'static Throttle) -> Self {
+        Self { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.throttle.take(bytes_read);
+        Ok(bytes_read)
+    }
+}
+
+impl<S: Transport> Transport for Throttled<&S> {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.throttle.take(bytes_read);
+        Ok(bytes_read)
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)
+    }
+
+    fn peer(&self) -> String {
+        self.inner.peer()
+    }
+}
+
+/// Where lifetime totals are kept between runs of the client.
+pub const STATS_FILE: &str = "client_stats.json";
+
+/// Bytes and files moved since the client started, or since the last reset.
+pub static SESSION: TransferStats = TransferStats::new();
+
+/// Counters updated by the transfer threads and read by the window.
+pub struct TransferStats {
+    sent: AtomicU64,
+    received: AtomicU64,
+    files: AtomicU64,
+}
+
+impl TransferStats {
+    pub const fn new() -> Self {
+        Self {
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            files: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn record_sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn record_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn record_file(&self) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn totals(&self) -> Totals {
+        Totals {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            files: self.files.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Zero the counters, returning what they held.
+    pub fn take(&self) -> Totals {
+        Totals {
+            sent: self.sent.swap(0, Ordering::Relaxed),
+            received: self.received.swap(0, Ordering::Relaxed),
+            files: self.files.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Totals {
+    pub sent: u64,
+    pub received: u64,
+    pub files: u64,
+}
+
+impl Totals {
+    pub fn load() -> io::Result<Self> {
+        match fs::read_to_string(STATS_FILE) {
+            Ok(json) => Ok(serde_json::from_str(&json)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        fs::write(STATS_FILE, serde_json::to_string(self)?)
+    }
+}
+
+impl Add for Totals {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            sent: self.sent + other.sent,
+            received: self.received + other.received,
+            files: self.files + other.files,
+        }
+    }
+}
+
+/// Seconds of throughput kept by `Bandwidth`.
+const HISTORY_SECS: usize = 60;
+
+/// Bytes per second in each direction over the last minute, sampled from `SESSION`.
+pub struct Bandwidth {
+    sent: VecDeque<f32>,
+    received: VecDeque<f32>,
+    last: Totals,
+    last_sample: Instant,
+    started: Instant,
+}
+
+impl Bandwidth {
+    pub fn new() -> Self {
+        Self {
+            sent: VecDeque::from(vec![0.0; HISTORY_SECS]),
+            received: VecDeque::from(vec![0.0; HISTORY_SECS]),
+            last: SESSION.totals(),
+            last_sample: Instant::now(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Take a sample if a second has passed, called every frame.
+    pub fn update(&mut self) {
+        if self.last_sample.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+
+        let now = SESSION.totals();
+        let secs = self.last_sample.elapsed().as_secs_f32();
+
+        for (history, bytes) in [
+            (&mut self.sent, now.sent.saturating_sub(self.last.sent)),
+            (
+                &mut self.received,
+                now.received.saturating_sub(self.last.received),
+            ),
+        ] {
+            history.pop_front();
+            history.push_back(bytes as f32 / secs);
+        }
+
+        self.last = now;
+        self.last_sample = Instant::now();
+    }
+
+    pub fn sent(&mut self) -> &[f32] {
+        self.sent.make_contiguous()
+    }
+
+    pub fn received(&mut self) -> &[f32] {
+        self.received.make_contiguous()
+    }
+
+    /// Average bytes per second in both directions since the session started.
+    pub fn average(&self) -> f64 {
+        let totals = SESSION.totals();
+        (totals.sent + totals.received) as f64 / self.started.elapsed().as_secs_f64().max(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_with(sizes: &[u64], priority: Priority) -> TransferQueue {
+        let mut queue = TransferQueue::default();
+        for (i, &size) in sizes.iter().enumerate() {
+            queue.push(format!("local/{i}"), i.to_string(), false, size, priority);
+        }
+        queue
+    }
+
+    /// Run every queued transfer to the end, returning their names in the order started.
+    fn run_all(queue: &mut TransferQueue) -> Vec<String> {
+        let mut order = Vec::new();
+        while let Some(item) = queue.start_next() {
+            let id = item.id;
+            order.push(item.name.clone());
+            queue.finish(id, Ok(()));
+        }
+        order
+    }
+
+    #[test]
+    fn interactive_transfers_run_before_background_ones() {
+        let mut queue = queue_with(&[1, 1], Priority::Background);
+        queue.push(
+            "local/x".to_string(),
+            "x".to_string(),
+            false,
+            1,
+            Priority::Interactive,
+        );
+
+        assert_eq!(run_all(&mut queue), ["x", "0", "1"]);
+    }
+
+    #[test]
+    fn equal_priorities_keep_their_order() {
+        let mut queue = queue_with(&[1, 1, 1], Priority::Interactive);
+        queue.push(
+            "local/bg".to_string(),
+            "bg".to_string(),
+            false,
+            1,
+            Priority::Background,
+        );
+        queue.push(
+            "local/3".to_string(),
+            "3".to_string(),
+            false,
+            1,
+            Priority::Interactive,
+        );
+
+        assert_eq!(run_all(&mut queue), ["0", "1", "2", "3", "bg"]);
+    }
+
+    #[test]
+    fn background_transfer_already_running_is_not_preempted() {
+        let mut queue = queue_with(&[1, 1], Priority::Background);
+        let running = queue.start_next().unwrap().id;
+        queue.push(
+            "local/x".to_string(),
+            "x".to_string(),
+            false,
+            1,
+            Priority::Interactive,
+        );
+
+        // Only one runs at a time, the interactive one goes next
+        assert!(queue.start_next().is_none());
+        queue.finish(running, Ok(()));
+        assert_eq!(run_all(&mut queue), ["x", "1"]);
+    }
+
+    #[test]
+    fn batch_progress_reaches_the_end_of_a_batch() {
+        let mut queue = queue_with(&[100, 300, 600], Priority::Interactive);
+        assert_eq!(queue.batch().fraction(), 0.0);
+
+        let mut last = 0.0;
+        while let Some(item) = queue.start_next() {
+            let (id, size, progress) = (item.id, item.size, item.progress.clone());
+
+            // Sent in halves, the whole batch moving forward with each
+            for sent in [size / 2, size] {
+                progress.store(sent, Ordering::Relaxed);
+                let fraction = queue.batch().fraction();
+                assert!(fraction > last, "{fraction} after {last}");
+                last = fraction;
+            }
+            queue.finish(id, Ok(()));
+        }
+
+        let batch = queue.batch();
+        assert_eq!(batch.fraction(), 1.0);
+        assert_eq!((batch.files, batch.finished), (3, 3));
+        assert_eq!(batch.transferred, 1000);
+    }
+
+    #[test]
+    fn failed_and_unsized_transfers_still_finish_the_batch() {
+        let mut batch = BatchProgress::default();
+        batch.add(Some(100), 40, false);
+        batch.add(None, 5000, false);
+        assert_eq!(batch.fraction(), 0.4);
+
+        let mut batch = BatchProgress::default();
+        batch.add(Some(100), 100, true);
+        batch.add(None, 5000, true);
+        batch.add(Some(0), 0, true);
+        assert_eq!(batch.fraction(), 1.0);
+    }
+
+    #[test]
+    fn next_batch_starts_from_zero() {
+        let mut queue = queue_with(&[10], Priority::Interactive);
+        let id = queue.start_next().unwrap().id;
+        queue.finish(id, Ok(()));
+        assert_eq!(queue.batch().fraction(), 1.0);
+
+        queue.push(
+            "local/next".to_string(),
+            "next".to_string(),
+            false,
+            10,
+            Priority::Interactive,
+        );
+        assert_eq!(queue.batch().files, 1);
+        assert_eq!(queue.batch().fraction(), 0.0);
+    }
+
+    #[test]
+    fn capped_reads_take_as_long_as_the_rate_allows() {
+        static LIMIT: Throttle = Throttle::new();
+        const RATE: u32 = 50_000;
+        LIMIT.set(RATE);
+
+        let contents = vec![0; 3 * RATE as usize];
+        let started = Instant::now();
+        let copied = io::copy(&mut Throttled::new(&contents[..], &LIMIT), &mut io::sink()).unwrap();
+        let elapsed = started.elapsed();
+
+        // The first second's worth goes straight through, the rest at the rate
+        assert_eq!(copied, contents.len() as u64);
+        assert!(elapsed >= Duration::from_millis(1900), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+    }
+
+    #[test]
+    fn unset_throttle_doesnt_wait() {
+        static UNLIMITED: Throttle = Throttle::new();
+
+        let started = Instant::now();
+        UNLIMITED.take(usize::MAX / 2);
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+
+use p2p_service::TreeNode;
+
+/// A folder or file while the tree is put together, children sorted by name.
+#[derive(Default)]
+struct Node {
+    size: u64,
+    modified: u64,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn add(&mut self, path: &[&str], size: u64, modified: u64) {
+        self.size += size;
+        self.modified = self.modified.max(modified);
+
+        if let Some((first, rest)) = path.split_first() {
+            self.children
+                .entry(first.to_string())
+                .or_default()
+                .add(rest, size, modified);
+        }
+    }
+
+    /// Keep `depth` levels below this node, taking children from `budget` until it runs out.
+    fn into_tree(self, name: String, depth: usize, budget: &mut usize) -> TreeNode {
+        let mut tree = TreeNode {
+            name,
+            size: self.size,
+            modified: self.modified,
+            ..TreeNode::default()
+        };
+
+        if depth == 0 {
+            tree.truncated = !self.children.is_empty();
+            return tree;
+        }
+
+        for (name, child) in self.children {
+            if *budget == 0 {
+                tree.truncated = true;
+                break;
+            }
+            *budget -= 1;
+            tree.children.push(child.into_tree(name, depth - 1, budget));
+        }
+        tree
+    }
+}
+
+/// The tree of `files` (name, size, modified) under `path`, split into folders on '/'.
+///
+/// Stored names have no folders today, so this is usually the root and one level of
+/// files. `None` if nothing is at `path`, the root always exists even with no files.
+pub fn build<'a>(
+    files: impl IntoIterator<Item = (&'a str, u64, u64)>,
+    path: &str,
+    depth: usize,
+    max_nodes: usize,
+) -> Option<TreeNode> {
+    let path: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    let mut root = Node::default();
+    let mut found = path.is_empty();
+
+    for (name, size, modified) in files {
+        let parts: Vec<&str> = name.split('/').filter(|part| !part.is_empty()).collect();
+        if let Some(rest) = parts.strip_prefix(path.as_slice()) {
+            root.add(rest, size, modified);
+            found = true;
+        }
+    }
+
+    if !found {
+        return None;
+    }
+
+    // The node for `path` itself is the first one sent
+    let mut budget = max_nodes.saturating_sub(1);
+    let name = path
+        .last()
+        .map_or_else(String::new, |name| name.to_string());
+    Some(root.into_tree(name, depth, &mut budget))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILES: [(&str, u64, u64); 5] = [
+        ("b.txt", 1, 10),
+        ("docs/z.md", 2, 20),
+        ("docs/a.md", 4, 40),
+        ("docs/old/notes.txt", 8, 5),
+        ("a.txt", 16, 30),
+    ];
+
+    fn names(tree: &TreeNode) -> Vec<&str> {
+        tree.children
+            .iter()
+            .map(|child| child.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn nested_paths_become_folders_sorted_by_name() {
+        let root = build(FILES, "", 8, 100).unwrap();
+        assert_eq!(root.name, "");
+        assert_eq!(names(&root), ["a.txt", "b.txt", "docs"]);
+
+        let docs = &root.children[2];
+        assert_eq!(names(docs), ["a.md", "old", "z.md"]);
+        assert_eq!(names(&docs.children[1]), ["notes.txt"]);
+        assert!(docs.children[0].children.is_empty());
+    }
+
+    #[test]
+    fn folders_total_their_contents() {
+        let root = build(FILES, "", 8, 100).unwrap();
+        assert_eq!((root.size, root.modified), (31, 40));
+
+        let docs = &root.children[2];
+        assert_eq!((docs.size, docs.modified), (14, 40));
+        assert_eq!((docs.children[1].size, docs.children[1].modified), (8, 5));
+    }
+
+    #[test]
+    fn subtree_at_a_path() {
+        for path in ["docs", "/docs/", "docs//"] {
+            let docs = build(FILES, path, 8, 100).unwrap();
+            assert_eq!(docs.name, "docs");
+            assert_eq!(names(&docs), ["a.md", "old", "z.md"]);
+        }
+
+        assert!(build(FILES, "missing", 8, 100).is_none());
+        assert!(build(FILES, "doc", 8, 100).is_none());
+    }
+
+    #[test]
+    fn empty_root_still_exists() {
+        let root = build([], "", 8, 100).unwrap();
+        assert!(root.children.is_empty());
+        assert!(!root.truncated);
+        assert_eq!(root.size, 0);
+
+        assert!(build([], "docs", 8, 100).is_none());
+    }
+
+    #[test]
+    fn depth_and_node_limits_truncate() {
+        let root = build(FILES, "", 1, 100).unwrap();
+        assert_eq!(names(&root), ["a.txt", "b.txt", "docs"]);
+        assert!(root.children[2].children.is_empty());
+        assert!(root.children[2].truncated);
+        assert!(!root.children[0].truncated);
+
+        // The root and two children
+        let root = build(FILES, "", 8, 3).unwrap();
+        assert_eq!(names(&root), ["a.txt", "b.txt"]);
+        assert!(root.truncated);
+    }
+}
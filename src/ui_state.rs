@@ -0,0 +1,256 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex, OnceLock,
+    },
+};
+
+use p2p_service::FileEntry;
+
+/// Lines of the server's log kept for the window, older ones are dropped.
+const LOG_LINES: usize = 500;
+
+/// A change to `UiState`, sent from any thread and applied by the render loop.
+pub enum UiEvent {
+    /// A file on the server as it reported it, such as a finished upload.
+    ///
+    /// Replaces what was known about a file of the same name. Comes with the
+    /// server's index generation once the file was there, when it is known.
+    FileAdded(FileEntry, Option<u64>),
+    FileRemoved(String),
+    /// A fresh listing, shown in place of the current one.
+    ///
+    /// With the server's index generation from before it was fetched, files added
+    /// at a later generation are kept even if the listing is missing them, see `stale`.
+    ListingReplaced(Vec<String>, Option<u64>),
+    /// Whether the window's connection to the server is still usable.
+    ConnectionStatus(bool),
+    /// A message for the user, listed at the top of the window.
+    Notification(String),
+    /// A line from the server's log, while following it.
+    LogLine(String),
+}
+
+/// What the window shows, owned by the render loop.
+///
+/// The render loop changes it directly or through `apply`. Other threads send
+/// `UiEvent`s from `sender` instead, which are applied at the start of the next frame.
+#[derive(Default)]
+pub struct UiState {
+    /// Files on the server by name, with their metadata once the server has sent it.
+    ///
+    /// Listings only hold names, metadata comes from looking a file up.
+    pub files: BTreeMap<String, Option<FileEntry>>,
+    /// Files added with a known generation, by name, so older listings don't hide them.
+    added: BTreeMap<String, u64>,
+    /// Files kept over a listing that didn't have them, to be looked up again.
+    stale: Vec<String>,
+    pub disconnected: bool,
+    pub notices: Vec<String>,
+    /// The most recent lines of the server's log, oldest first.
+    pub log: VecDeque<String>,
+}
+
+impl UiState {
+    pub fn apply(&mut self, event: UiEvent) {
+        match event {
+            UiEvent::FileAdded(entry, generation) => {
+                if let Some(generation) = generation {
+                    let added = self.added.entry(entry.name.clone()).or_default();
+                    *added = generation.max(*added);
+                }
+                self.files.insert(entry.name.clone(), Some(entry));
+            }
+            UiEvent::FileRemoved(name) => {
+                self.files.remove(&name);
+                self.added.remove(&name);
+            }
+            UiEvent::ListingReplaced(files, generation) => self.replace_listing(files, generation),
+            UiEvent::ConnectionStatus(connected) => self.disconnected = !connected,
+            UiEvent::Notification(msg) => self.notices.push(msg),
+            UiEvent::LogLine(line) => {
+                if self.log.len() == LOG_LINES {
+                    self.log.pop_front();
+                }
+                self.log.push_back(line);
+            }
+        }
+    }
+
+    /// Show `files` in place of the current listing, keeping files added after `generation`.
+    ///
+    /// A listing fetched while an upload completes can be from before the server
+    /// stored it. Files it is missing that were added at a later generation are
+    /// kept as they were and marked stale, anything else it is missing is dropped.
+    /// Without a generation the listing is taken as is.
+    fn replace_listing(&mut self, files: Vec<String>, generation: Option<u64>) {
+        let mut previous = std::mem::take(&mut self.files);
+        self.files = files.into_iter().map(|name| (name, None)).collect();
+
+        let Some(generation) = generation else {
+            self.added.clear();
+            return;
+        };
+
+        // Newer than the listing, so it may still be the only sign of them
+        self.added.retain(|_, added| *added > generation);
+        for name in self.added.keys() {
+            if !self.files.contains_key(name) {
+                let entry = previous.remove(name).flatten();
+                self.files.insert(name.clone(), entry);
+
+                if !self.stale.contains(name) {
+                    self.stale.push(name.clone());
+                }
+            }
+        }
+    }
+
+    /// Files kept over a listing that didn't have them since the last call.
+    ///
+    /// Each should be looked up on the server and applied as `FileAdded` or
+    /// `FileRemoved`, whichever it turns out to be.
+    pub fn take_stale(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.stale)
+    }
+
+    /// Apply every event sent since the last call, without waiting for more.
+    pub fn apply_pending(&mut self) {
+        let receiver = channel().1.lock().unwrap();
+
+        for event in receiver.try_iter() {
+            self.apply(event);
+        }
+    }
+}
+
+/// A handle for sending events to the window from another thread.
+pub fn sender() -> Sender<UiEvent> {
+    channel().0.clone()
+}
+
+/// One channel for the whole client, so events sent before the window opens still reach it.
+fn channel() -> &'static (Sender<UiEvent>, Mutex<Receiver<UiEvent>>) {
+    static CHANNEL: OnceLock<(Sender<UiEvent>, Mutex<Receiver<UiEvent>>)> = OnceLock::new();
+
+    CHANNEL.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        (sender, Mutex::new(receiver))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, size: u64) -> FileEntry {
+        FileEntry {
+            name: name.to_string(),
+            size,
+            modified: 0,
+            tags: Vec::new(),
+            description: String::new(),
+            private: false,
+            downloads: 0,
+        }
+    }
+
+    fn names(state: &UiState) -> Vec<&str> {
+        state.files.keys().map(String::as_str).collect()
+    }
+
+    fn listing(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn added_files_replace_and_removed_ones_go() {
+        let mut state = UiState::default();
+        state.apply(UiEvent::FileAdded(entry("a", 1), None));
+        state.apply(UiEvent::FileAdded(entry("a", 2), None));
+        state.apply(UiEvent::FileAdded(entry("b", 3), None));
+        assert_eq!(names(&state), ["a", "b"]);
+        assert_eq!(state.files["a"].as_ref().unwrap().size, 2);
+
+        state.apply(UiEvent::FileRemoved("a".to_string()));
+        state.apply(UiEvent::FileRemoved("missing".to_string()));
+        assert_eq!(names(&state), ["b"]);
+    }
+
+    #[test]
+    fn listing_without_a_generation_is_taken_as_is() {
+        let mut state = UiState::default();
+        state.apply(UiEvent::FileAdded(entry("uploaded", 1), Some(5)));
+        state.apply(UiEvent::ListingReplaced(listing(&["x", "y"]), None));
+
+        assert_eq!(names(&state), ["x", "y"]);
+        assert!(state.files["x"].is_none());
+        assert!(state.take_stale().is_empty());
+    }
+
+    #[test]
+    fn older_listing_keeps_newer_files_as_stale() {
+        let mut state = UiState::default();
+        state.apply(UiEvent::FileAdded(entry("old", 1), Some(3)));
+        state.apply(UiEvent::FileAdded(entry("new", 2), Some(8)));
+
+        // Fetched at generation 5, before "new" was stored
+        state.apply(UiEvent::ListingReplaced(listing(&["other"]), Some(5)));
+        assert_eq!(names(&state), ["new", "other"]);
+        assert_eq!(state.files["new"].as_ref().unwrap().size, 2);
+        assert_eq!(state.take_stale(), ["new"]);
+        assert!(state.take_stale().is_empty());
+
+        // A listing from after it was stored settles it
+        state.apply(UiEvent::ListingReplaced(listing(&["other"]), Some(8)));
+        assert_eq!(names(&state), ["other"]);
+        assert!(state.take_stale().is_empty());
+    }
+
+    #[test]
+    fn removing_a_file_forgets_its_generation() {
+        let mut state = UiState::default();
+        state.apply(UiEvent::FileAdded(entry("a", 1), Some(8)));
+        state.apply(UiEvent::FileRemoved("a".to_string()));
+
+        state.apply(UiEvent::ListingReplaced(Vec::new(), Some(5)));
+        assert!(state.files.is_empty());
+    }
+
+    #[test]
+    fn connection_notices_and_log_lines() {
+        let mut state = UiState::default();
+        state.apply(UiEvent::ConnectionStatus(false));
+        assert!(state.disconnected);
+        state.apply(UiEvent::ConnectionStatus(true));
+        assert!(!state.disconnected);
+
+        state.apply(UiEvent::Notification("hello".to_string()));
+        assert_eq!(state.notices, ["hello"]);
+
+        for i in 0..LOG_LINES + 2 {
+            state.apply(UiEvent::LogLine(i.to_string()));
+        }
+        assert_eq!(state.log.len(), LOG_LINES);
+        assert_eq!(state.log.front().unwrap(), "2");
+        assert_eq!(state.log.back().unwrap(), &(LOG_LINES + 1).to_string());
+    }
+
+    #[test]
+    fn events_sent_from_other_threads_are_applied_next_frame() {
+        let mut state = UiState::default();
+        let sender = sender();
+        std::thread::spawn(move || {
+            sender
+                .send(UiEvent::FileAdded(entry("from a thread", 1), None))
+                .unwrap();
+        })
+        .join()
+        .unwrap();
+
+        assert!(state.files.is_empty());
+        state.apply_pending();
+        assert_eq!(names(&state), ["from a thread"]);
+    }
+}
@@ -0,0 +1,113 @@
+//! Uploads whose client goes away halfway through, which must leave the server as
+//! it was before they started.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, os::unix::net::UnixStream};
+
+use common::{upload, wait_for, TestServer};
+use p2p_service::{
+    fetch_files, fetch_stats, get_file, op, read_response, send_reader, send_stream, start_upload,
+    write_usize, Chunk,
+};
+
+const FILE_NAME: &str = "half.bin";
+const SIZE: usize = 64 * 1024;
+
+fn active_transfers(server: &TestServer) -> usize {
+    let (stream, info) = server.connect();
+    let stats = fetch_stats(&stream, &info).unwrap();
+    stats
+        .into_iter()
+        .find(|(name, _)| name == "active_transfers")
+        .map_or(0, |(_, value)| value)
+}
+
+fn temp_files(server: &TestServer) -> usize {
+    fs::read_dir(server.files_dir().join(".tmp")).map_or(0, |dir| dir.count())
+}
+
+/// Nothing stored, listed or indexed under `FILE_NAME`, and no temporary files left.
+fn assert_untouched(server: &TestServer) {
+    assert!(!server.files_dir().join(FILE_NAME).exists());
+    assert_eq!(temp_files(server), 0);
+
+    let (stream, info) = server.connect();
+    assert!(fetch_files(&stream, &info).unwrap().is_empty());
+
+    let index = server.dir().join("server_index.json");
+    if index.exists() {
+        let index = fs::read_to_string(index).unwrap();
+        assert!(!index.contains(FILE_NAME), "{index}");
+    }
+}
+
+/// The same name can be uploaded whole afterwards.
+fn assert_uploads_again(server: &TestServer, contents: &[u8]) {
+    let (stream, info) = server.connect();
+    upload(&stream, &info, FILE_NAME, contents, false).unwrap();
+
+    let fetched = get_file(&stream, &info, FILE_NAME).unwrap();
+    assert_eq!(fetched.unwrap(), contents);
+    assert_eq!(temp_files(server), 0);
+}
+
+#[test]
+fn upload_dropped_halfway_leaves_nothing_behind() {
+    let server = TestServer::start(&[]);
+    let contents = vec![7; SIZE];
+
+    let (stream, info) = server.connect();
+    let mut chunk = Chunk::<1024, UnixStream>::new(&stream);
+    start_upload(&mut chunk, &info, op::ADD_FILE, FILE_NAME, false).unwrap();
+    // Announces the whole size, then runs out halfway
+    let sent = send_reader(&mut chunk, &contents[..SIZE / 2], SIZE);
+    assert!(sent.is_err());
+
+    wait_for("the upload to start", || active_transfers(&server) == 1);
+    drop(chunk);
+    drop(stream);
+    wait_for("the upload to be dropped", || {
+        active_transfers(&server) == 0
+    });
+
+    assert_untouched(&server);
+    assert_uploads_again(&server, &contents);
+}
+
+#[test]
+fn streamed_upload_dropped_halfway_leaves_nothing_behind() {
+    let server = TestServer::start(&[]);
+    let contents = vec![7; SIZE];
+
+    let (stream, info) = server.connect();
+    let mut chunk = Chunk::<1024, UnixStream>::new(&stream);
+    start_upload(&mut chunk, &info, op::ADD_FILE_STREAM, FILE_NAME, false).unwrap();
+    // Frames of half the contents, and never the empty frame that ends the stream
+    for frame in contents[..SIZE / 2].chunks(1024) {
+        write_usize(&mut chunk, frame.len()).unwrap();
+        chunk.write_and_send(frame).unwrap();
+    }
+
+    wait_for("the stream's temporary file", || temp_files(&server) == 1);
+    drop(chunk);
+    drop(stream);
+    wait_for("the temporary file to be removed", || {
+        temp_files(&server) == 0
+    });
+
+    assert_untouched(&server);
+
+    // And streamed again just as well
+    let (stream, info) = server.connect();
+    let mut chunk = Chunk::<1024, UnixStream>::new(&stream);
+    start_upload(&mut chunk, &info, op::ADD_FILE_STREAM, FILE_NAME, false).unwrap();
+    send_stream(&mut chunk, &contents[..]).unwrap();
+    read_response(&mut chunk).unwrap();
+    assert_eq!(
+        fs::read(server.files_dir().join(FILE_NAME)).unwrap(),
+        contents
+    );
+}
@@ -0,0 +1,50 @@
+//! Connections accepted no faster than `--accept-rate` allows.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::TestServer;
+
+/// Connections accepted per second.
+const RATE: u32 = 5;
+const CONNECTIONS: u32 = 20;
+
+#[test]
+fn burst_of_connections_is_accepted_at_the_rate() {
+    let server = TestServer::start(&["--accept-rate", &RATE.to_string()]);
+
+    // All opened at once, each timed until the server has answered its handshake
+    let started = Instant::now();
+    let mut answered: Vec<Duration> = thread::scope(|scope| {
+        let clients: Vec<_> = (0..CONNECTIONS)
+            .map(|_| {
+                scope.spawn(|| {
+                    server.connect();
+                    started.elapsed()
+                })
+            })
+            .collect();
+        clients
+            .into_iter()
+            .map(|client| client.join().unwrap())
+            .collect()
+    });
+    answered.sort();
+
+    // A full second's worth goes straight in, the rest waits its turn
+    let in_first_second = answered
+        .iter()
+        .filter(|at| **at < Duration::from_secs(1))
+        .count() as u32;
+    assert!(in_first_second <= 2 * RATE + 1, "{answered:?}");
+
+    let least = Duration::from_secs_f64(f64::from(CONNECTIONS - RATE) / f64::from(RATE));
+    let last = *answered.last().unwrap();
+    assert!(last >= least - Duration::from_millis(500), "{answered:?}");
+}
@@ -0,0 +1,106 @@
+//! Files read by name through `ReadThroughCache`.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{cell::Cell, fs, io, os::unix::net::UnixStream};
+
+use common::{temp_dir, upload, TestServer};
+use p2p_service::{cache::ReadThroughCache, ConnectionInfo, Transport};
+
+const SIZE: usize = 64 * 1024;
+
+/// A connection that counts the bytes read from the server.
+struct Counted {
+    stream: UnixStream,
+    received: Cell<usize>,
+}
+
+impl Counted {
+    fn take_received(&self) -> usize {
+        self.received.replace(0)
+    }
+}
+
+impl Transport for Counted {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = Transport::read(&self.stream, buf)?;
+        self.received.set(self.received.get() + n);
+        Ok(n)
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        Transport::write_all(&self.stream, buf)
+    }
+
+    fn peer(&self) -> String {
+        "counted server".to_string()
+    }
+}
+
+fn connect(server: &TestServer) -> (Counted, ConnectionInfo) {
+    let (stream, info) = server.connect();
+    let counted = Counted {
+        stream,
+        received: Cell::new(0),
+    };
+    (counted, info)
+}
+
+#[test]
+fn second_read_is_served_from_the_cache() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = connect(&server);
+    upload(&stream, &info, "a.bin", &[1; SIZE], false).unwrap();
+    stream.take_received();
+
+    let dir = temp_dir("cache");
+    let mut cache = ReadThroughCache::open(&dir, 4 * SIZE as u64).unwrap();
+
+    let first = cache.read(&stream, &info, "a.bin").unwrap();
+    assert_eq!(first.as_deref(), Some(&[1; SIZE][..]));
+    assert!(stream.take_received() >= SIZE);
+
+    // Only validated, the contents aren't sent again
+    let second = cache.read(&stream, &info, "a.bin").unwrap();
+    assert_eq!(second, first);
+    assert!(stream.take_received() < 1024);
+
+    // Until the server's copy changes
+    upload(&stream, &info, "a.bin", &[2; SIZE], false).unwrap();
+    stream.take_received();
+    let changed = cache.read(&stream, &info, "a.bin").unwrap();
+    assert_eq!(changed.as_deref(), Some(&[2; SIZE][..]));
+    assert!(stream.take_received() >= SIZE);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn least_recently_read_is_evicted() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = connect(&server);
+    for name in ["a", "b", "c"] {
+        upload(&stream, &info, name, &[0; SIZE], false).unwrap();
+    }
+
+    let dir = temp_dir("cache");
+    let mut cache = ReadThroughCache::open(&dir, 2 * SIZE as u64).unwrap();
+
+    cache.read(&stream, &info, "a").unwrap();
+    cache.read(&stream, &info, "b").unwrap();
+    cache.read(&stream, &info, "a").unwrap();
+    cache.read(&stream, &info, "c").unwrap();
+
+    assert!(cache.contains("a") && cache.contains("c"));
+    assert!(!cache.contains("b"));
+    assert_eq!(cache.used_bytes(), 2 * SIZE as u64);
+
+    // Kept for the next run
+    drop(cache);
+    let cache = ReadThroughCache::open(&dir, 2 * SIZE as u64).unwrap();
+    assert!(cache.contains("a") && cache.contains("c"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
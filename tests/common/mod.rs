@@ -0,0 +1,211 @@
+//! A server run from the built binary in a directory of its own, for tests to talk to
+//! over a Unix socket.
+
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    net::TcpListener,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use p2p_service::{
+    handshake, hash_reader, op, read_response, send_reader, start_upload, write_op, write_string,
+    write_usize, Chunk, ConnectionInfo, ProtocolError, ProtocolResult, Transport,
+};
+
+/// How long a server gets to start listening.
+const STARTUP_DEADLINE: Duration = Duration::from_secs(10);
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh directory under the system's temp dir, removed by the caller.
+pub fn temp_dir(label: &str) -> PathBuf {
+    let n = NEXT_DIR.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("p2p-{label}-{}-{n}", std::process::id()));
+
+    _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// How long `wait_for` waits, long enough for a mirror to poll its primary twice.
+const WAIT_DEADLINE: Duration = Duration::from_secs(20);
+
+/// A TCP port on localhost that nothing was listening on a moment ago.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Wait until `done` holds, failing the test after `WAIT_DEADLINE`.
+pub fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(
+            start.elapsed() < WAIT_DEADLINE,
+            "timed out waiting for {what}"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// A running server, killed and its directory removed when dropped.
+pub struct TestServer {
+    dir: PathBuf,
+    socket: PathBuf,
+    args: Vec<String>,
+    child: Child,
+}
+
+impl TestServer {
+    /// Start a server with no files, passing it `args` on top of its socket.
+    pub fn start(args: &[&str]) -> Self {
+        Self::start_with(|_| {}, args)
+    }
+
+    /// Like `start`, with `setup` given the server's directory first to put files in place.
+    ///
+    /// The directory already has an empty `server_files` in it.
+    pub fn start_with(setup: impl FnOnce(&Path), args: &[&str]) -> Self {
+        let dir = temp_dir("server");
+        fs::create_dir(dir.join("server_files")).unwrap();
+        setup(&dir);
+
+        let socket = dir.join("server.sock");
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let child = spawn(&dir, &socket, &args);
+
+        let server = Self {
+            dir,
+            socket,
+            args,
+            child,
+        };
+        server.wait_until_listening();
+        server
+    }
+
+    /// Stop the server and start it again on the same directory, as after a crash.
+    pub fn restart(&mut self) {
+        _ = self.child.kill();
+        _ = self.child.wait();
+
+        self.child = spawn(&self.dir, &self.socket, &self.args);
+        self.wait_until_listening();
+    }
+
+    fn wait_until_listening(&self) {
+        let start = Instant::now();
+        while UnixStream::connect(&self.socket).is_err() {
+            assert!(
+                start.elapsed() < STARTUP_DEADLINE,
+                "server did not start listening"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// The directory the server runs in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Where the server keeps its files.
+    pub fn files_dir(&self) -> PathBuf {
+        self.dir.join("server_files")
+    }
+
+    /// A connection that hasn't sent anything yet.
+    pub fn connect_raw(&self) -> UnixStream {
+        UnixStream::connect(&self.socket).unwrap()
+    }
+
+    /// A connection that has agreed on a protocol version.
+    pub fn connect(&self) -> (UnixStream, ConnectionInfo) {
+        let stream = self.connect_raw();
+        let info = handshake(&stream).unwrap();
+        (stream, info)
+    }
+
+    /// Ask the server to shut down with SIGTERM, and wait for it to exit.
+    pub fn stop(&mut self) -> ExitStatus {
+        unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM) };
+
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            assert!(start.elapsed() < WAIT_DEADLINE, "server did not shut down");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Whether the server process is still running.
+    pub fn is_running(&mut self) -> bool {
+        self.child.try_wait().unwrap().is_none()
+    }
+}
+
+fn spawn(dir: &Path, socket: &Path, args: &[String]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_p2p_service"))
+        .current_dir(dir)
+        .arg("--unix")
+        .arg(socket)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        _ = self.child.kill();
+        _ = self.child.wait();
+        _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Upload `contents` as `file_name` with `op::ADD_FILE`.
+pub fn upload<S: Transport>(
+    stream: &S,
+    info: &ConnectionInfo,
+    file_name: &str,
+    contents: &[u8],
+    private: bool,
+) -> ProtocolResult<()> {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    start_upload(&mut chunk, info, op::ADD_FILE, file_name, private)?;
+    send_reader(&mut chunk, contents, contents.len())?;
+    read_response(&mut chunk)
+}
+
+/// Send part `number` of multipart upload `id` along with a hash that isn't its own,
+/// as if it was damaged on the way.
+pub fn upload_damaged_part<S: Transport>(stream: &S, id: &str, number: usize, contents: &[u8]) {
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    write_op(&mut chunk, op::MULTIPART_PART).unwrap();
+    write_string(&mut chunk, id).unwrap();
+    write_usize(&mut chunk, number).unwrap();
+    write_string(
+        &mut chunk,
+        &hash_reader(&b"something else"[..], |_| {}).unwrap(),
+    )
+    .unwrap();
+    send_reader(&mut chunk, contents, contents.len()).unwrap();
+
+    match read_response(&mut chunk) {
+        Err(ProtocolError::ChecksumMismatch(_)) => {}
+        other => panic!("expected a checksum mismatch, got {other:?}"),
+    }
+}
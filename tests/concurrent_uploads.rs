@@ -0,0 +1,68 @@
+//! Many clients transferring at once, sharing the server's pool of transfer buffers.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, os::unix::net::UnixStream, thread};
+
+use common::TestServer;
+use p2p_service::{
+    find_by_hash, get_file, hash_reader, op, read_response, send_stream, start_upload, Chunk,
+    ConnectionInfo,
+};
+
+const CLIENTS: usize = 8;
+const FILES_PER_CLIENT: usize = 4;
+
+/// Contents unique to each file and bigger than a buffer, so a buffer handed to two
+/// transfers at once or returned dirty would show.
+fn contents(client: usize, file: usize) -> Vec<u8> {
+    let len = 20_000 + client * 1000 + file * 37;
+    (0..len)
+        .map(|i| (i * 31 + client * 7 + file) as u8)
+        .collect()
+}
+
+fn upload_streamed(stream: &UnixStream, info: &ConnectionInfo, name: &str, contents: &[u8]) {
+    let mut chunk = Chunk::<1024, UnixStream>::new(stream);
+    start_upload(&mut chunk, info, op::ADD_FILE_STREAM, name, false).unwrap();
+    send_stream(&mut chunk, contents).unwrap();
+    read_response(&mut chunk).unwrap();
+}
+
+#[test]
+fn concurrent_transfers_keep_their_contents_apart() {
+    let server = TestServer::start(&[]);
+
+    thread::scope(|scope| {
+        for client in 0..CLIENTS {
+            let server = &server;
+            scope.spawn(move || {
+                let (stream, info) = server.connect();
+                for file in 0..FILES_PER_CLIENT {
+                    let name = format!("{client}-{file}.bin");
+                    let contents = contents(client, file);
+
+                    upload_streamed(&stream, &info, &name, &contents);
+                    let read = get_file(&stream, &info, &name).unwrap().unwrap();
+                    assert!(read == contents, "{name} came back changed");
+                }
+            });
+        }
+    });
+
+    // Every file stored whole, and hashed from the right bytes
+    let (stream, info) = server.connect();
+    for client in 0..CLIENTS {
+        for file in 0..FILES_PER_CLIENT {
+            let name = format!("{client}-{file}.bin");
+            let contents = contents(client, file);
+
+            assert!(fs::read(server.files_dir().join(&name)).unwrap() == contents);
+            let hash = hash_reader(&contents[..], |_| {}).unwrap();
+            let found = find_by_hash(&stream, &info, &hash).unwrap();
+            assert_eq!(found.as_deref(), Some(name.as_str()));
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! Clients flooding the server with control ops, limited by `--control-op-rate`.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    os::unix::net::UnixStream,
+    time::{Duration, Instant},
+};
+
+use common::TestServer;
+use p2p_service::{op, read_response, write_op, Chunk, ProtocolError, Transport};
+
+#[test]
+fn keep_alive_flood_is_disconnected() {
+    let server = TestServer::start(&["--control-op-rate", "10"]);
+    let (stream, _) = server.connect();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut chunk = Chunk::<1024, UnixStream>::new(&stream);
+
+    // Throttled at first, only cut off after several seconds over the rate
+    let started = Instant::now();
+    while write_op(&mut chunk, op::KEEP_ALIVE).is_ok() {
+        assert!(started.elapsed() < Duration::from_secs(20), "never cut off");
+    }
+    assert!(started.elapsed() >= Duration::from_secs(4));
+
+    assert!(matches!(
+        read_response(&mut chunk),
+        Err(ProtocolError::RateLimited(_))
+    ));
+    assert_eq!(Transport::read(&stream, &mut [0; 1]).unwrap(), 0);
+
+    // Others are still served
+    server.connect();
+}
@@ -0,0 +1,43 @@
+//! Stored files copied under a new name.
+
+#![cfg(unix)]
+
+mod common;
+
+use common::{upload, TestServer};
+use p2p_service::{copy_file, fetch_files, get_file, ProtocolError};
+
+#[test]
+fn copy_is_stored_under_the_new_name() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+    upload(&stream, &info, "a.txt", b"hello", false).unwrap();
+
+    copy_file(&stream, &info, "a.txt", "b.txt").unwrap();
+
+    let mut files = fetch_files(&stream, &info).unwrap();
+    files.sort();
+    assert_eq!(files, ["a.txt", "b.txt"]);
+    assert_eq!(
+        get_file(&stream, &info, "b.txt").unwrap().unwrap(),
+        b"hello"
+    );
+}
+
+#[test]
+fn names_other_than_bare_ones_are_refused() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+    upload(&stream, &info, "a.txt", b"hello", false).unwrap();
+
+    for to in ["", "a.txt", "../b.txt", "dir/b.txt", "./b.txt", "b.txt/"] {
+        let result = copy_file(&stream, &info, "a.txt", to);
+        assert!(
+            matches!(result, Err(ProtocolError::InvalidRequest(_))),
+            "{to:?}: {result:?}"
+        );
+    }
+
+    assert_eq!(fetch_files(&stream, &info).unwrap(), ["a.txt"]);
+    assert!(!server.dir().join("b.txt").exists());
+}
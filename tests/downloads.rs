@@ -0,0 +1,74 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, os::unix::net::UnixStream};
+
+use common::{upload, wait_for, TestServer};
+use p2p_service::{get_file, stat_file, ConnectionInfo};
+
+/// Asked on the connection that downloaded, which is only counted after the file is out.
+fn downloads(stream: &UnixStream, info: &ConnectionInfo, file_name: &str) -> u64 {
+    stat_file(stream, info, file_name)
+        .unwrap()
+        .unwrap()
+        .downloads
+}
+
+/// The saved index, as the server last wrote it.
+fn saved_index(server: &TestServer) -> String {
+    fs::read_to_string(server.dir().join("server_index.json")).unwrap()
+}
+
+#[test]
+fn download_counts_are_saved_on_shutdown() {
+    let mut server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+
+    upload(&stream, &info, "a", b"contents", false).unwrap();
+    for _ in 0..2 {
+        get_file(&stream, &info, "a").unwrap().unwrap();
+    }
+    assert_eq!(downloads(&stream, &info, "a"), 2);
+
+    // Not worth writing the whole index for on every download
+    assert!(saved_index(&server).contains("\"downloads\":0"));
+
+    // Nothing but downloads changed the index since the upload
+    assert!(server.stop().success());
+    assert!(saved_index(&server).contains("\"downloads\":2"));
+    server.restart();
+    let (stream, info) = server.connect();
+    assert_eq!(downloads(&stream, &info, "a"), 2);
+}
+
+#[test]
+fn download_counts_are_saved_with_the_next_change() {
+    let mut server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+
+    upload(&stream, &info, "a", b"contents", false).unwrap();
+    get_file(&stream, &info, "a").unwrap().unwrap();
+    upload(&stream, &info, "b", b"contents", false).unwrap();
+
+    server.restart();
+    let (stream, info) = server.connect();
+    assert_eq!(downloads(&stream, &info, "a"), 1);
+}
+
+#[test]
+fn periodic_sync_saves_download_counts() {
+    let mut server = TestServer::start(&["--index-sync", "1"]);
+    let (stream, info) = server.connect();
+
+    upload(&stream, &info, "a", b"contents", false).unwrap();
+    get_file(&stream, &info, "a").unwrap().unwrap();
+
+    wait_for("the index to be flushed", || {
+        let index = fs::read_to_string(server.dir().join("server_index.json"));
+        index.is_ok_and(|index| index.contains("\"downloads\":1"))
+    });
+    server.restart();
+    let (stream, info) = server.connect();
+    assert_eq!(downloads(&stream, &info, "a"), 1);
+}
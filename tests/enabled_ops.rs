@@ -0,0 +1,39 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::net::UnixStream;
+
+use common::{upload, TestServer};
+use p2p_service::{get_file, op, read_response, write_op, Chunk, ProtocolError};
+
+#[test]
+fn disabled_downloads_are_refused_while_uploads_work() {
+    let server = TestServer::start(&["--disable-ops", "get_file"]);
+
+    let (stream, info) = server.connect();
+    upload(&stream, &info, "a.txt", b"contents", false).unwrap();
+    assert!(server.files_dir().join("a.txt").exists());
+
+    // Refused on the op alone, then the connection is closed. A name left unread
+    // by then could reset the connection before the refusal is read, so none is sent
+    let (stream, _) = server.connect();
+    let mut chunk = Chunk::<1024, UnixStream>::new(&stream);
+    write_op(&mut chunk, op::GET_FILE).unwrap();
+    assert!(matches!(
+        read_response(&mut chunk),
+        Err(ProtocolError::Denied(_))
+    ));
+}
+
+#[test]
+fn repeated_enable_ops_enable_every_listed_op() {
+    let server = TestServer::start(&["--enable-ops", "add_file", "--enable-ops", "get_file"]);
+
+    let (stream, info) = server.connect();
+    upload(&stream, &info, "a.txt", b"contents", false).unwrap();
+    assert_eq!(
+        get_file(&stream, &info, "a.txt").unwrap().as_deref(),
+        Some(&b"contents"[..])
+    );
+}
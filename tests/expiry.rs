@@ -0,0 +1,67 @@
+//! Files picked by age, listed with `op::LIST_OLDER_THAN` and deleted by `--expire-after`.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use common::{upload, wait_for, TestServer};
+use p2p_service::{fetch_files, list_older_than};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+/// Files last written three hours, two hours and ten minutes ago.
+fn staggered(dir: &Path) {
+    let now = SystemTime::now();
+    for (name, age) in [
+        ("three_hours", 3 * HOUR),
+        ("two_hours", 2 * HOUR),
+        ("ten_minutes", HOUR / 6),
+    ] {
+        let file = fs::File::create(dir.join("server_files").join(name)).unwrap();
+        file.set_modified(now - age).unwrap();
+    }
+}
+
+#[test]
+fn files_older_than_the_age_are_listed_oldest_first() {
+    let server = TestServer::start_with(staggered, &[]);
+    let (stream, info) = server.connect();
+    upload(&stream, &info, "just_now", b"new", false).unwrap();
+
+    let names = |age| -> Vec<String> {
+        list_older_than(&stream, &info, age)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    };
+
+    assert_eq!(names(HOUR), ["three_hours", "two_hours"]);
+    assert_eq!(
+        names(HOUR / 60),
+        ["three_hours", "two_hours", "ten_minutes"]
+    );
+    assert!(names(4 * HOUR).is_empty());
+}
+
+#[test]
+fn files_past_their_ttl_are_expired() {
+    let server = TestServer::start_with(staggered, &["--expire-after", "3600"]);
+    let (stream, info) = server.connect();
+    upload(&stream, &info, "just_now", b"new", false).unwrap();
+
+    let files_dir = server.files_dir();
+    wait_for("the old files to expire", || {
+        !files_dir.join("three_hours").exists() && !files_dir.join("two_hours").exists()
+    });
+
+    let mut files = fetch_files(&stream, &info).unwrap();
+    files.sort();
+    assert_eq!(files, ["just_now", "ten_minutes"]);
+}
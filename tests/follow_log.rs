@@ -0,0 +1,59 @@
+//! The server's log followed live by an admin, see `op::FOLLOW_LOG`.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    net::Shutdown,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use common::{upload, wait_for, TestServer};
+use p2p_service::{authenticate, follow_log, ProtocolError};
+
+const ADMIN_SECRET: &str = "admin";
+
+#[test]
+fn logged_upload_reaches_the_admin() {
+    let server = TestServer::start(&["--admin-secret", ADMIN_SECRET]);
+    let (admin, admin_info) = server.connect();
+    authenticate(&admin, ADMIN_SECRET.as_bytes()).unwrap();
+
+    let (sender, lines) = mpsc::channel();
+    let following = admin.try_clone().unwrap();
+    let follower = thread::spawn(move || {
+        follow_log(&following, &admin_info, |line| {
+            _ = sender.send(line.to_string());
+        })
+    });
+
+    // The follower may not be listening yet, so uploads are repeated until one shows up
+    let (stream, info) = server.connect();
+    let mut received: Vec<String> = Vec::new();
+    wait_for("the upload in the log", || {
+        upload(&stream, &info, "logged.txt", b"hello", false).unwrap();
+        received.extend(drain(&lines));
+        received.iter().any(|line| line.contains("\"logged.txt\""))
+    });
+
+    // Stopped by shutting the connection down under it
+    admin.shutdown(Shutdown::Both).unwrap();
+    assert!(follower.join().unwrap().is_err());
+}
+
+#[test]
+fn only_admins_can_follow() {
+    let server = TestServer::start(&["--admin-secret", ADMIN_SECRET]);
+    let (stream, info) = server.connect();
+
+    let result = follow_log(&stream, &info, |line| panic!("received {line:?}"));
+    assert!(matches!(result, Err(ProtocolError::Denied(_))));
+}
+
+fn drain(lines: &Receiver<String>) -> Vec<String> {
+    thread::sleep(Duration::from_millis(50));
+    lines.try_iter().collect()
+}
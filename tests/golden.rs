@@ -0,0 +1,319 @@
+//! The bytes sent each way for a fixed session, compared against the fixtures in
+//! `tests/golden`, one per protocol version.
+//!
+//! The session is a handshake, an upload of a 10 byte "a.txt", a listing and a
+//! download of the file. The client helpers have to send exactly the fixture's
+//! client bytes when the server's are played back to them, and the server has to
+//! answer the fixture's client bytes with exactly its server bytes.
+//!
+//! A changed fixture means a changed wire format, which needs a version bump.
+//! To write them again, run with `UPDATE_GOLDEN=1`.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt::Write as _,
+    fs,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use common::TestServer;
+use p2p_service::{
+    capability, fetch_files, get_file, handshake, op, read_response, send_reader, start_upload,
+    version, Chunk, Transport,
+};
+
+const FILE_NAME: &str = "a.txt";
+const CONTENTS: &[u8; 10] = b"0123456789";
+
+/// What each step of `session` is called in the fixtures.
+const STEPS: [&str; 4] = ["handshake", "add", "fetch", "get"];
+
+/// Capabilities whose values change from run to run, matched by any bytes.
+const VARYING: [&str; 2] = [capability::SERVER_TIME, capability::FREE_BYTES];
+
+/// Bytes printed per line of a fixture.
+const BYTES_PER_LINE: usize = 16;
+
+/// What went each way during one step, `None` standing for any byte.
+#[derive(Default)]
+struct Exchange {
+    client: Vec<u8>,
+    server: Vec<Option<u8>>,
+}
+
+/// Run the session over `stream`, calling `done` with the name of each step as it ends.
+fn session<S: Transport>(stream: &S, mut done: impl FnMut(&str)) {
+    let info = handshake(stream).unwrap();
+    done(STEPS[0]);
+
+    let mut chunk = Chunk::<1024, S>::new(stream);
+    start_upload(&mut chunk, &info, op::ADD_FILE, FILE_NAME, false).unwrap();
+    send_reader(&mut chunk, &CONTENTS[..], CONTENTS.len()).unwrap();
+    if info.version >= version::V2 {
+        read_response(&mut chunk).unwrap();
+    }
+    done(STEPS[1]);
+
+    assert_eq!(fetch_files(stream, &info).unwrap(), [FILE_NAME]);
+    done(STEPS[2]);
+
+    assert_eq!(
+        get_file(stream, &info, FILE_NAME).unwrap().unwrap(),
+        CONTENTS
+    );
+    done(STEPS[3]);
+}
+
+fn fixture_path(version: u8) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(format!("tests/golden/v{version}.hex"))
+}
+
+/// Read a fixture: `>` lines hold client bytes and `<` lines server bytes, in hex,
+/// with `??` for any byte. `#` starts a comment naming the step that follows.
+fn load(version: u8) -> Vec<Exchange> {
+    let path = fixture_path(version);
+    let text = fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("{}: {err}, see UPDATE_GOLDEN", path.display()));
+
+    let mut exchanges: Vec<Exchange> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            // A step's name comes before its bytes
+            if line.trim_start_matches('#').trim().starts_with("step") {
+                exchanges.push(Exchange::default());
+            }
+            continue;
+        }
+        let Some(exchange) = exchanges.last_mut() else {
+            continue;
+        };
+
+        let (direction, bytes) = line.split_at(line.len().min(1));
+        let bytes = bytes.split_whitespace().map(|byte| match byte {
+            "??" => None,
+            byte => Some(u8::from_str_radix(byte, 16).unwrap()),
+        });
+        match direction {
+            ">" => exchange
+                .client
+                .extend(bytes.map(|byte| byte.expect("client bytes never vary"))),
+            "<" => exchange.server.extend(bytes),
+            _ => {}
+        }
+    }
+
+    assert_eq!(exchanges.len(), STEPS.len(), "{}", path.display());
+    exchanges
+}
+
+fn save(version: u8, exchanges: &[Exchange]) {
+    let mut text = format!(
+        "# Protocol version {version}, `>` client to server, `<` server to client.\n\
+         # Written by tests/golden.rs, `??` is any byte.\n"
+    );
+
+    for (step, exchange) in STEPS.iter().zip(exchanges) {
+        writeln!(text, "\n# step: {step}").unwrap();
+
+        let client: Vec<_> = exchange.client.iter().copied().map(Some).collect();
+        for (direction, bytes) in [('>', &client), ('<', &exchange.server)] {
+            if bytes.is_empty() {
+                writeln!(text, "{direction}").unwrap();
+            }
+            for line in bytes.chunks(BYTES_PER_LINE) {
+                let hex: Vec<String> = line
+                    .iter()
+                    .map(|byte| byte.map_or_else(|| "??".to_string(), |b| format!("{b:02x}")))
+                    .collect();
+                writeln!(text, "{direction} {}", hex.join(" ")).unwrap();
+            }
+        }
+    }
+
+    fs::create_dir_all(fixture_path(version).parent().unwrap()).unwrap();
+    fs::write(fixture_path(version), text).unwrap();
+}
+
+/// A server speaking at most `version`.
+fn server(version: u8) -> TestServer {
+    TestServer::start(&["--protocol-version", &version.to_string()])
+}
+
+/// A connection to a real server that keeps what went each way, see `Exchange`.
+struct Recorder {
+    stream: UnixStream,
+    current: RefCell<Exchange>,
+}
+
+impl Transport for Recorder {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = Read::read(&mut &self.stream, buf)?;
+        let server = &mut self.current.borrow_mut().server;
+        server.extend(buf[..n].iter().copied().map(Some));
+        Ok(n)
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.current.borrow_mut().client.extend(buf);
+        Write::write_all(&mut &self.stream, buf)
+    }
+
+    fn peer(&self) -> String {
+        "recorded server".to_string()
+    }
+}
+
+/// Replace the values of `VARYING` capabilities in a handshake answer with `None`.
+fn mask_varying(handshake: &mut [Option<u8>]) {
+    let bytes: Vec<u8> = handshake.iter().map(|byte| byte.unwrap()).collect();
+    if bytes[0] < version::V5 {
+        return;
+    }
+
+    let usize_at = |at: usize| usize::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    let count = usize_at(1);
+    let mut at = 9;
+    for _ in 0..count {
+        let len = usize_at(at);
+        let key = std::str::from_utf8(&bytes[at + 8..at + 8 + len]).unwrap();
+        at += 8 + len;
+
+        if VARYING.contains(&key) {
+            handshake[at..at + 8].fill(None);
+        }
+        at += 8;
+    }
+}
+
+fn record(version: u8) -> Vec<Exchange> {
+    let server = server(version);
+    let recorder = Recorder {
+        stream: server.connect_raw(),
+        current: RefCell::default(),
+    };
+
+    let mut exchanges = Vec::new();
+    session(&recorder, |_| exchanges.push(recorder.current.take()));
+    mask_varying(&mut exchanges[0].server);
+    exchanges
+}
+
+/// Plays the server's side of a fixture back, keeping what the client sends.
+#[derive(Default)]
+struct Replay {
+    server: RefCell<VecDeque<u8>>,
+    client: RefCell<Vec<u8>>,
+}
+
+impl Transport for Replay {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut server = self.server.borrow_mut();
+        let n = buf.len().min(server.len());
+        for (byte, played) in buf.iter_mut().zip(server.drain(..n)) {
+            *byte = played;
+        }
+        Ok(n)
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.client.borrow_mut().extend(buf);
+        Ok(())
+    }
+
+    fn peer(&self) -> String {
+        "replayed server".to_string()
+    }
+}
+
+fn matches(expected: &[Option<u8>], actual: &[u8]) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual)
+            .all(|(expected, actual)| expected.is_none_or(|expected| expected == *actual))
+}
+
+fn versions() -> impl Iterator<Item = u8> {
+    version::V1..=version::LATEST
+}
+
+#[test]
+fn client_sends_the_fixture_bytes() {
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        for version in versions() {
+            save(version, &record(version));
+        }
+    }
+
+    for version in versions() {
+        let exchanges = load(version);
+
+        // Varying values are played back as zero, which the session doesn't look at
+        let replay = Replay::default();
+        replay.server.borrow_mut().extend(
+            exchanges
+                .iter()
+                .flat_map(|exchange| &exchange.server)
+                .map(|byte| byte.unwrap_or(0)),
+        );
+
+        let mut exchanges = exchanges.iter();
+        let mut left = replay.server.borrow().len();
+        session(&replay, |step| {
+            let expected = exchanges.next().unwrap();
+            let sent = replay.client.take();
+            assert_eq!(sent, expected.client, "v{version} {step}: client bytes");
+
+            left -= expected.server.len();
+            assert_eq!(
+                replay.server.borrow().len(),
+                left,
+                "v{version} {step}: server bytes read"
+            );
+        });
+    }
+}
+
+#[test]
+fn server_answers_with_the_fixture_bytes() {
+    for version in versions() {
+        let server = server(version);
+        let stream = server.connect_raw();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        for (step, exchange) in STEPS.iter().zip(load(version)) {
+            Write::write_all(&mut &stream, &exchange.client).unwrap();
+
+            let mut answer = vec![0; exchange.server.len()];
+            Read::read_exact(&mut &stream, &mut answer)
+                .unwrap_or_else(|err| panic!("v{version} {step}: no answer, {err}"));
+            assert!(
+                matches(&exchange.server, &answer),
+                "v{version} {step}: server bytes {answer:02x?}"
+            );
+        }
+
+        // Nothing more than the fixture has
+        stream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let extra = Read::read(&mut &stream, &mut [0; 1]);
+        assert!(extra.is_err(), "v{version}: server sent more, {extra:?}");
+
+        let stored = fs::read(server.files_dir().join(FILE_NAME)).unwrap();
+        assert_eq!(stored, CONTENTS, "v{version}");
+        let index = fs::read_to_string(server.dir().join("server_index.json")).unwrap();
+        assert!(index.contains(FILE_NAME), "v{version}: {index}");
+    }
+}
@@ -0,0 +1,36 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::{upload, TestServer};
+use p2p_service::check_health;
+
+#[test]
+fn healthy_server_reports_ok() {
+    let server = TestServer::start(&["--quota", "100", "--disk-headroom", "0"]);
+    let (stream, info) = server.connect();
+
+    upload(&stream, &info, "a", &[0; 10], false).unwrap();
+    assert_eq!(check_health(&stream, &info).unwrap(), None);
+}
+
+#[test]
+fn server_near_its_quota_is_degraded() {
+    let server = TestServer::start(&["--quota", "100", "--disk-headroom", "0"]);
+    let (stream, info) = server.connect();
+
+    upload(&stream, &info, "a", &[0; 95], false).unwrap();
+    let reason = check_health(&stream, &info).unwrap().unwrap();
+    assert!(reason.starts_with("Storage near quota"), "{reason}");
+}
+
+#[test]
+fn server_without_disk_headroom_is_degraded() {
+    // No disk has this much free, so the server is as good as full
+    let headroom = u64::MAX.to_string();
+    let server = TestServer::start(&["--disk-headroom", &headroom]);
+    let (stream, info) = server.connect();
+
+    let reason = check_health(&stream, &info).unwrap().unwrap();
+    assert!(reason.starts_with("Disk almost full"), "{reason}");
+}
@@ -0,0 +1,38 @@
+//! Sessions closed by `--idle-timeout`, however many keep-alives they send.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    os::unix::net::UnixStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::TestServer;
+use p2p_service::{op, read_response, write_op, Chunk, ProtocolError, Transport};
+
+#[test]
+fn keep_alives_alone_expire_the_session() {
+    let server = TestServer::start(&["--idle-timeout", "1"]);
+    let (stream, _) = server.connect();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut chunk = Chunk::<1024, UnixStream>::new(&stream);
+
+    // Kept up until the server hangs up, which it does once the timeout is reached
+    let started = Instant::now();
+    while write_op(&mut chunk, op::KEEP_ALIVE).is_ok() {
+        assert!(started.elapsed() < Duration::from_secs(5), "never expired");
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(started.elapsed() >= Duration::from_secs(1));
+
+    assert!(matches!(
+        read_response(&mut chunk),
+        Err(ProtocolError::SessionExpired(_))
+    ));
+    assert_eq!(Transport::read(&stream, &mut [0; 1]).unwrap(), 0);
+}
@@ -0,0 +1,66 @@
+//! The index saved gzip compressed with `--compress-index`, and read back in either format.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, io::Write};
+
+use common::{upload, TestServer};
+use flate2::{write::GzEncoder, Compression};
+use p2p_service::{fetch_files, get_tags, set_tags};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[test]
+fn compressed_index_survives_a_restart() {
+    let mut server = TestServer::start(&["--compress-index"]);
+    let (stream, info) = server.connect();
+
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        upload(&stream, &info, name, name.as_bytes(), false).unwrap();
+    }
+    set_tags(
+        &stream,
+        &info,
+        "b.txt",
+        &["one".to_string(), "two".to_string()],
+    )
+    .unwrap();
+
+    let saved = fs::read(server.dir().join("server_index.json")).unwrap();
+    assert!(saved.starts_with(&GZIP_MAGIC));
+    drop(stream);
+
+    server.restart();
+    let (stream, info) = server.connect();
+
+    let mut files = fetch_files(&stream, &info).unwrap();
+    files.sort();
+    assert_eq!(files, ["a.txt", "b.txt", "c.txt"]);
+    assert_eq!(get_tags(&stream, &info, "b.txt").unwrap(), ["one", "two"]);
+    assert!(get_tags(&stream, &info, "a.txt").unwrap().is_empty());
+}
+
+#[test]
+fn compressed_index_is_read_without_the_flag() {
+    let server = TestServer::start_with(
+        |dir| {
+            fs::write(dir.join("server_files/a.txt"), b"a").unwrap();
+
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder
+                .write_all(br#"{"a.txt": {"tags": ["kept"]}}"#)
+                .unwrap();
+            fs::write(dir.join("server_index.json"), encoder.finish().unwrap()).unwrap();
+        },
+        &[],
+    );
+    let (stream, info) = server.connect();
+    assert_eq!(get_tags(&stream, &info, "a.txt").unwrap(), ["kept"]);
+
+    // And saved plain from the next change on
+    upload(&stream, &info, "b.txt", b"b", false).unwrap();
+    let saved = fs::read_to_string(server.dir().join("server_index.json")).unwrap();
+    assert!(saved.contains("\"kept\""), "{saved}");
+}
@@ -0,0 +1,56 @@
+//! The whole index exported in one go with `op::EXPORT_INDEX`.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+
+use common::{upload, TestServer};
+use p2p_service::{
+    authenticate, delete_files, export_index, hash_reader, set_tags, ProtocolError, SnapshotEntry,
+};
+
+const SECRET: &str = "secret";
+
+#[test]
+fn snapshot_matches_the_index_and_round_trips() {
+    let server = TestServer::start(&["--secret", SECRET]);
+    let (stream, info) = server.connect();
+    authenticate(&stream, SECRET.as_bytes()).unwrap();
+
+    upload(&stream, &info, "a.txt", b"first", false).unwrap();
+    upload(&stream, &info, "b.txt", b"second file", false).unwrap();
+    upload(&stream, &info, "gone.txt", b"deleted", false).unwrap();
+    set_tags(&stream, &info, "b.txt", &["work".to_string()]).unwrap();
+    delete_files(&stream, &info, &["gone.txt"]).unwrap();
+
+    let mut snapshot = export_index(&stream, &info).unwrap();
+    snapshot.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let names: Vec<_> = snapshot.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, ["a.txt", "b.txt"]);
+    for entry in &snapshot {
+        let contents = fs::read(server.files_dir().join(&entry.name)).unwrap();
+        assert_eq!(entry.size, contents.len() as u64);
+        assert_eq!(entry.hash, Some(hash_reader(&contents[..], |_| {}).unwrap()));
+        assert!(entry.modified > 0);
+    }
+    assert!(snapshot[0].tags.is_empty());
+    assert_eq!(snapshot[1].tags, ["work"]);
+
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let read_back: Vec<SnapshotEntry> = serde_json::from_str(&json).unwrap();
+    assert_eq!(read_back, snapshot);
+}
+
+#[test]
+fn snapshot_needs_authentication() {
+    let server = TestServer::start(&["--secret", SECRET]);
+    let (stream, info) = server.connect();
+
+    assert!(matches!(
+        export_index(&stream, &info),
+        Err(ProtocolError::Denied(_) | ProtocolError::Unauthenticated(_))
+    ));
+}
@@ -0,0 +1,91 @@
+//! Servers listening on more than one address, each with its own rules.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, net::TcpStream, time::Duration};
+
+use common::{free_port, upload, TestServer};
+use p2p_service::{authenticate, connect, fetch_files, handshake, reset_downloads, ProtocolError};
+
+const ADMIN_SECRET: &str = "admin";
+
+/// A server with a writable and a read-only listener from its config file,
+/// returning it with their addresses.
+fn server() -> (TestServer, String, String) {
+    let writable = format!("127.0.0.1:{}", free_port());
+    let read_only = format!("127.0.0.1:{}", free_port());
+
+    let config = format!(
+        "[[listener]]\n\
+         addr = \"{writable}\"\n\
+         \n\
+         [[listener]]\n\
+         addr = \"{read_only}\"\n\
+         read_only = true\n"
+    );
+    let server = TestServer::start_with(
+        |dir| fs::write(dir.join("server.toml"), config).unwrap(),
+        &["--config", "server.toml", "--admin-secret", ADMIN_SECRET],
+    );
+    (server, writable, read_only)
+}
+
+fn connect_tcp(addr: &str) -> TcpStream {
+    connect(addr, Duration::from_secs(5)).unwrap()
+}
+
+#[test]
+fn each_listener_keeps_its_own_rules() {
+    let (server, writable, read_only) = server();
+
+    let stream = connect_tcp(&writable);
+    let info = handshake(&stream).unwrap();
+    upload(&stream, &info, "a.txt", b"hello", false).unwrap();
+
+    let stream = connect_tcp(&read_only);
+    let info = handshake(&stream).unwrap();
+    match upload(&stream, &info, "b.txt", b"hello", false) {
+        Err(ProtocolError::Denied(_)) => {}
+        other => panic!("expected the upload to be refused, got {other:?}"),
+    }
+
+    // Both serve the same files
+    let stream = connect_tcp(&read_only);
+    let info = handshake(&stream).unwrap();
+    assert_eq!(fetch_files(&stream, &info).unwrap(), ["a.txt"]);
+    assert!(!server.files_dir().join("b.txt").exists());
+}
+
+#[test]
+fn admins_cant_write_through_a_read_only_listener() {
+    let (_server, writable, read_only) = server();
+
+    let stream = connect_tcp(&read_only);
+    let info = handshake(&stream).unwrap();
+    authenticate(&stream, ADMIN_SECRET.as_bytes()).unwrap();
+    match reset_downloads(&stream, &info) {
+        Err(ProtocolError::Denied(msg)) => assert!(msg.contains("read-only"), "{msg}"),
+        other => panic!("expected the reset to be refused, got {other:?}"),
+    }
+
+    let stream = connect_tcp(&writable);
+    let info = handshake(&stream).unwrap();
+    authenticate(&stream, ADMIN_SECRET.as_bytes()).unwrap();
+    reset_downloads(&stream, &info).unwrap();
+}
+
+#[test]
+fn shutting_down_closes_every_listener() {
+    let (mut server, writable, read_only) = server();
+
+    // Connected when the server stops, which mustn't hold it up
+    let idle = connect_tcp(&writable);
+    handshake(&idle).unwrap();
+
+    assert!(server.stop().success());
+    for addr in [writable, read_only] {
+        assert!(TcpStream::connect(&addr).is_err(), "{addr} still open");
+    }
+}
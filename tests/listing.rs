@@ -0,0 +1,74 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+
+use common::TestServer;
+use p2p_service::{list_page, FileEntry, SortKey};
+
+/// Names and sizes, with sizes shared so ties have to be broken by name.
+const FILES: [(&str, usize); 7] = [
+    ("a", 30),
+    ("b", 10),
+    ("c", 30),
+    ("d", 20),
+    ("e", 10),
+    ("f", 5),
+    ("g", 30),
+];
+
+fn names(entries: &[FileEntry]) -> Vec<&str> {
+    entries.iter().map(|entry| entry.name.as_str()).collect()
+}
+
+#[test]
+fn pages_by_size_descending_cover_every_file_once() {
+    let server = TestServer::start_with(
+        |dir| {
+            for (name, size) in FILES {
+                fs::write(dir.join("server_files").join(name), vec![0; size]).unwrap();
+            }
+        },
+        &[],
+    );
+    let (stream, info) = server.connect();
+
+    let mut listed = Vec::new();
+    let mut offset = 0;
+    loop {
+        let page = list_page(&stream, &info, SortKey::Size, true, offset, 3).unwrap();
+        assert_eq!(page.total, FILES.len());
+        if page.entries.is_empty() {
+            break;
+        }
+        assert!(page.entries.len() <= 3);
+
+        // The same page asked for again comes back the same
+        let again = list_page(&stream, &info, SortKey::Size, true, offset, 3).unwrap();
+        assert_eq!(names(&again.entries), names(&page.entries));
+
+        offset += page.entries.len();
+        listed.extend(
+            page.entries
+                .into_iter()
+                .map(|entry| (entry.name, entry.size)),
+        );
+    }
+
+    // Largest first, ties in reverse name order along with everything else
+    let expected = [
+        ("g", 30),
+        ("c", 30),
+        ("a", 30),
+        ("d", 20),
+        ("e", 10),
+        ("b", 10),
+        ("f", 5),
+    ];
+    let expected: Vec<_> = expected
+        .iter()
+        .map(|&(name, size)| (name.to_string(), size as u64))
+        .collect();
+    assert_eq!(listed, expected);
+}
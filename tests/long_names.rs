@@ -0,0 +1,39 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::io;
+
+use common::{upload, TestServer};
+use p2p_service::{fetch_files, ProtocolError};
+
+#[test]
+fn names_too_long_to_store_are_refused() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+
+    match upload(&stream, &info, &"n".repeat(201), b"abc", false) {
+        Err(ProtocolError::InvalidRequest(msg)) => assert!(msg.contains("too long"), "{msg}"),
+        other => panic!("expected the name to be refused, got {other:?}"),
+    }
+
+    // The contents were read and dropped, so the connection carries on
+    let longest = "n".repeat(200);
+    upload(&stream, &info, &longest, b"abc", false).unwrap();
+    assert_eq!(fetch_files(&stream, &info).unwrap(), [longest]);
+}
+
+#[test]
+fn names_too_long_to_send_are_refused_before_sending() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+
+    match upload(&stream, &info, &"n".repeat(2000), b"abc", false) {
+        Err(ProtocolError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+        other => panic!("expected the name to be refused, got {other:?}"),
+    }
+
+    // Nothing went out, so the connection carries on
+    upload(&stream, &info, "short", b"abc", false).unwrap();
+    assert_eq!(fetch_files(&stream, &info).unwrap(), ["short"]);
+}
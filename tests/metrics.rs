@@ -0,0 +1,70 @@
+//! Stats scraped from the Prometheus endpoint of `--metrics`.
+
+#![cfg(all(unix, feature = "metrics"))]
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use common::{free_port, upload, wait_for, TestServer};
+
+/// The body of `GET /metrics`, once the endpoint is up.
+fn scrape(port: u16) -> String {
+    let mut stream = None;
+    wait_for("the metrics endpoint", || {
+        stream = TcpStream::connect(("127.0.0.1", port)).ok();
+        stream.is_some()
+    });
+    let mut stream = stream.unwrap();
+
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    body.to_string()
+}
+
+#[test]
+fn every_metric_has_its_type() {
+    let port = free_port();
+    let server = TestServer::start(&["--metrics", &format!("127.0.0.1:{port}")]);
+    let (stream, info) = server.connect();
+    upload(&stream, &info, "a.txt", b"hello", false).unwrap();
+
+    // Counted by an event and timed once the response is out, so it can show up late
+    let mut body = String::new();
+    wait_for("the upload to be counted", || {
+        body = scrape(port);
+        body.contains("transfers_total{direction=\"upload\"} 1")
+            && body.contains("_count{op=\"add_file\"}")
+    });
+    for line in [
+        "# TYPE p2p_transfers_total counter",
+        "# TYPE p2p_files gauge",
+        "# TYPE p2p_request_duration_milliseconds histogram",
+        "p2p_transfers_total{direction=\"upload\"} 1",
+        "p2p_files 1",
+        "p2p_request_duration_milliseconds_bucket{op=\"add_file\",le=\"+Inf\"} 1",
+        "p2p_request_duration_milliseconds_count{op=\"add_file\"} 1",
+    ] {
+        assert!(body.lines().any(|got| got == line), "{line} in {body}");
+    }
+    assert!(body.contains("p2p_request_duration_milliseconds_sum{op=\"add_file\"} "));
+
+    // One TYPE line per metric, ahead of all of its samples
+    let types: Vec<_> = body
+        .lines()
+        .filter(|line| line.starts_with("# TYPE"))
+        .collect();
+    let mut unique = types.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(types.len(), unique.len(), "{body}");
+}
@@ -0,0 +1,142 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+
+use common::{free_port, upload, wait_for, TestServer};
+use p2p_service::{authenticate, delete_files, fetch_files, get_file, Status};
+
+const SECRET: &str = "secret";
+
+/// A primary listening on TCP for its mirrors, with `SECRET` so they get hashes.
+fn primary() -> (TestServer, String) {
+    let addr = format!("127.0.0.1:{}", free_port());
+    let server = TestServer::start(&["--listen", &addr, "--secret", SECRET]);
+    (server, addr)
+}
+
+fn mirror(primary: &str, args: &[&str]) -> TestServer {
+    let mut all = vec!["--mirror", primary, "--mirror-secret", SECRET];
+    all.extend(args);
+    TestServer::start(&all)
+}
+
+fn put(server: &TestServer, name: &str, contents: &[u8]) {
+    let (stream, info) = server.connect();
+    authenticate(&stream, SECRET.as_bytes()).unwrap();
+    upload(&stream, &info, name, contents, false).unwrap();
+}
+
+fn read(server: &TestServer, name: &str) -> Option<Vec<u8>> {
+    fs::read(server.files_dir().join(name)).ok()
+}
+
+#[test]
+fn new_and_changed_files_are_pulled() {
+    let (primary, addr) = primary();
+    put(&primary, "a", b"one");
+
+    let mirror = mirror(&addr, &[]);
+    wait_for("the new file", || {
+        read(&mirror, "a").as_deref() == Some(b"one")
+    });
+
+    let index = fs::read_to_string(mirror.dir().join("server_index.json")).unwrap();
+    assert!(index.contains("\"a\""), "pulled file wasn't saved: {index}");
+
+    // Same size, so only the hash tells the change apart
+    put(&primary, "a", b"two");
+    wait_for("the changed file", || {
+        read(&mirror, "a").as_deref() == Some(b"two")
+    });
+}
+
+#[test]
+fn pulled_files_are_stored_like_uploads() {
+    let (primary, addr) = primary();
+    put(&primary, "a", &[b'x'; 4096]);
+
+    let mirror = mirror(&addr, &["--compress-storage"]);
+    wait_for("the file", || read(&mirror, "a").is_some());
+
+    let stored = read(&mirror, "a").unwrap();
+    assert_eq!(&stored[..2], [0x1f, 0x8b], "not gzipped");
+
+    let (stream, info) = mirror.connect();
+    authenticate(&stream, SECRET.as_bytes()).unwrap();
+    assert_eq!(
+        get_file(&stream, &info, "a").unwrap().unwrap(),
+        [b'x'; 4096]
+    );
+}
+
+#[test]
+fn local_uploads_survive_a_restart() {
+    let (primary, addr) = primary();
+    put(&primary, "remote", b"from the primary");
+
+    let mut mirror = mirror(
+        &addr,
+        &["--mirror-conflict", "local-wins", "--mirror-delete"],
+    );
+    wait_for("the first sync", || read(&mirror, "remote").is_some());
+    put(&mirror, "local", b"uploaded here");
+
+    mirror.restart();
+    put(&primary, "later", b"after the restart");
+    wait_for("a sync after the restart", || {
+        read(&mirror, "later").is_some()
+    });
+
+    assert_eq!(
+        read(&mirror, "local").as_deref(),
+        Some(&b"uploaded here"[..])
+    );
+}
+
+#[test]
+fn deleted_files_are_pulled_again() {
+    let (primary, addr) = primary();
+    put(&primary, "remote", b"from the primary");
+
+    let mirror = mirror(&addr, &["--mirror-conflict", "local-wins"]);
+    wait_for("the first sync", || read(&mirror, "remote").is_some());
+
+    let (stream, info) = mirror.connect();
+    authenticate(&stream, SECRET.as_bytes()).unwrap();
+    assert_eq!(
+        delete_files(&stream, &info, &["remote"]).unwrap(),
+        [Status::Ok]
+    );
+
+    // Deleting doesn't make a file local, so the primary's copy comes back
+    wait_for("the file to come back", || {
+        read(&mirror, "remote").is_some()
+    });
+}
+
+#[test]
+fn files_already_gone_here_are_still_dropped() {
+    let (primary, addr) = primary();
+    put(&primary, "kept", b"stays");
+    put(&primary, "dropped", b"goes");
+
+    let mirror = mirror(&addr, &["--mirror-delete"]);
+    wait_for("the first sync", || read(&mirror, "dropped").is_some());
+
+    // Removed behind the mirror's back before the primary drops it too
+    fs::remove_file(mirror.files_dir().join("dropped")).unwrap();
+    let (stream, info) = primary.connect();
+    authenticate(&stream, SECRET.as_bytes()).unwrap();
+    assert_eq!(
+        delete_files(&stream, &info, &["dropped"]).unwrap(),
+        [Status::Ok]
+    );
+
+    let (stream, info) = mirror.connect();
+    wait_for("the entry to be dropped", || {
+        fetch_files(&stream, &info).unwrap() == ["kept"]
+    });
+    assert_eq!(read(&mirror, "kept").as_deref(), Some(&b"stays"[..]));
+}
@@ -0,0 +1,71 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+
+use common::{upload_damaged_part, TestServer};
+use p2p_service::{
+    complete_multipart, get_file, hash_reader, initiate_multipart, upload_part, ProtocolError,
+    MAX_PARTS, MAX_PART_LEN,
+};
+
+/// Contents that differ from part to part, so parts joined out of order would show.
+fn contents(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn parts_are_joined_in_order_after_a_retry() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+
+    let contents = contents(3 * 4000 + 123);
+    let parts: Vec<&[u8]> = contents.chunks(4000 + 41).collect();
+    assert_eq!(parts.len(), 3);
+    let hash = hash_reader(&contents[..], |_| {}).unwrap();
+
+    let id = initiate_multipart(&stream, &info, "joined", contents.len() as u64, false).unwrap();
+
+    // Sent last to first, with the middle one refused and sent again
+    upload_part(&stream, &info, &id, 3, parts[2]).unwrap();
+    upload_damaged_part(&stream, &id, 2, parts[1]);
+    upload_part(&stream, &info, &id, 1, parts[0]).unwrap();
+
+    // Not complete without the part that was refused
+    let early = complete_multipart(&stream, &info, &id, &hash);
+    assert!(early.is_err(), "{early:?}");
+
+    upload_part(&stream, &info, &id, 2, parts[1]).unwrap();
+    complete_multipart(&stream, &info, &id, &hash).unwrap();
+
+    // Joined on disk, with nothing left behind once stored
+    let leftovers: Vec<_> = fs::read_dir(server.files_dir().join(".tmp"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name != "multipart")
+        .collect();
+    assert!(leftovers.is_empty(), "{leftovers:?}");
+
+    let stored = fs::read(server.files_dir().join("joined")).unwrap();
+    assert_eq!(hash_reader(&stored[..], |_| {}).unwrap(), hash);
+    assert_eq!(
+        get_file(&stream, &info, "joined").unwrap().unwrap(),
+        contents
+    );
+}
+
+#[test]
+fn uploads_too_big_to_send_in_parts_are_refused() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+
+    let most = MAX_PARTS as u64 * MAX_PART_LEN as u64;
+    match initiate_multipart(&stream, &info, "huge", most + 1, false) {
+        Err(ProtocolError::InvalidRequest(msg)) => assert!(msg.contains("parts of"), "{msg}"),
+        other => panic!("expected the upload to be refused, got {other:?}"),
+    }
+
+    // The connection carries on
+    initiate_multipart(&stream, &info, "small", 10, false).unwrap();
+}
@@ -0,0 +1,45 @@
+//! A chunk that owns its connection, kept for a whole session.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::net::UnixStream;
+
+use common::TestServer;
+use p2p_service::{
+    disconnect, get_file, handshake, op, read_compressed, read_response, send_reader, start_upload,
+    write_op, ConnectionInfo, OwnedChunk, Transport,
+};
+
+fn upload(chunk: &mut OwnedChunk<1024, UnixStream>, info: &ConnectionInfo, name: &str) {
+    start_upload(chunk, info, op::ADD_FILE, name, false).unwrap();
+    send_reader(chunk, name.as_bytes(), name.len()).unwrap();
+    read_response(chunk).unwrap();
+}
+
+#[test]
+fn one_owned_chunk_serves_a_session() {
+    let server = TestServer::start(&[]);
+    let stream = server.connect_raw();
+    let info = handshake(&stream).unwrap();
+    let mut chunk = OwnedChunk::<1024, UnixStream>::owned(stream);
+
+    upload(&mut chunk, &info, "a.txt");
+    write_op(&mut chunk, op::KEEP_ALIVE).unwrap();
+    upload(&mut chunk, &info, "b.txt");
+
+    write_op(&mut chunk, op::FETCH_FILES).unwrap();
+    read_response(&mut chunk).unwrap();
+    let mut files: Vec<String> = read_compressed(&mut chunk).unwrap();
+    files.sort();
+    assert_eq!(files, ["a.txt", "b.txt"]);
+
+    // Requests made on the stream itself go over the same connection
+    let contents = get_file(chunk.stream(), &info, "b.txt").unwrap();
+    assert_eq!(contents.unwrap(), b"b.txt");
+
+    // And it is closed through the chunk, the server hanging up in turn
+    disconnect(chunk.stream());
+    assert_eq!(Transport::read(chunk.stream(), &mut [0; 1]).unwrap(), 0);
+}
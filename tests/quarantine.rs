@@ -0,0 +1,119 @@
+//! Uploads that fail their checksum, kept in quarantine with `--quarantine`.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, path::PathBuf, thread, time::Duration};
+
+use common::{upload_damaged_part, TestServer};
+use p2p_service::{
+    complete_multipart, fetch_files, hash_reader, initiate_multipart, upload_part, ProtocolError,
+};
+
+const PART_LEN: usize = 4000;
+
+/// Files in quarantine with their contents, oldest first.
+fn quarantined(server: &TestServer) -> Vec<(String, Vec<u8>)> {
+    let Ok(entries) = fs::read_dir(server.dir().join("server_quarantine")) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries.map(|entry| entry.unwrap().path()).collect();
+    paths.sort_by_key(|path| fs::metadata(path).unwrap().modified().unwrap());
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(path).unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn damaged_part_lands_in_quarantine() {
+    let server = TestServer::start(&["--quarantine", "1000000"]);
+    let (stream, info) = server.connect();
+
+    let id = initiate_multipart(&stream, &info, "a.bin", PART_LEN as u64, false).unwrap();
+    upload_damaged_part(&stream, &id, 1, &[7; PART_LEN]);
+
+    let kept = quarantined(&server);
+    assert_eq!(kept.len(), 1);
+    assert!(
+        kept[0].0.starts_with(&format!("a.bin.{id}.part1.")),
+        "{}",
+        kept[0].0
+    );
+    assert_eq!(kept[0].1, [7; PART_LEN]);
+
+    // Not stored, and the part has to be sent again
+    let hash = hash_reader(&[7; PART_LEN][..], |_| {}).unwrap();
+    assert!(complete_multipart(&stream, &info, &id, &hash).is_err());
+    assert!(fetch_files(&stream, &info).unwrap().is_empty());
+    assert!(!server.files_dir().join("a.bin").exists());
+}
+
+#[test]
+fn file_that_fails_once_joined_lands_in_quarantine() {
+    let server = TestServer::start(&["--quarantine", "1000000"]);
+    let (stream, info) = server.connect();
+
+    let id = initiate_multipart(&stream, &info, "a.bin", 2 * PART_LEN as u64, false).unwrap();
+    upload_part(&stream, &info, &id, 1, &[1; PART_LEN]).unwrap();
+    upload_part(&stream, &info, &id, 2, &[2; PART_LEN]).unwrap();
+
+    let wrong = hash_reader(&b"something else"[..], |_| {}).unwrap();
+    match complete_multipart(&stream, &info, &id, &wrong) {
+        Err(ProtocolError::ChecksumMismatch(_)) => {}
+        other => panic!("expected a checksum mismatch, got {other:?}"),
+    }
+
+    let kept = quarantined(&server);
+    assert_eq!(kept.len(), 1);
+    assert!(
+        kept[0].0.starts_with(&format!("a.bin.{id}.")),
+        "{}",
+        kept[0].0
+    );
+    assert_eq!(kept[0].1, [[1; PART_LEN], [2; PART_LEN]].concat());
+
+    assert!(fetch_files(&stream, &info).unwrap().is_empty());
+    assert!(!server.files_dir().join("a.bin").exists());
+}
+
+#[test]
+fn oldest_are_removed_to_stay_under_the_cap() {
+    let cap = 2 * PART_LEN + PART_LEN / 2;
+    let server = TestServer::start(&["--quarantine", &cap.to_string()]);
+    let (stream, info) = server.connect();
+
+    let id = initiate_multipart(&stream, &info, "a.bin", 3 * PART_LEN as u64, false).unwrap();
+    for number in 1..=3 {
+        upload_damaged_part(&stream, &id, number, &[number as u8; PART_LEN]);
+        // Far enough apart to be ordered by modification time
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let kept: Vec<Vec<u8>> = quarantined(&server)
+        .into_iter()
+        .map(|(_, contents)| contents)
+        .collect();
+    assert_eq!(kept, [[2; PART_LEN], [3; PART_LEN]]);
+
+    // Too big to keep at all, and nothing is removed for it
+    let id = initiate_multipart(&stream, &info, "b.bin", 3 * PART_LEN as u64, false).unwrap();
+    upload_damaged_part(&stream, &id, 1, &[9; 3 * PART_LEN]);
+    assert_eq!(quarantined(&server).len(), 2);
+}
+
+#[test]
+fn damaged_parts_are_deleted_by_default() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+
+    let id = initiate_multipart(&stream, &info, "a.bin", PART_LEN as u64, false).unwrap();
+    upload_damaged_part(&stream, &id, 1, &[7; PART_LEN]);
+
+    assert!(!server.dir().join("server_quarantine").exists());
+}
@@ -0,0 +1,62 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{os::unix::net::UnixStream, time::Duration};
+
+use common::{upload, TestServer};
+use p2p_service::{op, read_response, start_upload, write_usize, Chunk, ProtocolError};
+
+#[test]
+fn replacing_a_file_only_counts_the_difference() {
+    let server = TestServer::start(&["--quota", "100"]);
+    let (stream, info) = server.connect();
+
+    upload(&stream, &info, "a", &[1; 80], false).unwrap();
+    // 80 + 90 is over the quota, but the 80 bytes are freed by the replacement
+    upload(&stream, &info, "a", &[2; 90], false).unwrap();
+
+    // Refused before the payload, which would race the server closing the connection
+    let mut chunk = Chunk::<1024, UnixStream>::new(&stream);
+    start_upload(&mut chunk, &info, op::ADD_FILE, "b", false).unwrap();
+    write_usize(&mut chunk, 20).unwrap();
+    assert!(matches!(
+        read_response(&mut chunk),
+        Err(ProtocolError::Denied(_))
+    ));
+
+    // The connection is closed after the refusal
+    let (stream, info) = server.connect();
+    upload(&stream, &info, "b", &[3; 10], false).unwrap();
+}
+
+#[test]
+fn compressed_files_count_what_they_take_on_disk() {
+    let server = TestServer::start(&["--quota", "1000", "--compress-storage"]);
+    let (stream, info) = server.connect();
+
+    // Each is too big to fit twice at its full size, but compresses to almost nothing
+    for name in ["a", "b", "c", "a"] {
+        upload(&stream, &info, name, &[0; 900], false).unwrap();
+    }
+}
+
+#[test]
+fn over_quota_uploads_are_refused_before_the_payload() {
+    let server = TestServer::start(&["--quota", "100"]);
+    let (stream, info) = server.connect();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    // Only the size is sent, the answer has to come without waiting for the rest
+    let mut chunk = Chunk::<1024, UnixStream>::new(&stream);
+    start_upload(&mut chunk, &info, op::ADD_FILE, "big", false).unwrap();
+    write_usize(&mut chunk, 1000).unwrap();
+
+    assert!(matches!(
+        read_response(&mut chunk),
+        Err(ProtocolError::Denied(_))
+    ));
+    assert!(!server.files_dir().join("big").exists());
+}
@@ -0,0 +1,53 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::{fs, os::unix::net::UnixStream};
+
+use common::TestServer;
+use p2p_service::{get_file, op, read_response, send_stream, start_upload, Chunk};
+
+/// Upload `contents` as `file_name` with `op::ADD_FILE_STREAM`.
+fn upload_stream(server: &TestServer, file_name: &str, contents: &[u8]) {
+    let (stream, info) = server.connect();
+    let mut chunk = Chunk::<1024, UnixStream>::new(&stream);
+
+    start_upload(&mut chunk, &info, op::ADD_FILE_STREAM, file_name, false).unwrap();
+    send_stream(&mut chunk, contents).unwrap();
+    read_response(&mut chunk).unwrap();
+}
+
+/// Contents that don't repeat every frame, so misordered frames would show.
+fn contents(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn streams_larger_than_a_buffer_are_stored_whole() {
+    let server = TestServer::start(&[]);
+    let contents = contents(256 * 1024 + 17);
+
+    upload_stream(&server, "big", &contents);
+
+    assert_eq!(fs::read(server.files_dir().join("big")).unwrap(), contents);
+    // Received into a temporary file, which was moved into place
+    let leftovers = fs::read_dir(server.files_dir().join(".tmp"))
+        .unwrap()
+        .count();
+    assert_eq!(leftovers, 0);
+}
+
+#[test]
+fn streams_are_compressed_like_other_uploads() {
+    let server = TestServer::start(&["--compress-storage"]);
+    let contents = contents(64 * 1024);
+
+    upload_stream(&server, "big", &contents);
+
+    let stored = fs::read(server.files_dir().join("big")).unwrap();
+    assert_eq!(&stored[..2], [0x1f, 0x8b], "not gzipped");
+
+    let (stream, info) = server.connect();
+    let fetched = get_file(&stream, &info, "big").unwrap();
+    assert_eq!(fetched.unwrap(), contents);
+}
@@ -0,0 +1,52 @@
+//! Temporary files a crashed server leaves behind, and where new ones go.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+
+use common::{upload, TestServer};
+
+#[test]
+fn leftovers_are_removed_on_startup() {
+    let server = TestServer::start_with(
+        |dir| {
+            let files = dir.join("server_files");
+            fs::create_dir_all(files.join(".tmp/multipart/0123abcd")).unwrap();
+
+            fs::write(files.join(".partial-old.txt"), b"old").unwrap();
+            fs::write(files.join(".tmp/upload-a.txt"), b"upload").unwrap();
+            fs::write(files.join(".tmp/stream-3"), b"stream").unwrap();
+            fs::write(files.join(".tmp/index"), b"{}").unwrap();
+            fs::write(files.join(".tmp/multipart/0123abcd/1"), b"part").unwrap();
+            fs::write(dir.join("server_index.json.tmp"), b"{").unwrap();
+
+            fs::write(files.join("kept.txt"), b"kept").unwrap();
+        },
+        &[],
+    );
+
+    let left: Vec<_> = fs::read_dir(server.files_dir().join(".tmp"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert!(left.is_empty(), "{left:?}");
+    assert!(!server.files_dir().join(".partial-old.txt").exists());
+    assert!(!server.dir().join("server_index.json.tmp").exists());
+
+    assert!(server.files_dir().join("kept.txt").exists());
+}
+
+#[test]
+fn index_is_written_next_to_the_saved_one() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+
+    upload(&stream, &info, "a.txt", b"hello", false).unwrap();
+
+    let index = fs::read_to_string(server.dir().join("server_index.json")).unwrap();
+    assert!(index.contains("a.txt"), "{index}");
+    assert!(!server.dir().join("server_index.json.tmp").exists());
+    assert!(!server.files_dir().join(".tmp/index").exists());
+}
@@ -0,0 +1,46 @@
+//! Files uploaded under a name other than their own, as with the client's `--as`.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+
+use common::{temp_dir, upload, TestServer};
+use p2p_service::{fetch_files, get_file};
+
+#[test]
+fn local_file_is_stored_under_the_name_given() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+
+    let dir = temp_dir("upload-name");
+    let local = dir.join("local.txt");
+    fs::write(&local, b"from local").unwrap();
+
+    upload(&stream, &info, "remote.txt", &fs::read(&local).unwrap(), false).unwrap();
+
+    assert_eq!(fetch_files(&stream, &info).unwrap(), ["remote.txt"]);
+    assert_eq!(
+        fs::read(server.files_dir().join("remote.txt")).unwrap(),
+        b"from local"
+    );
+    assert!(!server.files_dir().join("local.txt").exists());
+    assert_eq!(
+        get_file(&stream, &info, "remote.txt").unwrap().unwrap(),
+        b"from local"
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn given_name_is_sanitized_to_its_last_part() {
+    let server = TestServer::start(&[]);
+    let (stream, info) = server.connect();
+
+    upload(&stream, &info, "../elsewhere/remote.txt", b"contents", false).unwrap();
+
+    assert_eq!(fetch_files(&stream, &info).unwrap(), ["remote.txt"]);
+    assert!(!server.dir().join("elsewhere").exists());
+}
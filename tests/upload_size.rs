@@ -0,0 +1,105 @@
+//! Sized uploads whose bytes don't match the size they announced.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    io::Read,
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    time::{Duration, Instant},
+};
+
+use common::{upload, TestServer};
+use p2p_service::{
+    fetch_files, get_file, handshake, op, read_response, start_upload, write_usize, Chunk,
+    ProtocolError,
+};
+
+const FILE_NAME: &str = "sized.bin";
+
+/// Start an upload announcing `announced` bytes and send `sent` of them.
+fn send_sized<'a>(
+    stream: &'a UnixStream,
+    announced: usize,
+    sent: &[u8],
+) -> Chunk<'a, 1024, UnixStream> {
+    let info = handshake(stream).unwrap();
+    let mut chunk = Chunk::<1024, UnixStream>::new(stream);
+
+    start_upload(&mut chunk, &info, op::ADD_FILE, FILE_NAME, false).unwrap();
+    write_usize(&mut chunk, announced).unwrap();
+    chunk.write_and_send(sent).unwrap();
+    chunk
+}
+
+/// Nothing is stored under `FILE_NAME`, and the server still takes uploads.
+fn assert_not_stored(server: &TestServer) {
+    assert!(!server.files_dir().join(FILE_NAME).exists());
+
+    let (stream, info) = server.connect();
+    assert!(fetch_files(&stream, &info).unwrap().is_empty());
+    upload(&stream, &info, "after", b"fine", false).unwrap();
+}
+
+#[test]
+fn upload_short_of_its_size_is_not_stored() {
+    let server = TestServer::start(&[]);
+    let stream = server.connect_raw();
+
+    // Half of it, then nothing more will come
+    let chunk = send_sized(&stream, 10, &[1; 5]);
+    stream.shutdown(Shutdown::Write).unwrap();
+    drop(chunk);
+
+    // The server gives up on the connection without storing anything
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut rest = Vec::new();
+    (&stream).read_to_end(&mut rest).unwrap();
+
+    assert_not_stored(&server);
+}
+
+#[test]
+fn bytes_past_the_announced_size_are_not_stored() {
+    let server = TestServer::start(&[]);
+    let stream = server.connect_raw();
+
+    // Five bytes announced, then five more that aren't part of the file
+    let mut sent = b"01234".to_vec();
+    sent.extend_from_slice(&[0xee; 5]);
+    let mut chunk = send_sized(&stream, 5, &sent);
+    // The extra bytes are taken as the next request, after the file is stored
+    read_response(&mut chunk).unwrap();
+
+    let (stream, info) = server.connect();
+    let stored = get_file(&stream, &info, FILE_NAME).unwrap();
+    assert_eq!(stored.unwrap(), b"01234");
+}
+
+#[test]
+fn stalled_upload_is_given_up_on() {
+    let server = TestServer::start(&["--transfer-timeout", "1"]);
+    let stream = server.connect_raw();
+
+    // Half of it, with the connection left open
+    let started = Instant::now();
+    let mut chunk = send_sized(&stream, 10, &[1; 5]);
+    chunk.set_deadline(Some(Duration::from_secs(10))).unwrap();
+
+    match read_response(&mut chunk) {
+        Err(ProtocolError::IncompleteUpload(msg)) => assert!(msg.contains("5 bytes"), "{msg}"),
+        other => panic!("expected an incomplete upload, got {other:?}"),
+    }
+    assert!(started.elapsed() < Duration::from_secs(10));
+
+    // And the connection closed
+    let mut rest = Vec::new();
+    (&stream).read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    assert_not_stored(&server);
+}
@@ -0,0 +1,109 @@
+//! Files fetched over HTTP and uploaded by the client's `--upload-url`, against a local
+//! fixture standing in for the web server.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    process::{Command, Output},
+    thread,
+};
+
+use common::{temp_dir, TestServer};
+use p2p_service::fetch_files;
+
+const CONTENTS: &[u8] = b"fetched from the web";
+
+/// Serve `/file` in chunks, redirect `/old` to it and answer anything else with 404.
+///
+/// Returns the fixture's address.
+fn http_fixture() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            // The whole request is read, closing on the client part way through would fail it
+            let mut lines = BufReader::new(&stream).lines();
+            let request = lines.next().unwrap().unwrap();
+            for line in lines {
+                if line.unwrap().is_empty() {
+                    break;
+                }
+            }
+
+            let path = request.split(' ').nth(1).unwrap_or_default();
+            let response = match path {
+                "/file" => {
+                    let (head, tail) = CONTENTS.split_at(7);
+                    let mut response =
+                        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+                    for chunk in [head, tail] {
+                        response.extend(format!("{:x}\r\n", chunk.len()).as_bytes());
+                        response.extend(chunk);
+                        response.extend(b"\r\n");
+                    }
+                    response.extend(b"0\r\n\r\n");
+                    response
+                }
+                "/old" => {
+                    b"HTTP/1.1 301 Moved\r\nLocation: /file\r\nContent-Length: 0\r\n\r\n".to_vec()
+                }
+                _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            };
+            _ = stream.write_all(&response);
+        }
+    });
+
+    addr
+}
+
+/// Run the client in a directory of its own against `server`.
+fn client(server: &TestServer, args: &[&str]) -> Output {
+    let dir = temp_dir("client");
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .current_dir(&dir)
+        .arg("--unix")
+        .arg(server.dir().join("server.sock"))
+        .args(args)
+        .output()
+        .unwrap();
+
+    fs::remove_dir_all(dir).unwrap();
+    output
+}
+
+#[test]
+fn url_is_uploaded_under_the_name_given() {
+    let server = TestServer::start(&[]);
+    let url = format!("http://{}/old", http_fixture());
+
+    let output = client(&server, &["--upload-url", &url, "--as", "web.txt"]);
+    assert!(output.status.success(), "{output:?}");
+
+    assert_eq!(
+        fs::read(server.files_dir().join("web.txt")).unwrap(),
+        CONTENTS
+    );
+}
+
+#[test]
+fn missing_url_fails_before_uploading() {
+    let server = TestServer::start(&[]);
+    let url = format!("http://{}/missing", http_fixture());
+
+    let output = client(&server, &["--upload-url", &url, "--as", "web.txt"]);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("404"),
+        "{output:?}"
+    );
+
+    let (stream, info) = server.connect();
+    assert!(fetch_files(&stream, &info).unwrap().is_empty());
+}
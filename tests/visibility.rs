@@ -0,0 +1,147 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+
+use common::TestServer;
+use p2p_service::{
+    authenticate, delete_files, fetch_files, get_file, get_file_if_changed, get_files, hash_reader,
+    Fetched, Status,
+};
+
+const ADMIN_SECRET: &str = "admin";
+
+/// A server with "public" and "secret", the second private to "alice".
+fn server() -> TestServer {
+    TestServer::start_with(
+        |dir| {
+            fs::write(dir.join("server_files/public"), "for everyone").unwrap();
+            fs::write(dir.join("server_files/secret"), "for alice").unwrap();
+            fs::write(
+                dir.join("server_index.json"),
+                r#"{"secret": {"owner": "alice", "private": true}}"#,
+            )
+            .unwrap();
+        },
+        &["--admin-secret", ADMIN_SECRET],
+    )
+}
+
+#[test]
+fn private_files_are_hidden_from_others() {
+    let server = server();
+    let (stream, info) = server.connect();
+
+    let files = fetch_files(&stream, &info).unwrap();
+    assert_eq!(files, ["public"]);
+
+    assert_eq!(
+        get_file(&stream, &info, "public").unwrap().as_deref(),
+        Some(&b"for everyone"[..])
+    );
+    assert_eq!(get_file(&stream, &info, "secret").unwrap(), None);
+}
+
+#[test]
+fn private_files_are_visible_to_admins() {
+    let server = server();
+    let (stream, info) = server.connect();
+    authenticate(&stream, ADMIN_SECRET.as_bytes()).unwrap();
+
+    let mut files = fetch_files(&stream, &info).unwrap();
+    files.sort();
+    assert_eq!(files, ["public", "secret"]);
+    assert_eq!(
+        get_file(&stream, &info, "secret").unwrap().as_deref(),
+        Some(&b"for alice"[..])
+    );
+}
+
+/// Names that resolve to a private file, or to something outside the stored files.
+const BYPASSES: [&str; 5] = [
+    "./secret",
+    "x/../secret",
+    "secret/",
+    "../server_index.json",
+    "../server_files/secret",
+];
+
+#[test]
+fn get_file_refuses_paths() {
+    let server = server();
+    let (stream, info) = server.connect();
+
+    for name in BYPASSES {
+        assert_eq!(get_file(&stream, &info, name).unwrap(), None, "{name}");
+    }
+    // Public files aren't reachable under another name either
+    assert_eq!(get_file(&stream, &info, "./public").unwrap(), None);
+}
+
+#[test]
+fn get_files_refuses_private_files_and_paths() {
+    let server = server();
+    let (stream, info) = server.connect();
+
+    let mut names = vec!["secret", "public"];
+    names.extend(BYPASSES);
+
+    let files = get_files(&stream, &info, &names).unwrap();
+    for (name, result) in &files {
+        match name.as_str() {
+            "public" => assert_eq!(result.as_ref().unwrap(), b"for everyone"),
+            _ => assert!(result.is_err(), "{name} was sent"),
+        }
+    }
+}
+
+#[test]
+fn get_file_if_changed_refuses_private_files_and_paths() {
+    let server = server();
+    let (stream, info) = server.connect();
+
+    for name in ["secret"].into_iter().chain(BYPASSES) {
+        match get_file_if_changed(&stream, &info, name, "").unwrap() {
+            Fetched::Changed(None) => {}
+            _ => panic!("{name} was sent"),
+        }
+    }
+}
+
+#[test]
+fn get_file_if_changed_does_not_confirm_private_contents() {
+    let server = server();
+    let (stream, info) = server.connect();
+
+    // Not modified would tell a non-owner what the private file holds
+    let hash = hash_reader(&b"for alice"[..], |_| {}).unwrap();
+    match get_file_if_changed(&stream, &info, "secret", &hash).unwrap() {
+        Fetched::Changed(None) => {}
+        _ => panic!("private file was matched"),
+    }
+}
+
+#[test]
+fn delete_files_reports_each_name() {
+    let server = server();
+    let (stream, info) = server.connect();
+
+    let names = ["public", "missing", "secret", "../server_index.json"];
+    let statuses = delete_files(&stream, &info, &names).unwrap();
+    assert_eq!(
+        statuses,
+        [
+            Status::Ok,
+            Status::NotFound,
+            // Someone else's private file is as good as missing
+            Status::NotFound,
+            Status::InvalidRequest,
+        ]
+    );
+
+    assert!(!server.files_dir().join("public").exists());
+    assert!(server.files_dir().join("secret").exists());
+    assert!(server.dir().join("server_index.json").exists());
+    assert!(fetch_files(&stream, &info).unwrap().is_empty());
+}
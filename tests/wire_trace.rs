@@ -0,0 +1,59 @@
+//! The protocol events `--wire-trace` writes for each connection.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+
+use common::{upload, wait_for, TestServer};
+use p2p_service::op;
+
+#[test]
+fn upload_is_traced_event_by_event() {
+    let server = TestServer::start(&["--wire-trace", "trace.log"]);
+    let (stream, info) = server.connect();
+    upload(&stream, &info, "a.txt", b"hello", false).unwrap();
+
+    // Written as the server goes, the last of it can land after the client has its answer
+    let path = server.dir().join("trace.log");
+    let mut trace = String::new();
+    wait_for("the upload's status in the trace", || {
+        trace = fs::read_to_string(&path).unwrap_or_default();
+        trace.contains("write status")
+    });
+
+    let start = trace.find(&format!("read op {}", op::ADD_FILE)).unwrap();
+    let start = trace[..start].rfind('\n').unwrap() + 1;
+    let lines: Vec<_> = trace[start..].lines().collect();
+    let events: Vec<_> = lines
+        .iter()
+        .map(|line| {
+            let (_, rest) = line.split_once(" out@").unwrap();
+            rest.split_once(' ').unwrap().1
+        })
+        .collect();
+
+    assert_eq!(
+        events[..9],
+        [
+            format!("read op {}", op::ADD_FILE).as_str(),
+            "read 8 bytes",
+            "read size 5",
+            "read 5 bytes",
+            "read 1 bytes",
+            "read 8 bytes",
+            "read size 5",
+            "read 5 bytes",
+            "write status Ok \"\"",
+        ]
+    );
+
+    // The handshake, then the op, the name, whether it's private and the contents
+    let received = 2 + 1 + (8 + 5) + 1 + (8 + 5);
+    assert!(
+        lines[8].contains(&format!(" in@{received} ")),
+        "{}",
+        lines[8]
+    );
+}